@@ -34,15 +34,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // to support it (or learn how to use super-dense map reduces)
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
     let _local_addr = listener.local_addr()?;
-    let mut incoming = listener.incoming();
+    let mut incoming = oc_http::accept::resilient(listener.incoming());
     // Accepting incoming reqeusts
     while let Some(stream) = incoming.next().await {
         // consider that I have 12 cores; so a single thread would need to run at 1/12 of my total CPU to
         // block other threads; therefore, this thread could do 1/12 of what a single thread does in order
         // to become the bottleneck.. Which is a fair bit, so don't be stingy.
-        if let Ok(stream) = stream {
-            task::spawn(handle_request(stream));
-        }
+        task::spawn(handle_request(stream));
     }
     Ok(())
 }
@@ -72,7 +70,7 @@ where S: AsyncRead + AsyncWrite + Clone + Unpin
     } else if request.path == "/echo" && request.method == "POST" {
         post_echo(&mut reader, &mut writer).await;
         if let Some(_c) = cookies.get("Who") {
-            writer.write(format!("You are a fool of a took!").as_bytes()).await.unwrap();
+            writer.write_all(b"You are a fool of a took!").await.unwrap();
         }
     } else {
         let mut res = oc_http::Response{
@@ -95,7 +93,7 @@ where S: AsyncWrite + Unpin
         reason: "OK",
         headers: vec!(),
     }).await.unwrap();
-    stream.write(b"
+    stream.write_all(b"
 <html>
     <body>
         <form method=\"POST\">
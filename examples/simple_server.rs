@@ -26,12 +26,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // start the server; this uses standard stdlib-esque tools rather than saving a few
     // lines by just sending the ToSocketAddr item.
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
-    let mut incoming = listener.incoming();
+    let mut incoming = oc_http::accept::resilient(listener.incoming());
     // Accepting incoming reqeusts
     while let Some(stream) = incoming.next().await {
-        if let Ok(stream) = stream {
-            task::spawn(handle_request(stream));
-        }
+        task::spawn(handle_request(stream));
     }
     Ok(())
 }
@@ -57,7 +55,7 @@ where S: AsyncRead + AsyncWrite + Clone + Unpin
         headers: vec!(),
     }).await.unwrap();
     // after sending the HTTP header, we can write anything to the body
-    writer.write(b"
+    writer.write_all(b"
 <html>
     <body>
         <h1>Hello world!</h1>
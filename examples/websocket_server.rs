@@ -36,12 +36,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // to support it (or learn how to use super-dense map reduces)
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
     let _local_addr = listener.local_addr()?;
-    let mut incoming = listener.incoming();
+    let mut incoming = oc_http::accept::resilient(listener.incoming());
     // Accepting incoming reqeusts
     while let Some(stream) = incoming.next().await {
-        if let Ok(stream) = stream {
-            task::spawn(handle_request(stream));
-        }
+        task::spawn(handle_request(stream));
     }
     Ok(())
 }
@@ -64,8 +62,9 @@ where S: AsyncRead + AsyncWrite + Clone + Unpin
     };
     // make sure it goes to /ws
     if request.path == "/ws" && request.method == "GET" {
-        let ws = websocket::upgrade(&request, stream).await.unwrap();
-        handle_websocket(ws.0, ws.1).await;
+        // the client may have sent its first frame along with the request
+        let (rdr, wrt) = websocket::upgrade(&request, stream, reader.buffer()).await.unwrap();
+        handle_websocket(rdr, wrt).await;
     } else {
         let mut writer = BufWriter::new(stream);
         oc_http::respond(&mut writer, oc_http::Response{
@@ -77,8 +76,12 @@ where S: AsyncRead + AsyncWrite + Clone + Unpin
     }
 }
 
-async fn handle_websocket<S>(mut rdr: WebSocketReader<S>, mut wrt: WebSocketWriter<S>)
+async fn handle_websocket<S>(mut rdr: WebSocketReader<S>, wrt: WebSocketWriter<S>)
 where S: AsyncRead + AsyncWrite + Clone + Unpin {
+    // keep the client's keepalive happy without having to look at pings ourselves; the
+    // shared writer makes sure pongs and our own messages don't interleave
+    let wrt = wrt.into_shared();
+    rdr.answer_pings(wrt.clone());
     loop {
         // this will return an error when the socket is closed;
         // oc_http::websocket::WebSocketError::ConnectionClosed
@@ -0,0 +1,161 @@
+//! Accept loops that keep going through accept() errors.
+//!
+//! Skipping failed accepts straight away spins the CPU when the process is out of file
+//! descriptors, and giving up on the first error takes the whole server down. Wrap the
+//! listener's incoming stream instead; it only yields connections, backing off while accept
+//! keeps failing:
+//!
+//! ```no_run
+//! # use async_std::{net::{TcpListener, TcpStream}, task};
+//! # use futures::prelude::*;
+//! # use log::warn;
+//! # use oc_http::accept;
+//! # async fn handle_request(stream: TcpStream) {}
+//! # async fn serve(listener: TcpListener) {
+//! let mut incoming = accept::resilient(listener.incoming())
+//!     .on_error(|err| warn!("accept failed: {}", err));
+//! while let Some(stream) = incoming.next().await {
+//!     task::spawn(handle_request(stream));
+//! }
+//! # }
+//! ```
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{
+    prelude::*,
+    ready,
+};
+use futures_timer::Delay;
+use log::warn;
+
+pub const DEFAULT_MIN_BACKOFF: Duration = Duration::from_millis(5);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+type ErrorFn = dyn FnMut(&io::Error) + Send;
+
+/// Wraps a stream of accepted connections; see the module docs.
+pub fn resilient<St>(incoming: St) -> Resilient<St> {
+    Resilient{
+        incoming,
+        on_error: Box::new(|err| warn!("accept failed: {}", err)),
+        min: DEFAULT_MIN_BACKOFF,
+        max: DEFAULT_MAX_BACKOFF,
+        backoff: None,
+        sleeping: None,
+    }
+}
+
+/// A stream of connections that outlives accept() errors.
+pub struct Resilient<St> {
+    incoming: St,
+    on_error: Box<ErrorFn>,
+    min: Duration,
+    max: Duration,
+    // how long the last wait was, while accept keeps failing
+    backoff: Option<Duration>,
+    sleeping: Option<Delay>,
+}
+
+impl<St> Resilient<St> {
+    /// Called with each error, instead of logging it.
+    pub fn on_error<F>(mut self, on_error: F) -> Self
+    where F: FnMut(&io::Error) + Send + 'static
+    {
+        self.on_error = Box::new(on_error);
+        self
+    }
+
+    /// Waits `min` after the first error, doubling up to `max` while errors keep coming.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min = min;
+        self.max = max;
+        self
+    }
+}
+
+/// errors that are about the one connection, rather than the listener or the process
+fn is_connection_error(err: &io::Error) -> bool {
+    matches!(err.kind(),
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted)
+}
+
+impl<St, T> Stream for Resilient<St>
+where St: Stream<Item = io::Result<T>> + Unpin
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(sleeping) = &mut this.sleeping {
+                ready!(sleeping.poll_unpin(cx));
+                this.sleeping = None;
+            }
+            match ready!(this.incoming.poll_next_unpin(cx)) {
+                Some(Ok(stream)) => {
+                    this.backoff = None;
+                    return Poll::Ready(Some(stream));
+                },
+                Some(Err(err)) => {
+                    (this.on_error)(&err);
+                    // the client gave up before we got to it; the next one is likely fine
+                    if is_connection_error(&err) {
+                        continue;
+                    }
+                    // e.g. EMFILE, which won't clear until some connections close
+                    let wait = this.backoff.map_or(this.min, |last| (last * 2).min(this.max));
+                    this.backoff = Some(wait);
+                    this.sleeping = Some(Delay::new(wait));
+                },
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    use super::*;
+
+    #[async_std::test]
+    async fn test_resilient() {
+        let emfile = || io::Error::from_raw_os_error(24);
+        let results = vec!(
+            Ok(1),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Ok(2),
+            Err(emfile()),
+            Err(emfile()),
+            Err(emfile()),
+            Ok(3),
+            Err(emfile()),
+            Ok(4),
+        );
+        let errors = Arc::new(Mutex::new(0));
+        let counted = errors.clone();
+        let started = Instant::now();
+        let accepted: Vec<i32> = resilient(stream::iter(results))
+            .backoff(Duration::from_millis(20), Duration::from_millis(30))
+            .on_error(move |_| *counted.lock().unwrap() += 1)
+            .collect()
+            .await;
+        assert_eq!(accepted, vec!(1, 2, 3, 4));
+        assert_eq!(*errors.lock().unwrap(), 5);
+        // 20, then 30 (capped) twice, then back to 20 after the success
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+}
@@ -0,0 +1,202 @@
+//! Access logs: one entry per response, written in whatever format the AccessLog picks.
+//!
+//! Common Log Format and JSON lines come built in. Build an entry once the response has
+//! been written:
+//!
+//! ```no_run
+//! # use std::{net::SocketAddr, time::Instant};
+//! # use oc_http::{access_log::{AccessEntry, AccessLog, CommonLog}, Request};
+//! # fn serve(request: &Request, peer: Option<SocketAddr>, body: Vec<u8>) {
+//! let log = CommonLog::new(std::io::stdout());
+//! let started = Instant::now();
+//! // ... read the request, write the response ...
+//! log.log(&AccessEntry::new(&request, peer, 200, body.len() as u64, started));
+//! # }
+//! ```
+use std::{
+    fmt::Write as _,
+    io::Write,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use time::OffsetDateTime;
+
+use crate::Request;
+
+/// What happened with one request.
+#[derive(Debug, Clone)]
+pub struct AccessEntry<'a> {
+    /// When the response finished.
+    pub time: SystemTime,
+    pub peer: Option<SocketAddr>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: usize,
+    /// Bytes of body sent.
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl<'a> AccessEntry<'a> {
+    /// An entry for a request finishing now, that started at `started`.
+    pub fn new(req: &'a Request, peer: Option<SocketAddr>, status: usize, bytes: u64, started: Instant) -> Self {
+        AccessEntry{
+            time: SystemTime::now(),
+            peer,
+            method: &req.method,
+            path: &req.path,
+            status,
+            bytes,
+            duration: started.elapsed(),
+        }
+    }
+}
+
+pub trait AccessLog: Send + Sync {
+    fn log(&self, entry: &AccessEntry);
+}
+
+impl<F: Fn(&AccessEntry) + Send + Sync> AccessLog for F {
+    fn log(&self, entry: &AccessEntry) {
+        self(entry)
+    }
+}
+
+/// Formats an entry in Common Log Format, without the trailing newline, e.g.
+/// `127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326`.
+pub fn common_log_format(entry: &AccessEntry) -> String {
+    let host = entry.peer.map_or("-".into(), |peer| peer.ip().to_string());
+    let time = OffsetDateTime::from(entry.time).format("%d/%b/%Y:%H:%M:%S %z");
+    // quotes and control characters in the path would break the line apart
+    let path: String = entry.path.escape_default().collect();
+    let bytes = match entry.bytes {
+        0 => "-".into(),
+        bytes => bytes.to_string(),
+    };
+    format!("{} - - [{}] \"{} {} HTTP/1.1\" {} {}", host, time, entry.method, path, entry.status, bytes)
+}
+
+/// Formats an entry as one line of JSON, without the trailing newline.
+pub fn json_line(entry: &AccessEntry) -> String {
+    let peer = entry.peer.map_or("null".into(), |peer| json_string(&peer.to_string()));
+    format!(
+        "{{\"time\":{},\"peer\":{},\"method\":{},\"path\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3}}}",
+        json_string(&OffsetDateTime::from(entry.time).format("%Y-%m-%dT%H:%M:%SZ")),
+        peer,
+        json_string(entry.method),
+        json_string(entry.path),
+        entry.status,
+        entry.bytes,
+        entry.duration.as_secs_f64() * 1000.0,
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Writes entries in Common Log Format, one per line.
+pub struct CommonLog<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> CommonLog<W> {
+    pub fn new(out: W) -> Self {
+        CommonLog{out: Mutex::new(out)}
+    }
+}
+
+impl<W: Write + Send> AccessLog for CommonLog<W> {
+    fn log(&self, entry: &AccessEntry) {
+        // a log line that can't be written isn't worth failing the request over
+        let _ = writeln!(self.out.lock().unwrap(), "{}", common_log_format(entry));
+    }
+}
+
+/// Writes entries as JSON, one object per line.
+pub struct JsonLines<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        JsonLines{out: Mutex::new(out)}
+    }
+}
+
+impl<W: Write + Send> AccessLog for JsonLines<W> {
+    fn log(&self, entry: &AccessEntry) {
+        let _ = writeln!(self.out.lock().unwrap(), "{}", json_line(entry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// a Write whose contents can be looked at after it's handed over
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        let entry = AccessEntry{
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(971_186_136),
+            peer: Some("127.0.0.1:4000".parse().unwrap()),
+            method: "GET",
+            path: "/a \"quoted\"\npath",
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+        };
+        let out = Shared::default();
+        let log = CommonLog::new(out.clone());
+        log.log(&entry);
+        log.log(&AccessEntry{peer: None, bytes: 0, path: "/", ..entry.clone()});
+        assert_eq!(String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /a \\\"quoted\\\"\\npath HTTP/1.1\" 200 2326\n\
+             - - - [10/Oct/2000:13:55:36 +0000] \"GET / HTTP/1.1\" 200 -\n");
+
+        let out = Shared::default();
+        JsonLines::new(out.clone()).log(&entry);
+        assert_eq!(String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            "{\"time\":\"2000-10-10T13:55:36Z\",\"peer\":\"127.0.0.1:4000\",\"method\":\"GET\",\
+             \"path\":\"/a \\\"quoted\\\"\\npath\",\"status\":200,\"bytes\":2326,\"duration_ms\":1.500}\n");
+
+        // closures work too
+        let seen = Mutex::new(vec!());
+        let log = |entry: &AccessEntry| seen.lock().unwrap().push(entry.status);
+        log.log(&entry);
+        assert_eq!(*seen.lock().unwrap(), vec!(200));
+    }
+}
@@ -0,0 +1,192 @@
+//! API keys, taken from a header or query parameter and resolved to whoever they belong to by
+//! an async lookup, e.g. against a database or cache.
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{auth::api_key::{ApiKeyAuth, KeySource}, respond, Request};
+//! # struct Db;
+//! # impl Db { async fn account_for(&self, key: String) -> Option<u64> { None } }
+//! # async fn serve(request: &Request<'_>, stream: &mut TcpStream, db: &Db) -> std::io::Result<()> {
+//! let auth = ApiKeyAuth::new(KeySource::Header("X-API-Key".into()), |key| db.account_for(key));
+//! let account = match auth.verify(&request).await {
+//!     Ok(account) => account,
+//!     Err(_) => return respond(stream, auth.challenge()).await,
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! On a Router, ApiKeyAuth::middleware does the same for every request it wraps, and hands
+//! what the key resolved to to handlers in the request's extensions.
+use std::{future::Future, sync::Arc};
+
+use futures::future::BoxFuture;
+
+use super::{unauthorized, AuthError};
+use crate::{
+    encoding::form_urldecode,
+    router::{Context, HandlerError, HandlerResult, Next},
+    Request, Response,
+};
+
+/// Where to look for the key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    Header(String),
+    Query(String),
+}
+
+pub struct ApiKeyAuth<F> {
+    sources: Vec<KeySource>,
+    lookup: F,
+}
+
+impl<F, Fut, P> ApiKeyAuth<F>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Option<P>>,
+{
+    pub fn new(source: KeySource, lookup: F) -> Self {
+        ApiKeyAuth{
+            sources: vec!(source),
+            lookup,
+        }
+    }
+
+    /// Also looks here, if the key isn't in the places added before.
+    pub fn or(mut self, source: KeySource) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// The key the request carries, from the first source that has one; an empty value
+    /// counts as none.
+    pub fn key(&self, req: &Request) -> Option<String> {
+        self.sources.iter().find_map(|source| match source {
+            KeySource::Header(name) => req.header(name)
+                .and_then(|value| std::str::from_utf8(value).ok())
+                .map(|value| value.trim().to_string())
+                .filter(|key| !key.is_empty()),
+            KeySource::Query(name) => req.path.split_once('?')
+                .and_then(|(_, query)| form_urldecode(query.as_bytes()).into_iter()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v))
+                .filter(|key| !key.is_empty()),
+        })
+    }
+
+    /// Whatever the lookup resolved the request's key to.
+    pub async fn verify(&self, req: &Request<'_>) -> Result<P, AuthError> {
+        let key = self.key(req).ok_or(AuthError::Missing)?;
+        (self.lookup)(key).await.ok_or(AuthError::Invalid)
+    }
+
+    /// The 401 to send when verify fails.
+    pub fn challenge(&self) -> Response {
+        unauthorized("ApiKey")
+    }
+}
+
+impl<F, Fut, P> ApiKeyAuth<F>
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Option<P>> + Send,
+    P: Send + 'static,
+{
+    /// Router middleware letting in requests whose key resolves, with what it resolved to in
+    /// the request's extensions for the handler; the rest get the challenge.
+    pub fn middleware(self) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static {
+        let auth = Arc::new(self);
+        move |cx, next| {
+            let auth = auth.clone();
+            Box::pin(async move { auth.run(cx, next).await })
+        }
+    }
+
+    async fn run<'a>(&'a self, cx: &'a Context<'a>, next: Next<'a>) -> HandlerResult {
+        match self.verify(cx.request).await {
+            Ok(principal) => {
+                cx.extensions.insert(principal);
+                next.run(cx).await
+            },
+            Err(err) => Err(HandlerError::from_response(self.challenge(), err.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{ready, Ready};
+
+    use super::*;
+
+    type Lookup = fn(String) -> Ready<Option<&'static str>>;
+
+    /// the header, then the query, with one good key
+    fn auth() -> ApiKeyAuth<Lookup> {
+        let lookup: Lookup = |key| ready((key == "k1").then_some("account 1"));
+        ApiKeyAuth::new(KeySource::Header("X-API-Key".into()), lookup)
+            .or(KeySource::Query("api_key".into()))
+    }
+
+    #[async_std::test]
+    async fn test_header() {
+        let req = Request::builder().header("X-API-Key", " k1 ");
+        assert_eq!(auth().verify(&req.request()).await, Ok("account 1"));
+        let req = Request::builder().header("X-API-Key", "k2");
+        assert_eq!(auth().verify(&req.request()).await, Err(AuthError::Invalid));
+        // it has to be the header named
+        let req = Request::builder().header("Authorization", "k1");
+        assert_eq!(auth().verify(&req.request()).await, Err(AuthError::Missing));
+    }
+
+    #[async_std::test]
+    async fn test_query() {
+        let req = Request::builder().path("/x?a=b&api_key=k1");
+        assert_eq!(auth().verify(&req.request()).await, Ok("account 1"));
+        let req = Request::builder().path("/x?api_key=");
+        assert_eq!(auth().verify(&req.request()).await, Err(AuthError::Missing));
+    }
+
+    #[async_std::test]
+    async fn test_source_order() {
+        // the header wins
+        let req = Request::builder().path("/x?api_key=k1").header("X-API-Key", "k2");
+        assert_eq!(auth().verify(&req.request()).await, Err(AuthError::Invalid));
+        // but an empty one doesn't hide the key in the query
+        let req = Request::builder().path("/x?api_key=k1").header("X-API-Key", " ");
+        assert_eq!(auth().verify(&req.request()).await, Ok("account 1"));
+        assert_eq!(auth().verify(&Request::builder().request()).await, Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn test_challenge() {
+        let challenge = auth().challenge();
+        assert_eq!(challenge.code, 401);
+        assert_eq!(challenge.headers[0], ("WWW-Authenticate".into(), Vec::from("ApiKey")));
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        use crate::router::Router;
+
+        #[derive(Clone)]
+        struct Account(u32);
+        let auth = ApiKeyAuth::new(KeySource::Header("X-API-Key".into()), |key| async move {
+            (key == "k1").then_some(Account(1))
+        });
+        let router = Router::new()
+            .wrap(auth.middleware())
+            .route("GET", "/", |cx: &Context| Box::pin(async move {
+                Ok(format!("account {}", cx.extensions.get::<Account>().unwrap().0))
+            }));
+        let authed = Request::builder().header("X-API-Key", "k1");
+        let (response, body) = router.handle(&authed.request(), b"").await;
+        assert_eq!((response.code, body), (200, Vec::from("account 1")));
+        for request in &[Request::builder(), Request::builder().header("X-API-Key", "k2")] {
+            let (response, _) = router.handle(&request.request(), b"").await;
+            assert_eq!(response.code, 401);
+            assert_eq!(response.headers[0], ("WWW-Authenticate".into(), Vec::from("ApiKey")));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Bearer token auth (RFC 6750), with the token checking left to a TokenVerifier so it can be
+//! backed by jsonwebtoken, a PASETO library or a lookup of opaque tokens.
+//!
+//! The verifier checks the signature and decodes the claims; BearerAuth then checks they
+//! haven't expired and, if configured, that they're meant for this audience. On a Router,
+//! BearerAuth::middleware does both, and hands the claims to handlers in the request's
+//! extensions.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::BoxFuture;
+
+use super::{parse_authorization, quote, unauthorized, AuthError, Authorization};
+use crate::{
+    router::{Context, HandlerError, HandlerResult, Next},
+    Request, Response,
+};
+
+/// The registered JWT claims BearerAuth checks; both default to not being set.
+pub trait Claims {
+    /// `exp`, in seconds since the Unix epoch.
+    fn exp(&self) -> Option<u64> {
+        None
+    }
+
+    /// `aud`; a token may be meant for several audiences.
+    fn aud(&self) -> Vec<&str> {
+        vec!()
+    }
+}
+
+pub trait TokenVerifier: Send + Sync {
+    type Claims: Claims;
+
+    /// The token's claims, if its signature (or whatever else proves it) is valid.
+    fn verify(&self, token: &str) -> Result<Self::Claims, AuthError>;
+}
+
+pub struct BearerAuth<V> {
+    verifier: V,
+    realm: String,
+    audience: Option<String>,
+    leeway: Duration,
+}
+
+impl<V: TokenVerifier> BearerAuth<V> {
+    pub fn new(realm: &str, verifier: V) -> Self {
+        BearerAuth{
+            verifier,
+            realm: realm.into(),
+            audience: None,
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    /// Only accepts tokens whose `aud` includes this.
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// How long after `exp` tokens are still accepted, for clocks that disagree; a minute by
+    /// default.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The claims of the request's bearer token, if it's valid.
+    pub fn verify(&self, req: &Request) -> Result<V::Claims, AuthError> {
+        let token = match parse_authorization(req) {
+            Some(Authorization::Bearer(token)) => token,
+            _ => return Err(AuthError::Missing),
+        };
+        let claims = self.verifier.verify(&token)?;
+        if let Some(exp) = claims.exp() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            if now > Duration::from_secs(exp) + self.leeway {
+                return Err(AuthError::Invalid);
+            }
+        }
+        if let Some(audience) = &self.audience {
+            if !claims.aud().contains(&&audience[..]) {
+                return Err(AuthError::Invalid);
+            }
+        }
+        Ok(claims)
+    }
+
+    /// The 401 to send when verify fails; bad tokens are reported as invalid_token.
+    pub fn challenge(&self, err: &AuthError) -> Response {
+        let realm = quote(&self.realm);
+        match err {
+            AuthError::Missing => unauthorized(&format!("Bearer realm={}", realm)),
+            AuthError::Invalid | AuthError::Stale => unauthorized(&format!("Bearer realm={}, error=\"invalid_token\"", realm)),
+        }
+    }
+}
+
+impl<V: TokenVerifier + 'static> BearerAuth<V>
+where V::Claims: Send + 'static
+{
+    /// Router middleware letting in requests with a valid token, with its claims in the
+    /// request's extensions for the handler; the rest get the challenge.
+    pub fn middleware(self) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static {
+        move |cx, next| match self.verify(cx.request) {
+            Ok(claims) => {
+                cx.extensions.insert(claims);
+                next.run(cx)
+            },
+            Err(err) => {
+                let err = HandlerError::from_response(self.challenge(&err), err.to_string());
+                Box::pin(async move { Err(err) })
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestClaims {
+        sub: String,
+        exp: Option<u64>,
+        aud: Vec<String>,
+    }
+
+    impl Claims for TestClaims {
+        fn exp(&self) -> Option<u64> {
+            self.exp
+        }
+
+        fn aud(&self) -> Vec<&str> {
+            self.aud.iter().map(|a| &a[..]).collect()
+        }
+    }
+
+    /// valid tokens look like sub.exp.aud.signed
+    struct TestVerifier;
+
+    impl TokenVerifier for TestVerifier {
+        type Claims = TestClaims;
+
+        fn verify(&self, token: &str) -> Result<TestClaims, AuthError> {
+            let parts: Vec<_> = token.split('.').collect();
+            match &parts[..] {
+                [sub, exp, aud, "signed"] => Ok(TestClaims{
+                    sub: sub.to_string(),
+                    exp: exp.parse().ok(),
+                    aud: aud.split(',').map(Into::into).collect(),
+                }),
+                _ => Err(AuthError::Invalid),
+            }
+        }
+    }
+
+    fn verify(auth: &BearerAuth<TestVerifier>, header: &str) -> Result<String, AuthError> {
+        let req = Request::builder().header("Authorization", header);
+        auth.verify(&req.request()).map(|claims| claims.sub)
+    }
+
+    #[test]
+    fn test_bearer_auth() {
+        let auth = BearerAuth::new("api", TestVerifier);
+        assert_eq!(verify(&auth, "Bearer alice.x.us.signed"), Ok("alice".into()));
+        assert_eq!(verify(&auth, "Bearer alice.x.us.forged"), Err(AuthError::Invalid));
+        assert_eq!(verify(&auth, "Basic dTpwOjE="), Err(AuthError::Missing));
+        assert_eq!(auth.verify(&Request::builder().request()).err(), Some(AuthError::Missing));
+    }
+
+    #[test]
+    fn test_audience() {
+        let auth = BearerAuth::new("api", TestVerifier).audience("us");
+        assert_eq!(verify(&auth, "Bearer alice.x.them,us.signed"), Ok("alice".into()));
+        assert_eq!(verify(&auth, "Bearer alice.x.them.signed"), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let auth = BearerAuth::new("api", TestVerifier);
+        let later = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 100;
+        assert_eq!(verify(&auth, &format!("Bearer alice.{}.us.signed", later)), Ok("alice".into()));
+        // expired, even with the leeway
+        assert_eq!(verify(&auth, "Bearer alice.1000.us.signed"), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_challenge() {
+        let auth = BearerAuth::new("api", TestVerifier);
+        let challenge = auth.challenge(&AuthError::Invalid);
+        assert_eq!(challenge.code, 401);
+        assert_eq!(challenge.headers[0].1, b"Bearer realm=\"api\", error=\"invalid_token\"");
+        assert_eq!(auth.challenge(&AuthError::Missing).headers[0].1, b"Bearer realm=\"api\"");
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        use crate::router::Router;
+
+        let router = Router::new()
+            .wrap(BearerAuth::new("api", TestVerifier).middleware())
+            .route("GET", "/", |cx: &Context| Box::pin(async move {
+                Ok(cx.extensions.with(|claims: &mut TestClaims| claims.sub.clone()).unwrap())
+            }));
+        let authed = Request::builder().header("Authorization", "Bearer alice.x.us.signed");
+        let (response, body) = router.handle(&authed.request(), b"").await;
+        assert_eq!((response.code, body), (200, Vec::from("alice")));
+        let (response, _) = router.handle(&Request::builder().request(), b"").await;
+        assert_eq!(response.code, 401);
+        assert_eq!(response.headers[0], ("WWW-Authenticate".into(), Vec::from("Bearer realm=\"api\"")));
+        let forged = Request::builder().header("Authorization", "Bearer alice.x.us.forged");
+        assert_eq!(router.handle(&forged.request(), b"").await.0.code, 401);
+    }
+}
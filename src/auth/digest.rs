@@ -0,0 +1,376 @@
+//! HTTP Digest auth (RFC 7616), for the embedded and legacy clients that don't do anything
+//! else. SHA-256 is offered first, MD5 after it for the clients that only know RFC 2617.
+//!
+//! Nonces aren't stored until they're used: each one carries its creation time and a MAC of
+//! it, so the server can tell its own nonces apart and stop accepting them after a while.
+//! Within that window, each nonce count may only be used once. Clients sending requests in
+//! parallel may have them arrive out of order, so any of the last NC_WINDOW counts below the
+//! highest seen is still accepted, once.
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use super::{parse_authorization, quote, AuthError, Authorization};
+use crate::{constant_time_eq, Request, Response};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            n if n.eq_ignore_ascii_case("MD5") => Some(Algorithm::Md5),
+            n if n.eq_ignore_ascii_case("SHA-256") => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// the hex digest of the parts joined with colons
+    fn hash(self, parts: &[&str]) -> String {
+        let joined = parts.join(":");
+        let digest = match self {
+            Algorithm::Md5 => Md5::digest(joined.as_bytes()).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(joined.as_bytes()).to_vec(),
+        };
+        digest.iter().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{:02x}", b);
+            hex
+        })
+    }
+}
+
+/// How far below the highest nonce count seen a count may arrive and still be accepted.
+pub const NC_WINDOW: u32 = 64;
+
+/// Checks Digest credentials, looking up users' passwords with a callback.
+pub struct DigestAuth<F> {
+    realm: String,
+    lookup: F,
+    algorithms: Vec<Algorithm>,
+    nonce_lifetime: Duration,
+    secret: [u8; 32],
+    used: Mutex<HashMap<String, NonceCounts>>,
+}
+
+/// the nonce counts used with one nonce
+#[derive(Default)]
+struct NonceCounts {
+    highest: u32,
+    // bit i is set once highest - i has been used
+    seen: u64,
+}
+
+impl NonceCounts {
+    /// records the count, false if it's been used or is too far behind to tell
+    fn use_count(&mut self, nc: u32) -> bool {
+        if nc == 0 {
+            return false;
+        }
+        if nc > self.highest {
+            let shift = nc - self.highest;
+            self.seen = if shift < NC_WINDOW { (self.seen << shift) | 1 } else { 1 };
+            self.highest = nc;
+            return true;
+        }
+        let behind = self.highest - nc;
+        if behind >= NC_WINDOW || self.seen & (1 << behind) != 0 {
+            return false;
+        }
+        self.seen |= 1 << behind;
+        true
+    }
+}
+
+impl<F: Fn(&str) -> Option<String>> DigestAuth<F> {
+    pub fn new(realm: &str, lookup: F) -> Self {
+        DigestAuth{
+            realm: realm.into(),
+            lookup,
+            algorithms: vec!(Algorithm::Sha256, Algorithm::Md5),
+            nonce_lifetime: Duration::from_secs(300),
+            secret: rand::random(),
+            used: Mutex::default(),
+        }
+    }
+
+    /// The algorithms to offer, most preferred first.
+    pub fn algorithms(mut self, algorithms: &[Algorithm]) -> Self {
+        self.algorithms = algorithms.into();
+        self
+    }
+
+    /// How long a nonce is accepted for; five minutes by default. Clients get a fresh one
+    /// (with stale=true) without asking the user again.
+    pub fn nonce_lifetime(mut self, lifetime: Duration) -> Self {
+        self.nonce_lifetime = lifetime;
+        self
+    }
+
+    /// The user name, if the request carries valid Digest credentials with qop=auth.
+    pub fn verify(&self, req: &Request) -> Result<String, AuthError> {
+        let params = match parse_authorization(req) {
+            Some(Authorization::Other{scheme, params}) if scheme.eq_ignore_ascii_case("Digest") => params,
+            _ => return Err(AuthError::Missing),
+        };
+        let params = parse_params(&params).ok_or(AuthError::Invalid)?;
+        let param = |name: &str| params.get(name).map(|v| &v[..]).ok_or(AuthError::Invalid);
+        let algorithm = Algorithm::from_name(params.get("algorithm").map_or("MD5", |a| &a[..]))
+            .filter(|a| self.algorithms.contains(a))
+            .ok_or(AuthError::Invalid)?;
+        let (user, nonce, uri, nc, cnonce) =
+            (param("username")?, param("nonce")?, param("uri")?, param("nc")?, param("cnonce")?);
+        if param("realm")? != self.realm || param("qop")? != "auth" || uri != req.path {
+            return Err(AuthError::Invalid);
+        }
+        let created = self.check_nonce(nonce).ok_or(AuthError::Invalid)?;
+        let pass = (self.lookup)(user).ok_or(AuthError::Invalid)?;
+        let expected = response(algorithm, user, &self.realm, &pass, &req.method, uri, nonce, nc, cnonce);
+        if !constant_time_eq(expected.as_bytes(), param("response")?.as_bytes()) {
+            return Err(AuthError::Invalid);
+        }
+        // only now, so that a correct password is needed to be told the nonce is stale
+        if created.elapsed().unwrap_or_default() > self.nonce_lifetime {
+            return Err(AuthError::Stale);
+        }
+        self.use_nonce(nonce, u32::from_str_radix(nc, 16).or(Err(AuthError::Invalid))?)?;
+        Ok(user.into())
+    }
+
+    /// The 401 to send when verify fails, with a challenge for each algorithm.
+    pub fn challenge(&self, err: &AuthError) -> Response {
+        let nonce = self.new_nonce();
+        let mut headers: Vec<(String, Vec<u8>)> = self.algorithms.iter()
+            .map(|algorithm| {
+                let mut challenge = format!(
+                    "Digest realm={}, qop=\"auth\", algorithm={}, nonce=\"{}\"",
+                    quote(&self.realm), algorithm.name(), nonce);
+                if *err == AuthError::Stale {
+                    challenge.push_str(", stale=true");
+                }
+                ("WWW-Authenticate".into(), challenge.into_bytes())
+            })
+            .collect();
+        headers.push(("Content-Length".into(), Vec::from("0")));
+        Response{
+            code: 401,
+            reason: "Unauthorized",
+            headers,
+        }
+    }
+
+    fn nonce_mac(&self, created: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("hmac takes keys of any size");
+        mac.update(created);
+        mac
+    }
+
+    /// the creation time in milliseconds, then the first half of its MAC
+    fn new_nonce(&self) -> String {
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut nonce = Vec::from(&created.to_be_bytes()[..]);
+        nonce.extend(&self.nonce_mac(&nonce).finalize().into_bytes()[..16]);
+        base64::encode_config(nonce, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// when the nonce was made, if it was made by us
+    fn check_nonce(&self, nonce: &str) -> Option<SystemTime> {
+        let nonce = base64::decode_config(nonce, base64::URL_SAFE_NO_PAD).ok()?;
+        if nonce.len() != 24 {
+            return None;
+        }
+        let (created, tag) = nonce.split_at(8);
+        self.nonce_mac(created).verify_truncated_left(tag).ok()?;
+        let mut millis = [0; 8];
+        millis.copy_from_slice(created);
+        Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)))
+    }
+
+    /// records the nonce count, failing if it's been used before
+    fn use_nonce(&self, nonce: &str, nc: u32) -> Result<(), AuthError> {
+        let mut used = self.used.lock().unwrap();
+        used.retain(|nonce, _| self.check_nonce(nonce)
+            .is_some_and(|created| created.elapsed().unwrap_or_default() <= self.nonce_lifetime));
+        match used.entry(nonce.into()).or_default().use_count(nc) {
+            true => Ok(()),
+            false => Err(AuthError::Invalid),
+        }
+    }
+}
+
+/// the response a client with the right password sends, for qop=auth
+#[allow(clippy::too_many_arguments)]
+fn response(algorithm: Algorithm, user: &str, realm: &str, pass: &str, method: &str, uri: &str,
+            nonce: &str, nc: &str, cnonce: &str) -> String {
+    let ha1 = algorithm.hash(&[user, realm, pass]);
+    let ha2 = algorithm.hash(&[method, uri]);
+    algorithm.hash(&[&ha1, nonce, nc, cnonce, "auth", &ha2])
+}
+
+/// parses `name=token, name="quoted string"`
+fn parse_params(mut rest: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            return Some(params);
+        }
+        let eq = rest.find('=')?;
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            loop {
+                match chars.next()? {
+                    (i, '"') => {
+                        rest = &quoted[i + 1..];
+                        break;
+                    },
+                    (_, '\\') => value.push(chars.next()?.1),
+                    (_, c) => value.push(c),
+                }
+            }
+            value
+        } else {
+            let end = rest.find([',', ' ', '\t']).unwrap_or(rest.len());
+            let (value, tail) = rest.split_at(end);
+            rest = tail;
+            value.into()
+        };
+        params.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> DigestAuth<fn(&str) -> Option<String>> {
+        DigestAuth::new("http-auth@example.org", |user| (user == "Mufasa").then(|| "Circle of Life".into()))
+    }
+
+    fn verify<F: Fn(&str) -> Option<String>>(auth: &DigestAuth<F>, header: &str) -> Result<String, AuthError> {
+        let req = Request::builder().path("/dir/index.html").header("Authorization", header);
+        auth.verify(&req.request())
+    }
+
+    /// the Authorization header a client answering the challenge would send
+    fn answer(challenge: &Response, algorithm: Algorithm, pass: &str, nc: &str) -> String {
+        let header = String::from_utf8(challenge.headers.iter()
+            .map(|(_, v)| v.clone())
+            .find(|v| v.windows(algorithm.name().len()).any(|w| w == algorithm.name().as_bytes()))
+            .unwrap()).unwrap();
+        let params = parse_params(header.trim_start_matches("Digest ")).unwrap();
+        let nonce = &params["nonce"];
+        let digest = response(algorithm, "Mufasa", &params["realm"], pass, "GET", "/dir/index.html", nonce, nc, "abc");
+        format!("Digest username=\"Mufasa\", realm=\"{}\", uri=\"/dir/index.html\", algorithm={}, \
+                 nonce=\"{}\", nc={}, cnonce=\"abc\", qop=auth, response=\"{}\"",
+                params["realm"], algorithm.name(), nonce, nc, digest)
+    }
+
+    #[test]
+    fn test_response() {
+        // the examples from RFC 7616 section 3.9.1
+        let digest = |algorithm| response(algorithm, "Mufasa", "http-auth@example.org", "Circle of Life",
+            "GET", "/dir/index.html", "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", "00000001",
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ");
+        assert_eq!(digest(Algorithm::Md5), "8ca523f5e9506fed4657c9700eebdbec");
+        assert_eq!(digest(Algorithm::Sha256), "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1");
+    }
+
+    #[test]
+    fn test_nonce_counts() {
+        let mut counts = NonceCounts::default();
+        assert!(!counts.use_count(0));
+        // out of order, but each only once
+        for nc in &[1, 3, 2, 5] {
+            assert!(counts.use_count(*nc), "{}", nc);
+        }
+        for nc in &[1, 2, 3, 5] {
+            assert!(!counts.use_count(*nc), "{}", nc);
+        }
+        assert!(counts.use_count(4));
+        assert!(counts.use_count(100));
+        assert!(counts.use_count(100 - NC_WINDOW + 1));
+        assert!(!counts.use_count(100 - NC_WINDOW));
+        assert!(counts.use_count(99));
+        // a jump past the window forgets everything below it
+        assert!(counts.use_count(1000));
+        assert!(!counts.use_count(99));
+        assert!(counts.use_count(999));
+        assert!(!counts.use_count(1000));
+    }
+
+    #[test]
+    fn test_digest_auth() {
+        let auth = auth();
+        let challenge = auth.challenge(&AuthError::Missing);
+        assert_eq!(challenge.code, 401);
+        assert_eq!(challenge.headers.len(), 3);
+        for (algorithm, nc) in &[(Algorithm::Sha256, "00000001"), (Algorithm::Md5, "00000002")] {
+            let header = answer(&challenge, *algorithm, "Circle of Life", nc);
+            assert_eq!(verify(&auth, &header), Ok("Mufasa".into()));
+        }
+        assert_eq!(verify(&auth, "Basic dTpwOjE="), Err(AuthError::Missing));
+    }
+
+    #[test]
+    fn test_replay() {
+        let auth = auth();
+        let challenge = auth.challenge(&AuthError::Missing);
+        let header = answer(&challenge, Algorithm::Md5, "Circle of Life", "00000002");
+        assert_eq!(verify(&auth, &header), Ok("Mufasa".into()));
+        // a nonce count works once; a lower one not used yet still works, but only once too
+        assert_eq!(verify(&auth, &header), Err(AuthError::Invalid));
+        let header = answer(&challenge, Algorithm::Md5, "Circle of Life", "00000001");
+        assert_eq!(verify(&auth, &header), Ok("Mufasa".into()));
+        assert_eq!(verify(&auth, &header), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let auth = auth();
+        let challenge = auth.challenge(&AuthError::Missing);
+        let header = answer(&challenge, Algorithm::Sha256, "wrong", "00000001");
+        assert_eq!(verify(&auth, &header), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_foreign_nonce() {
+        let auth = auth();
+        let challenge = auth.challenge(&AuthError::Missing);
+        let header = answer(&challenge, Algorithm::Sha256, "Circle of Life", "00000001")
+            .replace("nonce=\"", "nonce=\"AAAA");
+        assert_eq!(verify(&auth, &header), Err(AuthError::Invalid));
+    }
+
+    #[test]
+    fn test_stale() {
+        let auth = auth().nonce_lifetime(Duration::from_millis(0));
+        let challenge = auth.challenge(&AuthError::Missing);
+        std::thread::sleep(Duration::from_millis(5));
+        let header = answer(&challenge, Algorithm::Sha256, "Circle of Life", "00000001");
+        assert_eq!(verify(&auth, &header), Err(AuthError::Stale));
+        let challenge = auth.challenge(&AuthError::Stale);
+        assert!(String::from_utf8_lossy(&challenge.headers[0].1).ends_with(", stale=true"));
+    }
+}
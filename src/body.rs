@@ -0,0 +1,372 @@
+//! Reading request bodies, framed by Content-Length or chunked Transfer-Encoding.
+//!
+//! http() leaves the body in the stream. Read it with a Body, or drain it, before reading the
+//! next request on the same connection, or the body will be parsed as if it were a request:
+//!
+//! ```no_run
+//! # use async_std::{io::BufReader, net::TcpStream};
+//! # use oc_http::{body::Body, http};
+//! # async fn serve(mut reader: BufReader<TcpStream>) -> std::io::Result<()> {
+//! # let mut buf = vec![0; 65536];
+//! let req = http(&mut reader, &mut buf).await?;
+//! let mut body = Body::new(&req, &mut reader)?;
+//! // ... handle the request, maybe reading some of the body ...
+//! body.drain(64 * 1024).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    cmp,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    prelude::*,
+    ready,
+};
+
+#[cfg(feature = "serde")]
+use crate::{form::{self, FormError}, multipart::Multipart};
+use crate::{content_type::ContentType, RequestError, Request};
+
+// chunk size lines and trailers longer than this are refused
+const MAX_LINE: usize = 4096;
+// the most form() reads, unless limit() says otherwise
+#[cfg(feature = "serde")]
+const FORM_LIMIT: u64 = 1024 * 1024;
+
+enum State {
+    /// bytes left of a Content-Length body
+    Fixed(u64),
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// the line ending after a chunk's data
+    ChunkEnd,
+    Trailers(Vec<u8>),
+    Done,
+}
+
+/// The body of a request, read from the stream the request came from.
+pub struct Body<'s, S> {
+    stream: &'s mut S,
+    state: State,
+    limit: Option<u64>,
+    // body bytes so far
+    read: u64,
+    content_type: Option<ContentType>,
+}
+
+impl<'s, S> Body<'s, S>
+where S: AsyncRead + Unpin
+{
+    /// Works out how the body is framed. Requests with neither Content-Length nor chunked
+    /// Transfer-Encoding have no body; ones with invalid or conflicting lengths are refused,
+    /// since there's no telling where the next request starts.
+    pub fn new(req: &Request, stream: &'s mut S) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "bad body framing");
+        let encodings = req.header_values("Transfer-Encoding");
+        let lengths = req.header_values("Content-Length");
+        let state = if let Some(last) = encodings.last() {
+            // chunked has to be the last coding; anything else can't be framed
+            let last = String::from_utf8_lossy(last);
+            let chunked = last.rsplit(',').next().is_some_and(|c| c.trim().eq_ignore_ascii_case("chunked"));
+            if !chunked || !lengths.is_empty() {
+                return Err(invalid());
+            }
+            State::ChunkSize(vec!())
+        } else if let Some(first) = lengths.first() {
+            let parse = |v: &[u8]| std::str::from_utf8(v).ok().and_then(|v| v.trim().parse::<u64>().ok());
+            let length = parse(first).ok_or_else(invalid)?;
+            if lengths.iter().any(|v| parse(v) != Some(length)) {
+                return Err(invalid());
+            }
+            State::Fixed(length)
+        } else {
+            State::Done
+        };
+        Ok(Body{stream, state, limit: None, read: 0, content_type: ContentType::from_request(req)})
+    }
+
+    /// Refuses bodies longer than `max` with RequestError::BodyTooLarge; straight away if
+    /// the Content-Length says so, or once a chunked body gets there.
+    pub fn limit(mut self, max: u64) -> io::Result<Self> {
+        if let State::Fixed(length) = self.state {
+            if length > max {
+                return Err(RequestError::BodyTooLarge.into());
+            }
+        }
+        self.limit = Some(max);
+        Ok(self)
+    }
+
+    /// The request's Content-Type, if it sent a valid one.
+    pub fn content_type(&self) -> Option<&ContentType> {
+        self.content_type.as_ref()
+    }
+
+    /// Reads the whole body as a form, urlencoded or multipart/form-data, and decodes it;
+    /// see the form module. Files in multipart bodies are taken as fields, so they have to
+    /// be text. Bodies over the limit (1MB, unless limit() set one) are refused.
+    #[cfg(feature = "serde")]
+    pub async fn form<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, FormError> {
+        let max = self.limit.unwrap_or(FORM_LIMIT);
+        let too_large = || FormError::Io(RequestError::BodyTooLarge.into());
+        let content_type = self.content_type.clone().ok_or(FormError::Unsupported)?;
+        if content_type.is("application/x-www-form-urlencoded") {
+            let mut form = vec!();
+            (&mut *self).take(max + 1).read_to_end(&mut form).await?;
+            if form.len() as u64 > max {
+                return Err(too_large());
+            }
+            return form::from_urlencoded(&form);
+        }
+        let boundary = match content_type.boundary() {
+            Some(boundary) if content_type.is("multipart/form-data") => boundary,
+            _ => return Err(FormError::Unsupported),
+        };
+        let mut parts = Multipart::new(&mut *self, boundary);
+        let (mut fields, mut total) = (vec!(), 0);
+        let mut buf = [0; 8192];
+        while let Some(part) = parts.next_part().await? {
+            let mut value = vec!();
+            loop {
+                let count = parts.read(&mut buf).await?;
+                if count == 0 {
+                    break;
+                }
+                total += count as u64;
+                if total > max {
+                    return Err(too_large());
+                }
+                value.extend_from_slice(&buf[..count]);
+            }
+            let value = String::from_utf8(value)
+                .map_err(|_| FormError::Field{field: part.name.clone(), message: "isn't text".into()})?;
+            fields.push((part.name.unwrap_or_default(), value));
+        }
+        form::from_pairs(fields)
+    }
+
+    /// True once the whole body has been read.
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done | State::Fixed(0))
+    }
+
+    /// Reads and throws away the rest of the body, so the connection is ready for the next
+    /// request; returns how many bytes that was. Fails, leaving the connection unusable, if
+    /// more than `limit` bytes are left; close it rather than read a huge upload nobody wants.
+    pub async fn drain(&mut self, limit: u64) -> io::Result<u64> {
+        let mut buf = [0; 8192];
+        let mut drained = 0;
+        loop {
+            let want = cmp::min(buf.len() as u64, (limit - drained).saturating_add(1)) as usize;
+            let count = self.read(&mut buf[..want]).await?;
+            if count == 0 {
+                return Ok(drained);
+            }
+            drained += count as u64;
+            if drained > limit {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "body too large to drain"));
+            }
+        }
+    }
+}
+
+fn poll_byte<S>(stream: &mut S, cx: &mut Context) -> Poll<io::Result<u8>>
+where S: AsyncRead + Unpin
+{
+    let mut byte = [0];
+    match ready!(Pin::new(stream).poll_read(cx, &mut byte))? {
+        0 => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+        _ => Poll::Ready(Ok(byte[0])),
+    }
+}
+
+/// adds a byte to the line; Some(line) once it's complete, without its line ending
+fn push_line(line: &mut Vec<u8>, byte: u8) -> io::Result<Option<Vec<u8>>> {
+    if byte == b'\n' {
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        return Ok(Some(std::mem::take(line)));
+    }
+    if line.len() == MAX_LINE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "chunked body line too long"));
+    }
+    line.push(byte);
+    Ok(None)
+}
+
+impl<S> AsyncRead for Body<'_, S>
+where S: AsyncRead + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            match &mut this.state {
+                State::Done | State::Fixed(0) => return Poll::Ready(Ok(0)),
+                State::Fixed(left) | State::ChunkData(left) => {
+                    let want = cmp::min(buf.len() as u64, *left) as usize;
+                    let count = ready!(Pin::new(&mut *this.stream).poll_read(cx, &mut buf[..want]))?;
+                    if count == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    *left -= count as u64;
+                    this.read += count as u64;
+                    if let State::ChunkData(0) = this.state {
+                        this.state = State::ChunkEnd;
+                    }
+                    return Poll::Ready(Ok(count));
+                },
+                State::ChunkSize(line) => {
+                    let byte = ready!(poll_byte(this.stream, cx))?;
+                    if let Some(line) = push_line(line, byte)? {
+                        // chunk extensions follow a semicolon, and are ignored
+                        let size = std::str::from_utf8(&line).ok()
+                            .and_then(|l| u64::from_str_radix(l.split(';').next().unwrap_or("").trim(), 16).ok())
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+                        if this.limit.is_some_and(|limit| this.read.saturating_add(size) > limit) {
+                            return Poll::Ready(Err(RequestError::BodyTooLarge.into()));
+                        }
+                        this.state = match size {
+                            0 => State::Trailers(vec!()),
+                            size => State::ChunkData(size),
+                        };
+                    }
+                },
+                State::ChunkEnd => {
+                    match ready!(poll_byte(this.stream, cx))? {
+                        b'\r' => {},
+                        b'\n' => this.state = State::ChunkSize(vec!()),
+                        _ => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "chunk too long"))),
+                    }
+                },
+                State::Trailers(line) => {
+                    let byte = ready!(poll_byte(this.stream, cx))?;
+                    if let Some(line) = push_line(line, byte)? {
+                        if line.is_empty() {
+                            this.state = State::Done;
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::io::Cursor;
+
+    use super::*;
+    use crate::http;
+
+    #[async_std::test]
+    async fn test_body() {
+        let mut stream = Cursor::new(Vec::from(&b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\
+            POST /b HTTP/1.1\r\ntransfer-encoding: gzip, chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n\
+            GET /c HTTP/1.1\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(req.path, "/a");
+        // handlers that ignore the body don't break the next request
+        assert_eq!(Body::new(&req, &mut stream).unwrap().drain(100).await.unwrap(), 5);
+
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(req.path, "/b");
+        let mut body = Body::new(&req, &mut stream).unwrap();
+        let mut contents = String::new();
+        body.read_to_string(&mut contents).await.unwrap();
+        assert_eq!(contents, "hello, world");
+        assert!(body.is_done());
+
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(req.path, "/c");
+        assert_eq!(Body::new(&req, &mut stream).unwrap().drain(0).await.unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_limit() {
+        let mut stream = Cursor::new(Vec::from(&b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        let err = Body::new(&req, &mut stream).unwrap().limit(10).err().unwrap();
+        assert_eq!(RequestError::from_io(&err), Some(RequestError::BodyTooLarge));
+
+        let mut stream = Cursor::new(Vec::from(&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        let mut body = Body::new(&req, &mut stream).unwrap().limit(10).unwrap();
+        let mut contents = vec!();
+        let err = body.read_to_end(&mut contents).await.unwrap_err();
+        assert_eq!(contents, b"hello");
+        assert_eq!(crate::error_response(&err).unwrap().code, 413);
+    }
+
+    #[cfg(feature = "serde")]
+    #[async_std::test]
+    async fn test_form() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Login { user: String, remember: Option<bool> }
+
+        async fn form(request: &[u8]) -> Result<Login, FormError> {
+            let mut stream = Cursor::new(Vec::from(request));
+            let mut buf = vec![0; 1024];
+            let req = http(&mut stream, &mut buf).await?;
+            let mut body = Body::new(&req, &mut stream)?.limit(128)?;
+            body.form().await
+        }
+        let login = Login{user: "a b".into(), remember: Some(true)};
+        let urlencoded = form(b"POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+            Content-Length: 22\r\n\r\nuser=a+b&remember=true").await.unwrap();
+        assert_eq!(urlencoded, login);
+        let multipart = form(b"POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+            Content-Length: 118\r\n\r\n--b\r\nContent-Disposition: form-data; name=user\r\n\r\na b\r\n\
+            --b\r\nContent-Disposition: form-data; name=remember\r\n\r\non\r\n--b--").await.unwrap();
+        assert_eq!(multipart, login);
+
+        let code = |result: Result<Login, FormError>| result.unwrap_err().response().code;
+        assert_eq!(code(form(b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 1\r\n\r\nx").await), 415);
+        assert_eq!(code(form(b"POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+            Content-Length: 13\r\n\r\nuser=a&user=b").await), 422);
+        assert_eq!(code(form(b"POST / HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\
+            Transfer-Encoding: chunked\r\n\r\n81\r\nuser=").await), 413);
+    }
+
+    #[async_std::test]
+    async fn test_bad_bodies() {
+        async fn body(request: &[u8]) -> io::Result<u64> {
+            let mut stream = Cursor::new(Vec::from(request));
+            let mut buf = vec![0; 1024];
+            let req = http(&mut stream, &mut buf).await?;
+            let mut body = Body::new(&req, &mut stream)?;
+            body.drain(10).await
+        }
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789").await.unwrap(), 10);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n0123456789a").await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n012").await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof);
+        for bad in &[
+            &b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 2\r\n\r\n",
+        ] {
+            assert_eq!(body(bad).await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n").await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData);
+        assert_eq!(body(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n").await.unwrap_err().kind(),
+            io::ErrorKind::InvalidData);
+    }
+}
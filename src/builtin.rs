@@ -0,0 +1,153 @@
+//! Answers for requests about the server rather than any resource: `OPTIONS *` and TRACE.
+//!
+//! Try them before routing; TRACE is off unless asked for, since echoing requests back can
+//! leak headers set by proxies along the way:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{builtin::Builtins, Request};
+//! # async fn serve(request: &Request<'_>, mut stream: TcpStream) -> std::io::Result<()> {
+//! let builtins = Builtins::new().allow(&["GET", "HEAD", "POST", "OPTIONS"]);
+//! if builtins.respond(&request, &mut stream).await? {
+//!     return Ok(());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::io;
+
+use futures::AsyncWrite;
+
+use crate::{respond, send_content, Request, Response};
+
+/// headers left out of TRACE echoes, since they carry credentials
+const SENSITIVE: &[&str] = &["Authorization", "Cookie", "Proxy-Authorization"];
+
+pub struct Builtins {
+    allow: Vec<String>,
+    options: bool,
+    trace: bool,
+}
+
+impl Default for Builtins {
+    fn default() -> Self {
+        Builtins{
+            allow: ["GET", "HEAD", "POST", "PUT", "DELETE", "OPTIONS"].iter().map(|m| m.to_string()).collect(),
+            options: true,
+            trace: false,
+        }
+    }
+}
+
+impl Builtins {
+    pub fn new() -> Self {
+        Builtins::default()
+    }
+
+    /// The methods `OPTIONS *` says the server supports.
+    pub fn allow(mut self, methods: &[&str]) -> Self {
+        self.allow = methods.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    /// Whether to answer `OPTIONS *`; on by default.
+    pub fn options(mut self, enabled: bool) -> Self {
+        self.options = enabled;
+        self
+    }
+
+    /// Whether to answer TRACE by echoing the request back; off by default. Credentials
+    /// are left out of the echo.
+    pub fn trace(mut self, enabled: bool) -> Self {
+        self.trace = enabled;
+        self
+    }
+
+    /// The response and its body, for requests these handle.
+    pub fn response(&self, req: &Request) -> Option<(Response, Vec<u8>)> {
+        if self.options && req.method == "OPTIONS" && req.path == "*" {
+            return Some((Response{
+                code: 200,
+                reason: "OK",
+                headers: vec!(
+                    ("Allow".into(), self.allow.join(", ").into_bytes()),
+                    ("Content-Length".into(), Vec::from("0")),
+                ),
+            }, vec!()));
+        }
+        if self.trace && req.method == "TRACE" {
+            let body = echo(req);
+            return Some((Response{
+                code: 200,
+                reason: "OK",
+                headers: vec!(
+                    ("Content-Type".into(), Vec::from("message/http")),
+                    ("Content-Length".into(), Vec::from(body.len().to_string())),
+                ),
+            }, body));
+        }
+        None
+    }
+
+    /// Writes the response if these handle the request; false if they don't, and it's up
+    /// to the caller.
+    pub async fn respond<S>(&self, req: &Request<'_>, stream: &mut S) -> io::Result<bool>
+    where S: AsyncWrite + Unpin
+    {
+        match self.response(req) {
+            Some((response, body)) => {
+                respond(stream, response).await?;
+                send_content(stream, &body).await?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+/// the request head as received, give or take header order and the sensitive ones
+fn echo(req: &Request) -> Vec<u8> {
+    let mut names: Vec<_> = req.headers.keys()
+        .filter(|name| !SENSITIVE.iter().any(|s| s.eq_ignore_ascii_case(name)))
+        .collect();
+    names.sort();
+    let mut out = format!("{} {} HTTP/1.1\r\n", req.method, req.path).into_bytes();
+    for name in names {
+        for value in req.header_values(name) {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins() {
+        let builtins = Builtins::new().allow(&["GET", "OPTIONS"]);
+        let options = Request::builder().method("OPTIONS").path("*");
+        let (response, body) = builtins.response(&options.request()).unwrap();
+        assert_eq!(response.headers[0], ("Allow".into(), Vec::from("GET, OPTIONS")));
+        assert!(body.is_empty());
+        // OPTIONS on a resource is the router's business
+        assert!(builtins.response(&Request::builder().method("OPTIONS").path("/x").request()).is_none());
+        assert!(Builtins::new().options(false).response(&options.request()).is_none());
+
+        let trace = Request::builder()
+            .method("TRACE")
+            .path("/x")
+            .header("Via", "1.1 proxy")
+            .header("Cookie", "secret=1")
+            .header("Accept", "*/*");
+        assert!(builtins.response(&trace.request()).is_none());
+        let (response, body) = builtins.trace(true).response(&trace.request()).unwrap();
+        assert_eq!(response.headers[0], ("Content-Type".into(), Vec::from("message/http")));
+        assert_eq!(body, b"TRACE /x HTTP/1.1\r\nAccept: */*\r\nVia: 1.1 proxy\r\n\r\n");
+    }
+}
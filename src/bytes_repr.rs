@@ -0,0 +1,74 @@
+//! serde representations for bytes that are nearly always text, like header values and
+//! bodies: a string when they're utf-8, and an array of numbers when they aren't, so
+//! nothing's lost either way.
+use serde::{ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::Headers;
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Repr<'a> {
+    Text(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> From<&'a [u8]> for Repr<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Repr::Text(text),
+            Err(_) => Repr::Bytes(bytes),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OwnedRepr {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<OwnedRepr> for Vec<u8> {
+    fn from(repr: OwnedRepr) -> Self {
+        match repr {
+            OwnedRepr::Text(text) => text.into_bytes(),
+            OwnedRepr::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    Repr::from(bytes).serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    OwnedRepr::deserialize(deserializer).map(Vec::from)
+}
+
+/// header lists, as [name, value] pairs
+pub(crate) mod list {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(headers: &[(String, Vec<u8>)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(headers.iter().map(|(name, value)| (name, Repr::from(&value[..]))))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(String, Vec<u8>)>, D::Error> {
+        let headers: Vec<(String, OwnedRepr)> = Deserialize::deserialize(deserializer)?;
+        Ok(headers.into_iter().map(|(name, value)| (name, value.into())).collect())
+    }
+}
+
+/// a request's header map, as [name, value] pairs with repeated headers repeated
+pub(crate) fn serialize_map<S: Serializer>(headers: &Headers, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut names: Vec<_> = headers.keys().collect();
+    names.sort();
+    let mut seq = serializer.serialize_seq(None)?;
+    for name in names {
+        let (first, rest) = &headers[name];
+        for value in std::iter::once(first).chain(rest.iter().flatten()) {
+            seq.serialize_element(&(name, Repr::from(*value)))?;
+        }
+    }
+    seq.end()
+}
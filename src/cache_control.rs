@@ -0,0 +1,238 @@
+//! The Cache-Control header, built for responses and parsed from requests.
+//!
+//! ```no_run
+//! # use oc_http::{cache_control::CacheControl, Request, Response};
+//! # fn serve(request: &Request, response: &mut Response) {
+//! response.headers.push(CacheControl::new().public().max_age(3600).immutable().header());
+//!
+//! if CacheControl::from_request(&request).no_cache {
+//!     // the client wants a fresh copy, not one from a cache
+//! }
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{quote, split_quoted, unquote, Request};
+
+/// Cache-Control directives. Fields that only make sense in a request (max_stale,
+/// min_fresh, only_if_cached) or a response (the rest, mostly) are all here, since parsing
+/// either side gives the same kind of thing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub public: bool,
+    pub private: bool,
+    pub no_cache: bool,
+    pub no_store: bool,
+    pub no_transform: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub immutable: bool,
+    pub only_if_cached: bool,
+    /// In seconds, as are the other times.
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    /// u64::MAX for a bare `max-stale`, which takes a response however stale it is.
+    pub max_stale: Option<u64>,
+    pub min_fresh: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+    /// Directives this doesn't know, with their values if they have them.
+    pub extensions: Vec<(String, Option<String>)>,
+}
+
+impl CacheControl {
+    pub fn new() -> Self {
+        CacheControl::default()
+    }
+
+    /// Parses a header value. Unknown directives go in extensions; known ones with values
+    /// that don't parse are dropped. When a directive is repeated, the first wins.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut cc = CacheControl::default();
+        let value = String::from_utf8_lossy(value);
+        for directive in split_quoted(&value, ',') {
+            let (name, arg) = match directive.split_once('=') {
+                Some((name, arg)) => (name.trim().to_ascii_lowercase(), Some(unquote(arg.trim()))),
+                None => (directive.to_ascii_lowercase(), None),
+            };
+            let secs = arg.as_deref().and_then(|a| a.parse::<u64>().ok());
+            let time = |field: &mut Option<u64>| if field.is_none() { *field = secs };
+            match &name[..] {
+                "public" => cc.public = true,
+                // these may list header names, which are treated as covering the whole response
+                "private" => cc.private = true,
+                "no-cache" => cc.no_cache = true,
+                "no-store" => cc.no_store = true,
+                "no-transform" => cc.no_transform = true,
+                "must-revalidate" => cc.must_revalidate = true,
+                "proxy-revalidate" => cc.proxy_revalidate = true,
+                "immutable" => cc.immutable = true,
+                "only-if-cached" => cc.only_if_cached = true,
+                "max-age" => time(&mut cc.max_age),
+                "s-maxage" => time(&mut cc.s_maxage),
+                "max-stale" if arg.is_none() => cc.max_stale = cc.max_stale.or(Some(u64::MAX)),
+                "max-stale" => time(&mut cc.max_stale),
+                "min-fresh" => time(&mut cc.min_fresh),
+                "stale-while-revalidate" => time(&mut cc.stale_while_revalidate),
+                "stale-if-error" => time(&mut cc.stale_if_error),
+                _ => cc.extensions.push((name, arg)),
+            }
+        }
+        cc
+    }
+
+    /// The request's Cache-Control, from every line of it; empty if there isn't one.
+    pub fn from_request(req: &Request) -> Self {
+        let values: Vec<_> = req.header_values("Cache-Control").iter()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+            .collect();
+        CacheControl::parse(values.join(",").as_bytes())
+    }
+
+    /// The header for a response.
+    pub fn header(&self) -> (String, Vec<u8>) {
+        ("Cache-Control".into(), self.to_string().into_bytes())
+    }
+
+    pub fn public(mut self) -> Self {
+        self.public = true;
+        self
+    }
+
+    pub fn private(mut self) -> Self {
+        self.private = true;
+        self
+    }
+
+    pub fn no_cache(mut self) -> Self {
+        self.no_cache = true;
+        self
+    }
+
+    pub fn no_store(mut self) -> Self {
+        self.no_store = true;
+        self
+    }
+
+    pub fn no_transform(mut self) -> Self {
+        self.no_transform = true;
+        self
+    }
+
+    pub fn must_revalidate(mut self) -> Self {
+        self.must_revalidate = true;
+        self
+    }
+
+    pub fn proxy_revalidate(mut self) -> Self {
+        self.proxy_revalidate = true;
+        self
+    }
+
+    /// The response won't change while it's fresh, so clients needn't revalidate it on reload.
+    pub fn immutable(mut self) -> Self {
+        self.immutable = true;
+        self
+    }
+
+    pub fn max_age(mut self, secs: u64) -> Self {
+        self.max_age = Some(secs);
+        self
+    }
+
+    /// max-age for shared caches, like CDNs.
+    pub fn s_maxage(mut self, secs: u64) -> Self {
+        self.s_maxage = Some(secs);
+        self
+    }
+
+    pub fn stale_while_revalidate(mut self, secs: u64) -> Self {
+        self.stale_while_revalidate = Some(secs);
+        self
+    }
+
+    pub fn stale_if_error(mut self, secs: u64) -> Self {
+        self.stale_if_error = Some(secs);
+        self
+    }
+
+    /// Any other directive; its value is quoted if it has to be.
+    pub fn extension(mut self, name: &str, value: Option<&str>) -> Self {
+        self.extensions.push((name.into(), value.map(String::from)));
+        self
+    }
+}
+
+impl fmt::Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.public, "public"),
+            (self.private, "private"),
+            (self.no_cache, "no-cache"),
+            (self.no_store, "no-store"),
+            (self.no_transform, "no-transform"),
+            (self.must_revalidate, "must-revalidate"),
+            (self.proxy_revalidate, "proxy-revalidate"),
+            (self.immutable, "immutable"),
+            (self.only_if_cached, "only-if-cached"),
+        ];
+        let mut directives: Vec<String> = flags.iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| name.to_string())
+            .collect();
+        let times = [
+            (self.max_age, "max-age"),
+            (self.s_maxage, "s-maxage"),
+            (self.min_fresh, "min-fresh"),
+            (self.stale_while_revalidate, "stale-while-revalidate"),
+            (self.stale_if_error, "stale-if-error"),
+        ];
+        for (secs, name) in &times {
+            if let Some(secs) = secs {
+                directives.push(format!("{}={}", name, secs));
+            }
+        }
+        match self.max_stale {
+            Some(u64::MAX) => directives.push("max-stale".into()),
+            Some(secs) => directives.push(format!("max-stale={}", secs)),
+            None => {},
+        }
+        for (name, value) in &self.extensions {
+            directives.push(match value {
+                Some(v) if !v.is_empty() && v.bytes().all(crate::is_tchar) => format!("{}={}", name, v),
+                Some(v) => format!("{}={}", name, quote(v)),
+                None => name.clone(),
+            });
+        }
+        write!(f, "{}", directives.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_control() {
+        let cc = CacheControl::new().public().max_age(3600).immutable();
+        assert_eq!(cc.to_string(), "public, immutable, max-age=3600");
+        assert_eq!(cc.header().1, b"public, immutable, max-age=3600");
+        assert_eq!(CacheControl::parse(cc.to_string().as_bytes()), cc);
+
+        let cc = CacheControl::parse(br#"No-Cache, max-age=60, max-age=10, private="Set-Cookie, X", community="UCI", max-stale, s-maxage=x"#);
+        assert!(cc.no_cache);
+        assert!(cc.private);
+        assert_eq!(cc.max_age, Some(60));
+        assert_eq!(cc.max_stale, Some(u64::MAX));
+        assert_eq!(cc.s_maxage, None);
+        assert_eq!(cc.extensions, vec!(("community".into(), Some("UCI".into()))));
+        assert_eq!(CacheControl::new().extension("x", Some("a b")).extension("y", None).to_string(), r#"x="a b", y"#);
+
+        let builder = Request::builder()
+            .header("Cache-Control", "no-cache")
+            .header("cache-control", "max-stale=5");
+        let cc = CacheControl::from_request(&builder.request());
+        assert_eq!((cc.no_cache, cc.max_stale), (true, Some(5)));
+        assert_eq!(CacheControl::from_request(&Request::builder().request()), CacheControl::default());
+    }
+}
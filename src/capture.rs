@@ -0,0 +1,346 @@
+//! Recording connections byte for byte, and replaying what was recorded.
+//!
+//! Wrap each connection before reading from it; once every clone of the wrapper is
+//! dropped, what went each way is handed to the recorder's sink:
+//!
+//! ```no_run
+//! # use futures::{AsyncRead, AsyncWrite};
+//! # use oc_http::capture::{Capture, Recorder};
+//! # #[derive(Default)]
+//! # struct Queue;
+//! # impl Queue { fn push(&self, capture: Capture) {} }
+//! # async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {}
+//! # async fn serve(stream: async_std::net::TcpStream, peer: std::net::SocketAddr) {
+//! # let queue = Queue::default();
+//! let recorder = Recorder::new(move |capture: Capture| queue.push(capture))
+//!     .limit(64 * 1024)
+//!     .redact_header("Authorization")
+//!     .redact_header("Cookie");
+//! let stream = recorder.record(stream, Some(peer));
+//! handle_connection(stream).await;
+//! # }
+//! ```
+//!
+//! Later, feed a capture back through a handler, and see what it says this time:
+//!
+//! ```no_run
+//! # use futures::{AsyncRead, AsyncWrite};
+//! # use oc_http::capture::{self, Capture};
+//! # async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {}
+//! # async fn check(capture: Capture) -> std::io::Result<()> {
+//! let response = capture::replay(&capture, |stream| handle_connection(stream)).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use futures::{AsyncRead, AsyncWrite, Future};
+
+use crate::testing::{Duplex, TestClient, TestResponse};
+
+/// What went across one connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capture {
+    /// When the connection was wrapped.
+    pub time: SystemTime,
+    pub peer: Option<SocketAddr>,
+    /// Everything read from the client, up to the limit.
+    #[cfg_attr(feature = "serde", serde(with = "crate::bytes_repr"))]
+    pub request: Vec<u8>,
+    /// Everything written back, up to the limit.
+    #[cfg_attr(feature = "serde", serde(with = "crate::bytes_repr"))]
+    pub response: Vec<u8>,
+    /// Whether the request went past the limit, and was cut off.
+    pub request_truncated: bool,
+    pub response_truncated: bool,
+}
+
+pub trait CaptureSink: Send + Sync {
+    fn record(&self, capture: Capture);
+}
+
+impl<F: Fn(Capture) + Send + Sync> CaptureSink for F {
+    fn record(&self, capture: Capture) {
+        self(capture)
+    }
+}
+
+type Redactor = Box<dyn Fn(&mut Capture) + Send + Sync>;
+
+struct Config {
+    sink: Box<dyn CaptureSink>,
+    limit: usize,
+    headers: Vec<String>,
+    redactors: Vec<Redactor>,
+}
+
+/// Wraps connections to record them; cheap to clone, and clones share the sink.
+#[derive(Clone)]
+pub struct Recorder {
+    config: Arc<Config>,
+}
+
+impl Recorder {
+    /// Records to the sink, keeping up to 1MB each way.
+    pub fn new<S: CaptureSink + 'static>(sink: S) -> Self {
+        Recorder{config: Arc::new(Config{
+            sink: Box::new(sink),
+            limit: 1024 * 1024,
+            headers: vec!(),
+            redactors: vec!(),
+        })}
+    }
+
+    /// The most bytes kept each way; the rest still go through, but aren't recorded.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.config_mut().limit = limit;
+        self
+    }
+
+    /// Replaces the header's values with `[redacted]`, in the request and the response.
+    /// Every message each way is looked at, so keep-alive connections are covered too.
+    pub fn redact_header(mut self, name: &str) -> Self {
+        self.config_mut().headers.push(name.into());
+        self
+    }
+
+    /// Called on each capture before it goes to the sink, after headers are redacted.
+    pub fn redact<F: Fn(&mut Capture) + Send + Sync + 'static>(mut self, redactor: F) -> Self {
+        self.config_mut().redactors.push(Box::new(redactor));
+        self
+    }
+
+    fn config_mut(&mut self) -> &mut Config {
+        Arc::get_mut(&mut self.config).expect("Recorder configured after it was cloned")
+    }
+
+    /// Wraps the connection; the capture is recorded once it, and every clone of it, is
+    /// dropped.
+    pub fn record<S>(&self, stream: S, peer: Option<SocketAddr>) -> Recorded<S> {
+        Recorded{
+            inner: stream,
+            shared: Arc::new(Shared{
+                config: self.config.clone(),
+                capture: Mutex::new(Capture{
+                    time: SystemTime::now(),
+                    peer,
+                    request: vec!(),
+                    response: vec!(),
+                    request_truncated: false,
+                    response_truncated: false,
+                }),
+            }),
+        }
+    }
+}
+
+struct Shared {
+    config: Arc<Config>,
+    capture: Mutex<Capture>,
+}
+
+impl Shared {
+    fn tee(&self, bytes: &[u8], read: bool) {
+        let mut capture = self.capture.lock().unwrap();
+        let capture = &mut *capture;
+        let (kept, truncated) = if read {
+            (&mut capture.request, &mut capture.request_truncated)
+        } else {
+            (&mut capture.response, &mut capture.response_truncated)
+        };
+        let room = self.config.limit.saturating_sub(kept.len());
+        kept.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        *truncated |= bytes.len() > room;
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let capture = self.capture.get_mut().unwrap();
+        let mut capture = Capture{
+            request: std::mem::take(&mut capture.request),
+            response: std::mem::take(&mut capture.response),
+            ..capture.clone()
+        };
+        for name in &self.config.headers {
+            redact_header(&mut capture.request, name);
+            redact_header(&mut capture.response, name);
+        }
+        for redactor in &self.config.redactors {
+            redactor(&mut capture);
+        }
+        self.config.sink.record(capture);
+    }
+}
+
+/// blanks the header's values in every head: the one at the start of the message, and
+/// any after a blank line. Bodies aren't framed here, so a body that looks like a head is
+/// redacted too; that errs the safe way.
+fn redact_header(message: &mut Vec<u8>, name: &str) {
+    let mut out = Vec::with_capacity(message.len());
+    let mut rest = &message[..];
+    while !rest.is_empty() {
+        let end = rest.windows(4).position(|w| w == b"\r\n\r\n").map_or(rest.len(), |end| end + 4);
+        redact_head(&mut out, &rest[..end], name);
+        rest = &rest[end..];
+    }
+    *message = out;
+}
+
+fn redact_head(out: &mut Vec<u8>, head: &[u8], name: &str) {
+    let mut lines = head.split(|b| *b == b'\n');
+    // the request or status line
+    if let Some(line) = lines.next() {
+        out.extend_from_slice(line);
+    }
+    for line in lines {
+        out.push(b'\n');
+        match line.iter().position(|b| *b == b':') {
+            Some(colon) if line[..colon].eq_ignore_ascii_case(name.as_bytes()) => {
+                out.extend_from_slice(&line[..colon]);
+                out.extend_from_slice(b": [redacted]");
+                if line.ends_with(b"\r") {
+                    out.push(b'\r');
+                }
+            },
+            _ => out.extend_from_slice(line),
+        }
+    }
+}
+
+/// A connection being recorded; reads and writes go through to the one it wraps.
+pub struct Recorded<S> {
+    inner: S,
+    shared: Arc<Shared>,
+}
+
+impl<S: Clone> Clone for Recorded<S> {
+    fn clone(&self) -> Self {
+        Recorded{inner: self.inner.clone(), shared: self.shared.clone()}
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(count)) = poll {
+            self.shared.tee(&buf[..count], true);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = poll {
+            self.shared.tee(&buf[..count], false);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Sends the captured request bytes through the handler, over an in-memory connection,
+/// and returns the (first) response. Fails if the request was cut off by the limit.
+pub async fn replay<H, Fut>(capture: &Capture, handler: H) -> io::Result<TestResponse>
+where
+    H: Fn(Duplex) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    if capture.request_truncated {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the captured request was truncated"));
+    }
+    TestClient::new(handler).send(&capture.request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http, respond, send_content, Response};
+
+    async fn handler<S: AsyncRead + AsyncWrite + Clone + Unpin>(stream: S) {
+        let mut reader = stream.clone();
+        let mut writer = stream;
+        let mut buf = vec![0; 1024];
+        let req = http(&mut reader, &mut buf).await.unwrap();
+        let body = format!("{} {}", req.method, req.path);
+        respond(&mut writer, Response{
+            headers: vec!(("Content-Length".into(), Vec::from(body.len().to_string()))),
+            ..Response::default()
+        }).await.unwrap();
+        send_content(&mut writer, body.as_bytes()).await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_capture_replay() {
+        let captures = Arc::new(Mutex::new(vec!()));
+        let sink = captures.clone();
+        let recorder = Recorder::new(move |capture| sink.lock().unwrap().push(capture))
+            .redact_header("authorization")
+            .redact(|capture| capture.peer = None);
+        let client = TestClient::new(|stream| handler(recorder.record(stream, "127.0.0.1:1".parse().ok())));
+        let response = client.request("GET", "/a", &[("Authorization", "Basic c2VjcmV0")], b"").await.unwrap();
+        assert_eq!(response.text(), Some("GET /a"));
+
+        let capture = captures.lock().unwrap().pop().unwrap();
+        assert_eq!(capture.request, b"GET /a HTTP/1.1\r\nHost: test\r\nAuthorization: [redacted]\r\n\r\n");
+        assert_eq!(capture.response, b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nGET /a");
+        assert_eq!((capture.peer, capture.request_truncated), (None, false));
+
+        let replayed = replay(&capture, handler).await.unwrap();
+        assert_eq!(replayed.text(), Some("GET /a"));
+
+        let sink = captures.clone();
+        let recorder = Recorder::new(move |capture| sink.lock().unwrap().push(capture)).limit(4);
+        TestClient::new(|stream| handler(recorder.record(stream, None))).get("/b").await.unwrap();
+        let capture = captures.lock().unwrap().pop().unwrap();
+        assert_eq!((&capture.request[..], capture.request_truncated), (&b"GET "[..], true));
+        assert!(replay(&capture, handler).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_redact_pipelined() {
+        let captures = Arc::new(Mutex::new(vec!()));
+        let sink = captures.clone();
+        let recorder = Recorder::new(move |capture| sink.lock().unwrap().push(capture))
+            .redact_header("Authorization")
+            .redact_header("Cookie");
+        let pipelined = concat!(
+            "GET /a HTTP/1.1\r\nAuthorization: Basic c2VjcmV0\r\nCookie: id=1\r\n\r\n",
+            "GET /b HTTP/1.1\r\nCookie: id=2\r\nAuthorization: Basic c2VjcmV0\r\n\r\n",
+        );
+        let mut stream = recorder.record(futures::io::Cursor::new(pipelined.as_bytes()), None);
+        futures::AsyncReadExt::read_to_end(&mut stream, &mut vec!()).await.unwrap();
+        drop(stream);
+
+        let capture = captures.lock().unwrap().pop().unwrap();
+        assert_eq!(capture.request, concat!(
+            "GET /a HTTP/1.1\r\nAuthorization: [redacted]\r\nCookie: [redacted]\r\n\r\n",
+            "GET /b HTTP/1.1\r\nCookie: [redacted]\r\nAuthorization: [redacted]\r\n\r\n",
+        ).as_bytes());
+    }
+
+    #[test]
+    fn test_redact_after_body() {
+        let mut message = Vec::from(&b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi"[..]);
+        message.extend_from_slice(b"HTTP/1.1 200 OK\r\nSet-Cookie: id=1\r\n\r\n");
+        redact_header(&mut message, "set-cookie");
+        assert_eq!(message, &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhiHTTP/1.1 200 OK\r\nSet-Cookie: [redacted]\r\n\r\n"[..]);
+    }
+}
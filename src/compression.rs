@@ -0,0 +1,180 @@
+//! When responses are worth compressing, and with what.
+//!
+//! The crate doesn't compress anything itself; whatever encoder the application uses asks
+//! a CompressionConfig first, so tiny responses and media that's compressed already (images,
+//! video, archives) go as they are:
+//!
+//! ```no_run
+//! # use oc_http::{compression::{self, CompressionConfig}, vary::Vary, Request, Response};
+//! # fn encode(coding: &str, level: u32, body: &[u8]) -> Vec<u8> { body.to_vec() }
+//! # fn serve(req: &Request, response: &mut Response, mut body: Vec<u8>, vary: &mut Vary) {
+//! let compression = CompressionConfig::new().min_size(860).exclude("application/x-ndjson");
+//! let coding = compression::choose_encoding(&req, &["br", "gzip"]);
+//! vary.add("Accept-Encoding");
+//! if let (Some(coding), true) = (coding, compression.should_compress(&response, Some(body.len() as u64))) {
+//!     body = encode(coding, compression.level, &body);
+//!     response.headers.push(("Content-Encoding".into(), Vec::from(coding)));
+//! }
+//! # }
+//! ```
+use crate::{content_type::ContentType, quality_list, Request, Response};
+
+/// Already compressed, so compressing them again only costs time.
+pub const DEFAULT_EXCLUDED: &[&str] = &[
+    "image/png", "image/jpeg", "image/gif", "image/webp", "image/avif",
+    "video/*", "audio/*",
+    "font/woff", "font/woff2",
+    "application/zip", "application/gzip", "application/x-gzip", "application/zstd", "application/x-xz",
+    "application/x-bzip2", "application/x-7z-compressed", "application/vnd.rar", "application/pdf",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// Bodies smaller than this, in bytes, aren't compressed; the headers cost more than
+    /// the saving.
+    pub min_size: u64,
+    /// Only these media types (`text/*` style patterns) are compressed; empty for any that
+    /// isn't excluded.
+    pub include_content_types: Vec<String>,
+    /// Never compressed, even if included; DEFAULT_EXCLUDED to start with.
+    pub exclude_content_types: Vec<String>,
+    /// For the encoder, from 0 (fastest) to 9 (smallest).
+    pub level: u32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig{
+            min_size: 1024,
+            include_content_types: vec!(),
+            exclude_content_types: DEFAULT_EXCLUDED.iter().map(|t| t.to_string()).collect(),
+            level: 6,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn new() -> Self {
+        CompressionConfig::default()
+    }
+
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Adds a media type to compress; once there's one, others aren't.
+    pub fn include(mut self, media_type: &str) -> Self {
+        self.include_content_types.push(media_type.into());
+        self
+    }
+
+    pub fn exclude(mut self, media_type: &str) -> Self {
+        self.exclude_content_types.push(media_type.into());
+        self
+    }
+
+    /// Clamped to 9.
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Whether to compress the response, with a body of `body_len` bytes if that's known
+    /// (its Content-Length is used if not). Not for responses that are already encoded, ask
+    /// for no-transform, or are partial, nor ones without a Content-Type to go by.
+    pub fn should_compress(&self, response: &Response, body_len: Option<u64>) -> bool {
+        let header = |name: &str| {
+            response.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| &v[..])
+        };
+        let encoded = header("Content-Encoding").is_some_and(|v| !v.eq_ignore_ascii_case(b"identity"));
+        let no_transform = header("Cache-Control")
+            .is_some_and(|v| String::from_utf8_lossy(v).split(',').any(|d| d.trim().eq_ignore_ascii_case("no-transform")));
+        if encoded || no_transform || response.code == 206 || response.code == 204 || response.code == 304 {
+            return false;
+        }
+        let len = body_len.or_else(|| std::str::from_utf8(header("Content-Length")?).ok()?.trim().parse().ok());
+        if len.is_some_and(|len| len < self.min_size) {
+            return false;
+        }
+        let content_type = match header("Content-Type").and_then(ContentType::parse) {
+            Some(content_type) => content_type,
+            None => return false,
+        };
+        let included = self.include_content_types.is_empty()
+            || self.include_content_types.iter().any(|t| content_type.is(t));
+        included && !self.exclude_content_types.iter().any(|t| content_type.is(t))
+    }
+}
+
+/// The content coding to use of those the encoder supports, in the order it prefers them,
+/// going by the request's Accept-Encoding; None for none at all (identity).
+pub fn choose_encoding<'a>(req: &Request, supported: &[&'a str]) -> Option<&'a str> {
+    let accepted: Vec<(String, f32)> = req.header_values("Accept-Encoding").into_iter().flat_map(quality_list).collect();
+    let q = |coding: &str| {
+        accepted.iter().find(|(name, _)| name.eq_ignore_ascii_case(coding))
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+    let mut best: Option<(&str, f32)> = None;
+    for coding in supported {
+        let q = q(coding);
+        if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str, length: usize) -> Response {
+        Response{
+            headers: vec!(
+                ("Content-Type".into(), Vec::from(content_type)),
+                ("Content-Length".into(), Vec::from(length.to_string())),
+            ),
+            ..Response::default()
+        }
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let config = CompressionConfig::new();
+        assert!(config.should_compress(&response("text/html; charset=utf-8", 4096), None));
+        assert!(config.should_compress(&response("image/svg+xml", 4096), None));
+        assert!(!config.should_compress(&response("text/html", 100), None));
+        assert!(!config.should_compress(&response("text/html", 4096), Some(10)));
+        assert!(!config.should_compress(&response("image/png", 4096), None));
+        assert!(!config.should_compress(&response("video/mp4", 1 << 20), None));
+
+        let mut encoded = response("text/html", 4096);
+        encoded.headers.push(("Content-Encoding".into(), Vec::from("gzip")));
+        assert!(!config.should_compress(&encoded, None));
+        let mut no_transform = response("text/html", 4096);
+        no_transform.headers.push(("Cache-Control".into(), Vec::from("public, no-transform")));
+        assert!(!config.should_compress(&no_transform, None));
+        assert!(!config.should_compress(&Response::default(), Some(4096)));
+
+        let config = CompressionConfig::new().min_size(0).include("text/*").include("application/json").exclude("text/csv");
+        assert!(config.should_compress(&response("application/json", 1), None));
+        assert!(!config.should_compress(&response("application/xml", 4096), None));
+        assert!(!config.should_compress(&response("text/csv", 4096), None));
+        assert_eq!(CompressionConfig::new().level(12).level, 9);
+    }
+
+    #[test]
+    fn test_choose_encoding() {
+        let choose = |accept: &str| {
+            let builder = Request::builder().header("Accept-Encoding", accept);
+            choose_encoding(&builder.request(), &["br", "gzip"])
+        };
+        assert_eq!(choose("gzip, deflate, br"), Some("br"));
+        assert_eq!(choose("gzip;q=1, br;q=0.5"), Some("gzip"));
+        assert_eq!(choose("br;q=0, *"), Some("gzip"));
+        assert_eq!(choose("identity"), None);
+        assert_eq!(choose_encoding(&Request::builder().request(), &["gzip"]), None);
+    }
+}
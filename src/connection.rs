@@ -0,0 +1,75 @@
+//! What's known about the connection a request came in on, beyond the request itself.
+//!
+//! Whatever accepts connections fills it in; the router hands it to handlers as
+//! Context::connection:
+//!
+//! ```no_run
+//! # #[cfg(feature = "tls")]
+//! # async fn serve(acceptor: oc_http::tls::TlsAcceptor, router: oc_http::router::Router, tcp: async_std::net::TcpStream,
+//! #     peer: std::net::SocketAddr, local: std::net::SocketAddr, req: oc_http::Request<'_>, body: Vec<u8>) -> std::io::Result<()> {
+//! let stream = acceptor.accept(tcp).await?;
+//! let connection = stream.connection_info(Some(peer), Some(local));
+//! let (response, body) = router.handle_from(&connection, &req, &body).await;
+//! # Ok(())
+//! # }
+//! ```
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub peer: Option<SocketAddr>,
+    pub local: Option<SocketAddr>,
+    /// None for plain connections.
+    pub tls: Option<TlsInfo>,
+}
+
+impl ConnectionInfo {
+    /// Knowing nothing; what handlers see when the router isn't told.
+    pub const UNKNOWN: ConnectionInfo = ConnectionInfo{peer: None, local: None, tls: None};
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+}
+
+/// What a TLS handshake settled on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Like `TLSv1.3`.
+    pub version: String,
+    /// The IANA name, like `TLS13_AES_128_GCM_SHA256`.
+    pub cipher_suite: String,
+    /// The name the client asked for with SNI.
+    pub server_name: Option<String>,
+    /// The protocol agreed with ALPN, like `http/1.1`.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// The client's certificate chain, DER-encoded and leaf first, if it sent one.
+    pub peer_certificates: Option<Vec<Vec<u8>>>,
+    /// Who the client's certificate says it is, once it's been verified.
+    pub client_identity: Option<ClientIdentity>,
+}
+
+/// The names in a verified client certificate, for deciding what the client may do.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// The subject's attributes in the order the certificate has them, by their short
+    /// names (`CN`, `O`...) or dotted OIDs.
+    pub subject: Vec<(String, String)>,
+    pub alt_names: Vec<SubjectAltName>,
+}
+
+impl ClientIdentity {
+    /// The last CN in the subject, the most specific one.
+    pub fn common_name(&self) -> Option<&str> {
+        self.subject.iter().rev().find(|(name, _)| name == "CN").map(|(_, value)| value.as_str())
+    }
+}
+
+/// The subject alternative names the crate reads; others are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
@@ -0,0 +1,146 @@
+//! Media types, as in Content-Type: `text/html; charset=utf-8`.
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{content_type::ContentType, respond, Request, Response};
+//! # fn read_json() {}
+//! # fn read_parts(boundary: Option<&str>) {}
+//! # async fn serve(request: &Request<'_>, stream: &mut TcpStream, unsupported: Response) -> std::io::Result<()> {
+//! match ContentType::from_request(&request) {
+//!     Some(ct) if ct.is("application/json") => read_json(),
+//!     Some(ct) if ct.is("multipart/form-data") => read_parts(ct.boundary()),
+//!     _ => return respond(stream, unsupported).await,
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{is_tchar, quote, split_quoted, unquote, Request};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// Lowercased, as are subtype and parameter names; they're case-insensitive.
+    pub typ: String,
+    pub subtype: String,
+    /// Unquoted, in the order they were given.
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn new(typ: &str, subtype: &str) -> Self {
+        ContentType{
+            typ: typ.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params: vec!(),
+        }
+    }
+
+    /// Parses a header value; None unless it starts with a valid type/subtype. Parameters
+    /// that aren't name=value are skipped.
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        let value = std::str::from_utf8(value).ok()?;
+        let mut parts = split_quoted(value, ';').into_iter();
+        let (typ, subtype) = parts.next()?.split_once('/')?;
+        let (typ, subtype) = (typ.trim(), subtype.trim());
+        let token = |t: &str| !t.is_empty() && t.bytes().all(is_tchar);
+        if !token(typ) || !token(subtype) {
+            return None;
+        }
+        let mut ct = ContentType::new(typ, subtype);
+        for param in parts {
+            if let Some((name, value)) = param.split_once('=') {
+                let name = name.trim();
+                if token(name) {
+                    ct.params.push((name.to_ascii_lowercase(), unquote(value.trim())));
+                }
+            }
+        }
+        Some(ct)
+    }
+
+    /// The request's Content-Type, if it has a valid one.
+    pub fn from_request(req: &Request) -> Option<Self> {
+        ContentType::parse(req.header("Content-Type")?)
+    }
+
+    /// Adds a parameter.
+    pub fn param(mut self, name: &str, value: &str) -> Self {
+        self.params.push((name.to_ascii_lowercase(), value.into()));
+        self
+    }
+
+    /// The parameter's value; the first one, if it's repeated.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| &v[..])
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.get("charset")
+    }
+
+    pub fn boundary(&self) -> Option<&str> {
+        self.get("boundary")
+    }
+
+    /// type/subtype, without the parameters.
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.typ, self.subtype)
+    }
+
+    /// Whether it's the media type, ignoring parameters; `*` matches any type or subtype,
+    /// e.g. `text/*`.
+    pub fn is(&self, media_type: &str) -> bool {
+        let (typ, subtype) = match media_type.split_once('/') {
+            Some(split) => split,
+            None => return false,
+        };
+        (typ == "*" || typ.eq_ignore_ascii_case(&self.typ))
+            && (subtype == "*" || subtype.eq_ignore_ascii_case(&self.subtype))
+    }
+
+    pub fn header(&self) -> (String, Vec<u8>) {
+        ("Content-Type".into(), self.to_string().into_bytes())
+    }
+}
+
+impl fmt::Display for ContentType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.typ, self.subtype)?;
+        for (name, value) in &self.params {
+            if !value.is_empty() && value.bytes().all(is_tchar) {
+                write!(f, "; {}={}", name, value)?;
+            } else {
+                write!(f, "; {}={}", name, quote(value))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_type() {
+        let ct = ContentType::parse(b"Multipart/Form-Data; Boundary=\"a;b \\\"c\\\"\"; charset=UTF-8; junk").unwrap();
+        assert_eq!(ct.essence(), "multipart/form-data");
+        assert!(ct.is("multipart/form-data"));
+        assert!(ct.is("multipart/*"));
+        assert!(ct.is("*/*"));
+        assert!(!ct.is("text/*"));
+        assert_eq!(ct.boundary(), Some("a;b \"c\""));
+        assert_eq!(ct.charset(), Some("UTF-8"));
+        assert_eq!(ct.to_string(), "multipart/form-data; boundary=\"a;b \\\"c\\\"\"; charset=UTF-8");
+        assert_eq!(ContentType::parse(ct.to_string().as_bytes()), Some(ct));
+
+        for bad in &[&b""[..], b"text", b"text/", b"te xt/html", b"/html; charset=utf-8"] {
+            assert_eq!(ContentType::parse(bad), None, "{:?}", bad);
+        }
+        let json = ContentType::new("application", "json").param("charset", "utf-8");
+        assert_eq!(json.header().1, b"application/json; charset=utf-8");
+        let builder = Request::builder().header("content-type", "text/plain");
+        assert!(ContentType::from_request(&builder.request()).unwrap().is("text/plain"));
+    }
+}
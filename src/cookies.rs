@@ -1,39 +1,156 @@
 use std::{
     collections::HashMap,
+    fmt,
     str,
+    time::Duration,
 };
 
 use log::{warn};
-pub use cookie::Cookie;
+pub use cookie::{Cookie, SameSite};
 
 use crate::{
     Request,
     Response,
 };
 
+pub mod private;
+pub mod signed;
+
+use private::PrivateCookies;
+use signed::SignedCookies;
+
+/// Server secrets for signed and private cookies. New cookies use the newest key, while ones made with
+/// older keys are still accepted, so keys can be rotated without invalidating every cookie
+/// at once. Use at least 32 random bytes per key.
+#[derive(Clone)]
+pub struct Keys {
+    // newest first
+    keys: Vec<Vec<u8>>,
+}
+
+impl Keys {
+    pub fn new(key: &[u8]) -> Self {
+        Keys{
+            keys: vec!(Vec::from(key)),
+        }
+    }
+
+    /// Makes `key` the one new cookies use; the current keys are still accepted.
+    pub fn rotate(mut self, key: &[u8]) -> Self {
+        self.keys.insert(0, Vec::from(key));
+        self
+    }
+
+    /// Also accepts cookies made with an older key.
+    pub fn with_old_key(mut self, key: &[u8]) -> Self {
+        self.keys.push(Vec::from(key));
+        self
+    }
+
+    fn newest(&self) -> &[u8] {
+        &self.keys[0]
+    }
+
+    fn all(&self) -> impl Iterator<Item = &[u8]> {
+        self.keys.iter().map(|k| &k[..])
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never print the secrets themselves
+        write!(f, "Keys({} keys)", self.keys.len())
+    }
+}
+
+/// Attributes every cookie added to a jar gets unless it sets them itself, whether it's
+/// added with Cookies::add and friends or built by hand and given to add_cookie. The
+/// defaults are the safe ones, suiting a session-style cookie: sent for the whole site,
+/// only over https, hidden from scripts, and not sent on cross-site subrequests.
+///
+/// A cookie opts out by setting the attribute itself, like `set_secure(false)` for one that
+/// has to work over plain http during development; a jar opts out by changing the defaults.
+/// Cookies with `SameSite=None` are always made Secure, since browsers refuse them otherwise.
+#[derive(Debug, Clone)]
+pub struct CookieDefaults {
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>,
+    /// None makes it a session cookie, dropped when the browser closes.
+    pub max_age: Option<Duration>,
+}
+
+impl Default for CookieDefaults {
+    fn default() -> Self {
+        CookieDefaults{
+            path: Some("/".into()),
+            domain: None,
+            secure: true,
+            http_only: true,
+            same_site: Some(SameSite::Lax),
+            max_age: None,
+        }
+    }
+}
+
+impl CookieDefaults {
+    /// Sets the attributes the cookie hasn't.
+    pub fn apply(&self, cookie: &mut Cookie) {
+        if let (None, Some(path)) = (cookie.path(), &self.path) {
+            cookie.set_path(path.clone());
+        }
+        if let (None, Some(domain)) = (cookie.domain(), &self.domain) {
+            cookie.set_domain(domain.clone());
+        }
+        if self.secure && cookie.secure().is_none() {
+            cookie.set_secure(true);
+        }
+        if self.http_only && cookie.http_only().is_none() {
+            cookie.set_http_only(true);
+        }
+        if let (None, Some(same_site)) = (cookie.same_site(), self.same_site) {
+            cookie.set_same_site(same_site);
+        }
+        if cookie.same_site() == Some(SameSite::None) {
+            cookie.set_secure(true);
+        }
+        if let (None, Some(max_age)) = (cookie.max_age(), self.max_age) {
+            cookie.set_max_age(to_time(max_age));
+        }
+    }
+}
+
 pub struct Cookies<'c> {
     cookies: HashMap<String, Cookie<'c>>,
     cookies_to_set: Vec<Cookie<'c>>,
+    defaults: CookieDefaults,
 }
 
 impl<'a> Cookies<'a> {
     pub fn new(req: &'a Request) -> Self {
+        Cookies::with_defaults(req, CookieDefaults::default())
+    }
+
+    /// Like new, but cookies added get the given attributes rather than the safe defaults.
+    pub fn with_defaults(req: &'a Request, defaults: CookieDefaults) -> Self {
         let mut cookies = HashMap::default();
-        let iter_cookies = req.headers.get("Cookie");
-        if iter_cookies.is_none() {
-            return Cookies{
-                cookies,
-                cookies_to_set: vec!(),
-            }
-        }
-        for cookie in iter_cookies.unwrap().0.split(|x| *x == b';') {
+        // clients and proxies may split cookies over several headers
+        let pairs = req.header_values("Cookie").into_iter()
+            .flat_map(|header| header.split(|x| *x == b';'));
+        for cookie in pairs {
             let cookie = match str::from_utf8(cookie) {
-                Ok(s) => s,
+                Ok(s) => s.trim(),
                 Err(_) => {
                     warn!("Invalid cookie being ignored!");
                     continue;
                 }
             };
+            if cookie.is_empty() {
+                // e.g. a trailing ;
+                continue;
+            }
             let cookie = Cookie::parse_encoded(cookie);
             if cookie.is_err() {
                 warn!("Invalid cookie being ignored!");
@@ -45,21 +162,218 @@ impl<'a> Cookies<'a> {
         Cookies {
             cookies,
             cookies_to_set: vec!(),
+            defaults,
         }
     }
 
-    pub fn get(&self, s: &str) -> Option<&'a Cookie> {
-        self.cookies.get(s)
+    pub fn get(&self, name: &str) -> Option<&Cookie<'a>> {
+        self.cookies.get(name)
     }
 
-    pub fn add_cookie(&mut self, cookie: Cookie<'a>) {
+    pub fn get_value(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(|c| c.value())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.cookies.contains_key(name)
+    }
+
+    /// Every cookie, including ones added since the request came in, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Cookie<'a>> {
+        self.cookies.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cookies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cookies.is_empty()
+    }
+
+    /// Adds the cookie, with the defaults for whatever attributes it doesn't set itself.
+    pub fn add_cookie(&mut self, mut cookie: Cookie<'a>) {
+        self.defaults.apply(&mut cookie);
         self.cookies_to_set.push(cookie.clone());
         self.cookies.insert(String::from(cookie.name()), cookie);
     }
 
+    /// Adds a cookie with the default attributes.
+    pub fn add(&mut self, name: &str, value: &str) {
+        let cookie = self.build(name, value);
+        self.add_cookie(cookie);
+    }
+
+    /// Adds a cookie that's only sent over https and can't be read by scripts.
+    pub fn add_secure(&mut self, name: &str, value: &str, same_site: SameSite) {
+        let mut cookie = self.build(name, value);
+        cookie.set_secure(true);
+        cookie.set_http_only(true);
+        cookie.set_same_site(same_site);
+        self.add_cookie(cookie);
+    }
+
+    /// Adds a cookie with the default attributes that expires after `max_age`.
+    pub fn add_with_max_age(&mut self, name: &str, value: &str, max_age: Duration) {
+        let mut cookie = self.build(name, value);
+        cookie.set_max_age(to_time(max_age));
+        self.add_cookie(cookie);
+    }
+
+    /// Tells the browser to forget the cookie; it has to use the same path and domain as
+    /// the cookie being removed, which it will if both used the same defaults.
+    pub fn remove(&mut self, name: &str) {
+        let mut cookie = self.build(name, "");
+        cookie.set_max_age(time::Duration::zero());
+        cookie.set_expires(time::OffsetDateTime::now_utc() - time::Duration::days(365));
+        self.add_cookie(cookie);
+        self.cookies.remove(name);
+    }
+
+    /// The cookies signed with `keys`; see the signed module.
+    pub fn signed<'k>(&'k mut self, keys: &'k Keys) -> SignedCookies<'k, 'a> {
+        SignedCookies::new(self, keys)
+    }
+
+    /// The cookies encrypted with `keys`; see the private module.
+    pub fn private<'k>(&'k mut self, keys: &'k Keys) -> PrivateCookies<'k, 'a> {
+        PrivateCookies::new(self, keys)
+    }
+
     pub fn write_cookies(&self, resp: &mut Response) {
         for cookie in &self.cookies_to_set {
             resp.headers.push(("Set-Cookie".into(), Vec::from(format!("{}", cookie.encoded()))));
         }
     }
-}
\ No newline at end of file
+
+    fn build(&self, name: &str, value: &str) -> Cookie<'a> {
+        Cookie::new(String::from(name), String::from(value))
+    }
+}
+
+fn to_time(d: Duration) -> time::Duration {
+    time::Duration::seconds(d.as_secs().min(i64::MAX as u64) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn set_cookies(cookies: &Cookies) -> Vec<String> {
+        let mut resp = Response::default();
+        cookies.write_cookies(&mut resp);
+        resp.headers.into_iter()
+            .map(|(_, v)| String::from_utf8(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_attribute_helpers() {
+        let req = Request{
+            method: "GET".into(),
+            path: "/".into(),
+            headers: HashMap::default(),
+        };
+        let mut cookies = Cookies::new(&req);
+        cookies.add("theme", "dark");
+        cookies.add_secure("sid", "abc", SameSite::Strict);
+        cookies.add_with_max_age("seen", "1", Duration::from_secs(60));
+        assert_eq!(set_cookies(&cookies), vec!(
+            "theme=dark; HttpOnly; SameSite=Lax; Secure; Path=/",
+            "sid=abc; HttpOnly; SameSite=Strict; Secure; Path=/",
+            "seen=1; HttpOnly; SameSite=Lax; Secure; Path=/; Max-Age=60",
+        ));
+        assert_eq!(cookies.get("sid").unwrap().value(), "abc");
+
+        let mut cookies = Cookies::with_defaults(&req, CookieDefaults{
+            path: None,
+            secure: true,
+            http_only: false,
+            same_site: None,
+            ..CookieDefaults::default()
+        });
+        cookies.add("a", "b");
+        cookies.remove("old");
+        let got = set_cookies(&cookies);
+        assert_eq!(got[0], "a=b; Secure");
+        assert!(got[1].starts_with("old=; Secure; Max-Age=0"), "{}", got[1]);
+        assert!(cookies.get("old").is_none());
+    }
+
+    #[test]
+    fn test_add_cookie_defaults() {
+        let builder = Request::builder();
+        let req = builder.request();
+        let mut cookies = Cookies::new(&req);
+        cookies.add_cookie(Cookie::new("sid", "abc"));
+        let mut dev = Cookie::new("dev", "1");
+        dev.set_secure(false);
+        dev.set_http_only(false);
+        cookies.add_cookie(dev);
+        let mut admin = Cookie::new("admin", "1");
+        admin.set_path("/admin");
+        admin.set_same_site(SameSite::Strict);
+        cookies.add_cookie(admin);
+        // browsers drop SameSite=None cookies that aren't Secure
+        let mut embed = Cookie::new("embed", "1");
+        embed.set_secure(false);
+        embed.set_same_site(SameSite::None);
+        cookies.add_cookie(embed);
+        assert_eq!(set_cookies(&cookies), vec!(
+            "sid=abc; HttpOnly; SameSite=Lax; Secure; Path=/",
+            "dev=1; SameSite=Lax; Path=/",
+            "admin=1; HttpOnly; SameSite=Strict; Secure; Path=/admin",
+            "embed=1; HttpOnly; SameSite=None; Secure; Path=/",
+        ));
+
+        let mut cookies = Cookies::with_defaults(&req, CookieDefaults{secure: false, ..CookieDefaults::default()});
+        let mut embed = Cookie::new("embed", "1");
+        embed.set_same_site(SameSite::None);
+        cookies.add_cookie(embed);
+        cookies.add("plain", "1");
+        assert_eq!(set_cookies(&cookies), vec!(
+            "embed=1; HttpOnly; SameSite=None; Secure; Path=/",
+            "plain=1; HttpOnly; SameSite=Lax; Path=/",
+        ));
+    }
+
+    #[test]
+    fn test_multiple_headers() {
+        let mut headers = HashMap::default();
+        headers.insert("Cookie", (&b"a=1;b=2"[..], Some(vec!(&b" c=3 ;; "[..], &b"a=4"[..]))));
+        let req = Request{
+            method: "GET".into(),
+            path: "/".into(),
+            headers,
+        };
+        let cookies = Cookies::new(&req);
+        assert_eq!(cookies.get("b").unwrap().value(), "2");
+        assert_eq!(cookies.get("c").unwrap().value(), "3");
+        // the last one wins, like it would if they'd all been in one header
+        assert_eq!(cookies.get("a").unwrap().value(), "4");
+    }
+
+    #[test]
+    fn test_inspecting() {
+        let mut headers = HashMap::default();
+        headers.insert("Cookie", (&b"a=1; b=2"[..], None));
+        let req = Request{
+            method: "GET".into(),
+            path: "/".into(),
+            headers,
+        };
+        let mut cookies = Cookies::new(&req);
+        // borrows of the jar don't have to outlive the request
+        let value = cookies.get_value("a").map(String::from);
+        cookies.add("c", "3");
+        assert_eq!(value.as_deref(), Some("1"));
+        assert_eq!(cookies.get_value("c"), Some("3"));
+        assert!(cookies.contains("b") && !cookies.contains("d"));
+        assert_eq!(cookies.len(), 3);
+        let mut names: Vec<_> = cookies.iter().map(|c| c.name()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!("a", "b", "c"));
+    }
+}
@@ -0,0 +1,155 @@
+//! Cookies whose values are encrypted with ChaCha20-Poly1305, so the client can neither read
+//! nor change them; e.g. for session identifiers or small bits of user data.
+//!
+//! The encryption key is derived from each of the Keys' secrets, so the same Keys can be used
+//! for signed and private cookies. The cookie's name is authenticated along with the value.
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+    Key,
+    Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{Cookie, Cookies, Keys, SameSite};
+
+const NONCE_LEN: usize = 12;
+
+pub struct PrivateCookies<'k, 'c> {
+    cookies: &'k mut Cookies<'c>,
+    keys: &'k Keys,
+}
+
+impl<'k, 'c> PrivateCookies<'k, 'c> {
+    pub(super) fn new(cookies: &'k mut Cookies<'c>, keys: &'k Keys) -> Self {
+        PrivateCookies{cookies, keys}
+    }
+
+    /// The decrypted value of the cookie, if it's there and was encrypted with one of the keys.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let cookie = self.cookies.get(name)?;
+        let sealed = base64::decode_config(cookie.value(), base64::URL_SAFE_NO_PAD).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.keys.all()
+            .find_map(|key| {
+                let payload = Payload{msg: ciphertext, aad: name.as_bytes()};
+                cipher(key).decrypt(Nonce::from_slice(nonce), payload).ok()
+            })
+            .and_then(|plain| String::from_utf8(plain).ok())
+    }
+
+    /// Adds an encrypted cookie with the default attributes.
+    pub fn add(&mut self, name: &str, value: &str) {
+        let cookie = self.cookies.build(name, value);
+        self.add_cookie(cookie);
+    }
+
+    /// Like Cookies::add_secure, but encrypted.
+    pub fn add_secure(&mut self, name: &str, value: &str, same_site: SameSite) {
+        let mut cookie = self.cookies.build(name, value);
+        cookie.set_secure(true);
+        cookie.set_http_only(true);
+        cookie.set_same_site(same_site);
+        self.add_cookie(cookie);
+    }
+
+    /// Encrypts the cookie's value with the newest key and adds it, keeping its attributes.
+    pub fn add_cookie(&mut self, mut cookie: Cookie<'c>) {
+        // a fresh nonce every time, so equal values don't give equal cookies
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let payload = Payload{msg: cookie.value().as_bytes(), aad: cookie.name().as_bytes()};
+        let mut sealed = Vec::from(&nonce[..]);
+        sealed.extend(cipher(self.keys.newest())
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("encrypting into a Vec can't fail"));
+        cookie.set_value(base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD));
+        self.cookies.add_cookie(cookie);
+    }
+}
+
+/// derives the encryption key from a secret, so it's never the same as the signing key
+fn cipher(secret: &[u8]) -> ChaCha20Poly1305 {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).expect("hmac takes keys of any size");
+    mac.update(b"oc-http private cookies");
+    ChaCha20Poly1305::new(Key::from_slice(&mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Request, Response};
+
+    fn set_cookie_values(cookies: &Cookies) -> Vec<String> {
+        let mut resp = Response::default();
+        cookies.write_cookies(&mut resp);
+        resp.headers.iter()
+            .map(|(_, v)| String::from_utf8(v.clone()).unwrap())
+            .map(|c| c.split(';').next().unwrap().to_string())
+            .collect()
+    }
+
+    fn keys() -> (Keys, Keys) {
+        let old = Keys::new(b"an old key that's been retired!!");
+        let keys = old.clone().rotate(b"the current key, 32 bytes long!!");
+        (old, keys)
+    }
+
+    /// "sid" encrypted by the old key, and "sid2", with the same value, by the current one
+    fn encrypted(old: &Keys, keys: &Keys) -> Vec<String> {
+        let empty = Request::builder();
+        let empty = empty.request();
+        let mut cookies = Cookies::new(&empty);
+        cookies.private(old).add("sid", "secret session id");
+        cookies.private(keys).add("sid2", "secret session id");
+        set_cookie_values(&cookies)
+    }
+
+    #[test]
+    fn test_encrypted() {
+        let (old, keys) = keys();
+        let set = encrypted(&old, &keys);
+        assert!(!set[0].contains("secret"), "{}", set[0]);
+        // same value, different nonce
+        assert_ne!(set[0].trim_start_matches("sid="), set[1].trim_start_matches("sid2="));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (old, keys) = keys();
+        let req = Request::builder().header("Cookie", encrypted(&old, &keys).join("; "));
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.private(&keys).get("sid").as_deref(), Some("secret session id"));
+        assert_eq!(cookies.private(&keys).get("sid2").as_deref(), Some("secret session id"));
+        // only the new key encrypted "sid2"
+        assert_eq!(cookies.private(&old).get("sid2"), None);
+        // signed and private cookies don't use the same key
+        assert_eq!(cookies.signed(&keys).get("sid"), None);
+    }
+
+    #[test]
+    fn test_renamed() {
+        let (old, keys) = keys();
+        let set = encrypted(&old, &keys);
+        let req = Request::builder().header("Cookie", format!("other={}", set[0].trim_start_matches("sid=")));
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.private(&keys).get("other"), None);
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (old, keys) = keys();
+        let mut flipped = encrypted(&old, &keys).remove(0).into_bytes();
+        let last = flipped.len() - 1;
+        flipped[last] = if flipped[last] == b'A' { b'B' } else { b'A' };
+        let req = Request::builder().header("Cookie", flipped);
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.private(&keys).get("sid"), None);
+    }
+}
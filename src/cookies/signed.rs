@@ -0,0 +1,158 @@
+//! Cookies carrying an HMAC-SHA256 tag, so the client can read them but not change them.
+//!
+//! The tag covers the cookie's name as well as its value, so a value signed for one cookie
+//! can't be passed off as another.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{Cookie, Cookies, Keys, SameSite};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// a 32 byte tag in unpadded url-safe base64
+const TAG_LEN: usize = 43;
+
+pub struct SignedCookies<'k, 'c> {
+    cookies: &'k mut Cookies<'c>,
+    keys: &'k Keys,
+}
+
+impl<'k, 'c> SignedCookies<'k, 'c> {
+    pub(super) fn new(cookies: &'k mut Cookies<'c>, keys: &'k Keys) -> Self {
+        SignedCookies{cookies, keys}
+    }
+
+    /// The value of the cookie, if it's there and was signed with one of the keys.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let cookie = self.cookies.get(name)?;
+        verify(self.keys, name, cookie.value()).map(Into::into)
+    }
+
+    /// Adds a signed cookie with the default attributes.
+    pub fn add(&mut self, name: &str, value: &str) {
+        let cookie = self.cookies.build(name, value);
+        self.add_cookie(cookie);
+    }
+
+    /// Like Cookies::add_secure, but signed.
+    pub fn add_secure(&mut self, name: &str, value: &str, same_site: SameSite) {
+        let mut cookie = self.cookies.build(name, value);
+        cookie.set_secure(true);
+        cookie.set_http_only(true);
+        cookie.set_same_site(same_site);
+        self.add_cookie(cookie);
+    }
+
+    /// Signs the cookie's value with the newest key and adds it, keeping its attributes.
+    pub fn add_cookie(&mut self, mut cookie: Cookie<'c>) {
+        let signed = sign(self.keys.newest(), cookie.name(), cookie.value());
+        cookie.set_value(signed);
+        self.cookies.add_cookie(cookie);
+    }
+}
+
+fn mac(key: &[u8], name: &str, value: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac takes keys of any size");
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(value.as_bytes());
+    mac
+}
+
+fn sign(key: &[u8], name: &str, value: &str) -> String {
+    let tag = mac(key, name, value).finalize().into_bytes();
+    let mut signed = base64::encode_config(tag, base64::URL_SAFE_NO_PAD);
+    signed.push_str(value);
+    signed
+}
+
+/// returns the value without its tag if any of the keys signed it
+fn verify<'v>(keys: &Keys, name: &str, signed: &'v str) -> Option<&'v str> {
+    if signed.len() < TAG_LEN || !signed.is_char_boundary(TAG_LEN) {
+        return None;
+    }
+    let (tag, value) = signed.split_at(TAG_LEN);
+    let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+    // verify_slice compares in constant time
+    keys.all()
+        .any(|key| mac(key, name, value).verify_slice(&tag).is_ok())
+        .then_some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Request, Response};
+
+    /// the Cookie header a browser would send back after the response
+    fn round_trip(cookies: &Cookies) -> String {
+        let mut resp = Response::default();
+        cookies.write_cookies(&mut resp);
+        resp.headers.iter()
+            .map(|(_, v)| String::from_utf8(v.clone()).unwrap())
+            .map(|c| c.split(';').next().unwrap().to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    fn keys() -> (Keys, Keys) {
+        let old = Keys::new(b"an old key that's been retired!!");
+        let keys = old.clone().rotate(b"the current key, 32 bytes long!!");
+        (old, keys)
+    }
+
+    /// the Cookie header with "user" signed by the old key, and "role" by the current one
+    fn signed(old: &Keys, keys: &Keys) -> String {
+        let empty = Request::builder();
+        let empty = empty.request();
+        let mut cookies = Cookies::new(&empty);
+        cookies.signed(old).add("user", "alice");
+        cookies.signed(keys).add("role", "admin; really");
+        round_trip(&cookies)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (old, keys) = keys();
+        let req = Request::builder().header("Cookie", signed(&old, &keys));
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.signed(&keys).get("user").as_deref(), Some("alice"));
+        assert_eq!(cookies.signed(&keys).get("role").as_deref(), Some("admin; really"));
+        assert_eq!(cookies.signed(&keys).get("missing"), None);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let (old, keys) = keys();
+        let req = Request::builder().header("Cookie", signed(&old, &keys));
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        // only the new key signed "role"
+        assert_eq!(cookies.signed(&old).get("user").as_deref(), Some("alice"));
+        assert_eq!(cookies.signed(&old).get("role"), None);
+        assert_eq!(cookies.signed(&Keys::new(b"someone else")).get("user"), None);
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (old, keys) = keys();
+        let header = signed(&old, &keys);
+        let req = Request::builder().header("Cookie", header.replace("alice", "mallory"));
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.signed(&keys).get("user"), None);
+    }
+
+    #[test]
+    fn test_renamed() {
+        // a value signed for one name doesn't verify under another
+        let (old, keys) = keys();
+        let header = signed(&old, &keys);
+        let moved = format!("admin={}", header.split("; ").next().unwrap().trim_start_matches("user="));
+        let req = Request::builder().header("Cookie", moved);
+        let req = req.request();
+        let mut cookies = Cookies::new(&req);
+        assert_eq!(cookies.signed(&keys).get("admin"), None);
+    }
+}
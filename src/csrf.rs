@@ -0,0 +1,265 @@
+//! Cross-site request forgery protection with double-submit cookies.
+//!
+//! The token lives in a signed cookie, and every form (or script) that changes something has to
+//! send it back in a field or header. Another site can make the browser send the cookie, but it
+//! can't read it, so it can't send the token too.
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{cookies::{Cookies, Keys}, csrf::Csrf, respond, Request};
+//! # async fn serve(request: &Request<'_>, stream: &mut TcpStream, keys: Keys, mut cookies: Cookies<'_>, body: Vec<u8>) -> std::io::Result<()> {
+//! let csrf = Csrf::new(keys);
+//! // when rendering a form
+//! let input = csrf.hidden_input(&csrf.token(&mut cookies));
+//! // when handling any request
+//! if let Err(e) = csrf.verify(&request, &mut cookies, Some(&body)) {
+//!     return respond(stream, e.response()).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! On a Router, Csrf::middleware does the checking for every request it wraps, looking in
+//! urlencoded form bodies as well as the header.
+use std::fmt;
+
+use futures::future::BoxFuture;
+
+use crate::{
+    constant_time_eq,
+    content_type::ContentType,
+    cookies::{Cookies, Keys},
+    encoding::form_urldecode,
+    router::{Context, HandlerError, HandlerResult, Next},
+    Request,
+    Response,
+};
+
+pub const DEFAULT_COOKIE_NAME: &str = "csrf";
+pub const DEFAULT_HEADER_NAME: &str = "X-CSRF-Token";
+pub const DEFAULT_FIELD_NAME: &str = "csrf_token";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsrfError {
+    /// The request has no (validly signed) token cookie.
+    NoCookie,
+    /// The request didn't send the token in the header or form.
+    NoToken,
+    Mismatch,
+}
+
+impl CsrfError {
+    /// What to reply with: a 403.
+    pub fn response(&self) -> Response {
+        Response{
+            code: 403,
+            reason: "Forbidden",
+            headers: vec!(("Content-Length".into(), Vec::from("0"))),
+        }
+    }
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrfError::NoCookie => write!(f, "csrf: no token cookie"),
+            CsrfError::NoToken => write!(f, "csrf: no token in the request"),
+            CsrfError::Mismatch => write!(f, "csrf: token doesn't match the cookie"),
+        }
+    }
+}
+
+impl std::error::Error for CsrfError {}
+
+pub struct Csrf {
+    keys: Keys,
+    cookie_name: String,
+    header_name: String,
+    field_name: String,
+}
+
+impl Csrf {
+    pub fn new(keys: Keys) -> Self {
+        Csrf{
+            keys,
+            cookie_name: DEFAULT_COOKIE_NAME.into(),
+            header_name: DEFAULT_HEADER_NAME.into(),
+            field_name: DEFAULT_FIELD_NAME.into(),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// The header scripts send the token in.
+    pub fn header_name(mut self, name: &str) -> Self {
+        self.header_name = name.into();
+        self
+    }
+
+    /// The form field forms send the token in.
+    pub fn field_name(mut self, name: &str) -> Self {
+        self.field_name = name.into();
+        self
+    }
+
+    /// The client's token, setting the cookie if it doesn't have one yet. For scripts, put it
+    /// somewhere on the page they can read, e.g. a meta tag.
+    pub fn token(&self, cookies: &mut Cookies) -> String {
+        let mut signed = cookies.signed(&self.keys);
+        if let Some(token) = signed.get(&self.cookie_name) {
+            return token;
+        }
+        let token = base64::encode_config(rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+        signed.add(&self.cookie_name, &token);
+        token
+    }
+
+    /// A hidden form input carrying the token.
+    pub fn hidden_input(&self, token: &str) -> String {
+        // tokens are url-safe base64, so there's nothing to escape
+        format!("<input type=\"hidden\" name=\"{}\" value=\"{}\">", self.field_name, token)
+    }
+
+    /// Checks requests with unsafe methods (anything but GET, HEAD, OPTIONS and TRACE) carry
+    /// the token, in the header or in the urlencoded form body if one is given.
+    pub fn verify(&self, req: &Request, cookies: &mut Cookies, form: Option<&[u8]>) -> Result<(), CsrfError> {
+        if ["GET", "HEAD", "OPTIONS", "TRACE"].contains(&&req.method[..]) {
+            return Ok(());
+        }
+        let expected = cookies.signed(&self.keys).get(&self.cookie_name).ok_or(CsrfError::NoCookie)?;
+        let sent = match req.header(&self.header_name) {
+            Some(value) => String::from_utf8_lossy(value).into_owned(),
+            None => form
+                .and_then(|form| form_urldecode(form).into_iter()
+                    .find(|(k, _)| *k == self.field_name)
+                    .map(|(_, v)| v))
+                .ok_or(CsrfError::NoToken)?,
+        };
+        if constant_time_eq(sent.as_bytes(), expected.as_bytes()) {
+            Ok(())
+        } else {
+            Err(CsrfError::Mismatch)
+        }
+    }
+
+    /// Router middleware answering requests with unsafe methods that don't carry the token
+    /// with a 403; the token can be in the header, or in the body of a urlencoded form.
+    pub fn middleware(self) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static {
+        move |cx, next| {
+            let form = ContentType::from_request(cx.request)
+                .filter(|ct| ct.is("application/x-www-form-urlencoded"))
+                .map(|_| cx.body);
+            match self.verify(cx.request, &mut Cookies::new(cx.request), form) {
+                Ok(()) => next.run(cx),
+                Err(err) => {
+                    let err = HandlerError::from_response(err.response(), err.to_string());
+                    Box::pin(async move { Err(err) })
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csrf() -> Csrf {
+        Csrf::new(Keys::new(b"a key that's 32 bytes long, ok?!"))
+    }
+
+    /// a new client's token, and the cookie it'd send back
+    fn issue(csrf: &Csrf) -> (String, String) {
+        let empty = Request::builder();
+        let empty = empty.request();
+        let mut cookies = Cookies::new(&empty);
+        let token = csrf.token(&mut cookies);
+        let mut resp = Response::default();
+        cookies.write_cookies(&mut resp);
+        let header = String::from_utf8(resp.headers[0].1.clone()).unwrap();
+        (token, header.split(';').next().unwrap().to_string())
+    }
+
+    fn verify(csrf: &Csrf, req: &crate::RequestBuilder, form: Option<&[u8]>) -> Result<(), CsrfError> {
+        let req = req.request();
+        csrf.verify(&req, &mut Cookies::new(&req), form)
+    }
+
+    #[test]
+    fn test_token() {
+        let csrf = csrf();
+        let (token, cookie) = issue(&csrf);
+        assert!(csrf.hidden_input(&token).contains(&token));
+        // the same client keeps its token, and safe methods need none
+        let req = Request::builder().header("Cookie", &*cookie);
+        let req = req.request();
+        assert_eq!(csrf.token(&mut Cookies::new(&req)), token);
+        assert_eq!(csrf.verify(&req, &mut Cookies::new(&req), None), Ok(()));
+    }
+
+    #[test]
+    fn test_header_token() {
+        let csrf = csrf();
+        let (token, cookie) = issue(&csrf);
+        let req = Request::builder().method("POST").header("Cookie", &*cookie);
+        assert_eq!(verify(&csrf, &req.clone().header("X-CSRF-Token", &*token), None), Ok(()));
+        assert_eq!(verify(&csrf, &req.clone().header("X-CSRF-Token", "guess"), None), Err(CsrfError::Mismatch));
+        assert_eq!(verify(&csrf, &req, None), Err(CsrfError::NoToken));
+    }
+
+    #[test]
+    fn test_form_token() {
+        let csrf = csrf();
+        let (token, cookie) = issue(&csrf);
+        let req = Request::builder().method("POST").header("Cookie", &*cookie);
+        let form = format!("name=x&csrf_token={}", token);
+        assert_eq!(verify(&csrf, &req, Some(form.as_bytes())), Ok(()));
+        assert_eq!(verify(&csrf, &req, Some(b"name=x")), Err(CsrfError::NoToken));
+        assert_eq!(verify(&csrf, &req, Some(b"csrf_token=guess")), Err(CsrfError::Mismatch));
+    }
+
+    #[test]
+    fn test_no_cookie() {
+        // a token without the cookie, or with a forged one, doesn't do
+        let csrf = csrf();
+        let (token, _) = issue(&csrf);
+        let req = Request::builder().method("DELETE").header("X-CSRF-Token", &*token);
+        assert_eq!(verify(&csrf, &req, None), Err(CsrfError::NoCookie));
+        let req = req.method("PUT").header("Cookie", format!("csrf={}", token));
+        assert_eq!(verify(&csrf, &req, None), Err(CsrfError::NoCookie));
+        assert_eq!(CsrfError::NoCookie.response().code, 403);
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        use crate::router::Router;
+
+        let csrf = csrf();
+        let (token, cookie) = issue(&csrf);
+        let router = Router::new()
+            .wrap(csrf.middleware())
+            .route("GET", "/", |_: &Context| Box::pin(async { Ok("form") }))
+            .route("POST", "/", |_: &Context| Box::pin(async { Ok("saved") }));
+        let handle = |request: crate::RequestBuilder, body: String| {
+            let router = &router;
+            async move {
+                let (response, body) = router.handle(&request.request(), body.as_bytes()).await;
+                (response.code, String::from_utf8(body).unwrap())
+            }
+        };
+        assert_eq!(handle(Request::builder(), String::new()).await, (200, "form".into()));
+        let post = Request::builder().method("POST").header("Cookie", &*cookie);
+        assert_eq!(handle(post.clone().header("X-CSRF-Token", &*token), String::new()).await, (200, "saved".into()));
+        let form = post.clone().header("Content-Type", "application/x-www-form-urlencoded");
+        assert_eq!(handle(form, format!("csrf_token={}", token)).await, (200, "saved".into()));
+        // only form bodies are looked in
+        let json = post.clone().header("Content-Type", "application/json");
+        assert_eq!(handle(json, format!("csrf_token={}", token)).await.0, 403);
+        assert_eq!(handle(post.header("X-CSRF-Token", "guess"), String::new()).await.0, 403);
+        let no_cookie = Request::builder().method("DELETE").header("X-CSRF-Token", &*token);
+        assert_eq!(handle(no_cookie, String::new()).await.0, 403);
+    }
+}
@@ -0,0 +1,129 @@
+//! Percent-encoding, and application/x-www-form-urlencoded, for everything in the crate
+//! that needs them.
+//!
+//! What has to be escaped depends on where the text is going; a `/` is fine in a path but
+//! not in one of its segments, and a `&` is fine in a path but not in a query value:
+//!
+//! ```no_run
+//! # use oc_http::encoding::{form_urldecode, percent_encode, Set};
+//! # let (file_id, name, query) = ("f1", "a b.txt", "q=1");
+//! let location = format!("/files/{}?name={}",
+//!     percent_encode(&file_id, Set::PathSegment),
+//!     percent_encode(&name, Set::Query));
+//! let query = form_urldecode(query.as_bytes());
+//! ```
+use std::fmt::Write;
+
+/// Characters that can be left as they are; everything else is escaped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Set {
+    /// Only the unreserved characters (letters, digits, `-._~`); safe anywhere, like in
+    /// cookie values.
+    Component,
+    /// One segment of a path: also the sub-delims, `:` and `@`, but not `/`.
+    PathSegment,
+    /// A whole path, keeping its `/`s.
+    Path,
+    /// A key or value in a query string: what a path segment can have, plus `/` and `?`,
+    /// less `&`, `=` and `+`, which mean something there.
+    Query,
+}
+
+impl Set {
+    fn keeps(self, b: u8) -> bool {
+        let unreserved = b.is_ascii_alphanumeric() || b"-._~".contains(&b);
+        let pchar = unreserved || b"!$&'()*+,;=:@".contains(&b);
+        match self {
+            Set::Component => unreserved,
+            Set::PathSegment => pchar,
+            Set::Path => pchar || b == b'/',
+            Set::Query => (pchar || b"/?".contains(&b)) && !b"&=+".contains(&b),
+        }
+    }
+}
+
+/// Escapes every byte of the input that the set doesn't keep, as %XX.
+pub fn percent_encode(input: &str, set: Set) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        if set.keeps(b) {
+            out.push(b as char);
+        } else {
+            let _ = write!(out, "%{:02X}", b);
+        }
+    }
+    out
+}
+
+/// Decodes %XX escapes; `%` without two hex digits after it is left alone, as browsers do.
+/// `+` stays a `+`; it's only a space in forms.
+pub fn percent_decode_bytes(input: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut out = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let escaped = match input.get(i + 1..i + 3) {
+            Some(&[hi, lo]) if input[i] == b'%' => hex(hi).zip(hex(lo)),
+            _ => None,
+        };
+        match escaped {
+            Some((hi, lo)) => {
+                out.push(hi << 4 | lo);
+                i += 3;
+            },
+            None => {
+                out.push(input[i]);
+                i += 1;
+            },
+        }
+    }
+    out
+}
+
+/// Decodes %XX escapes; None if what they decode to isn't utf-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    String::from_utf8(percent_decode_bytes(input.as_bytes())).ok()
+}
+
+/// Encodes pairs as a form body or query string, without a leading `?`.
+pub fn form_urlencode<I, K, V>(pairs: I) -> String
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    form_urlencoded::Serializer::new(String::new()).extend_pairs(pairs).finish()
+}
+
+/// Decodes a form body or query string into its pairs, in order; `+` is a space, and
+/// anything that isn't utf-8 is replaced.
+pub fn form_urldecode(input: &[u8]) -> Vec<(String, String)> {
+    form_urlencoded::parse(input).into_owned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent() {
+        let text = "a b/c?d&e=f+g@h:é%";
+        assert_eq!(percent_encode(text, Set::Component), "a%20b%2Fc%3Fd%26e%3Df%2Bg%40h%3A%C3%A9%25");
+        assert_eq!(percent_encode(text, Set::PathSegment), "a%20b%2Fc%3Fd&e=f+g@h:%C3%A9%25");
+        assert_eq!(percent_encode(text, Set::Path), "a%20b/c%3Fd&e=f+g@h:%C3%A9%25");
+        assert_eq!(percent_encode(text, Set::Query), "a%20b/c?d%26e%3Df%2Bg@h:%C3%A9%25");
+        for set in &[Set::Component, Set::PathSegment, Set::Path, Set::Query] {
+            assert_eq!(percent_decode(&percent_encode(text, *set)).as_deref(), Some(text));
+        }
+        assert_eq!(percent_decode("100%+%2x%4").as_deref(), Some("100%+%2x%4"));
+        assert_eq!(percent_decode("%ff"), None);
+        assert_eq!(percent_decode_bytes(b"%ff%41"), b"\xffA");
+    }
+
+    #[test]
+    fn test_form() {
+        let form = form_urlencode(vec!(("a b", "c&d"), ("é", "")));
+        assert_eq!(form, "a+b=c%26d&%C3%A9=");
+        assert_eq!(form_urldecode(form.as_bytes()), vec!(("a b".into(), "c&d".into()), ("é".into(), "".into())));
+    }
+}
@@ -0,0 +1,220 @@
+//! Caching headers for files: ETag and Last-Modified validators, Cache-Control picked by
+//! path, and 304s for conditional requests.
+//!
+//! There's no file handler in the crate; these are for handlers that read files
+//! themselves:
+//!
+//! ```no_run
+//! # use std::fs;
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{cache_control::CacheControl, files::{CacheRules, Validators}, respond, Request};
+//! # async fn serve(req: &Request<'_>, stream: &mut TcpStream, path: std::path::PathBuf) -> std::io::Result<()> {
+//! let rules = CacheRules::new()
+//!     .rule("/assets/*", CacheControl::new().public().max_age(31536000).immutable())
+//!     .rule("*", CacheControl::new().no_cache());
+//! let metadata = fs::metadata(&path)?;
+//! let validators = Validators::from_metadata(&metadata);
+//! let mut headers = validators.headers();
+//! headers.extend(rules.header(&req.path));
+//! if let Some(not_modified) = validators.not_modified(&req, headers.clone()) {
+//!     return respond(stream, not_modified).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    fs,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+use time::{OffsetDateTime, PrimitiveDateTime};
+
+use crate::{cache_control::CacheControl, split_quoted, Request, Response};
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Formats a time as an HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+    OffsetDateTime::from(time).format(HTTP_DATE)
+}
+
+/// Parses an HTTP-date in the preferred format; the obsolete ones aren't accepted.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    PrimitiveDateTime::parse(date.trim(), HTTP_DATE).ok().map(|t| t.assume_utc().into())
+}
+
+/// What a client can check its copy of a file against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    /// Quoted, and starting with `W/` if it's weak.
+    pub etag: Option<String>,
+    /// To the second, as HTTP-dates are.
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// A weak ETag from the size and modification time, and that time as Last-Modified;
+    /// cheap, since nothing's read, but a rewrite that keeps both the size and the
+    /// modification time keeps the tag too.
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let since_epoch = modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        Validators{
+            etag: Some(format!("W/\"{:x}-{:x}\"", metadata.len(), since_epoch.as_nanos())),
+            last_modified: modified.map(|_| UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())),
+        }
+    }
+
+    /// A strong ETag from a hash of the contents.
+    pub fn from_contents(contents: &[u8]) -> Self {
+        let hash = Sha256::digest(contents);
+        Validators{
+            etag: Some(format!("\"{}\"", base64::encode_config(&hash[..16], base64::URL_SAFE_NO_PAD))),
+            last_modified: None,
+        }
+    }
+
+    /// ETag and Last-Modified, for the ones there are.
+    pub fn headers(&self) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec!();
+        if let Some(etag) = &self.etag {
+            headers.push(("ETag".into(), Vec::from(etag.as_str())));
+        }
+        if let Some(modified) = self.last_modified {
+            headers.push(("Last-Modified".into(), http_date(modified).into_bytes()));
+        }
+        headers
+    }
+
+    /// Whether the request's conditions say its copy is current: If-None-Match, using
+    /// weak comparison, or If-Modified-Since if there's no If-None-Match, as RFC 7232 has
+    /// it. Only GET and HEAD requests have copies to check.
+    pub fn is_fresh(&self, req: &Request) -> bool {
+        if req.method != "GET" && req.method != "HEAD" {
+            return false;
+        }
+        if let Some(value) = req.header("If-None-Match") {
+            let value = String::from_utf8_lossy(value);
+            let etag = match &self.etag {
+                Some(etag) => etag.trim_start_matches("W/"),
+                None => return false,
+            };
+            return split_quoted(&value, ',').iter().any(|tag| *tag == "*" || tag.trim_start_matches("W/") == etag);
+        }
+        let since = req.header("If-Modified-Since")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(parse_http_date);
+        match (since, self.last_modified) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// A 304 with the headers, if the request's copy is current; the headers should be the
+    /// ones a 200 would have had that describe the file, like these validators and its
+    /// Cache-Control.
+    pub fn not_modified(&self, req: &Request, headers: Vec<(String, Vec<u8>)>) -> Option<Response> {
+        self.is_fresh(req).then_some(Response{code: 304, reason: "Not Modified", headers})
+    }
+}
+
+/// Cache-Control for each path, by the first pattern it matches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheRules {
+    rules: Vec<(String, CacheControl)>,
+}
+
+impl CacheRules {
+    pub fn new() -> Self {
+        CacheRules::default()
+    }
+
+    /// Adds a pattern, after the others; `*` in it stands for anything, including `/`, so
+    /// `/assets/*` covers everything under /assets and `*.css` every stylesheet.
+    pub fn rule(mut self, pattern: &str, cache_control: CacheControl) -> Self {
+        self.rules.push((pattern.into(), cache_control));
+        self
+    }
+
+    /// The Cache-Control for the path; the query, if there is one, is ignored.
+    pub fn get(&self, path: &str) -> Option<&CacheControl> {
+        let path = path.split('?').next().unwrap_or("");
+        self.rules.iter().find(|(pattern, _)| glob(pattern, path)).map(|(_, cc)| cc)
+    }
+
+    pub fn header(&self, path: &str) -> Option<(String, Vec<u8>)> {
+        self.get(path).map(CacheControl::header)
+    }
+}
+
+fn glob(pattern: &str, path: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == path,
+        Some((prefix, rest)) => {
+            let path = match path.strip_prefix(prefix) {
+                Some(path) => path,
+                None => return false,
+            };
+            // try the star taking every possible amount
+            path.char_indices().map(|(i, _)| i).chain(Some(path.len())).any(|i| glob(rest, &path[i..]))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn test_conditional() {
+        let path = std::env::temp_dir().join(format!("oc-http-files-{}", rand::random::<u32>()));
+        fs::write(&path, b"body { }").unwrap();
+        let validators = Validators::from_metadata(&fs::metadata(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+        let etag = validators.etag.clone().unwrap();
+        assert!(etag.starts_with("W/\"8-"));
+        let modified = http_date(validators.last_modified.unwrap());
+
+        let get = Request::builder().path("/a.css");
+        let fresh = |builder: crate::RequestBuilder| validators.is_fresh(&builder.request());
+        assert!(!fresh(get.clone()));
+        assert!(fresh(get.clone().header("If-None-Match", format!("\"x\", {}", &etag[2..]))));
+        assert!(fresh(get.clone().header("If-None-Match", "*")));
+        assert!(!fresh(get.clone().header("If-None-Match", "\"x\"")));
+        assert!(fresh(get.clone().header("If-Modified-Since", modified.as_str())));
+        assert!(!fresh(get.clone().header("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")));
+        // If-None-Match wins
+        assert!(!fresh(get.clone().header("If-None-Match", "\"x\"").header("If-Modified-Since", modified.as_str())));
+        assert!(!fresh(get.clone().method("POST").header("If-None-Match", "*")));
+
+        let not_modified = validators.not_modified(&get.header("If-None-Match", etag.as_str()).request(), validators.headers());
+        let not_modified = not_modified.unwrap();
+        assert_eq!((not_modified.code, &not_modified.headers[0].1[..]), (304, etag.as_bytes()));
+
+        let strong = Validators::from_contents(b"body { }");
+        assert_eq!(strong.etag, Validators::from_contents(b"body { }").etag);
+        assert_ne!(strong.etag, Validators::from_contents(b"body {}").etag);
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = CacheRules::new()
+            .rule("/assets/*", CacheControl::new().max_age(60))
+            .rule("*.css", CacheControl::new().max_age(10))
+            .rule("/", CacheControl::new().no_cache());
+        assert_eq!(rules.get("/assets/a/b.css?v=1").and_then(|cc| cc.max_age), Some(60));
+        assert_eq!(rules.get("/site.css").and_then(|cc| cc.max_age), Some(10));
+        assert_eq!(rules.header("/"), Some(("Cache-Control".into(), Vec::from("no-cache"))));
+        assert_eq!(rules.get("/index.html"), None);
+        assert!(glob("a*b*c", "axxbyyc") && !glob("a*b*c", "axxbyy") && glob("*", ""));
+    }
+}
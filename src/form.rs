@@ -0,0 +1,283 @@
+//! Decoding forms into structs, with serde.
+//!
+//! Body::form() does this for request bodies, urlencoded or multipart; the fields can also
+//! come from anywhere else, like a query string:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use serde::Deserialize;
+//! # use oc_http::{body::Body, respond};
+//! # async fn serve(mut body: Body<'_, TcpStream>, stream: &mut TcpStream) -> std::io::Result<()> {
+//! #[derive(Deserialize)]
+//! struct Search { q: String, page: Option<u32>, #[serde(default)] tag: Vec<String> }
+//!
+//! let search: Search = match body.form().await {
+//!     Ok(search) => search,
+//!     Err(err) => return respond(stream, err.response()).await,
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Fields sent more than once fill sequences; anything else takes one value, parsed as the
+//! struct wants it. Checkboxes sending `on` count as true.
+use std::{fmt, io};
+
+use serde::{
+    de::{self, value::StringDeserializer, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor},
+    forward_to_deserialize_any,
+};
+
+use crate::{encoding::form_urldecode, error_response, Response};
+
+/// Why a form couldn't be decoded.
+#[derive(Debug)]
+pub enum FormError {
+    /// The body is neither urlencoded nor multipart/form-data.
+    Unsupported,
+    /// Reading the body failed, or it wasn't valid.
+    Io(io::Error),
+    /// A field was missing, or its value didn't fit; `field` is None when serde couldn't
+    /// say which.
+    Field { field: Option<String>, message: String },
+}
+
+impl FormError {
+    /// 422 for fields, 415 for bodies that aren't forms, and whatever error_response says
+    /// for reading errors (413 for bodies over the limit), defaulting to 400.
+    pub fn response(&self) -> Response {
+        let (code, reason) = match self {
+            FormError::Unsupported => (415, "Unsupported Media Type"),
+            FormError::Field{..} => (422, "Unprocessable Entity"),
+            FormError::Io(err) => match error_response(err) {
+                Some(response) => return response,
+                None => (400, "Bad Request"),
+            },
+        };
+        Response{
+            code,
+            reason,
+            headers: vec!(("Content-Length".into(), Vec::from("0"))),
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormError::Unsupported => write!(f, "not a form"),
+            FormError::Io(err) => write!(f, "reading form: {}", err),
+            FormError::Field{field: Some(field), message} => write!(f, "{}: {}", field, message),
+            FormError::Field{field: None, message} => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl From<io::Error> for FormError {
+    fn from(err: io::Error) -> Self {
+        FormError::Io(err)
+    }
+}
+
+impl de::Error for FormError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        FormError::Field{field: None, message: msg.to_string()}
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        FormError::Field{field: Some(field.into()), message: "missing".into()}
+    }
+}
+
+/// Decodes fields, in the order they were sent.
+pub fn from_pairs<T, I>(pairs: I) -> Result<T, FormError>
+where
+    T: DeserializeOwned,
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut fields: Vec<(String, Vec<String>)> = vec!();
+    for (name, value) in pairs {
+        match fields.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, values)) => values.push(value),
+            None => fields.push((name, vec!(value))),
+        }
+    }
+    T::deserialize(Fields{fields: fields.into_iter(), value: None})
+}
+
+/// Decodes an application/x-www-form-urlencoded body or query string.
+pub fn from_urlencoded<T: DeserializeOwned>(form: &[u8]) -> Result<T, FormError> {
+    from_pairs(form_urldecode(form))
+}
+
+struct Fields {
+    fields: std::vec::IntoIter<(String, Vec<String>)>,
+    /// the field whose key was just given out
+    value: Option<(String, Vec<String>)>,
+}
+
+impl<'de> de::Deserializer<'de> for Fields {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for Fields {
+    type Error = FormError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, FormError> {
+        match self.fields.next() {
+            Some((name, values)) => {
+                let key = seed.deserialize(StringDeserializer::<FormError>::new(name.clone()))?;
+                self.value = Some((name, values));
+                Ok(Some(key))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, FormError> {
+        let (name, values) = self.value.take().expect("value asked for before its key");
+        seed.deserialize(Values(values)).map_err(|err| match err {
+            FormError::Field{field: None, message} => FormError::Field{field: Some(name), message},
+            err => err,
+        })
+    }
+}
+
+/// every value a field was sent with
+struct Values(Vec<String>);
+
+impl Values {
+    fn single(mut self) -> Result<String, FormError> {
+        match self.0.len() {
+            1 => Ok(self.0.remove(0)),
+            _ => Err(de::Error::custom("sent more than once")),
+        }
+    }
+}
+
+macro_rules! parse {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+            let value = self.single()?;
+            match value.trim().parse() {
+                Ok(parsed) => visitor.$visit(parsed),
+                Err(_) => Err(de::Error::custom(format!("invalid value {:?}", value))),
+            }
+        }
+    )*};
+}
+
+impl<'de> de::Deserializer<'de> for Values {
+    type Error = FormError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_string(self.single()?)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        match &self.single()?[..] {
+            "true" | "on" => visitor.visit_bool(true),
+            "false" | "off" => visitor.visit_bool(false),
+            value => Err(de::Error::custom(format!("invalid value {:?}", value))),
+        }
+    }
+
+    parse! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_seq(de::value::SeqDeserializer::new(self.0.into_iter().map(|v| Values(vec!(v)))))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, FormError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, FormError> {
+        visitor.visit_enum(self.single()?.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, FormError> for Values {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort { Newest, Oldest }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        #[serde(default)]
+        exact: bool,
+        sort: Sort,
+    }
+
+    #[test]
+    fn test_from_urlencoded() {
+        let search: Search = from_urlencoded(b"q=a+b%21&tag=x&sort=oldest&tag=y&exact=on").unwrap();
+        assert_eq!(search, Search{q: "a b!".into(), page: None, tag: vec!("x".into(), "y".into()), exact: true, sort: Sort::Oldest});
+
+        let field = |form: &[u8]| match from_urlencoded::<Search>(form) {
+            Err(FormError::Field{field, ..}) => field,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(field(b"sort=newest").as_deref(), Some("q"));
+        assert_eq!(field(b"q=a&sort=newest&page=two").as_deref(), Some("page"));
+        assert_eq!(field(b"q=a&q=b&sort=newest").as_deref(), Some("q"));
+        assert_eq!(field(b"q=a&sort=sideways").as_deref(), Some("sort"));
+        let err = from_urlencoded::<Search>(b"q=a&page=-1&sort=newest").unwrap_err();
+        assert_eq!(err.to_string(), "page: invalid value \"-1\"");
+        assert_eq!(err.response().code, 422);
+    }
+}
@@ -0,0 +1,224 @@
+//! The host a request is for, from its Host header or an absolute-form target.
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{respond, Request};
+//! # async fn serve(request: &Request<'_>, stream: &mut TcpStream) -> std::io::Result<()> {
+//! let host = match request.host() {
+//!     Ok(host) => host,
+//!     Err(err) => return respond(stream, err.response()).await,
+//! };
+//! if host.name != "example.com" { /* ... */ }
+//! # Ok(())
+//! # }
+//! ```
+use std::{fmt, net::Ipv6Addr};
+
+use crate::{Request, Response};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host {
+    /// Lowercased; IPv6 addresses are given without their brackets.
+    pub name: String,
+    pub port: Option<u16>,
+}
+
+impl Host {
+    /// Parses an authority (host, then an optional :port) as RFC 3986 allows for http;
+    /// None if it isn't valid, or has user info.
+    pub fn parse(authority: &str) -> Option<Self> {
+        let literal = authority.starts_with('[');
+        let (name, port) = if let Some(rest) = authority.strip_prefix('[') {
+            let (literal, rest) = rest.split_once(']')?;
+            literal.parse::<Ipv6Addr>().ok()?;
+            let port = match rest {
+                "" => None,
+                rest => Some(rest.strip_prefix(':')?),
+            };
+            (literal, port)
+        } else {
+            match authority.rsplit_once(':') {
+                Some((name, port)) => (name, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if name.is_empty() || (!literal && !valid_reg_name(name)) {
+            return None;
+        }
+        let port = match port {
+            // an empty port is allowed, and means the default
+            Some("") | None => None,
+            Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => Some(port.parse().ok()?),
+            Some(_) => return None,
+        };
+        Some(Host{name: name.to_ascii_lowercase(), port})
+    }
+}
+
+/// reg-name, which covers IPv4 addresses too
+fn valid_reg_name(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3);
+                if !hex.is_some_and(|h| h.iter().all(u8::is_ascii_hexdigit)) {
+                    return false;
+                }
+                i += 2;
+            },
+            b if b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=".contains(&b) => {},
+            _ => return false,
+        }
+        i += 1;
+    }
+    true
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.contains(':') {
+            write!(f, "[{}]", self.name)?;
+        } else {
+            write!(f, "{}", self.name)?;
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Why request.host() refused the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostError {
+    /// No Host header, which HTTP/1.1 requires. HTTP/1.0 clients may leave it out, but a
+    /// Request doesn't record its version, so they get this too.
+    Missing,
+    /// More than one Host header; which one a proxy used is anyone's guess.
+    Multiple,
+    Invalid,
+}
+
+impl HostError {
+    /// A 400, as RFC 7230 asks for all of these.
+    pub fn response(&self) -> Response {
+        Response{
+            code: 400,
+            reason: "Bad Request",
+            headers: vec!(("Content-Length".into(), Vec::from("0"))),
+        }
+    }
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostError::Missing => write!(f, "no Host header"),
+            HostError::Multiple => write!(f, "more than one Host header"),
+            HostError::Invalid => write!(f, "invalid host"),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+impl Request<'_> {
+    /// The host the request is for. When the target is in absolute-form
+    /// (`GET http://example.com/ HTTP/1.1`, as sent to proxies) its authority wins over the
+    /// Host header, as RFC 7230 says; either way, more than one Host header is refused.
+    /// Without either, this is `Missing` whatever the HTTP version; fall back to a default
+    /// host yourself if HTTP/1.0 clients should still be served.
+    pub fn host(&self) -> Result<Host, HostError> {
+        let values = self.header_values("Host");
+        if values.len() > 1 {
+            return Err(HostError::Multiple);
+        }
+        let url = self.url().ok_or(HostError::Invalid)?;
+        if let (true, Some(authority)) = (url.is_absolute(), &url.authority) {
+            // user info has no business in an http target
+            if authority.contains('@') {
+                return Err(HostError::Invalid);
+            }
+            return Host::parse(authority).ok_or(HostError::Invalid);
+        }
+        let value = values.first().ok_or(HostError::Missing)?;
+        let value = std::str::from_utf8(value).map_err(|_| HostError::Invalid)?;
+        Host::parse(value).ok_or(HostError::Invalid)
+    }
+}
+
+/// Sends the request to the same host and target over https, on the port given or the
+/// default one. GET and HEAD get a 301; anything else a 308, so the method and body are
+/// kept. A request without a usable host gets HostError's 400.
+pub fn redirect_to_https(req: &Request, port: Option<u16>) -> Response {
+    let host = match req.host() {
+        Ok(host) => host,
+        Err(err) => return err.response(),
+    };
+    let target = req.url().map(|url| url.origin_form()).unwrap_or_default();
+    let target = if target.starts_with('/') { target } else { "/".into() };
+    let location = format!("https://{}{}", Host{port, ..host}, target);
+    let (code, reason) = match req.method.as_ref() {
+        "GET" | "HEAD" => (301, "Moved Permanently"),
+        _ => (308, "Permanent Redirect"),
+    };
+    Response{
+        code,
+        reason,
+        headers: vec!(
+            ("Location".into(), location.into_bytes()),
+            ("Content-Length".into(), Vec::from("0")),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let host = |name: &str, port| Some(Host{name: name.into(), port});
+        assert_eq!(Host::parse("Example.COM"), host("example.com", None));
+        assert_eq!(Host::parse("example.com:8080"), host("example.com", Some(8080)));
+        assert_eq!(Host::parse("example.com:"), host("example.com", None));
+        assert_eq!(Host::parse("127.0.0.1:80"), host("127.0.0.1", Some(80)));
+        assert_eq!(Host::parse("[::1]:443"), host("::1", Some(443)));
+        assert_eq!(Host::parse("[::1]"), host("::1", None));
+        assert_eq!(Host::parse("caf%C3%A9.example"), host("caf%c3%a9.example", None));
+        for bad in &["", ":80", "a b", "a/b", "example.com:99999", "example.com:8o", "[::1", "[zz]", "[::1]80",
+                     "user@example.com", "a%zz", "a:1:2"] {
+            assert_eq!(Host::parse(bad), None, "{}", bad);
+        }
+        assert_eq!(Host{name: "::1".into(), port: Some(80)}.to_string(), "[::1]:80");
+        assert_eq!(Host{name: "a".into(), port: None}.to_string(), "a");
+    }
+
+    #[test]
+    fn test_request_host() {
+        let host = |builder: crate::RequestBuilder| builder.request().host();
+        assert_eq!(host(Request::builder().header("host", "a.com:81")), Ok(Host{name: "a.com".into(), port: Some(81)}));
+        assert_eq!(host(Request::builder()), Err(HostError::Missing));
+        assert_eq!(host(Request::builder().header("Host", "a").header("Host", "b")), Err(HostError::Multiple));
+        assert_eq!(host(Request::builder().header("Host", "a b")), Err(HostError::Invalid));
+        // absolute-form wins
+        let proxied = Request::builder().path("http://b.com/x?y").header("Host", "a.com");
+        assert_eq!(host(proxied), Ok(Host{name: "b.com".into(), port: None}));
+        assert_eq!(host(Request::builder().path("http://u:p@b.com/")), Err(HostError::Invalid));
+        assert_eq!(HostError::Missing.response().code, 400);
+    }
+
+    #[test]
+    fn test_redirect_to_https() {
+        let location = |response: &Response| String::from_utf8(response.headers[0].1.clone()).unwrap();
+        let builder = Request::builder().path("/a?b=c").header("Host", "example.com:80");
+        let response = redirect_to_https(&builder.request(), None);
+        assert_eq!((response.code, location(&response)), (301, "https://example.com/a?b=c".into()));
+        let builder = Request::builder().method("POST").path("http://[::1]/");
+        let response = redirect_to_https(&builder.request(), Some(8443));
+        assert_eq!((response.code, location(&response)), (308, "https://[::1]:8443/".into()));
+        assert_eq!(redirect_to_https(&Request::builder().request(), None).code, 400);
+    }
+}
@@ -0,0 +1,152 @@
+//! Picking a content language from Accept-Language.
+//!
+//! The pick depends on the header, so say so with Vary, whether or not the client sent it:
+//!
+//! ```no_run
+//! # use oc_http::{language::{negotiate_language, LanguageTag}, vary::Vary, Request, Response};
+//! # fn serve(req: &Request, response: &mut Response, vary: &mut Vary) {
+//! let available = [LanguageTag::parse("en").unwrap(), LanguageTag::parse("de-CH").unwrap()];
+//! let language = negotiate_language(&req, &available).unwrap_or(&available[0]);
+//! vary.add("Accept-Language");
+//! response.headers.push(language.header());
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{quality_list, Request};
+
+/// A language tag like `en` or `de-CH`, kept as given; compared without regard to case.
+#[derive(Debug, Clone, Eq)]
+pub struct LanguageTag(String);
+
+impl LanguageTag {
+    /// Checks the tag's shape (subtags of one to eight letters or digits, the first all
+    /// letters), not that it names a real language.
+    pub fn parse(tag: &str) -> Option<Self> {
+        let mut subtags = tag.split('-');
+        let primary = subtags.next()?;
+        let valid = |s: &str| (1..=8).contains(&s.len()) && s.bytes().all(|b| b.is_ascii_alphanumeric());
+        if !valid(primary) || !primary.bytes().all(|b| b.is_ascii_alphabetic()) || !subtags.all(valid) {
+            return None;
+        }
+        Some(LanguageTag(tag.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the tag falls in the range, as RFC 4647 basic filtering has it: `*`, the
+    /// tag itself, or a prefix of it ending at a `-`.
+    pub fn matches(&self, range: &str) -> bool {
+        range == "*" || self.0.len() >= range.len() && self.0[..range.len()].eq_ignore_ascii_case(range)
+            && (self.0.len() == range.len() || self.0.as_bytes()[range.len()] == b'-')
+    }
+
+    /// The Content-Language header for content in this language.
+    pub fn header(&self) -> (String, Vec<u8>) {
+        ("Content-Language".into(), Vec::from(self.0.as_str()))
+    }
+}
+
+impl PartialEq for LanguageTag {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl fmt::Display for LanguageTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A parsed Accept-Language header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AcceptLanguage {
+    /// Language ranges and their q-values, best first; ties keep the order they were sent
+    /// in. A q of 0 means the client doesn't want that language.
+    pub ranges: Vec<(String, f32)>,
+}
+
+impl AcceptLanguage {
+    /// Parses a header value; ranges that aren't valid are skipped.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut ranges: Vec<_> = quality_list(value).into_iter()
+            .filter(|(range, _)| range == "*" || LanguageTag::parse(range).is_some())
+            .collect();
+        // stable, so ties keep their order
+        ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        AcceptLanguage{ranges}
+    }
+
+    /// From every Accept-Language header on the request; None if there aren't any.
+    pub fn from_request(req: &Request) -> Option<Self> {
+        let values = req.header_values("Accept-Language");
+        if values.is_empty() {
+            return None;
+        }
+        Some(AcceptLanguage::parse(&values.join(&b","[..])))
+    }
+
+    /// How much the client wants the language: the q of the longest range it falls in,
+    /// with `*` the shortest. 0 if it's in none.
+    pub fn quality(&self, tag: &LanguageTag) -> f32 {
+        self.ranges.iter()
+            .filter(|(range, _)| tag.matches(range))
+            .max_by_key(|(range, _)| if range == "*" { 0 } else { range.len() })
+            .map_or(0.0, |(_, q)| *q)
+    }
+
+    /// The available language the client wants most; the first of them on a tie. None if
+    /// it wants none of them.
+    pub fn negotiate<'a>(&self, available: &'a [LanguageTag]) -> Option<&'a LanguageTag> {
+        let mut best: Option<(&LanguageTag, f32)> = None;
+        for tag in available {
+            let q = self.quality(tag);
+            if q > 0.0 && best.is_none_or(|(_, best)| q > best) {
+                best = Some((tag, q));
+            }
+        }
+        best.map(|(tag, _)| tag)
+    }
+}
+
+/// The language to answer the request in: the first available one if it didn't send
+/// Accept-Language, otherwise the one it wants most. None if it wants none of them;
+/// falling back to a default anyway is usually kinder than a 406.
+pub fn negotiate_language<'a>(req: &Request, available: &'a [LanguageTag]) -> Option<&'a LanguageTag> {
+    match AcceptLanguage::from_request(req) {
+        Some(accept) => accept.negotiate(available),
+        None => available.first(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let tags: Vec<_> = ["en", "en-GB", "de-CH", "fr"].iter().map(|t| LanguageTag::parse(t).unwrap()).collect();
+        let accept = AcceptLanguage::parse(b"de;q=0.9, en-gb, *;q=0.1, fr;q=0, bad_range, en;q=2");
+        assert_eq!(accept.ranges, vec!(("en-gb".into(), 1.0), ("de".into(), 0.9), ("*".into(), 0.1), ("fr".into(), 0.0)));
+        assert_eq!(accept.quality(&tags[1]), 1.0);
+        assert_eq!(accept.quality(&tags[2]), 0.9);
+        // the wildcard, since nothing longer matches
+        assert_eq!(accept.quality(&tags[0]), 0.1);
+        assert_eq!(accept.quality(&tags[3]), 0.0);
+        assert_eq!(accept.negotiate(&tags), Some(&tags[1]));
+        assert_eq!(accept.negotiate(&tags[3..]), None);
+
+        let builder = Request::builder().header("Accept-Language", "fr-CA, fr;q=0.8").header("accept-language", "de;q=0.9");
+        assert_eq!(negotiate_language(&builder.request(), &tags), Some(&tags[2]));
+        assert_eq!(negotiate_language(&Request::builder().request(), &tags), Some(&tags[0]));
+
+        assert!(tags[2].matches("DE") && !tags[0].matches("en-GB") && !tags[1].matches("en-G"));
+        assert_eq!(tags[2].header(), ("Content-Language".into(), Vec::from("de-CH")));
+        for bad in &["", "e n", "1en", "en--gb", "toolongtag"] {
+            assert_eq!(LanguageTag::parse(bad), None, "{}", bad);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
+    fmt,
     io,
+    pin::Pin,
+    time::{Duration, Instant},
 };
 use log::{warn};
 
@@ -11,28 +15,242 @@ use futures::{
 
 /// rexport of the urlencoded crate for convenience.
 pub use form_urlencoded;
+/// rexport of the bytes crate, used for websocket payloads.
+pub use bytes;
 
+pub mod body;
 pub mod websocket;
 pub mod cookies;
+pub mod session;
+pub mod csrf;
+pub mod auth;
+pub mod ratelimit;
+pub mod access_log;
+pub mod metrics;
+pub mod stats;
+pub mod trace;
+pub mod accept;
+pub mod testing;
+pub mod cache_control;
+pub mod vary;
+pub mod content_type;
+pub mod language;
+pub mod negotiate;
+pub mod response_cache;
+pub mod files;
+pub mod router;
+pub mod reply;
+pub mod upgrade;
+pub mod connection;
+pub mod payload;
+pub mod compression;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod host;
+pub mod proxy;
+pub mod url;
+pub mod builtin;
+pub mod capture;
+pub mod multipart;
+pub mod encoding;
+#[cfg(feature = "serde")]
+pub mod form;
+#[cfg(unix)]
+pub mod socket;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+pub mod stopper;
 
 
-#[cfg(test)]
-pub mod stopper;
+#[cfg(feature = "serde")]
+mod bytes_repr;
 
 const NEWLINE: &[u8] = b"\r\n";
 
+/// Mapping of header => (first_value, other values). Names are kept as the client first sent
+/// them; look them up with Request::header or header_values, which ignore case.
+pub type Headers<'a> = HashMap<&'a str, HeaderValues<'a>>;
+
+/// A header's (first_value, other values).
+pub type HeaderValues<'a> = (&'a [u8], Option<Vec<&'a [u8]>>);
+
+/// With the serde feature, requests serialize for logging; headers go as [name, value] pairs,
+/// with values as strings unless they aren't utf-8. Use to_builder for one that deserializes.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Request<'a> {
-    pub method: String,
-    pub path: String,
+    /// Borrowed from the buffer given to http(), so parsing doesn't allocate for them.
+    pub method: Cow<'a, str>,
+    pub path: Cow<'a, str>,
     // Returns a mapping of header => (first_value, other values)
-    pub headers: HashMap<&'a str, (&'a [u8], Option<Vec<&'a [u8]>>)>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "bytes_repr::serialize_map"))]
+    pub headers: Headers<'a>,
+}
+
+impl<'a> Request<'a> {
+    /// Builds a request, e.g. for tests: `Request::builder().method("POST").path("/x")`.
+    pub fn builder() -> RequestBuilder {
+        RequestBuilder::default()
+    }
+
+    /// A copy that owns everything, without the body; header names are sorted, since the
+    /// order they came in isn't kept.
+    pub fn to_builder(&self) -> RequestBuilder {
+        let mut names: Vec<_> = self.headers.keys().collect();
+        names.sort();
+        let builder = Request::builder().method(&self.method).path(&self.path);
+        names.into_iter().fold(builder, |builder, name| {
+            self.header_values(name).into_iter().fold(builder, |b, value| b.header(name, value))
+        })
+    }
+
+    /// The header's first value, whatever case its name was sent in.
+    pub fn header(&self, name: &str) -> Option<&'a [u8]> {
+        self.header_entry(name).map(|(_, (first, _))| *first)
+    }
+
+    /// Every value of the header, in the order they were sent.
+    pub fn header_values(&self, name: &str) -> Vec<&'a [u8]> {
+        match self.header_entry(name) {
+            Some((_, (first, rest))) => {
+                let mut values = vec!(*first);
+                values.extend(rest.iter().flatten());
+                values
+            },
+            None => vec!(),
+        }
+    }
+
+    /// The header's name as the client sent it, e.g. for passing it on as it was.
+    pub fn header_name(&self, name: &str) -> Option<&'a str> {
+        self.header_entry(name).map(|(k, _)| *k)
+    }
+
+    fn header_entry(&self, name: &str) -> Option<(&&'a str, &HeaderValues<'a>)> {
+        self.headers.get_key_value(name)
+            .or_else(|| self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)))
+    }
+}
+
+/// Owns everything a Request borrows; see Request::builder. With the serde feature it
+/// serializes and deserializes, losslessly; header values and the body are strings if
+/// they're utf-8, and arrays of bytes if they aren't.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RequestBuilder {
+    method: String,
+    path: String,
+    #[cfg_attr(feature = "serde", serde(with = "bytes_repr::list"))]
+    headers: Vec<(String, Vec<u8>)>,
+    #[cfg_attr(feature = "serde", serde(with = "bytes_repr"))]
+    body: Vec<u8>,
+}
+
+impl Default for RequestBuilder {
+    fn default() -> Self {
+        RequestBuilder{
+            method: "GET".into(),
+            path: "/".into(),
+            headers: vec!(),
+            body: vec!(),
+        }
+    }
+}
+
+impl RequestBuilder {
+    pub fn method(mut self, method: &str) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Adds a header; repeating a name adds another value.
+    pub fn header<V: Into<Vec<u8>>>(mut self, name: &str, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The body, which only goes into to_bytes; a Request is just the head, with the body
+    /// left in the stream.
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The request, borrowing its headers from the builder as http() would from its buffer.
+    pub fn request(&self) -> Request<'_> {
+        let mut headers: Headers = HashMap::default();
+        for (name, value) in &self.headers {
+            let existing = headers.iter_mut()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v);
+            match existing {
+                Some(existing) => existing.1.get_or_insert(vec!()).push(value),
+                None => {
+                    headers.insert(name, (value, None));
+                },
+            }
+        }
+        Request{
+            method: Cow::Borrowed(&self.method),
+            path: Cow::Borrowed(&self.path),
+            headers,
+        }
+    }
+
+    /// The request as it would be sent, with Content-Length added for a body if there's
+    /// no framing header already.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} HTTP/1.1\r\n", self.method, self.path).into_bytes();
+        for (name, value) in &self.headers {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(NEWLINE);
+        }
+        let framed = self.headers.iter().any(|(k, _)|
+            k.eq_ignore_ascii_case("Content-Length") || k.eq_ignore_ascii_case("Transfer-Encoding"));
+        if !self.body.is_empty() && !framed {
+            out.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        out.extend_from_slice(NEWLINE);
+        out.extend_from_slice(&self.body);
+        out
+    }
+}
+
+/// How respond_with writes header names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderCase {
+    /// Exactly as they are in the Response; for proxies, that's as the upstream sent them.
+    Original,
+    /// Each dash separated word capitalised, e.g. `Content-Type`, for clients that can't
+    /// cope with anything else.
+    Canonical,
+}
+
+/// The name with each dash separated word capitalised, e.g. `x-request-id` => `X-Request-Id`.
+pub fn canonical_header_name(name: &str) -> String {
+    let mut upper = true;
+    name.chars().map(|c| {
+        let c = if upper { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() };
+        upper = c == '-';
+        c
+    }).collect()
 }
 
+/// With the serde feature, responses serialize like requests do. They don't deserialize,
+/// since the reason has to be static; testing::TestResponse is the owned kind.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Response {
     pub code: usize,
     pub reason: &'static str,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "bytes_repr::list::serialize"))]
     pub headers: Vec<(String, Vec<u8>)>,
 }
 
@@ -46,21 +264,187 @@ impl Default for Response {
     }
 }
 
-/// populates the provided buffer with bytes from the stream.
-async fn populate_buffer<S>(stream: &mut S, buf: &mut [u8]) -> std::io::Result<usize>
+/// compares secrets without giving away how much of them matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// a quoted-string, for header parameters
+pub(crate) fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// the value of a quoted-string, or the value as it is if it isn't quoted
+pub(crate) fn unquote(value: &str) -> String {
+    let inner = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return value.into(),
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// splits a header value at `sep`, except inside quoted-strings; pieces are trimmed, and
+/// empty ones dropped
+pub(crate) fn split_quoted(value: &str, sep: char) -> Vec<&str> {
+    let mut pieces = vec!();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                pieces.push(&value[start..i]);
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+    pieces.push(&value[start..]);
+    pieces.into_iter().map(str::trim).filter(|p| !p.is_empty()).collect()
+}
+
+/// splits an Accept-style header value into its items and their q-values, in the order
+/// sent; parameters other than q are dropped, and items with a q that isn't valid skipped
+pub(crate) fn quality_list(value: &[u8]) -> Vec<(String, f32)> {
+    let mut items = vec!();
+    for item in split_quoted(&String::from_utf8_lossy(value), ',') {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or("");
+        if name.is_empty() {
+            continue;
+        }
+        let q = params.find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")));
+        let q = match q.map(|q| q.parse::<f32>()) {
+            None => 1.0,
+            Some(Ok(q)) if (0.0..=1.0).contains(&q) => q,
+            Some(_) => continue,
+        };
+        items.push((name.to_string(), q));
+    }
+    items
+}
+
+/// How http_with parses requests. Requests it refuses fail with an error carrying a
+/// RequestError.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Longest request target, in bytes.
+    pub max_uri: usize,
+    /// Most header lines. The size of the headers is bounded by the buffer given to http().
+    pub max_headers: usize,
+    /// Refuse anything RFC 7230 doesn't allow, rather than the leniency most servers show:
+    /// bare LF line endings, whitespace before a header's colon, obs-fold continuation
+    /// lines, and methods or header names that aren't tokens. Worth it in front of anything
+    /// security sensitive, where a proxy and this server parsing a request differently can
+    /// be used to smuggle requests past the proxy.
+    pub strict: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions{
+            max_uri: 8 * 1024,
+            max_headers: 100,
+            strict: false,
+        }
+    }
+}
+
+/// Why a request was refused. http_with and Body return it inside the io::Error, so it can
+/// be told apart from other errors and answered with the right status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    BodyTooLarge,
+    UriTooLong,
+    HeadersTooLarge,
+    /// Refused by strict parsing, for the reason given.
+    Malformed(&'static str),
+}
+
+impl RequestError {
+    /// The RequestError inside the error, if that's what it is.
+    pub fn from_io(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref().copied()
+    }
+
+    /// 413, 414, 431 or 400; the connection should be closed after sending it, since the
+    /// rest of the request is still in the stream.
+    pub fn response(&self) -> Response {
+        let (code, reason) = match self {
+            RequestError::Malformed(_) => (400, "Bad Request"),
+            RequestError::BodyTooLarge => (413, "Payload Too Large"),
+            RequestError::UriTooLong => (414, "URI Too Long"),
+            RequestError::HeadersTooLarge => (431, "Request Header Fields Too Large"),
+        };
+        Response{
+            code,
+            reason,
+            headers: vec!(
+                ("Connection".into(), Vec::from("close")),
+                ("Content-Length".into(), Vec::from("0")),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::BodyTooLarge => write!(f, "request body too large"),
+            RequestError::UriTooLong => write!(f, "request URI too long"),
+            RequestError::HeadersTooLarge => write!(f, "request headers too large"),
+            RequestError::Malformed(why) => write!(f, "malformed request: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+impl From<RequestError> for io::Error {
+    fn from(err: RequestError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The response to send for an error from http() or Body, if it's one a client should be
+/// told about rather than just disconnected.
+pub fn error_response(err: &io::Error) -> Option<Response> {
+    RequestError::from_io(err).map(|e| e.response())
+}
+
+/// why populate_buffer stopped
+#[derive(PartialEq)]
+enum Filled {
+    Headers,
+    Eof,
+    Full,
+}
+
+/// populates the provided buffer with bytes from the stream; returns the number of lines
+/// read, why it stopped, and how many bytes it read
+async fn populate_buffer<S>(stream: &mut S, buf: &mut [u8]) -> std::io::Result<(usize, Filled, usize)>
 where S: AsyncRead + Unpin
 {
     let mut lines = 0;
     let mut i = 0;
     let mut j;
     let mut last_newline_at = 0;
-    'read_loop: loop {
+    loop {
         j = i+1;
         // read one byte
         let count = stream.read(&mut buf[i..j]).await?;
         if count == 0 {
             // this will likely only happen if the client disconnects before header is sent
-            break;
+            return Ok((lines, Filled::Eof, i));
         }
         // if the byte we read was a newline, extract logic
         if buf[i] == b'\n' {
@@ -68,7 +452,7 @@ where S: AsyncRead + Unpin
                 // we might be at the end; check if last_newline_at..j is a terminal case
                 let part = &buf[last_newline_at..j];
                 if part == b"\n\r\n" || part == b"\n\n" {
-                    break 'read_loop;
+                    return Ok((lines, Filled::Headers, j));
                 }
             }
             lines += 1;
@@ -76,10 +460,59 @@ where S: AsyncRead + Unpin
         }
         i += 1;
         if i == buf.len() {
-            break 'read_loop;
+            return Ok((lines, Filled::Full, i));
         }
     }
-    Ok(lines)
+}
+
+/// characters allowed in an RFC 7230 token
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// checks the request line and headers, up to and including the empty line, for what strict
+/// parsing refuses
+fn check_strict(head: &[u8]) -> Result<(), &'static str> {
+    // the last piece is what follows the final newline, i.e. nothing
+    let mut lines = head.split(|b| *b == b'\n');
+    let mut next_line = || match lines.next() {
+        Some(line) if !line.is_empty() || lines.clone().next().is_some() =>
+            line.strip_suffix(b"\r").ok_or("bare LF line ending").map(Some),
+        _ => Ok(None),
+    };
+    let request_line = next_line()?.ok_or("no request line")?;
+    let method = request_line.split(|b| *b == b' ').next().unwrap_or(b"");
+    if method.is_empty() || !method.iter().copied().all(is_tchar) {
+        return Err("method isn't a token");
+    }
+    while let Some(line) = next_line()? {
+        if line.is_empty() {
+            break;
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err("obs-fold continuation line");
+        }
+        let name = &line[..line.iter().position(|b| *b == b':').ok_or("header without a colon")?];
+        if name.last().is_some_and(|b| *b == b' ' || *b == b'\t') {
+            return Err("whitespace before a header's colon");
+        }
+        if name.is_empty() || !name.iter().copied().all(is_tchar) {
+            return Err("header name isn't a token");
+        }
+    }
+    Ok(())
+}
+
+/// strips the optional whitespace around a header value, refusing values with control
+/// characters in them; they're never legitimate, and could be used to forge log lines or
+/// smuggle headers through a proxy
+fn header_value(value: &[u8]) -> Result<&[u8], &'static str> {
+    if value.iter().any(|b| (*b < 0x20 && *b != b'\t') || *b == 0x7f) {
+        return Err("control character in a header value");
+    }
+    let start = value.iter().position(|b| *b != b' ' && *b != b'\t').unwrap_or(value.len());
+    let end = value.iter().rposition(|b| *b != b' ' && *b != b'\t').map_or(start, |i| i + 1);
+    Ok(&value[start..end])
 }
 
 /// Parses a stream for the http request; this does not parse the body at all,
@@ -91,7 +524,27 @@ where S: AsyncRead + Unpin
 pub async fn http<'a, S>(stream: &mut S, buf: &'a mut [u8]) -> std::io::Result<Request<'a>>
 where S: AsyncRead + Unpin
 {
-    let lines = populate_buffer(stream, buf).await?;
+    http_with(stream, buf, &ParseOptions::default()).await
+}
+
+/// Like http, with other options; see error_response for answering requests it refuses.
+pub async fn http_with<'a, S>(stream: &mut S, buf: &'a mut [u8], options: &ParseOptions) -> std::io::Result<Request<'a>>
+where S: AsyncRead + Unpin
+{
+    let (lines, filled, len) = populate_buffer(stream, buf).await?;
+    if filled == Filled::Full {
+        // the buffer filled up; either with the request line, or with headers
+        if lines == 0 {
+            return Err(RequestError::UriTooLong.into());
+        }
+        return Err(RequestError::HeadersTooLarge.into());
+    }
+    if lines > options.max_headers + 1 {
+        return Err(RequestError::HeadersTooLarge.into());
+    }
+    if options.strict && filled == Filled::Headers {
+        check_strict(&buf[..len]).map_err(RequestError::Malformed)?;
+    }
     if lines == 0 {
         // if the client disconnects before finishing the first line, we might have a problem
         return Err(io::ErrorKind::InvalidInput.into());
@@ -109,25 +562,33 @@ where S: AsyncRead + Unpin
             return Err(io::ErrorKind::InvalidInput.into());
         }
     }
+    if req.path.map_or(0, str::len) > options.max_uri {
+        return Err(RequestError::UriTooLong.into());
+    }
     // Accept any known version (at this time, I've only seen 1.1 and 1.0)
     if req.version.unwrap_or(1) > 2 {
         // not supported
         warn!("HTTP/1.{} request rejected; don't support that", &req.version.unwrap_or(1));
         return Err(io::ErrorKind::InvalidInput.into());
     }
-    let mut headers: HashMap<&str, (&[u8], Option<Vec<&[u8]>>)> = HashMap::default();
+    let mut headers: Headers = HashMap::default();
+    // lowercased name => the casing it was first sent in
+    let mut first_names: HashMap<String, &str> = HashMap::default();
     for header in req.headers {
-        if let Some(existing) = headers.get_mut(header.name) {
-            let v = existing.1.get_or_insert(vec!());
-            v.push(header.value);
-        } else {
-            headers.insert(header.name, (header.value, None));
+        let value = header_value(header.value).map_err(RequestError::Malformed)?;
+        // repeats go with the first, even in another case, so the name keeps its first casing
+        let name = *first_names.entry(header.name.to_ascii_lowercase()).or_insert(header.name);
+        match headers.get_mut(name) {
+            Some((_, rest)) => rest.get_or_insert(vec!()).push(value),
+            None => {
+                headers.insert(name, (value, None));
+            },
         }
     }
     // Convert the response to a request and return
     let request = Request{
-        method: String::from(req.method.unwrap_or("GET")),
-        path: String::from(req.path.unwrap_or("/")),
+        method: Cow::Borrowed(req.method.unwrap_or("GET")),
+        path: Cow::Borrowed(req.path.unwrap_or("/")),
         headers,
     };
     //info!("HTTP/1.1 {method} {path}", method=request.method, path=request.path);
@@ -139,17 +600,27 @@ where S: AsyncRead + Unpin
 /// directly to the stream.
 pub async fn respond<S>(stream: &mut S, response: Response) -> io::Result<()>
 where S: AsyncWrite + Unpin
+{
+    respond_with(stream, response, HeaderCase::Original).await
+}
+
+/// Like respond, writing the header names in the given case.
+pub async fn respond_with<S>(stream: &mut S, response: Response, case: HeaderCase) -> io::Result<()>
+where S: AsyncWrite + Unpin
 {
     let buf = format!("HTTP/1.1 {code} {reason}",
-        code=format!("{}", response.code),
+        code=response.code,
         reason=response.reason,
     );
-    stream.write_all(&buf.as_bytes()).await?;
+    stream.write_all(buf.as_bytes()).await?;
     for (name, value) in &response.headers {
         stream.write_all(NEWLINE).await?;
-        stream.write_all(name.as_bytes()).await?;
+        match case {
+            HeaderCase::Original => stream.write_all(name.as_bytes()).await?,
+            HeaderCase::Canonical => stream.write_all(canonical_header_name(name).as_bytes()).await?,
+        }
         stream.write_all(b": ").await?;
-        stream.write_all(&value).await?;
+        stream.write_all(value).await?;
     }
     // one to end the last header/status line, and one as required by the protocol
     stream.write_all(NEWLINE).await?;
@@ -157,6 +628,32 @@ where S: AsyncWrite + Unpin
     Ok(())
 }
 
+/// Writes an interim 1xx response, e.g. 100 Continue or 103 Early Hints, and flushes it so
+/// the client sees it while the final response is worked on. Any number may come before
+/// the final response; 101 isn't one, since nothing HTTP follows it.
+pub async fn respond_informational<S>(stream: &mut S, response: Response) -> io::Result<()>
+where S: AsyncWrite + Unpin
+{
+    if !(100..200).contains(&response.code) || response.code == 101 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not an informational status"));
+    }
+    respond(stream, response).await?;
+    stream.flush().await
+}
+
+/// Sends 103 Early Hints with a Link header for each value, e.g.
+/// `</style.css>; rel=preload; as=style`, so browsers can start fetching them early. The
+/// final response should carry the same links, since not everything passes hints on.
+pub async fn early_hints<S>(stream: &mut S, links: &[&str]) -> io::Result<()>
+where S: AsyncWrite + Unpin
+{
+    respond_informational(stream, Response{
+        code: 103,
+        reason: "Early Hints",
+        headers: links.iter().map(|link| ("Link".to_string(), Vec::from(*link))).collect(),
+    }).await
+}
+
 // send_content writes all of the contents to the specified stream. Use this to send
 // body contents (such as a HTML page).
 pub async fn send_content<S>(writer: &mut S, contents: &[u8]) -> io::Result<()>
@@ -172,7 +669,172 @@ where S: AsyncWrite + Unpin
     Ok(())
 }
 
+/// How copy_body sends a body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CopyOptions {
+    /// The most read at once, and so the largest chunk when chunked.
+    pub chunk_size: usize,
+    /// Frame the body with chunked Transfer-Encoding, for when its length isn't known; the
+    /// response should say so.
+    pub chunked: bool,
+    /// When to flush along the way; it always flushes at the end.
+    pub flush: FlushPolicy,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions{
+            chunk_size: 16 * 1024,
+            chunked: false,
+            flush: FlushPolicy::AtEnd,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    AtEnd,
+    /// After each chunk read, for bodies that trickle out, like event streams.
+    EachChunk,
+    /// Whenever this many bytes of the body have gone since the last flush.
+    Bytes(u64),
+    /// Whenever this long has passed since the last flush, checked as each chunk is written.
+    Interval(Duration),
+}
+
+/// What copy_body or respond_with_body sent of the body.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Copied {
+    /// Of the body itself.
+    pub body_bytes: u64,
+    /// Written to the stream, with chunked framing.
+    pub wire_bytes: u64,
+}
+
+/// Sends everything the reader has as the response body, after respond(); see CopyOptions.
+pub async fn copy_body<R, W>(reader: &mut R, writer: &mut W, options: &CopyOptions) -> io::Result<Copied>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; options.chunk_size.max(1)];
+    let mut copied = Copied::default();
+    let mut unflushed = 0;
+    let mut flushed_at = Instant::now();
+    loop {
+        let count = reader.read(&mut buf).await?;
+        if count == 0 {
+            break;
+        }
+        if options.chunked {
+            let size = format!("{:x}\r\n", count);
+            writer.write_all(size.as_bytes()).await?;
+            copied.wire_bytes += size.len() as u64 + 2;
+        }
+        writer.write_all(&buf[..count]).await?;
+        if options.chunked {
+            writer.write_all(NEWLINE).await?;
+        }
+        copied.body_bytes += count as u64;
+        copied.wire_bytes += count as u64;
+        unflushed += count as u64;
+        let flush = match options.flush {
+            FlushPolicy::AtEnd => false,
+            FlushPolicy::EachChunk => true,
+            FlushPolicy::Bytes(bytes) => unflushed >= bytes,
+            FlushPolicy::Interval(interval) => flushed_at.elapsed() >= interval,
+        };
+        if flush {
+            writer.flush().await?;
+            unflushed = 0;
+            flushed_at = Instant::now();
+        }
+    }
+    if options.chunked {
+        writer.write_all(b"0\r\n\r\n").await?;
+        copied.wire_bytes += 5;
+    }
+    writer.flush().await?;
+    Ok(copied)
+}
+
+/// Writes the response with a body from the stream, framed with Content-Length if the
+/// length is known and chunked otherwise; see respond_with_body.
+pub async fn respond_with_stream<W, B>(writer: &mut W, response: Response, body: B, length: Option<u64>) -> io::Result<Copied>
+where
+    W: AsyncWrite + Unpin,
+    B: Stream<Item = io::Result<bytes::Bytes>> + Unpin,
+{
+    respond_with_body(writer, response, payload::StreamBody::new(body).length(length)).await
+}
+
+/// Writes the response with the body, framed with Content-Length if its size_hint says
+/// how long it is and chunked otherwise, with its trailers; the framing headers are added
+/// to the response. A body that turns out longer or shorter than its size_hint fails with
+/// InvalidData or UnexpectedEof, and the connection shouldn't be used again.
+pub async fn respond_with_body<W, B>(writer: &mut W, mut response: Response, mut body: B) -> io::Result<Copied>
+where
+    W: AsyncWrite + Unpin,
+    B: payload::Body + Unpin,
+{
+    let length = body.size_hint();
+    response.headers.retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding")
+    });
+    match length {
+        Some(length) => response.headers.push(("Content-Length".into(), Vec::from(length.to_string()))),
+        None => response.headers.push(("Transfer-Encoding".into(), Vec::from("chunked"))),
+    }
+    respond(writer, response).await?;
+    let mut copied = Copied::default();
+    while let Some(chunk) = future::poll_fn(|cx| Pin::new(&mut body).poll_data(cx)).await {
+        let chunk = chunk?;
+        if chunk.is_empty() {
+            // an empty chunk would end a chunked body
+            continue;
+        }
+        copied.body_bytes += chunk.len() as u64;
+        match length {
+            Some(length) if copied.body_bytes > length => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "body longer than its Content-Length"));
+            },
+            Some(_) => writer.write_all(&chunk).await?,
+            None => {
+                let size = format!("{:x}\r\n", chunk.len());
+                writer.write_all(size.as_bytes()).await?;
+                writer.write_all(&chunk).await?;
+                writer.write_all(NEWLINE).await?;
+                copied.wire_bytes += size.len() as u64 + 2;
+            },
+        }
+        copied.wire_bytes += chunk.len() as u64;
+    }
+    match length {
+        Some(length) if copied.body_bytes < length => {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body shorter than its Content-Length"));
+        },
+        Some(_) => (),
+        None => {
+            let mut last = Vec::from(&b"0\r\n"[..]);
+            if let Some(trailers) = future::poll_fn(|cx| Pin::new(&mut body).poll_trailers(cx)).await? {
+                for (name, value) in trailers {
+                    last.extend_from_slice(name.as_bytes());
+                    last.extend_from_slice(b": ");
+                    last.extend_from_slice(&value);
+                    last.extend_from_slice(NEWLINE);
+                }
+            }
+            last.extend_from_slice(NEWLINE);
+            writer.write_all(&last).await?;
+            copied.wire_bytes += last.len() as u64;
+        },
+    }
+    writer.flush().await?;
+    Ok(copied)
+}
+
 #[cfg(test)]
+#[allow(clippy::never_loop, clippy::vec_init_then_push)]
 mod tests {
     use std::error::Error;
     use async_std::{
@@ -258,5 +920,224 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_copy_body() {
+        // records what was written by the time of each flush
+        #[derive(Default)]
+        struct Flushes(Vec<u8>, Vec<usize>);
+        impl AsyncWrite for Flushes {
+            fn poll_write(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context, buf: &[u8]) -> std::task::Poll<io::Result<usize>> {
+                self.0.extend_from_slice(buf);
+                std::task::Poll::Ready(Ok(buf.len()))
+            }
+            fn poll_flush(mut self: std::pin::Pin<&mut Self>, _: &mut std::task::Context) -> std::task::Poll<io::Result<()>> {
+                let written = self.0.len();
+                self.1.push(written);
+                std::task::Poll::Ready(Ok(()))
+            }
+            fn poll_close(self: std::pin::Pin<&mut Self>, _: &mut std::task::Context) -> std::task::Poll<io::Result<()>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+        }
+        let copy = |options: CopyOptions| async move {
+            let mut writer = Flushes::default();
+            let copied = copy_body(&mut &b"hello world"[..], &mut writer, &options).await.unwrap();
+            (copied, writer)
+        };
+
+        let (copied, writer) = copy(CopyOptions::default()).await;
+        assert_eq!((copied.body_bytes, copied.wire_bytes, &writer.0[..], writer.1), (11, 11, &b"hello world"[..], vec!(11)));
+
+        let (copied, writer) = copy(CopyOptions{chunk_size: 6, chunked: true, flush: FlushPolicy::EachChunk}).await;
+        assert_eq!(&writer.0[..], &b"6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n"[..]);
+        assert_eq!((copied.body_bytes, copied.wire_bytes as usize), (11, writer.0.len()));
+        assert_eq!(writer.1, vec!(11, 21, 26));
+
+        let (_, writer) = copy(CopyOptions{chunk_size: 3, flush: FlushPolicy::Bytes(6), ..CopyOptions::default()}).await;
+        assert_eq!(writer.1, vec!(6, 11));
+    }
+
+    #[async_std::test]
+    async fn test_respond_with_stream() {
+        let chunks = || stream::iter(vec!(Ok(bytes::Bytes::from("hello ")), Ok(bytes::Bytes::new()), Ok(bytes::Bytes::from("world"))));
+        let mut out = vec!();
+        let copied = respond_with_stream(&mut out, Response::default(), chunks(), None).await.unwrap();
+        let expected = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+        assert_eq!((copied.body_bytes, copied.wire_bytes), (11, 26));
+
+        let mut out = vec!();
+        let response = Response{headers: vec!(("content-length".into(), Vec::from("3"))), ..Response::default()};
+        respond_with_stream(&mut out, response, chunks(), Some(11)).await.unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nhello world");
+
+        let err = respond_with_stream(&mut vec!(), Response::default(), chunks(), Some(5)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = respond_with_stream(&mut vec!(), Response::default(), chunks(), Some(12)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let failing = stream::iter(vec!(Err(io::Error::other("db gone"))));
+        assert!(respond_with_stream(&mut vec!(), Response::default(), failing, None).await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_limits() {
+        async fn parse(request: &[u8], buf_size: usize) -> Option<usize> {
+            let mut stream = futures::io::Cursor::new(Vec::from(request));
+            let mut buf = vec![0; buf_size];
+            let options = ParseOptions{max_uri: 16, max_headers: 2, strict: false};
+            match http_with(&mut stream, &mut buf, &options).await {
+                Ok(_) => Some(200),
+                Err(e) => error_response(&e).map(|r| r.code),
+            }
+        }
+        assert_eq!(parse(b"GET /0123456789abcde HTTP/1.1\r\nA: b\r\nC: d\r\n\r\n", 1024).await, Some(200));
+        assert_eq!(parse(b"GET /0123456789abcdef HTTP/1.1\r\n\r\n", 1024).await, Some(414));
+        // a request line that doesn't even fit in the buffer
+        assert_eq!(parse(b"GET /0123456789abcdefg HTTP/1.1\r\n\r\n", 16).await, Some(414));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nA: b\r\nC: d\r\nE: f\r\n\r\n", 1024).await, Some(431));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nA: 0123456789abcdef\r\n\r\n", 24).await, Some(431));
+        // other bad requests just fail
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nA: b", 1024).await, None);
+        assert_eq!(RequestError::HeadersTooLarge.response().headers[0], ("Connection".into(), Vec::from("close")));
+    }
+
+    #[async_std::test]
+    async fn test_strict() {
+        async fn parse(request: &[u8], strict: bool) -> Result<(), Option<RequestError>> {
+            let mut stream = futures::io::Cursor::new(Vec::from(request));
+            let mut buf = vec![0; 1024];
+            let options = ParseOptions{strict, ..ParseOptions::default()};
+            match http_with(&mut stream, &mut buf, &options).await {
+                Ok(_) => Ok(()),
+                Err(e) => Err(RequestError::from_io(&e)),
+            }
+        }
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: x\r\nA-b_c: d\r\n\r\n", true).await, Ok(()));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n\r\n", true).await, Ok(()));
+        // lenient parsing takes bare LFs, strict parsing doesn't
+        assert_eq!(parse(b"GET / HTTP/1.1\nHost: x\n\n", false).await, Ok(()));
+        for (bad, why) in &[
+            (&b"GET / HTTP/1.1\nHost: x\n\n"[..], "bare LF line ending"),
+            (b"GET / HTTP/1.1\r\nHost: x\n\r\n", "bare LF line ending"),
+            (b"GET / HTTP/1.1\r\nHost: x\r\n\n", "bare LF line ending"),
+            (b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", "whitespace before a header's colon"),
+            (b"GET / HTTP/1.1\r\nA: b\r\n c\r\n\r\n", "obs-fold continuation line"),
+            (b"G(T / HTTP/1.1\r\n\r\n", "method isn't a token"),
+            (b"GET / HTTP/1.1\r\nA\"b: c\r\n\r\n", "header name isn't a token"),
+        ] {
+            assert_eq!(parse(bad, true).await, Err(Some(RequestError::Malformed(why))), "{:?}", String::from_utf8_lossy(bad));
+        }
+        assert_eq!(RequestError::Malformed("x").response().code, 400);
+    }
+
+    #[test]
+    fn test_header_value() {
+        assert_eq!(header_value(b" \tsome value\t "), Ok(&b"some value"[..]));
+        assert_eq!(header_value(b"tab\tinside"), Ok(&b"tab\tinside"[..]));
+        assert_eq!(header_value(b"  "), Ok(&b""[..]));
+        // obs-text is allowed, control characters aren't
+        assert_eq!(header_value("caf\u{e9}".as_bytes()), Ok("caf\u{e9}".as_bytes()));
+        for bad in &[&b"a\0b"[..], b"a\rb", b"a\nb", b"\x1b[31m", b"del\x7f"] {
+            assert!(header_value(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_control_characters() {
+        let mut stream = futures::io::Cursor::new(Vec::from(&b"GET / HTTP/1.1\r\nA:  padded \r\nB: x\x01y\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        assert!(http(&mut stream, &mut buf).await.is_err());
+        let mut stream = futures::io::Cursor::new(Vec::from(&b"GET / HTTP/1.1\r\nA:  padded \t\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(req.headers["A"].0, b"padded");
+    }
+
+    #[async_std::test]
+    async fn test_header_case() {
+        let mut stream = futures::io::Cursor::new(Vec::from(&b"GET / HTTP/1.1\r\nx-Custom-ID: 1\r\nX-CUSTOM-ID: 2\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(req.headers.len(), 1);
+        assert_eq!(req.header("x-custom-id"), Some(&b"1"[..]));
+        assert_eq!(req.header_values("X-Custom-Id"), vec!(&b"1"[..], b"2"));
+        assert_eq!(req.header_name("x-custom-id"), Some("x-Custom-ID"));
+        assert_eq!(req.header("Missing"), None);
+
+        let headers = vec!(("x-Custom-ID".into(), Vec::from("1")), ("etag".into(), Vec::from("\"a\"")));
+        let mut out = vec!();
+        respond(&mut out, Response{headers: headers.clone(), ..Response::default()}).await.unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nx-Custom-ID: 1\r\netag: \"a\"\r\n\r\n");
+        let mut out = vec!();
+        respond_with(&mut out, Response{headers, ..Response::default()}, HeaderCase::Canonical).await.unwrap();
+        assert_eq!(out, b"HTTP/1.1 200 OK\r\nX-Custom-Id: 1\r\nEtag: \"a\"\r\n\r\n");
+        assert_eq!(canonical_header_name("sec-websocket-KEY"), "Sec-Websocket-Key");
+    }
+
+    #[async_std::test]
+    async fn test_request_builder() {
+        let builder = Request::builder()
+            .method("POST")
+            .path("/x")
+            .header("Host", "a")
+            .header("Accept", "text/html")
+            .header("accept", "*/*")
+            .body("hello");
+        let req = builder.request();
+        assert_eq!((&req.method[..], &req.path[..]), ("POST", "/x"));
+        assert_eq!(req.header_values("Accept"), vec!(&b"text/html"[..], b"*/*"));
+        assert_eq!(builder.to_bytes(),
+            &b"POST /x HTTP/1.1\r\nHost: a\r\nAccept: text/html\r\naccept: */*\r\nContent-Length: 5\r\n\r\nhello"[..]);
+
+        // what it sends parses back to the same request
+        let mut stream = futures::io::Cursor::new(builder.to_bytes());
+        let mut buf = vec![0; 1024];
+        let parsed = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(parsed.header_values("accept"), req.header_values("accept"));
+        // parsing borrows the method and path rather than copying them
+        assert!(matches!((&parsed.method, &parsed.path), (Cow::Borrowed("POST"), Cow::Borrowed("/x"))));
+        assert_eq!(Request::builder().to_bytes(), b"GET / HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn test_quoting() {
+        assert_eq!(split_quoted(r#"a, b="x, \"y\"",, c"#, ','), vec!("a", r#"b="x, \"y\"""#, "c"));
+        assert_eq!(unquote(r#""x, \"y\"""#), r#"x, "y""#);
+        assert_eq!(unquote("plain"), "plain");
+        assert_eq!(unquote(&quote(r#"a\b"c"#)), r#"a\b"c"#);
+    }
+
+    #[async_std::test]
+    async fn test_informational() {
+        let mut out = vec!();
+        early_hints(&mut out, &["</a.css>; rel=preload; as=style", "</b.js>; rel=preload; as=script"]).await.unwrap();
+        respond(&mut out, Response::default()).await.unwrap();
+        assert_eq!(out, &b"HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload; as=style\r\n\
+            Link: </b.js>; rel=preload; as=script\r\n\r\nHTTP/1.1 200 OK\r\n\r\n"[..]);
+        for code in &[101, 200] {
+            let err = respond_informational(&mut out, Response{code: *code, ..Response::default()}).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[async_std::test]
+    async fn test_serde() {
+        let mut stream = futures::io::Cursor::new(Vec::from(&b"GET /x HTTP/1.1\r\nB: 2\r\nA: \xff\r\nB: 3\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(serde_json::to_string(&req).unwrap(),
+            r#"{"method":"GET","path":"/x","headers":[["A",[255]],["B","2"],["B","3"]]}"#);
+
+        let builder = req.to_builder().body(vec!(0, 159));
+        let json = serde_json::to_string(&builder).unwrap();
+        assert_eq!(json, r#"{"method":"GET","path":"/x","headers":[["A",[255]],["B","2"],["B","3"]],"body":[0,159]}"#);
+        assert_eq!(serde_json::from_str::<RequestBuilder>(&json).unwrap(), builder);
+
+        let response = Response{headers: vec!(("Content-Length".into(), Vec::from("0"))), ..Response::default()};
+        assert_eq!(serde_json::to_string(&response).unwrap(),
+            r#"{"code":200,"reason":"OK","headers":[["Content-Length","0"]]}"#);
+    }
+
     // TODO: test large messages
 }
\ No newline at end of file
@@ -0,0 +1,300 @@
+//! Request metrics, rendered in the Prometheus text format for a `/metrics` route.
+//!
+//! Wrap each request in start/finish, and give the same HttpMetrics to UpgradeConfig::metrics
+//! to count open websockets too:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{metrics::HttpMetrics, Request};
+//! # async fn serve(request: &Request<'_>, mut stream: TcpStream) -> std::io::Result<()> {
+//! let metrics = Arc::new(HttpMetrics::new());
+//! // for each request
+//! let timer = metrics.start();
+//! if request.path == "/metrics" {
+//!     metrics.respond(&mut stream).await?;
+//! }
+//! timer.finish(200);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Give it to Router::metrics as well, and requests are counted and timed by the route
+//! they matched too. They're labelled with the route's template (`/users/{id}`), not the
+//! path, so there's a series per route however many users there are:
+//!
+//! ```no_run
+//! # use std::sync::Arc;
+//! # use futures::future::BoxFuture;
+//! # use oc_http::{metrics::HttpMetrics, router::{Context, HandlerResult, Router}};
+//! # fn user<'a>(cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> { unimplemented!() }
+//! # let metrics = Arc::new(HttpMetrics::new());
+//! let router = Router::new()
+//!     .metrics(metrics.clone())
+//!     .route("GET", "/users/{id}", user);
+//! ```
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    sync::{atomic::{AtomicI64, AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::AsyncWrite;
+
+use crate::{respond, send_content, websocket, Response};
+
+/// Upper bounds of the request duration buckets, in seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// The route label for requests the router had no route for.
+pub const UNMATCHED: &str = "unmatched";
+
+pub struct HttpMetrics {
+    // by status class, 1xx first
+    requests: [AtomicU64; 5],
+    in_flight: AtomicI64,
+    bounds: Vec<f64>,
+    // not cumulative; that's done when rendering
+    buckets: Vec<AtomicU64>,
+    duration_micros: AtomicU64,
+    websockets: AtomicI64,
+    // by route template
+    routes: Mutex<BTreeMap<String, RouteSeries>>,
+}
+
+#[derive(Default)]
+struct RouteSeries {
+    requests: [u64; 5],
+    buckets: Vec<u64>,
+    duration_micros: u64,
+}
+
+impl Default for HttpMetrics {
+    fn default() -> Self {
+        HttpMetrics::with_buckets(DEFAULT_BUCKETS)
+    }
+}
+
+impl HttpMetrics {
+    pub fn new() -> Self {
+        HttpMetrics::default()
+    }
+
+    /// Uses other duration buckets; give their upper bounds in seconds, smallest first.
+    pub fn with_buckets(bounds: &[f64]) -> Self {
+        HttpMetrics{
+            requests: Default::default(),
+            in_flight: AtomicI64::new(0),
+            bounds: bounds.into(),
+            // one more for +Inf
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            duration_micros: AtomicU64::new(0),
+            websockets: AtomicI64::new(0),
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records a request the router handled, by the template of the route it matched (or
+    /// UNMATCHED); Router::metrics calls this for each request.
+    pub fn record_route(&self, route: &str, status: usize, elapsed: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        if !routes.contains_key(route) {
+            let buckets = vec!(0; self.bounds.len() + 1);
+            routes.insert(route.into(), RouteSeries{buckets, ..RouteSeries::default()});
+        }
+        let series = routes.get_mut(route).unwrap();
+        if let Some(i) = class(status) {
+            series.requests[i] += 1;
+        }
+        series.buckets[self.bucket(elapsed)] += 1;
+        series.duration_micros += elapsed.as_micros() as u64;
+    }
+
+    fn bucket(&self, elapsed: Duration) -> usize {
+        let secs = elapsed.as_secs_f64();
+        self.bounds.iter().position(|b| secs <= *b).unwrap_or(self.bounds.len())
+    }
+
+    /// Counts a request as in flight until the timer is finished or dropped.
+    pub fn start(&self) -> RequestTimer<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        RequestTimer{
+            metrics: self,
+            started: Instant::now(),
+        }
+    }
+
+    /// Everything, in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP oc_http_requests_total Requests finished, by status class.\n");
+        out.push_str("# TYPE oc_http_requests_total counter\n");
+        for (class, count) in CLASSES.iter().zip(&self.requests) {
+            let _ = writeln!(out, "oc_http_requests_total{{class=\"{}\"}} {}", class, count.load(Ordering::Relaxed));
+        }
+        out.push_str("# HELP oc_http_requests_in_flight Requests being handled.\n");
+        out.push_str("# TYPE oc_http_requests_in_flight gauge\n");
+        let _ = writeln!(out, "oc_http_requests_in_flight {}", self.in_flight.load(Ordering::Relaxed));
+        out.push_str("# HELP oc_http_request_duration_seconds How long requests took.\n");
+        out.push_str("# TYPE oc_http_request_duration_seconds histogram\n");
+        let mut total = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let le = self.bounds.get(i).map_or("+Inf".into(), |b| b.to_string());
+            let _ = writeln!(out, "oc_http_request_duration_seconds_bucket{{le=\"{}\"}} {}", le, total);
+        }
+        let sum = self.duration_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "oc_http_request_duration_seconds_sum {}", sum);
+        let _ = writeln!(out, "oc_http_request_duration_seconds_count {}", total);
+        out.push_str("# HELP oc_http_websocket_connections Open websocket connections.\n");
+        out.push_str("# TYPE oc_http_websocket_connections gauge\n");
+        let _ = writeln!(out, "oc_http_websocket_connections {}", self.websockets.load(Ordering::Relaxed));
+        let routes = self.routes.lock().unwrap();
+        if routes.is_empty() {
+            return out;
+        }
+        out.push_str("# HELP oc_http_route_requests_total Requests finished, by route and status class.\n");
+        out.push_str("# TYPE oc_http_route_requests_total counter\n");
+        for (route, series) in routes.iter() {
+            for (class, count) in CLASSES.iter().zip(&series.requests) {
+                let _ = writeln!(out, "oc_http_route_requests_total{{route=\"{}\",class=\"{}\"}} {}", escape(route), class, count);
+            }
+        }
+        out.push_str("# HELP oc_http_route_request_duration_seconds How long requests took, by route.\n");
+        out.push_str("# TYPE oc_http_route_request_duration_seconds histogram\n");
+        for (route, series) in routes.iter() {
+            let route = escape(route);
+            let mut total = 0;
+            for (i, count) in series.buckets.iter().enumerate() {
+                total += count;
+                let le = self.bounds.get(i).map_or("+Inf".into(), |b| b.to_string());
+                let _ = writeln!(out, "oc_http_route_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}", route, le, total);
+            }
+            let sum = series.duration_micros as f64 / 1e6;
+            let _ = writeln!(out, "oc_http_route_request_duration_seconds_sum{{route=\"{}\"}} {}", route, sum);
+            let _ = writeln!(out, "oc_http_route_request_duration_seconds_count{{route=\"{}\"}} {}", route, total);
+        }
+        out
+    }
+
+    /// Writes the whole response for a `/metrics` route.
+    pub async fn respond<S>(&self, stream: &mut S) -> io::Result<()>
+    where S: AsyncWrite + Unpin
+    {
+        let body = self.render();
+        respond(stream, Response{
+            code: 200,
+            reason: "OK",
+            headers: vec!(
+                ("Content-Type".into(), Vec::from("text/plain; version=0.0.4")),
+                ("Content-Length".into(), Vec::from(body.len().to_string())),
+            ),
+        }).await?;
+        send_content(stream, body.as_bytes()).await
+    }
+}
+
+impl websocket::metrics::Metrics for HttpMetrics {
+    fn connection_opened(&self) {
+        self.websockets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.websockets.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A request being handled; see HttpMetrics::start.
+pub struct RequestTimer<'a> {
+    metrics: &'a HttpMetrics,
+    started: Instant,
+}
+
+impl RequestTimer<'_> {
+    /// Records the request as done with the status code.
+    pub fn finish(self, status: usize) {
+        let metrics = self.metrics;
+        let elapsed = self.started.elapsed();
+        if let Some(i) = class(status) {
+            metrics.requests[i].fetch_add(1, Ordering::Relaxed);
+        }
+        metrics.buckets[metrics.bucket(elapsed)].fetch_add(1, Ordering::Relaxed);
+        metrics.duration_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        // dropping takes it off in_flight
+    }
+}
+
+impl Drop for RequestTimer<'_> {
+    fn drop(&mut self) {
+        self.metrics.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// the index into CLASSES
+fn class(status: usize) -> Option<usize> {
+    (status / 100).checked_sub(1).filter(|&i| i < CLASSES.len())
+}
+
+/// for a label value; templates with regexes can have anything in them
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::metrics::Metrics;
+
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = HttpMetrics::with_buckets(&[0.5, 60.0]);
+        metrics.start().finish(200);
+        metrics.start().finish(204);
+        metrics.start().finish(404);
+        let timer = metrics.start();
+        // dropped requests only count while in flight
+        drop(metrics.start());
+        metrics.connection_opened();
+        let rendered = metrics.render();
+        for line in &[
+            "oc_http_requests_total{class=\"2xx\"} 2\n",
+            "oc_http_requests_total{class=\"4xx\"} 1\n",
+            "oc_http_requests_total{class=\"5xx\"} 0\n",
+            "oc_http_requests_in_flight 1\n",
+            "oc_http_request_duration_seconds_bucket{le=\"0.5\"} 3\n",
+            "oc_http_request_duration_seconds_bucket{le=\"60\"} 3\n",
+            "oc_http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n",
+            "oc_http_request_duration_seconds_count 3\n",
+            "oc_http_websocket_connections 1\n",
+        ] {
+            assert!(rendered.contains(line), "{} not in\n{}", line, rendered);
+        }
+        timer.finish(500);
+        assert!(metrics.render().contains("oc_http_requests_in_flight 0\n"));
+        assert!(!metrics.render().contains("oc_http_route_"));
+    }
+
+    #[test]
+    fn test_record_route() {
+        let metrics = HttpMetrics::with_buckets(&[0.5]);
+        metrics.record_route("/users/{id}", 200, Duration::from_millis(10));
+        metrics.record_route("/users/{id}", 404, Duration::from_secs(1));
+        metrics.record_route("/{n:[\"a]}", 200, Duration::from_secs(0));
+        let rendered = metrics.render();
+        for line in &[
+            "oc_http_route_requests_total{route=\"/users/{id}\",class=\"2xx\"} 1\n",
+            "oc_http_route_requests_total{route=\"/users/{id}\",class=\"4xx\"} 1\n",
+            "oc_http_route_request_duration_seconds_bucket{route=\"/users/{id}\",le=\"0.5\"} 1\n",
+            "oc_http_route_request_duration_seconds_bucket{route=\"/users/{id}\",le=\"+Inf\"} 2\n",
+            "oc_http_route_request_duration_seconds_sum{route=\"/users/{id}\"} 1.01\n",
+            "oc_http_route_requests_total{route=\"/{n:[\\\"a]}\",class=\"2xx\"} 1\n",
+        ] {
+            assert!(rendered.contains(line), "{} not in\n{}", line, rendered);
+        }
+    }
+}
@@ -0,0 +1,550 @@
+//! multipart/form-data bodies, read a part at a time so nothing has to be held in memory.
+//!
+//! Parts come one after another; each part's data is read until read() returns 0, and
+//! next_part() skips whatever's left of the current one:
+//!
+//! ```no_run
+//! # use std::io;
+//! # use async_std::{io::BufReader, net::TcpStream};
+//! # use oc_http::{body::Body, content_type::ContentType, multipart::Multipart, Request};
+//! # async fn serve(req: &Request<'_>, mut reader: BufReader<TcpStream>) -> io::Result<()> {
+//! # let bad_request = io::Error::from(io::ErrorKind::InvalidInput);
+//! let boundary = ContentType::from_request(&req).and_then(|ct| ct.boundary().map(String::from));
+//! let mut parts = Multipart::new(Body::new(&req, &mut reader)?, &boundary.ok_or(bad_request)?);
+//! while let Some(part) = parts.next_part().await? {
+//!     let mut buf = [0; 8192];
+//!     while parts.read(&mut buf).await? > 0 { /* ... */ }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or let save_to_disk put the files somewhere, and collect the other fields. Uploads over
+//! the limits are stopped part way; reject() answers them, and says whether the connection
+//! can still be used:
+//!
+//! ```no_run
+//! # use std::path::Path;
+//! # use async_std::{io::BufReader, net::TcpStream};
+//! # use oc_http::{body::Body, multipart::{self, Limits, Multipart}, Request};
+//! # async fn serve(req: &Request<'_>, mut reader: BufReader<TcpStream>, mut writer: TcpStream, boundary: &str, upload_dir: &Path)
+//! #     -> std::io::Result<bool> {
+//! let limits = Limits::new();
+//! let mut body = Body::new(&req, &mut reader)?;
+//! let result = match limits.check(&req) {
+//!     Ok(()) => multipart::save_to_disk(&mut Multipart::new(&mut body, boundary), &upload_dir, limits).await,
+//!     Err(err) => Err(err),
+//! };
+//! let manifest = match result {
+//!     Ok(manifest) => manifest,
+//!     Err(err) => {
+//!         let keep_alive = multipart::reject(&err, &mut body, &mut writer, 64 * 1024).await?;
+//!         return Ok(keep_alive);
+//!     },
+//! };
+//! # Ok(true)
+//! # }
+//! ```
+use std::{
+    fmt,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use futures::prelude::*;
+
+use crate::{body::Body, respond, split_quoted, unquote, Request, Response};
+
+// part heads longer than this are refused
+const MAX_HEAD: usize = 8192;
+const MAX_HEADERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// before the first boundary
+    Preamble,
+    Headers,
+    Data,
+    Done,
+}
+
+/// The head of one part.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    /// The form field, from Content-Disposition.
+    pub name: Option<String>,
+    /// Set for file uploads; as the client sent it, so don't use it as a path.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Part {
+    fn new(headers: Vec<(String, Vec<u8>)>) -> Self {
+        let value = |name: &str| headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| std::str::from_utf8(v).ok().map(String::from));
+        let (mut name, mut filename) = (None, None);
+        if let Some(disposition) = value("Content-Disposition") {
+            for param in split_quoted(&disposition, ';').into_iter().skip(1) {
+                match param.split_once('=') {
+                    Some((key, v)) if key.trim().eq_ignore_ascii_case("name") => name = Some(unquote(v.trim())),
+                    Some((key, v)) if key.trim().eq_ignore_ascii_case("filename") => filename = Some(unquote(v.trim())),
+                    _ => {},
+                }
+            }
+        }
+        Part{name, filename, content_type: value("Content-Type"), headers}
+    }
+}
+
+/// Reads parts from a multipart body.
+pub struct Multipart<R> {
+    reader: R,
+    /// \r\n--boundary
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    /// The boundary is the Content-Type's boundary parameter.
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Multipart{
+            reader,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            // so the first boundary looks like the rest
+            buf: Vec::from("\r\n"),
+            eof: false,
+            state: State::Preamble,
+        }
+    }
+
+    /// The next part's head, skipping the rest of the current part; None after the last.
+    pub async fn next_part(&mut self) -> io::Result<Option<Part>> {
+        let mut scratch = [0; 4096];
+        while self.state == State::Preamble || self.state == State::Data {
+            self.read(&mut scratch).await?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+        let end = loop {
+            if let Some(end) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break end + 4;
+            }
+            // a part with no headers at all
+            if self.buf.starts_with(b"\r\n") {
+                break 2;
+            }
+            if self.buf.len() > MAX_HEAD {
+                return Err(invalid("part head too long"));
+            }
+            if !self.fill().await? {
+                return Err(invalid("body ended in a part's head"));
+            }
+        };
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let parsed = match httparse::parse_headers(&self.buf[..end], &mut headers) {
+            Ok(httparse::Status::Complete((_, parsed))) => parsed,
+            _ => return Err(invalid("bad part head")),
+        };
+        let headers = parsed.iter().map(|h| (h.name.to_string(), h.value.to_vec())).collect();
+        self.buf.drain(..end);
+        self.state = State::Data;
+        Ok(Some(Part::new(headers)))
+    }
+
+    /// Reads the current part's data; 0 at the end of it.
+    pub async fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Data && self.state != State::Preamble {
+            return Ok(0);
+        }
+        loop {
+            if let Some(at) = find(&self.buf, &self.delimiter) {
+                if at > 0 {
+                    return Ok(self.take(at, out));
+                }
+                // the boundary; what follows says whether it's the last
+                while self.buf.len() < self.delimiter.len() + 2 {
+                    if !self.fill().await? {
+                        return Err(invalid("body ended in a boundary"));
+                    }
+                }
+                if self.buf[self.delimiter.len()..].starts_with(b"--") {
+                    self.state = State::Done;
+                    return Ok(0);
+                }
+                // the rest of the boundary line is padding
+                let end = loop {
+                    if let Some(end) = find(&self.buf[self.delimiter.len()..], b"\r\n") {
+                        break self.delimiter.len() + end + 2;
+                    }
+                    if self.buf.len() > MAX_HEAD || !self.fill().await? {
+                        return Err(invalid("bad boundary line"));
+                    }
+                };
+                self.buf.drain(..end);
+                self.state = State::Headers;
+                return Ok(0);
+            }
+            // anything that can't be the start of a boundary is data
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                return Ok(self.take(safe, out));
+            }
+            if !self.fill().await? {
+                return Err(invalid("body ended before the last boundary"));
+            }
+        }
+    }
+
+    fn take(&mut self, available: usize, out: &mut [u8]) -> usize {
+        let count = available.min(out.len());
+        out[..count].copy_from_slice(&self.buf[..count]);
+        self.buf.drain(..count);
+        count
+    }
+
+    /// false at EOF
+    async fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0; 8192];
+        let count = self.reader.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..count]);
+        self.eof = count == 0;
+        Ok(count > 0)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// How much save_to_disk takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes in any one file.
+    pub part: u64,
+    /// Bytes across every part.
+    pub total: u64,
+    /// Bytes in a field that isn't a file; those are kept in memory.
+    pub field: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits{
+            part: 10 * 1024 * 1024,
+            total: 50 * 1024 * 1024,
+            field: 64 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// 10MB a file, 50MB in all, and 64KB a field.
+    pub fn new() -> Self {
+        Limits::default()
+    }
+
+    pub fn part(mut self, max: u64) -> Self {
+        self.part = max;
+        self
+    }
+
+    pub fn total(mut self, max: u64) -> Self {
+        self.total = max;
+        self
+    }
+
+    pub fn field(mut self, max: u64) -> Self {
+        self.field = max;
+        self
+    }
+
+    /// Refuses requests whose Content-Length is already over the total, before any of the
+    /// body is read.
+    pub fn check(&self, req: &Request) -> Result<(), MultipartError> {
+        let length = req.header("Content-Length")
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        match length {
+            Some(length) if length > self.total => Err(MultipartError::TooLarge),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A file part, saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SavedFile {
+    /// The form field.
+    pub name: Option<String>,
+    /// What the client called it.
+    pub filename: String,
+    pub content_type: Option<String>,
+    /// Where it was saved; moving it somewhere permanent, or removing it, is up to the caller.
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// What save_to_disk found: the files, and the fields that weren't files, in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub files: Vec<SavedFile>,
+    pub fields: Vec<(String, String)>,
+}
+
+/// Why save_to_disk stopped.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// A part went over Limits::part, or a field over Limits::field.
+    PartTooLarge,
+    /// The parts went over Limits::total.
+    TooLarge,
+    /// Reading the body (or writing a file) failed; bodies that aren't valid multipart fail
+    /// with InvalidData.
+    Io(io::Error),
+}
+
+impl MultipartError {
+    /// 413 for the limits, 400 for bodies that aren't valid, and 500 when saving failed.
+    /// Whatever's left of the body is still in the stream, so these say Connection: close;
+    /// reject() leaves that off if it can drain the rest.
+    pub fn response(&self) -> Response {
+        let (code, reason) = match self {
+            MultipartError::PartTooLarge | MultipartError::TooLarge => (413, "Payload Too Large"),
+            MultipartError::Io(err) if err.kind() == io::ErrorKind::InvalidData => (400, "Bad Request"),
+            MultipartError::Io(_) => (500, "Internal Server Error"),
+        };
+        Response{
+            code,
+            reason,
+            headers: vec!(
+                ("Connection".into(), Vec::from("close")),
+                ("Content-Length".into(), Vec::from("0")),
+            ),
+        }
+    }
+}
+
+/// Answers a failed upload. Reads and throws away up to `drain` more bytes of the body, so
+/// the connection can take another request; if there's more than that (or the body was
+/// broken), it answers with Connection: close and closes the writer instead, which stops
+/// the client mid-upload. Returns whether the connection can still be used.
+pub async fn reject<S, W>(err: &MultipartError, body: &mut Body<'_, S>, writer: &mut W, drain: u64) -> io::Result<bool>
+where
+    S: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut response = err.response();
+    let reusable = match err {
+        MultipartError::Io(_) => false,
+        _ => body.drain(drain).await.is_ok(),
+    };
+    if reusable {
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Connection"));
+    }
+    respond(writer, response).await?;
+    if reusable {
+        writer.flush().await?;
+    } else {
+        writer.close().await?;
+    }
+    Ok(reusable)
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::PartTooLarge => write!(f, "part too large"),
+            MultipartError::TooLarge => write!(f, "multipart body too large"),
+            MultipartError::Io(err) => write!(f, "reading multipart body: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> Self {
+        MultipartError::Io(err)
+    }
+}
+
+/// Streams each file part into a new file in `dir`, and collects the other fields, within
+/// the limits. Files are written with blocking calls, a chunk at a time. If anything fails,
+/// the files saved so far are removed.
+pub async fn save_to_disk<R>(parts: &mut Multipart<R>, dir: &Path, limits: Limits) -> Result<Manifest, MultipartError>
+where R: AsyncRead + Unpin
+{
+    let mut manifest = Manifest::default();
+    let result = save_parts(parts, dir, limits, &mut manifest).await;
+    if result.is_err() {
+        for file in &manifest.files {
+            let _ = fs::remove_file(&file.path);
+        }
+    }
+    result.map(|()| manifest)
+}
+
+async fn save_parts<R>(parts: &mut Multipart<R>, dir: &Path, limits: Limits, manifest: &mut Manifest) -> Result<(), MultipartError>
+where R: AsyncRead + Unpin
+{
+    let mut total = 0;
+    let mut buf = vec![0; 8192];
+    while let Some(part) = parts.next_part().await? {
+        let filename = match part.filename {
+            Some(filename) => filename,
+            None => {
+                let mut value = vec!();
+                loop {
+                    let count = parts.read(&mut buf).await?;
+                    if count == 0 {
+                        break;
+                    }
+                    total += count as u64;
+                    value.extend_from_slice(&buf[..count]);
+                    if total > limits.total {
+                        return Err(MultipartError::TooLarge);
+                    }
+                    if value.len() as u64 > limits.field {
+                        return Err(MultipartError::PartTooLarge);
+                    }
+                }
+                let value = String::from_utf8(value).map_err(|_| invalid("field isn't utf-8"))?;
+                manifest.fields.push((part.name.unwrap_or_default(), value));
+                continue;
+            },
+        };
+        let path = dir.join(format!("upload-{:016x}", rand::random::<u64>()));
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
+        // listed straight away, so it's removed if anything goes wrong
+        manifest.files.push(SavedFile{
+            name: part.name,
+            filename,
+            content_type: part.content_type,
+            path,
+            size: 0,
+        });
+        let saved = manifest.files.last_mut().unwrap();
+        loop {
+            let count = parts.read(&mut buf).await?;
+            if count == 0 {
+                break;
+            }
+            total += count as u64;
+            saved.size += count as u64;
+            if total > limits.total {
+                return Err(MultipartError::TooLarge);
+            }
+            if saved.size > limits.part {
+                return Err(MultipartError::PartTooLarge);
+            }
+            file.write_all(&buf[..count])?;
+        }
+        file.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        hello\r\n--XyZ  \r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a; b.txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--XY not a boundary\r\n--XyZ--\r\nepilogue";
+
+    #[async_std::test]
+    async fn test_parts() {
+        let mut parts = Multipart::new(BODY, "XyZ");
+        let part = parts.next_part().await.unwrap().unwrap();
+        assert_eq!((part.name.as_deref(), part.filename), (Some("title"), None));
+        let mut data = [0; 3];
+        assert_eq!(parts.read(&mut data).await.unwrap(), 3);
+        assert_eq!(&data, b"hel");
+        // the rest of it is skipped
+        let part = parts.next_part().await.unwrap().unwrap();
+        assert_eq!(part.filename.as_deref(), Some("a; b.txt"));
+        assert_eq!(part.content_type.as_deref(), Some("text/plain"));
+        let mut data = vec!();
+        let mut buf = [0; 4];
+        loop {
+            match parts.read(&mut buf).await.unwrap() {
+                0 => break,
+                count => data.extend_from_slice(&buf[..count]),
+            }
+        }
+        assert_eq!(data, b"line one\r\n--XY not a boundary");
+        assert_eq!(parts.next_part().await.unwrap(), None);
+
+        let mut parts = Multipart::new(&b"--XyZ\r\n\r\nunterminated"[..], "XyZ");
+        parts.next_part().await.unwrap().unwrap();
+        assert_eq!(parts.next_part().await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[async_std::test]
+    async fn test_save_to_disk() {
+        let dir = std::env::temp_dir().join(format!("oc-http-uploads-{}", rand::random::<u32>()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let manifest = save_to_disk(&mut Multipart::new(BODY, "XyZ"), &dir, Limits::new()).await.unwrap();
+        assert_eq!(manifest.fields, vec!(("title".into(), "hello".into())));
+        let file = &manifest.files[0];
+        assert_eq!((file.name.as_deref(), &file.filename[..], file.size), (Some("upload"), "a; b.txt", 29));
+        assert_eq!(fs::read(&file.path).unwrap(), b"line one\r\n--XY not a boundary");
+        fs::remove_file(&file.path).unwrap();
+
+        let err = save_to_disk(&mut Multipart::new(BODY, "XyZ"), &dir, Limits::new().part(10)).await.unwrap_err();
+        assert!(matches!(err, MultipartError::PartTooLarge));
+        assert_eq!(err.response().code, 413);
+        let err = save_to_disk(&mut Multipart::new(BODY, "XyZ"), &dir, Limits::new().total(20)).await.unwrap_err();
+        assert!(matches!(err, MultipartError::TooLarge));
+        let err = save_to_disk(&mut Multipart::new(&b"junk"[..], "XyZ"), &dir, Limits::new()).await.unwrap_err();
+        assert_eq!(err.response().code, 400);
+        // nothing's left behind by the failures
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_reject() {
+        let upload = |length: usize| {
+            let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", BODY.len() + length);
+            let mut request = request.into_bytes();
+            request.extend_from_slice(BODY);
+            request.resize(request.len() + length, b'x');
+            futures::io::Cursor::new(request)
+        };
+        let limits = Limits::new().part(10);
+        for (padding, drained) in [(0, true), (20000, false)] {
+            let mut stream = upload(padding);
+            let mut buf = vec![0; 1024];
+            let req = crate::http(&mut stream, &mut buf).await.unwrap();
+            let mut body = Body::new(&req, &mut stream).unwrap();
+            limits.check(&req).unwrap();
+            let err = save_to_disk(&mut Multipart::new(&mut body, "XyZ"), &std::env::temp_dir(), limits).await.unwrap_err();
+            let mut out = vec!();
+            assert_eq!(reject(&err, &mut body, &mut out, 50).await.unwrap(), drained);
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+            assert_eq!(out.contains("Connection: close"), !drained);
+        }
+        let req = Request::builder().header("Content-Length", "100");
+        assert!(matches!(Limits::new().total(99).check(&req.request()), Err(MultipartError::TooLarge)));
+        assert!(Limits::new().total(100).check(&req.request()).is_ok());
+    }
+}
@@ -0,0 +1,207 @@
+//! Choosing between representations of one resource with Accept, Accept-Encoding,
+//! Accept-Language and Accept-Charset all at once.
+//!
+//! Describe each representation on hand, and let negotiate() pick; it also works out the
+//! headers that say what was picked, and what the pick depended on:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{content_type::ContentType, language::LanguageTag, negotiate::{negotiate, Offer}, respond, Request, Response};
+//! # async fn serve(req: &Request<'_>, stream: &mut TcpStream, mut response: Response, not_acceptable: Response,
+//! #     en: LanguageTag, de: LanguageTag) -> std::io::Result<()> {
+//! let offers = [
+//!     Offer::new(ContentType::new("application", "json")),
+//!     Offer::new(ContentType::new("text", "html")).charset("utf-8").language(en.clone()),
+//!     Offer::new(ContentType::new("text", "html")).charset("utf-8").language(de.clone()),
+//! ];
+//! let chosen = match negotiate(&req, &offers) {
+//!     Some(chosen) => chosen,
+//!     None => return respond(stream, not_acceptable).await,
+//! };
+//! response.headers.extend(chosen.headers());
+//! # Ok(())
+//! # }
+//! ```
+use crate::{content_type::ContentType, language::{AcceptLanguage, LanguageTag}, quality_list, vary::Vary, Request};
+
+/// One representation the handler can send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Offer {
+    pub content_type: ContentType,
+    /// The content coding, like gzip; None for identity.
+    pub encoding: Option<String>,
+    pub language: Option<LanguageTag>,
+    /// Sent as the content type's charset parameter.
+    pub charset: Option<String>,
+}
+
+impl Offer {
+    pub fn new(content_type: ContentType) -> Self {
+        Offer{content_type, encoding: None, language: None, charset: None}
+    }
+
+    pub fn encoding(mut self, encoding: &str) -> Self {
+        self.encoding = Some(encoding.into());
+        self
+    }
+
+    pub fn language(mut self, language: LanguageTag) -> Self {
+        self.language = Some(language);
+        self
+    }
+
+    pub fn charset(mut self, charset: &str) -> Self {
+        self.charset = Some(charset.into());
+        self
+    }
+}
+
+/// The offer negotiate() picked, and how it fits with the others.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiated<'a> {
+    pub offer: &'a Offer,
+    /// The product of its q-values, from 0 (exclusive) to 1.
+    pub quality: f32,
+    /// The request headers the choice depended on: the ones for whatever the offers
+    /// differ in.
+    pub vary: Vary,
+}
+
+impl Negotiated<'_> {
+    /// Content-Type (with the charset), Content-Encoding and Content-Language as the offer
+    /// has them, and Vary if there was a choice to make.
+    pub fn headers(&self) -> Vec<(String, Vec<u8>)> {
+        let offer = self.offer;
+        let content_type = match &offer.charset {
+            Some(charset) => offer.content_type.clone().param("charset", charset),
+            None => offer.content_type.clone(),
+        };
+        let mut headers = vec!(content_type.header());
+        if let Some(encoding) = &offer.encoding {
+            headers.push(("Content-Encoding".into(), Vec::from(encoding.as_str())));
+        }
+        if let Some(language) = &offer.language {
+            headers.push(language.header());
+        }
+        if !self.vary.is_empty() {
+            headers.push(self.vary.header());
+        }
+        headers
+    }
+}
+
+/// Picks the offer the client wants most, taking all four headers together; the first
+/// offer wins a tie. Headers the client didn't send accept anything. None if the client
+/// accepts none of the offers, which calls for a 406 (or a default, if that's kinder).
+pub fn negotiate<'a>(req: &Request, offers: &'a [Offer]) -> Option<Negotiated<'a>> {
+    let list = |name| {
+        let values = req.header_values(name);
+        (!values.is_empty()).then(|| quality_list(&values.join(&b","[..])))
+    };
+    let accept = list("Accept");
+    let encodings = list("Accept-Encoding");
+    let charsets = list("Accept-Charset");
+    let languages = AcceptLanguage::from_request(req);
+
+    let mut best: Option<(&Offer, f32)> = None;
+    for offer in offers {
+        let quality = accept.as_ref().map_or(1.0, |accept| media_quality(accept, &offer.content_type))
+            * encodings.as_ref().map_or(1.0, |encodings| encoding_quality(encodings, offer.encoding.as_deref()))
+            * match (&charsets, &offer.charset) {
+                (Some(charsets), Some(charset)) => named_quality(charsets, charset).unwrap_or(0.0),
+                _ => 1.0,
+            }
+            * match (&languages, &offer.language) {
+                (Some(languages), Some(language)) => languages.quality(language),
+                _ => 1.0,
+            };
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((offer, quality));
+        }
+    }
+    let (offer, quality) = best?;
+
+    let mut vary = Vary::new();
+    let differ = |f: &dyn Fn(&Offer) -> String| offers.iter().any(|o| f(o) != f(offer));
+    if differ(&|o| o.content_type.essence().to_ascii_lowercase()) {
+        vary.add("Accept");
+    }
+    if differ(&|o| o.encoding.clone().unwrap_or_default().to_ascii_lowercase()) {
+        vary.add("Accept-Encoding");
+    }
+    if differ(&|o| o.language.as_ref().map(|l| l.as_str().to_ascii_lowercase()).unwrap_or_default()) {
+        vary.add("Accept-Language");
+    }
+    if differ(&|o| o.charset.clone().unwrap_or_default().to_ascii_lowercase()) {
+        vary.add("Accept-Charset");
+    }
+    Some(Negotiated{offer, quality, vary})
+}
+
+/// the q of the most specific media range covering the type
+fn media_quality(accept: &[(String, f32)], content_type: &ContentType) -> f32 {
+    accept.iter()
+        .filter(|(range, _)| content_type.is(range))
+        .max_by_key(|(range, _)| 2 - range.matches('*').count())
+        .map_or(0.0, |(_, q)| *q)
+}
+
+/// the item's q, or `*`'s if it's not listed
+fn named_quality(list: &[(String, f32)], name: &str) -> Option<f32> {
+    list.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .or_else(|| list.iter().find(|(n, _)| n == "*"))
+        .map(|(_, q)| *q)
+}
+
+/// identity is acceptable unless it's refused outright, as RFC 7231 says
+fn encoding_quality(encodings: &[(String, f32)], encoding: Option<&str>) -> f32 {
+    match encoding {
+        Some(encoding) => named_quality(encodings, encoding).unwrap_or(0.0),
+        None => named_quality(encodings, "identity").unwrap_or(1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let en = LanguageTag::parse("en").unwrap();
+        let de = LanguageTag::parse("de").unwrap();
+        let html = ContentType::new("text", "html");
+        let offers = [
+            Offer::new(ContentType::new("application", "json")),
+            Offer::new(html.clone()).charset("utf-8").language(en.clone()),
+            Offer::new(html.clone()).charset("utf-8").language(de),
+            Offer::new(html).charset("utf-8").language(en).encoding("gzip"),
+        ];
+        let pick = |headers: &[(&str, &str)]| {
+            let builder = headers.iter().fold(Request::builder(), |b, (k, v)| b.header(k, *v));
+            negotiate(&builder.request(), &offers)
+                .map(|n| (offers.iter().position(|o| o == n.offer).unwrap(), n.headers()))
+        };
+        let index = |headers: &[(&str, &str)]| pick(headers).map(|(index, _)| index);
+
+        assert_eq!(index(&[]), Some(0));
+        assert_eq!(index(&[("Accept", "text/html, application/json;q=0.5")]), Some(1));
+        assert_eq!(index(&[("Accept", "text/*"), ("Accept-Language", "de, en;q=0.5")]), Some(2));
+        assert_eq!(index(&[("Accept", "text/html"), ("Accept-Encoding", "gzip, identity;q=0.5")]), Some(3));
+        assert_eq!(index(&[("Accept", "text/html;q=1, */*;q=0.1"), ("Accept-Encoding", "identity;q=0, *;q=0.3")]), Some(3));
+        assert_eq!(index(&[("Accept", "text/html"), ("Accept-Encoding", "identity;q=0")]), None);
+        assert_eq!(index(&[("Accept", "text/html"), ("Accept-Charset", "iso-8859-1")]), None);
+        assert_eq!(index(&[("Accept", "image/png")]), None);
+
+        let (_, headers) = pick(&[("Accept", "text/html"), ("Accept-Encoding", "gzip, identity;q=0.5")]).unwrap();
+        assert_eq!(headers, vec!(
+            ("Content-Type".into(), Vec::from("text/html; charset=utf-8")),
+            ("Content-Encoding".into(), Vec::from("gzip")),
+            ("Content-Language".into(), Vec::from("en")),
+            ("Vary".into(), Vec::from("Accept, Accept-Encoding, Accept-Language, Accept-Charset")),
+        ));
+        // nothing to choose between, so nothing varies
+        let only = [Offer::new(ContentType::new("text", "plain"))];
+        let builder = Request::builder().header("Accept", "*/*");
+        assert!(negotiate(&builder.request(), &only).unwrap().vary.is_empty());
+    }
+}
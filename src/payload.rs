@@ -0,0 +1,259 @@
+//! Bodies to send, whatever they come from: a buffer, a file or anything else AsyncRead, a
+//! Stream of Bytes, or a channel another task writes into.
+//!
+//! They're all a Body (not to be confused with body::Body, which reads request bodies), and
+//! respond_with_body frames any of them: with Content-Length when the length is known up
+//! front, and chunked otherwise, with trailers if the body has them.
+//!
+//! ```no_run
+//! # use async_std::{fs::File, net::TcpStream, task};
+//! # use futures::prelude::*;
+//! # use oc_http::{bytes::Bytes, payload::{self, ReaderBody}, respond_with_body, Response};
+//! # async fn serve(mut stream: TcpStream, path: &str, response: Response, streamed: Response, rows: Vec<String>) -> std::io::Result<()> {
+//! let file = File::open(path).await?;
+//! let length = file.metadata().await?.len();
+//! respond_with_body(&mut stream, response, ReaderBody::new(file, Some(length))).await?;
+//!
+//! # let response = streamed;
+//! let (mut sender, body) = payload::channel(16);
+//! task::spawn(async move {
+//!     for row in rows {
+//!         sender.send(Bytes::from(row)).await?;
+//!     }
+//!     sender.send_trailers(vec!(("Row-Count".into(), Vec::from("3")))).await
+//! });
+//! respond_with_body(&mut stream, response, body).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    ready,
+};
+
+pub type Trailers = Vec<(String, Vec<u8>)>;
+
+/// A body to send, a chunk at a time.
+pub trait Body {
+    /// The next chunk, or None once there are no more. Chunks may be empty.
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>>;
+
+    /// Trailers to send after the data, asked for once poll_data has returned None. Only
+    /// chunked bodies can carry them.
+    fn poll_trailers(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<Option<Trailers>>> {
+        Poll::Ready(Ok(None))
+    }
+
+    /// The exact length, if it's known before the data's read.
+    fn size_hint(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<B: Body + Unpin + ?Sized> Body for &mut B {
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        Pin::new(&mut **self).poll_data(cx)
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Option<Trailers>>> {
+        Pin::new(&mut **self).poll_trailers(cx)
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        (**self).size_hint()
+    }
+}
+
+/// The whole body, in one chunk.
+impl Body for Bytes {
+    fn poll_data(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let data = std::mem::take(self.get_mut());
+        Poll::Ready((!data.is_empty()).then(|| Ok(data)))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
+
+impl Body for Vec<u8> {
+    fn poll_data(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let data = std::mem::take(self.get_mut());
+        Poll::Ready((!data.is_empty()).then(|| Ok(Bytes::from(data))))
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
+
+const READ_SIZE: usize = 16 * 1024;
+
+/// A body read from a file, a request body, or anything else AsyncRead.
+pub struct ReaderBody<R> {
+    reader: R,
+    length: Option<u64>,
+    buf: Vec<u8>,
+}
+
+impl<R> ReaderBody<R> {
+    /// With the length if it's known, like a file's size; it's up to the caller that the
+    /// reader has that much.
+    pub fn new(reader: R, length: Option<u64>) -> Self {
+        ReaderBody{reader, length, buf: vec![0; READ_SIZE]}
+    }
+}
+
+impl<R: AsyncRead + Unpin> Body for ReaderBody<R> {
+    fn poll_data(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        let this = self.get_mut();
+        match ready!(Pin::new(&mut this.reader).poll_read(cx, &mut this.buf)) {
+            Ok(0) => Poll::Ready(None),
+            Ok(count) => Poll::Ready(Some(Ok(Bytes::copy_from_slice(&this.buf[..count])))),
+            Err(err) => Poll::Ready(Some(Err(err))),
+        }
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+/// A body from a Stream of chunks, like rows from a database.
+pub struct StreamBody<S> {
+    stream: S,
+    length: Option<u64>,
+}
+
+impl<S> StreamBody<S> {
+    pub fn new(stream: S) -> Self {
+        StreamBody{stream, length: None}
+    }
+
+    /// Sends it with Content-Length rather than chunked; the stream has to have exactly
+    /// that much.
+    pub fn length(mut self, length: Option<u64>) -> Self {
+        self.length = length;
+        self
+    }
+}
+
+impl<S> Body for StreamBody<S>
+where S: Stream<Item = io::Result<Bytes>> + Unpin
+{
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        self.stream.poll_next_unpin(cx)
+    }
+
+    fn size_hint(&self) -> Option<u64> {
+        self.length
+    }
+}
+
+enum Item {
+    Data(Bytes),
+    Trailers(Trailers),
+}
+
+/// A body another task writes into, holding up to `buffer` chunks it hasn't sent yet. The
+/// body ends when the sender is dropped or sends trailers.
+pub fn channel(buffer: usize) -> (Sender, ChannelBody) {
+    let (tx, rx) = mpsc::channel(buffer);
+    (Sender{tx}, ChannelBody{rx, trailers: None})
+}
+
+/// The writing end of a channel body.
+pub struct Sender {
+    tx: mpsc::Sender<Item>,
+}
+
+impl Sender {
+    /// Waits for room, then queues the chunk; fails with BrokenPipe once the body's been
+    /// dropped, as when the client went away.
+    pub async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        self.tx.send(Item::Data(data)).await.map_err(closed)
+    }
+
+    /// Ends the body with trailers.
+    pub async fn send_trailers(mut self, trailers: Trailers) -> io::Result<()> {
+        self.tx.send(Item::Trailers(trailers)).await.map_err(closed)
+    }
+}
+
+fn closed(_: mpsc::SendError) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the body's no longer being sent")
+}
+
+/// The reading end of a channel body.
+pub struct ChannelBody {
+    rx: mpsc::Receiver<Item>,
+    trailers: Option<Trailers>,
+}
+
+impl Body for ChannelBody {
+    fn poll_data(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<io::Result<Bytes>>> {
+        match ready!(self.rx.poll_next_unpin(cx)) {
+            Some(Item::Data(data)) => Poll::Ready(Some(Ok(data))),
+            Some(Item::Trailers(trailers)) => {
+                self.trailers = Some(trailers);
+                self.rx.close();
+                Poll::Ready(None)
+            },
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn poll_trailers(mut self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<Option<Trailers>>> {
+        Poll::Ready(Ok(self.trailers.take()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+
+    use super::*;
+    use crate::{respond_with_body, Response};
+
+    async fn sent<B: Body + Unpin>(body: B) -> String {
+        let mut out = vec!();
+        respond_with_body(&mut out, Response::default(), body).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_bodies() {
+        assert_eq!(sent(Bytes::from("hi")).await, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        assert_eq!(sent(Vec::new()).await, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let reader = ReaderBody::new(&b"from a file"[..], Some(11));
+        assert_eq!(sent(reader).await, "HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\nfrom a file");
+        let reader = ReaderBody::new(&b"unknown"[..], None);
+        assert_eq!(sent(reader).await, "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n7\r\nunknown\r\n0\r\n\r\n");
+    }
+
+    #[async_std::test]
+    async fn test_channel() {
+        let (mut sender, body) = channel(1);
+        let sending = task::spawn(async move {
+            sender.send(Bytes::from("a")).await?;
+            sender.send(Bytes::from("bc")).await?;
+            sender.send_trailers(vec!(("Row-Count".into(), Vec::from("2")))).await
+        });
+        let expected = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            1\r\na\r\n2\r\nbc\r\n0\r\nRow-Count: 2\r\n\r\n";
+        assert_eq!(sent(body).await, expected);
+        sending.await.unwrap();
+
+        let (mut sender, body) = channel(1);
+        drop(body);
+        assert_eq!(sender.send(Bytes::from("a")).await.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+}
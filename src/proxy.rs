@@ -0,0 +1,100 @@
+//! Forward proxying: CONNECT tunnels.
+//!
+//! A CONNECT request's target is the host and port to tunnel to. Dial it, then hand both
+//! connections over; pass the reader the request was parsed from, so anything the client
+//! sent after the request isn't lost in its buffer:
+//!
+//! ```no_run
+//! # use async_std::{io::BufReader, net::TcpStream};
+//! # use oc_http::{http, proxy};
+//! # async fn serve(mut reader: BufReader<TcpStream>, stream: TcpStream) -> std::io::Result<()> {
+//! # let mut buf = vec![0; 65536];
+//! let req = http(&mut reader, &mut buf).await?;
+//! if let Some(target) = req.connect_target() {
+//!     let upstream = TcpStream::connect(target.to_string()).await?;
+//!     proxy::tunnel(reader, stream, upstream).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use futures::{io, prelude::*};
+
+use crate::{host::Host, respond, Request, Response};
+
+impl Request<'_> {
+    /// Where a CONNECT request wants to tunnel to; its target is in authority-form, which
+    /// has to have a port. None for other requests, or targets that aren't valid.
+    pub fn connect_target(&self) -> Option<Host> {
+        if !self.method.eq_ignore_ascii_case("CONNECT") {
+            return None;
+        }
+        Host::parse(&self.path).filter(|host| host.port.is_some())
+    }
+}
+
+/// Answers a CONNECT with 200, then copies bytes both ways until each side has closed,
+/// closing the other's write side as it does. Returns the bytes sent upstream, then those
+/// sent back. Use an unbuffered client writer, or bytes can sit in its buffer.
+pub async fn tunnel<R, W, U>(mut client_reader: R, mut client_writer: W, upstream: U) -> io::Result<(u64, u64)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Clone + Unpin,
+{
+    respond(&mut client_writer, Response{
+        code: 200,
+        reason: "Connection Established",
+        headers: vec!(),
+    }).await?;
+    client_writer.flush().await?;
+    let mut upstream_reader = upstream.clone();
+    let mut upstream_writer = upstream;
+    let sent = async {
+        let count = io::copy(&mut client_reader, &mut upstream_writer).await?;
+        upstream_writer.close().await?;
+        Ok::<_, io::Error>(count)
+    };
+    let received = async {
+        let count = io::copy(&mut upstream_reader, &mut client_writer).await?;
+        client_writer.close().await?;
+        Ok(count)
+    };
+    futures::try_join!(sent, received)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+
+    use super::*;
+    use crate::{http, testing::duplex};
+
+    #[async_std::test]
+    async fn test_tunnel() {
+        let (mut client, server) = duplex();
+        let (mut target, upstream) = duplex();
+        let handle = task::spawn(async move {
+            let mut reader = server.clone();
+            let mut buf = vec![0; 1024];
+            let req = http(&mut reader, &mut buf).await.unwrap();
+            assert_eq!(req.connect_target(), Some(Host{name: "example.com".into(), port: Some(443)}));
+            tunnel(reader, server, upstream).await.unwrap()
+        });
+        client.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nping").await.unwrap();
+        let mut ping = [0; 4];
+        target.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        target.write_all(b"pong").await.unwrap();
+        target.close().await.unwrap();
+        client.close().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 200 Connection Established\r\n\r\npong");
+        assert_eq!(handle.await, (4, 4));
+
+        let builder = Request::builder().method("CONNECT").path("example.com");
+        assert_eq!(builder.request().connect_target(), None);
+        let builder = Request::builder().path("example.com:443");
+        assert_eq!(builder.request().connect_target(), None);
+    }
+}
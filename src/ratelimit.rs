@@ -0,0 +1,408 @@
+//! Token bucket rate limiting, keyed by client IP, a header such as an API key, or anything
+//! else the request says about who sent it.
+//!
+//! Buckets live in one map shared by every connection's task, behind a lock that's only held
+//! long enough to update a bucket. Check each request before handling it:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{ratelimit::{Rate, RateLimiter}, respond, Request};
+//! # async fn serve(request: &Request<'_>, stream: &mut TcpStream) -> std::io::Result<()> {
+//! let limiter = RateLimiter::by_ip(Rate::per_second(10))
+//!     .route("/login", Rate::new(5, Duration::from_secs(60)));
+//! if let Err(limited) = limiter.check(&request, stream.peer_addr()?) {
+//!     return respond(stream, limited.response()).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or wrap a Router in RateLimiter::middleware, which checks each request against its
+//! connection's peer and answers the ones over the limit.
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    router::{Context, HandlerError, HandlerResult, Next},
+    Request, Response,
+};
+
+/// Up to `burst` requests at once, refilled at `burst` per `per`. A burst of 0 would never
+/// let anything through, so it isn't allowed; that's why the fields are only set through new.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    burst: u32,
+    per: Duration,
+}
+
+impl Rate {
+    /// Panics if `burst` is 0.
+    pub fn new(burst: u32, per: Duration) -> Self {
+        assert!(burst > 0, "a rate's burst has to be at least 1");
+        Rate{burst, per}
+    }
+
+    pub fn per_second(count: u32) -> Self {
+        Rate::new(count, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(count: u32) -> Self {
+        Rate::new(count, Duration::from_secs(60))
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    pub fn per(&self) -> Duration {
+        self.per
+    }
+}
+
+/// The request was over the limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Limited {
+    /// When the next request would be let through.
+    pub retry_after: Duration,
+}
+
+impl Limited {
+    /// A 429 telling the client when to try again.
+    pub fn response(&self) -> Response {
+        // whole seconds, rounded up so retrying right on time works
+        let secs = self.retry_after.as_secs() + (self.retry_after.subsec_nanos() > 0) as u64;
+        Response{
+            code: 429,
+            reason: "Too Many Requests",
+            headers: vec!(
+                ("Retry-After".into(), Vec::from(secs.to_string())),
+                ("Content-Length".into(), Vec::from("0")),
+            ),
+        }
+    }
+}
+
+type KeyFn = dyn Fn(&Request, &SocketAddr) -> Option<String> + Send + Sync;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let burst = rate.burst as f64;
+        self.tokens = (self.tokens + elapsed / rate.per.as_secs_f64() * burst).min(burst);
+        self.updated = now;
+    }
+}
+
+// (route, key) => bucket; route 0 is the default rate, then each override
+type Buckets = Mutex<HashMap<(usize, String), Bucket>>;
+
+/// How many buckets a limiter keeps by default; see RateLimiter::max_buckets.
+pub const DEFAULT_MAX_BUCKETS: usize = 100_000;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    rates: Vec<(String, Rate)>,
+    key: Arc<KeyFn>,
+    buckets: Arc<Buckets>,
+    max_buckets: usize,
+}
+
+impl RateLimiter {
+    /// Limits each client IP.
+    pub fn by_ip(rate: Rate) -> Self {
+        RateLimiter::by_key(rate, |_, _| None)
+    }
+
+    /// Limits each value of the header that `known` accepts, e.g. API keys that exist;
+    /// requests without one are limited by IP. Checking the values matters: otherwise a
+    /// client gets a fresh bucket by sending a made-up value with every request.
+    pub fn by_header<F>(rate: Rate, name: &str, known: F) -> Self
+    where F: Fn(&[u8]) -> bool + Send + Sync + 'static
+    {
+        let name = name.to_string();
+        RateLimiter::by_key(rate, move |req, _| req.header(&name)
+            .filter(|value| known(value))
+            .map(|value| String::from_utf8_lossy(value).into_owned()))
+    }
+
+    /// Limits by whatever key the function picks; when it returns None, by IP.
+    pub fn by_key<F>(rate: Rate, key: F) -> Self
+    where F: Fn(&Request, &SocketAddr) -> Option<String> + Send + Sync + 'static
+    {
+        RateLimiter{
+            rates: vec!((String::new(), rate)),
+            key: Arc::new(key),
+            buckets: Arc::default(),
+            max_buckets: DEFAULT_MAX_BUCKETS,
+        }
+    }
+
+    /// Keeps at most this many buckets. Once there are that many, a new client's bucket
+    /// replaces the one used longest ago, after the full ones have been swept.
+    pub fn max_buckets(mut self, max: usize) -> Self {
+        self.max_buckets = max.max(1);
+        self
+    }
+
+    /// A different rate for the path and everything under it, counted separately from the
+    /// rest: `/login` covers `/login` and `/login/...`, but not `/loginx`, like
+    /// Router::scope. The longest matching prefix wins.
+    pub fn route(mut self, prefix: &str, rate: Rate) -> Self {
+        self.rates.push((prefix.trim_end_matches('/').into(), rate));
+        self
+    }
+
+    /// Takes a token from the request's bucket, or says how long until there is one.
+    pub fn check(&self, req: &Request, peer: SocketAddr) -> Result<(), Limited> {
+        let key = (self.key)(req, &peer).unwrap_or_else(|| peer.ip().to_string());
+        let path = req.path.split('?').next().unwrap_or("");
+        self.check_key(&key, path)
+    }
+
+    /// Like check, for requests already boiled down to a key and path.
+    pub fn check_key(&self, key: &str, path: &str) -> Result<(), Limited> {
+        let (route, rate) = self.rates.iter()
+            .enumerate()
+            .filter(|(i, (prefix, _))| *i == 0 || path.strip_prefix(&prefix[..])
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .max_by_key(|(_, (prefix, _))| prefix.len())
+            .map(|(i, (_, rate))| (i, *rate))
+            .expect("the default rate matches every path");
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let id = (route, key.to_string());
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(&id) {
+            make_room(&mut buckets, &self.rates, now);
+        }
+        let bucket = buckets.entry(id).or_insert(Bucket{
+            tokens: rate.burst as f64,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let wait = (1.0 - bucket.tokens) * rate.per.as_secs_f64() / rate.burst as f64;
+        Err(Limited{retry_after: Duration::from_secs_f64(wait)})
+    }
+
+    /// Router middleware answering requests over the limit with Limited::response. It needs
+    /// the peer, so serve the router with serve or handle_from; requests from an unknown peer
+    /// all share one bucket.
+    pub fn middleware(self) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static {
+        let unknown = SocketAddr::from(([0, 0, 0, 0], 0));
+        move |cx, next| match self.check(cx.request, cx.connection.peer.unwrap_or(unknown)) {
+            Ok(()) => next.run(cx),
+            Err(limited) => {
+                let err = HandlerError::from_response(limited.response(), "too many requests");
+                Box::pin(async move { Err(err) })
+            },
+        }
+    }
+
+    /// Forgets buckets that have filled back up; they'd start out full anyway.
+    pub fn sweep(&self) {
+        sweep(&self.buckets, &self.rates);
+    }
+
+    /// A future that sweeps every `interval`, for the caller to spawn; it finishes once every
+    /// clone of the limiter has been dropped.
+    pub fn sweep_every(&self, interval: Duration) -> impl Future<Output = ()> {
+        let buckets: Weak<Buckets> = Arc::downgrade(&self.buckets);
+        let rates = self.rates.clone();
+        async move {
+            loop {
+                futures_timer::Delay::new(interval).await;
+                match buckets.upgrade() {
+                    Some(buckets) => sweep(&buckets, &rates),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    /// How many buckets are being kept.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn sweep(buckets: &Buckets, rates: &[(String, Rate)]) {
+    sweep_full(&mut buckets.lock().unwrap(), rates, Instant::now());
+}
+
+fn sweep_full(buckets: &mut HashMap<(usize, String), Bucket>, rates: &[(String, Rate)], now: Instant) {
+    buckets.retain(|(route, _), bucket| {
+        let rate = rates[*route].1;
+        bucket.refill(rate, now);
+        bucket.tokens < rate.burst as f64
+    });
+}
+
+/// frees at least one bucket: the full ones if there are any, else the one used longest ago
+fn make_room(buckets: &mut HashMap<(usize, String), Bucket>, rates: &[(String, Rate)], now: Instant) {
+    let before = buckets.len();
+    sweep_full(buckets, rates, now);
+    if buckets.len() < before {
+        return;
+    }
+    let oldest = buckets.iter().min_by_key(|(_, bucket)| bucket.updated).map(|(id, _)| id.clone());
+    if let Some(oldest) = oldest {
+        buckets.remove(&oldest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: ([u8; 4], u16) = ([10, 0, 0, 1], 1234);
+    const BOB: ([u8; 4], u16) = ([10, 0, 0, 2], 1234);
+
+    /// two requests a tenth of a second, by IP or by keys starting with "k"; one a minute for
+    /// logins
+    fn limiter() -> RateLimiter {
+        RateLimiter::by_header(Rate::new(2, Duration::from_millis(100)), "X-API-Key", |key| key.starts_with(b"k"))
+            .route("/login", Rate::new(1, Duration::from_secs(60)))
+    }
+
+    fn check(limiter: &RateLimiter, req: &crate::RequestBuilder, peer: ([u8; 4], u16)) -> Result<(), Limited> {
+        limiter.check(&req.request(), SocketAddr::from(peer))
+    }
+
+    #[test]
+    fn test_by_ip() {
+        let limiter = limiter();
+        let req = Request::builder().path("/things?page=2");
+        assert!(check(&limiter, &req, ALICE).is_ok());
+        // another port is the same client
+        assert!(check(&limiter, &req, (ALICE.0, 999)).is_ok());
+        let limited = check(&limiter, &req, ALICE).unwrap_err();
+        assert!(limited.retry_after <= Duration::from_millis(50), "{:?}", limited);
+        assert_eq!(limited.response().code, 429);
+        assert_eq!(limited.response().headers[0], ("Retry-After".into(), Vec::from("1")));
+        assert!(check(&limiter, &req, BOB).is_ok());
+    }
+
+    #[test]
+    fn test_by_key() {
+        // keyed requests get their own bucket, wherever they come from
+        let limiter = limiter();
+        let keyed = Request::builder().path("/things").header("X-API-Key", "k1");
+        assert!(check(&limiter, &keyed, ALICE).is_ok());
+        assert!(check(&limiter, &keyed, BOB).is_ok());
+        assert!(check(&limiter, &keyed, ALICE).is_err());
+        assert!(check(&limiter, &Request::builder(), ALICE).is_ok());
+    }
+
+    #[test]
+    fn test_made_up_keys() {
+        // they don't get around the IP's limit
+        let limiter = limiter();
+        assert!(check(&limiter, &Request::builder().header("X-API-Key", "made up"), BOB).is_ok());
+        assert!(check(&limiter, &Request::builder().header("X-API-Key", "made up too"), BOB).is_ok());
+        assert!(check(&limiter, &Request::builder(), BOB).is_err());
+    }
+
+    #[test]
+    fn test_routes() {
+        // counted separately from everything else
+        let limiter = limiter();
+        assert!(check(&limiter, &Request::builder().path("/login"), ALICE).is_ok());
+        let limited = check(&limiter, &Request::builder().path("/login/"), ALICE).unwrap_err();
+        assert!(limited.retry_after > Duration::from_secs(59));
+        assert_eq!(limited.response().headers[0], ("Retry-After".into(), Vec::from("60")));
+        assert!(check(&limiter, &Request::builder(), ALICE).is_ok());
+    }
+
+    #[async_std::test]
+    async fn test_refill() {
+        // tokens come back, and full buckets are swept
+        let limiter = limiter();
+        let req = Request::builder();
+        assert!(check(&limiter, &req, ALICE).is_ok());
+        assert!(check(&limiter, &req, ALICE).is_ok());
+        assert!(check(&limiter, &req, ALICE).is_err());
+        assert!(check(&limiter, &req, BOB).is_ok());
+        assert!(check(&limiter, &Request::builder().path("/login"), ALICE).is_ok());
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert!(check(&limiter, &req, ALICE).is_ok());
+        assert_eq!(limiter.len(), 3);
+        limiter.sweep();
+        // bob's is full again; alice has just spent a token, and her login's still empty
+        assert_eq!(limiter.len(), 2);
+    }
+
+    #[test]
+    fn test_route_segments() {
+        let limiter = RateLimiter::by_ip(Rate::per_minute(100))
+            .route("/login", Rate::per_minute(1))
+            .route("/api/", Rate::per_minute(1));
+        let check = |path: &str| limiter.check_key("alice", path).is_ok();
+        assert!(check("/login"));
+        assert!(!check("/login/again"));
+        // other paths that just start the same way aren't under it
+        assert!(check("/loginx") && check("/login-help"));
+        assert!(check("/api") && !check("/api/things"));
+        assert!(check("*"));
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        use crate::{connection::ConnectionInfo, router::Router};
+
+        let router = Router::new()
+            .wrap(RateLimiter::by_ip(Rate::per_minute(1)).middleware())
+            .route("GET", "/", |_: &Context| Box::pin(async { Ok("hi") }));
+        let from = |ip: [u8; 4]| ConnectionInfo{peer: Some(SocketAddr::from((ip, 1234))), ..ConnectionInfo::default()};
+        let req = Request::builder();
+        assert_eq!(router.handle_from(&from([10, 0, 0, 1]), &req.request(), b"").await.0.code, 200);
+        let (response, _) = router.handle_from(&from([10, 0, 0, 1]), &req.request(), b"").await;
+        assert_eq!(response.code, 429);
+        assert_eq!(response.headers[0], ("Retry-After".into(), Vec::from("60")));
+        assert_eq!(router.handle_from(&from([10, 0, 0, 2]), &req.request(), b"").await.0.code, 200);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_burst() {
+        Rate::per_second(0);
+    }
+
+    #[test]
+    fn test_rate() {
+        let rate = Rate::new(5, Duration::from_secs(60));
+        assert_eq!((rate.burst(), rate.per()), (5, Duration::from_secs(60)));
+        assert_eq!(Rate::per_minute(5), rate);
+    }
+
+    #[test]
+    fn test_max_buckets() {
+        let limiter = RateLimiter::by_ip(Rate::per_minute(1)).max_buckets(2);
+        let ip = |n: u8| SocketAddr::from(([10, 0, 0, n], 80));
+        for n in 0..10 {
+            assert!(limiter.check_key(&ip(n).ip().to_string(), "/").is_ok());
+            assert!(limiter.len() <= 2);
+        }
+        // the newest is still limited
+        assert!(limiter.check_key(&ip(9).ip().to_string(), "/").is_err());
+    }
+}
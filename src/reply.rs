@@ -0,0 +1,218 @@
+//! Turning handler return values into responses.
+//!
+//! Router handlers can return anything that's IntoResponse, and the router fills in the
+//! head around it: the status, Content-Type and Content-Length.
+//!
+//! ```no_run
+//! # #[cfg(feature = "json")]
+//! # fn build() {
+//! # use oc_http::{reply::{Json, StatusCode}, router::{Context, HandlerError, Router}};
+//! # async fn create(body: &[u8]) -> Result<Vec<String>, HandlerError> { Ok(vec!()) }
+//! let router = Router::new()
+//!     .route("GET", "/", |_: &Context| Box::pin(async { Ok("hello") }))
+//!     .route("POST", "/items", |cx: &Context| Box::pin(async move {
+//!         let item = create(cx.body).await?;
+//!         Ok((StatusCode::CREATED, Json(item)))
+//!     }));
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{router::{HandlerError, Reply}, Response};
+
+/// A status code and its reason phrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusCode {
+    pub code: usize,
+    pub reason: &'static str,
+}
+
+impl StatusCode {
+    pub const OK: StatusCode = StatusCode::new(200, "OK");
+    pub const CREATED: StatusCode = StatusCode::new(201, "Created");
+    pub const ACCEPTED: StatusCode = StatusCode::new(202, "Accepted");
+    pub const NO_CONTENT: StatusCode = StatusCode::new(204, "No Content");
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode::new(301, "Moved Permanently");
+    pub const FOUND: StatusCode = StatusCode::new(302, "Found");
+    pub const SEE_OTHER: StatusCode = StatusCode::new(303, "See Other");
+    pub const NOT_MODIFIED: StatusCode = StatusCode::new(304, "Not Modified");
+    pub const BAD_REQUEST: StatusCode = StatusCode::new(400, "Bad Request");
+    pub const UNAUTHORIZED: StatusCode = StatusCode::new(401, "Unauthorized");
+    pub const FORBIDDEN: StatusCode = StatusCode::new(403, "Forbidden");
+    pub const NOT_FOUND: StatusCode = StatusCode::new(404, "Not Found");
+    pub const CONFLICT: StatusCode = StatusCode::new(409, "Conflict");
+    pub const UNPROCESSABLE_ENTITY: StatusCode = StatusCode::new(422, "Unprocessable Entity");
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode::new(500, "Internal Server Error");
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode::new(503, "Service Unavailable");
+
+    pub const fn new(code: usize, reason: &'static str) -> Self {
+        StatusCode{code, reason}
+    }
+
+    /// Whether responses with this status never have a body.
+    pub fn is_bodiless(&self) -> bool {
+        (100..200).contains(&self.code) || self.code == 204 || self.code == 304
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.reason)
+    }
+}
+
+pub trait IntoResponse {
+    fn into_response(self) -> Reply;
+}
+
+/// A response and its body, as they are.
+impl IntoResponse for Reply {
+    fn into_response(self) -> Reply {
+        self
+    }
+}
+
+/// With no body; Content-Length is added if it's missing and the status allows a body.
+impl IntoResponse for Response {
+    fn into_response(mut self) -> Reply {
+        let status = StatusCode::new(self.code, self.reason);
+        let has_length = self.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"));
+        if !has_length && !status.is_bodiless() {
+            self.headers.push(("Content-Length".into(), Vec::from("0")));
+        }
+        (self, vec!())
+    }
+}
+
+/// As plain text.
+impl IntoResponse for &str {
+    fn into_response(self) -> Reply {
+        self.to_string().into_response()
+    }
+}
+
+/// As plain text.
+impl IntoResponse for String {
+    fn into_response(self) -> Reply {
+        with_body("text/plain; charset=utf-8", self.into_bytes())
+    }
+}
+
+/// As application/octet-stream.
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Reply {
+        with_body("application/octet-stream", self)
+    }
+}
+
+/// The value's response with the status instead; the body goes if the status can't have
+/// one.
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Reply {
+        let (status, value) = self;
+        let (mut response, mut body) = value.into_response();
+        response.code = status.code;
+        response.reason = status.reason;
+        if status.is_bodiless() {
+            response.headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Content-Type")
+            });
+            body.clear();
+        }
+        (response, body)
+    }
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> Reply {
+        self.reply()
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Result<T, HandlerError> {
+    fn into_response(self) -> Reply {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(err) => err.reply(),
+        }
+    }
+}
+
+/// A value sent as JSON.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+/// As application/json; a value that can't be serialized is a 500.
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Reply {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => with_body("application/json", body),
+            Err(err) => HandlerError::internal(err).reply(),
+        }
+    }
+}
+
+fn with_body(content_type: &str, body: Vec<u8>) -> Reply {
+    let headers = vec!(
+        ("Content-Type".into(), Vec::from(content_type)),
+        ("Content-Length".into(), Vec::from(body.len().to_string())),
+    );
+    (Response{headers, ..Response::default()}, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Request;
+
+    fn header<'a>(reply: &'a Reply, name: &str) -> Option<&'a [u8]> {
+        reply.0.headers.iter().find(|(n, _)| n == name).map(|(_, v)| &v[..])
+    }
+
+    #[test]
+    fn test_into_response() {
+        let reply = "hi".into_response();
+        assert_eq!((reply.0.code, &reply.1[..]), (200, &b"hi"[..]));
+        assert_eq!(header(&reply, "Content-Type"), Some(&b"text/plain; charset=utf-8"[..]));
+        assert_eq!(header(&reply, "Content-Length"), Some(&b"2"[..]));
+        assert_eq!(header(&vec!(0u8; 3).into_response(), "Content-Type"), Some(&b"application/octet-stream"[..]));
+
+        let created = (StatusCode::CREATED, String::from("made")).into_response();
+        assert_eq!((created.0.code, created.0.reason, &created.1[..]), (201, "Created", &b"made"[..]));
+        let empty = (StatusCode::NO_CONTENT, "ignored").into_response();
+        assert_eq!((empty.0.code, empty.0.headers.len(), empty.1.len()), (204, 0, 0));
+
+        let redirect = Response{code: 303, reason: "See Other", headers: vec!(("Location".into(), Vec::from("/")))};
+        let reply = redirect.into_response();
+        assert_eq!(header(&reply, "Content-Length"), Some(&b"0"[..]));
+        assert_eq!(Response{code: 304, ..Response::default()}.into_response().0.headers.len(), 0);
+
+        let failed: Result<&str, HandlerError> = Err(HandlerError::bad_request("no"));
+        assert_eq!(failed.into_response().0.code, 400);
+    }
+
+    #[async_std::test]
+    async fn test_router() {
+        use crate::router::{Context, Router};
+
+        let router = Router::new()
+            .route("GET", "/", |_: &Context| Box::pin(async { Ok("hello") }))
+            .route("POST", "/items", |cx: &Context| {
+                let name = String::from_utf8_lossy(cx.body).into_owned();
+                Box::pin(async move { Ok((StatusCode::CREATED, name)) })
+            });
+        let (response, body) = router.handle(&Request::builder().request(), b"").await;
+        assert_eq!((response.code, &body[..]), (200, &b"hello"[..]));
+        let (response, body) = router.handle(&Request::builder().method("POST").path("/items").request(), b"pen").await;
+        assert_eq!((response.code, &body[..]), (201, &b"pen"[..]));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json() {
+        let reply = Json(vec!(1, 2)).into_response();
+        assert_eq!((&reply.1[..], header(&reply, "Content-Type")), (&b"[1,2]"[..], Some(&b"application/json"[..])));
+    }
+}
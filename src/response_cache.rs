@@ -0,0 +1,335 @@
+//! An in-memory cache of small responses, for endpoints that are expensive to answer and
+//! fine to answer a little late.
+//!
+//! Responses are kept as long as their Cache-Control says (s-maxage, then max-age), or the
+//! cache's default if they don't say; ones marked private, no-store or no-cache, or setting
+//! cookies, aren't kept, and neither are answers to requests with Authorization unless
+//! they're marked public, s-maxage or must-revalidate (RFC 7234 section 3.2). Entries are told apart by method, target, and the request headers
+//! the response's Vary names.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{respond, response_cache::ResponseCache, send_content, Request, Response};
+//! # async fn render_dashboard(req: &Request<'_>) -> (Response, Vec<u8>) { unimplemented!() }
+//! # async fn serve(req: &Request<'_>, mut stream: TcpStream) -> std::io::Result<()> {
+//! let cache = ResponseCache::new().max_entries(100).default_ttl(Duration::from_secs(5));
+//! let (response, body) = cache.fetch(&req, || render_dashboard(&req)).await;
+//! respond(&mut stream, response).await?;
+//! send_content(&mut stream, &body).await?;
+//!
+//! // after the data behind it changes
+//! cache.invalidate("/dashboard");
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{cache_control::CacheControl, vary::Vary, Request, Response};
+
+struct Entry {
+    /// the request's values for each header the response varies on
+    vary: Vec<(String, Option<Vec<u8>>)>,
+    code: usize,
+    reason: &'static str,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Entry {
+    fn matches(&self, req: &Request) -> bool {
+        self.vary.iter().all(|(name, value)| req.header(name) == value.as_deref())
+    }
+}
+
+/// method and target
+type Key = (String, String);
+
+pub struct ResponseCache {
+    entries: Mutex<HashMap<Key, Vec<Entry>>>,
+    max_entries: usize,
+    max_body: usize,
+    default_ttl: Option<Duration>,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache{
+            entries: Mutex::new(HashMap::new()),
+            max_entries: 1000,
+            max_body: 64 * 1024,
+            default_ttl: None,
+        }
+    }
+}
+
+impl ResponseCache {
+    /// Up to 1000 responses of up to 64KB each, kept only if they say how long for.
+    pub fn new() -> Self {
+        ResponseCache::default()
+    }
+
+    /// The most responses kept; the oldest go first when there are too many.
+    pub fn max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// The largest body kept.
+    pub fn max_body(mut self, max: usize) -> Self {
+        self.max_body = max;
+        self
+    }
+
+    /// How long to keep responses that don't have a max-age.
+    pub fn default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// The cached response to the request, with an Age header, if there's one still fresh.
+    /// Only GET and HEAD hit, and requests asking for no-cache or no-store never do.
+    pub fn lookup(&self, req: &Request) -> Option<(Response, Vec<u8>)> {
+        self.lookup_at(req, Instant::now())
+    }
+
+    fn lookup_at(&self, req: &Request, now: Instant) -> Option<(Response, Vec<u8>)> {
+        let cc = CacheControl::from_request(req);
+        if !cacheable_method(req) || cc.no_cache || cc.no_store {
+            return None;
+        }
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key(req))?.iter().find(|e| e.matches(req) && e.expires > now)?;
+        let mut headers = entry.headers.clone();
+        headers.push(("Age".into(), Vec::from(now.duration_since(entry.stored).as_secs().to_string())));
+        let body = if req.method == "HEAD" { vec!() } else { entry.body.clone() };
+        Some((Response{code: entry.code, reason: entry.reason, headers}, body))
+    }
+
+    /// Keeps the response to the request, if it can be; returns whether it was. Only 200s
+    /// and 203s are kept.
+    pub fn store(&self, req: &Request, response: &Response, body: &[u8]) -> bool {
+        self.store_at(req, response, body, Instant::now())
+    }
+
+    fn store_at(&self, req: &Request, response: &Response, body: &[u8], now: Instant) -> bool {
+        let header = |name: &str| response.headers.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+            .collect::<Vec<_>>()
+            .join(",");
+        let cc = CacheControl::parse(header("Cache-Control").as_bytes());
+        let vary = Vary::parse(header("Vary").as_bytes());
+        let names = match vary.names() {
+            Some(names) => names,
+            None => return false,
+        };
+        let ttl = match cc.s_maxage.or(cc.max_age) {
+            Some(secs) => Duration::from_secs(secs),
+            None => match self.default_ttl {
+                Some(ttl) => ttl,
+                None => return false,
+            },
+        };
+        let storable = cacheable_method(req) && req.method != "HEAD"
+            && [200, 203].contains(&response.code)
+            && body.len() <= self.max_body
+            && !cc.private && !cc.no_store && !cc.no_cache
+            && !CacheControl::from_request(req).no_store
+            // one user's answer mustn't go to the next unless it says it's for everyone
+            && (req.header("Authorization").is_none() || cc.public || cc.s_maxage.is_some() || cc.must_revalidate)
+            && !response.headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("Set-Cookie"))
+            && ttl > Duration::ZERO;
+        if !storable || self.max_entries == 0 {
+            return false;
+        }
+        let entry = Entry{
+            vary: names.iter().map(|name| (name.clone(), req.header(name).map(Vec::from))).collect(),
+            code: response.code,
+            reason: response.reason,
+            headers: response.headers.clone(),
+            body: body.into(),
+            stored: now,
+            expires: now + ttl,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        let variants = entries.entry(key(req)).or_default();
+        variants.retain(|e| !e.matches(req) && e.expires > now);
+        variants.push(entry);
+        while entries.values().map(Vec::len).sum::<usize>() > self.max_entries {
+            evict_oldest(&mut entries);
+        }
+        true
+    }
+
+    /// The cached response if there is one; otherwise calls the handler, and keeps what it
+    /// returns if it can.
+    pub async fn fetch<F, Fut>(&self, req: &Request<'_>, handler: F) -> (Response, Vec<u8>)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = (Response, Vec<u8>)>,
+    {
+        if let Some(hit) = self.lookup(req) {
+            return hit;
+        }
+        let (response, body) = handler().await;
+        self.store(req, &response, &body);
+        (response, body)
+    }
+
+    /// Forgets every response for the target (the path and query), whatever the method.
+    pub fn invalidate(&self, target: &str) {
+        self.entries.lock().unwrap().retain(|(_, t), _| t != target);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// How many responses are kept, counting ones that have expired but not been dropped.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn cacheable_method(req: &Request) -> bool {
+    req.method == "GET" || req.method == "HEAD"
+}
+
+/// HEAD requests are answered from GET responses
+fn key(req: &Request) -> Key {
+    ("GET".into(), req.path.to_string())
+}
+
+fn evict_oldest(entries: &mut HashMap<Key, Vec<Entry>>) {
+    let oldest = entries.iter()
+        .flat_map(|(key, variants)| variants.iter().enumerate().map(move |(i, e)| (e.stored, key, i)))
+        .min_by_key(|(stored, _, _)| *stored)
+        .map(|(_, key, i)| (key.clone(), i));
+    if let Some((key, i)) = oldest {
+        let variants = entries.get_mut(&key).unwrap();
+        variants.remove(i);
+        if variants.is_empty() {
+            entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> Response {
+        Response{
+            headers: headers.iter().map(|(k, v)| (k.to_string(), Vec::from(*v))).collect(),
+            ..Response::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn test_fetch() {
+        let cache = ResponseCache::new();
+        let calls = Cell::new(0);
+        let handler = || async {
+            calls.set(calls.get() + 1);
+            (response(&[("Cache-Control", "max-age=60")]), Vec::from("expensive"))
+        };
+        let get = Request::builder().path("/dashboard");
+        let (_, body) = cache.fetch(&get.request(), handler).await;
+        let (hit, cached) = cache.fetch(&get.request(), handler).await;
+        assert_eq!((calls.get(), body, cached), (1, Vec::from("expensive"), Vec::from("expensive")));
+        assert_eq!(hit.headers.last().unwrap(), &("Age".into(), Vec::from("0")));
+        // HEAD is answered from the GET, without the body
+        let (_, body) = cache.fetch(&Request::builder().method("HEAD").path("/dashboard").request(), handler).await;
+        assert_eq!((calls.get(), body), (1, vec!()));
+        // the client asking for a fresh one gets it
+        let fresh = get.clone().header("Cache-Control", "no-cache");
+        cache.fetch(&fresh.request(), handler).await;
+        assert_eq!(calls.get(), 2);
+
+        cache.invalidate("/dashboard");
+        assert!(cache.is_empty());
+        cache.fetch(&get.request(), handler).await;
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn test_store() {
+        let cache = ResponseCache::new().max_entries(2);
+        let now = Instant::now();
+        let get = |path: &str| Request::builder().path(path);
+        let store = |req: &crate::RequestBuilder, headers, at| cache.store_at(&req.request(), &response(headers), b"x", at);
+
+        assert!(store(&get("/a"), &[("Cache-Control", "public, max-age=10")], now));
+        assert!(cache.lookup_at(&get("/a").request(), now + Duration::from_secs(9)).is_some());
+        assert!(cache.lookup_at(&get("/a").request(), now + Duration::from_secs(10)).is_none());
+        for headers in &[
+            &[][..],
+            &[("Cache-Control", "private, max-age=10")],
+            &[("Cache-Control", "max-age=10"), ("Set-Cookie", "a=b")],
+            &[("Cache-Control", "max-age=10"), ("Vary", "*")],
+            &[("Cache-Control", "max-age=0")],
+        ] {
+            assert!(!store(&get("/b"), headers, now), "{:?}", headers);
+        }
+        assert!(!store(&get("/b").method("POST"), &[("Cache-Control", "max-age=10")], now));
+
+        // variants, told apart by the headers named in Vary
+        let vary = &[("Cache-Control", "max-age=10"), ("Vary", "Accept-Language")];
+        assert!(store(&get("/v").header("Accept-Language", "de"), vary, now + Duration::from_secs(1)));
+        assert!(cache.lookup_at(&get("/v").header("accept-language", "de").request(), now).is_some());
+        assert!(cache.lookup_at(&get("/v").header("Accept-Language", "en").request(), now).is_none());
+        assert!(cache.lookup_at(&get("/v").request(), now).is_none());
+        // over max_entries, so the oldest (/a) goes
+        assert!(store(&get("/v"), vary, now + Duration::from_secs(2)));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup_at(&get("/v").request(), now).is_some());
+
+        let cache = ResponseCache::new().default_ttl(Duration::from_secs(5)).max_body(0);
+        assert!(!cache.store(&get("/a").request(), &response(&[]), b"x"));
+        assert!(cache.store(&get("/a").request(), &response(&[]), b""));
+    }
+
+    #[async_std::test]
+    async fn test_authorization() {
+        let cache = ResponseCache::new().default_ttl(Duration::from_secs(60));
+        let calls = Cell::new(0);
+        let handler = |headers: &'static [(&'static str, &'static str)]| {
+            let calls = &calls;
+            move || async move {
+                calls.set(calls.get() + 1);
+                (response(headers), Vec::from("alice's account"))
+            }
+        };
+        let authed = Request::builder().path("/account").header("Authorization", "Bearer alice");
+        let anonymous = Request::builder().path("/account");
+        cache.fetch(&authed.request(), handler(&[("Cache-Control", "max-age=60")])).await;
+        assert!(cache.is_empty());
+        cache.fetch(&anonymous.request(), handler(&[])).await;
+        assert_eq!(calls.get(), 2);
+
+        // unless the response says it's shared
+        for headers in &[
+            &[("Cache-Control", "public")][..],
+            &[("Cache-Control", "s-maxage=60")],
+            &[("Cache-Control", "max-age=60, must-revalidate")],
+        ] {
+            cache.clear();
+            assert!(cache.store(&authed.request(), &response(headers), b"x"), "{:?}", headers);
+            assert!(cache.lookup(&anonymous.request()).is_some());
+        }
+    }
+}
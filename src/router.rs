@@ -0,0 +1,902 @@
+//! Sending requests to handlers by method and path.
+//!
+//! Handlers get the request and its body (read it first, through Body, up to whatever
+//! limit suits), and return anything IntoResponse (a string, a status and JSON, a response
+//! and its body) or a HandlerError. Requests nothing
+//! matches go to the not_found handler, and errors through the error handler, so every
+//! request gets an answer in the application's own style:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{respond, send_content, router::{Context, HandlerError, Reply, Router}, Request};
+//! # const INDEX: &str = ""; const NOT_FOUND: &str = "";
+//! # fn html(page: &str) -> String { page.into() }
+//! # async fn login(cx: &Context<'_>) -> Result<String, HandlerError> { Ok(String::new()) }
+//! # fn json_error(err: HandlerError) -> Reply { err.reply() }
+//! # async fn serve(req: &Request<'_>, body: Vec<u8>, mut stream: TcpStream) -> std::io::Result<()> {
+//! let router = Router::new()
+//!     .route("GET", "/", |_cx: &Context| Box::pin(async { Ok(html(INDEX)) }))
+//!     .route("POST", "/login", |cx: &Context| Box::pin(login(cx)))
+//!     .not_found(|_cx: &Context| Box::pin(async { Ok(html(NOT_FOUND)) }))
+//!     .on_error(|_req: &Request, err: HandlerError| json_error(err));
+//!
+//! let (response, body) = router.handle(&req, &body).await;
+//! respond(&mut stream, response).await?;
+//! send_content(&mut stream, &body).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or have serve read the requests from the connection, bodies and all, up to the limits
+//! in ServeOptions; requests over them are answered with a 413, 414 or 431:
+//!
+//! ```no_run
+//! # use oc_http::{connection::ConnectionInfo, router::{Router, ServeOptions}};
+//! # async fn serve(router: Router, stream: async_std::net::TcpStream, peer: std::net::SocketAddr) -> std::io::Result<()> {
+//! let connection = ConnectionInfo{peer: Some(peer), ..ConnectionInfo::default()};
+//! router.serve(&connection, stream, &ServeOptions::default()).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Paths can capture segments, as a handler's params: `{name}` takes any one segment,
+//! `{name:u64}` one that parses as that integer type, `{name:[a-z0-9-]+}` one the regex
+//! matches all of, and a last segment of `*name` takes the rest of the path. Requests a
+//! capture doesn't fit don't match the route, so `/users/abc` is a 404 for `/users/{id:u64}`:
+//!
+//! ```no_run
+//! # use oc_http::router::{Context, HandlerError, Router};
+//! # fn json(user: &String) -> String { user.clone() }
+//! # async fn load_user(id: u64) -> Result<String, HandlerError> { Ok(String::new()) }
+//! # async fn serve(file: &str) -> Result<String, HandlerError> { Ok(String::new()) }
+//! let router = Router::new()
+//!     .route("GET", "/users/{id:u64}", |cx: &Context| Box::pin(async move {
+//!         let id: u64 = cx.param_as("id").unwrap();
+//!         Ok(json(&load_user(id).await?))
+//!     }))
+//!     .route("GET", "/static/*file", |cx: &Context| Box::pin(serve(cx.param("file").unwrap())));
+//! ```
+//!
+//! Middleware wraps handlers, and can answer for them or change what they return. It runs
+//! outermost first: what's added with wrap() for every request, then scope() middleware
+//! for the paths under each scope, then with() middleware for the one route, each in the
+//! order added:
+//!
+//! ```no_run
+//! # use futures::future::BoxFuture;
+//! # use oc_http::{router::{Context, HandlerError, HandlerResult, Next, Router}, Request};
+//! # async fn log_timing<'a>(cx: &'a Context<'a>, next: Next<'a>) -> HandlerResult { next.run(cx).await }
+//! # fn is_admin(req: &Request) -> bool { false }
+//! # fn reindex<'a>(cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> { unimplemented!() }
+//! # fn status<'a>(cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> { unimplemented!() }
+//! # fn rate_limited<'a>(cx: &'a Context<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> { next.run(cx) }
+//! let router = Router::new()
+//!     .wrap(|cx: &Context, next: Next| Box::pin(log_timing(cx, next)))
+//!     .scope("/admin", |cx: &Context, next: Next| Box::pin(async move {
+//!         if !is_admin(cx.request) {
+//!             return Err(HandlerError::new(403, "Forbidden", "admins only"));
+//!         }
+//!         next.run(cx).await
+//!     }))
+//!     .route("POST", "/admin/reindex", reindex)
+//!     .with(rate_limited)
+//!     .route("GET", "/public/status", status);
+//! ```
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt, io,
+    marker::PhantomData,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::{
+    future::BoxFuture,
+    io::BufReader,
+    prelude::*,
+};
+use regex::Regex;
+
+use crate::{
+    body::Body,
+    connection::ConnectionInfo,
+    encoding::percent_decode,
+    metrics::{self, HttpMetrics},
+    reply::IntoResponse,
+    upgrade::tokens,
+    error_response, http_with, respond, send_content, ParseOptions, Request, RequestError, Response,
+};
+
+/// A response and its body.
+pub type Reply = (Response, Vec<u8>);
+pub type HandlerResult = Result<Reply, HandlerError>;
+
+/// What a handler is given.
+pub struct Context<'a> {
+    pub request: &'a Request<'a>,
+    pub body: &'a [u8],
+    /// What the route's path captured, percent-decoded, in the order they're in the path.
+    pub params: &'a [(String, String)],
+    /// ConnectionInfo::UNKNOWN unless the request came through handle_from.
+    pub connection: &'a ConnectionInfo,
+    /// What middleware has attached to the request, like its session; empty to start with.
+    pub extensions: &'a Extensions,
+}
+
+impl Context<'_> {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    /// The param parsed; for typed captures, that never fails.
+    pub fn param_as<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.param(name)?.parse().ok()
+    }
+}
+
+/// Values attached to one request, at most one of each type, so middleware can hand things
+/// to handlers (and get them back afterwards) without the router knowing about them.
+#[derive(Default)]
+pub struct Extensions {
+    values: Mutex<HashMap<TypeId, Box<dyn Any + Send>>>,
+}
+
+impl Extensions {
+    /// Attaches the value, returning the one of the same type it replaces.
+    pub fn insert<T: Send + 'static>(&self, value: T) -> Option<T> {
+        let old = self.values.lock().unwrap().insert(TypeId::of::<T>(), Box::new(value));
+        old.and_then(|old| old.downcast().ok()).map(|old| *old)
+    }
+
+    pub fn remove<T: Send + 'static>(&self) -> Option<T> {
+        let old = self.values.lock().unwrap().remove(&TypeId::of::<T>());
+        old.and_then(|old| old.downcast().ok()).map(|old| *old)
+    }
+
+    /// A copy of the value of that type.
+    pub fn get<T: Clone + Send + 'static>(&self) -> Option<T> {
+        self.with(|value: &mut T| value.clone())
+    }
+
+    /// Calls `f` with the value of that type; None if there isn't one. The extensions are
+    /// locked meanwhile, so `f` mustn't use them itself.
+    pub fn with<T: Send + 'static, R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        let mut values = self.values.lock().unwrap();
+        values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut()).map(f)
+    }
+
+    pub fn contains<T: Send + 'static>(&self) -> bool {
+        self.values.lock().unwrap().contains_key(&TypeId::of::<T>())
+    }
+}
+
+/// Handlers are functions (or closures) from a Context to a boxed future, so they can be
+/// kept together whatever future each returns.
+pub trait Handler: Send + Sync {
+    fn call<'a>(&'a self, cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult>;
+}
+
+impl<F> Handler for F
+where F: for<'a> Fn(&'a Context<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync
+{
+    fn call<'a>(&'a self, cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> {
+        self(cx)
+    }
+}
+
+/// Middleware gets the request on its way to the handler; next.run() passes it on.
+pub trait Middleware: Send + Sync {
+    fn call<'a>(&'a self, cx: &'a Context<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult>;
+}
+
+impl<F> Middleware for F
+where F: for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync
+{
+    fn call<'a>(&'a self, cx: &'a Context<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        self(cx, next)
+    }
+}
+
+/// The rest of the middleware, then the handler.
+pub struct Next<'a> {
+    chain: &'a [&'a dyn Middleware],
+    handler: &'a dyn Handler,
+}
+
+impl<'a> Next<'a> {
+    pub fn run(self, cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> {
+        match self.chain.split_first() {
+            Some((first, rest)) => first.call(cx, Next{chain: rest, handler: self.handler}),
+            None => self.handler.call(cx),
+        }
+    }
+}
+
+/// what route() and not_found() make of handlers returning something IntoResponse
+struct Responds<H, R>(H, PhantomData<fn() -> R>);
+
+impl<H, R> Handler for Responds<H, R>
+where
+    H: for<'a> Fn(&'a Context<'a>) -> BoxFuture<'a, Result<R, HandlerError>> + Send + Sync,
+    R: IntoResponse,
+{
+    fn call<'a>(&'a self, cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> {
+        let result = (self.0)(cx);
+        Box::pin(async move { result.await.map(IntoResponse::into_response) })
+    }
+}
+
+/// stands in for the handler when the router refuses the request itself, so middleware
+/// still runs
+struct Refuse(HandlerError);
+
+impl Handler for Refuse {
+    fn call<'a>(&'a self, _cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> {
+        let err = self.0.clone();
+        Box::pin(async move { Err(err) })
+    }
+}
+
+type ErrorHandler = Box<dyn Fn(&Request, HandlerError) -> Reply + Send + Sync>;
+
+/// Why a handler couldn't answer, and the status that says so.
+#[derive(Debug, Clone)]
+pub struct HandlerError {
+    pub code: usize,
+    pub reason: &'static str,
+    /// For logs, and for the client when the code is under 500.
+    pub message: String,
+    /// Headers the response has to carry, like Allow on a 405.
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl HandlerError {
+    pub fn new<M: Into<String>>(code: usize, reason: &'static str, message: M) -> Self {
+        HandlerError{code, reason, message: message.into(), headers: vec!()}
+    }
+
+    pub fn bad_request<M: Into<String>>(message: M) -> Self {
+        HandlerError::new(400, "Bad Request", message)
+    }
+
+    pub fn not_found() -> Self {
+        HandlerError::new(404, "Not Found", "not found")
+    }
+
+    /// A 500; the error's only logged, never sent.
+    pub fn internal<E: fmt::Display>(err: E) -> Self {
+        HandlerError::new(500, "Internal Server Error", err.to_string())
+    }
+
+    /// For turning down a request with a ready-made response, like auth's 401s: its status
+    /// and headers, with the message as the body in place of whatever it had.
+    pub fn from_response<M: Into<String>>(response: Response, message: M) -> Self {
+        let headers = response.headers.into_iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Content-Type"))
+            .collect();
+        HandlerError{code: response.code, reason: response.reason, message: message.into(), headers}
+    }
+
+    pub fn header<V: Into<Vec<u8>>>(mut self, name: &str, value: V) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The message as plain text, or just the reason for 5xx errors, so internals don't
+    /// leak.
+    pub fn reply(self) -> Reply {
+        let body = if self.code >= 500 { self.reason.to_string() } else { self.message };
+        let mut headers = self.headers;
+        headers.push(("Content-Type".into(), Vec::from("text/plain; charset=utf-8")));
+        headers.push(("Content-Length".into(), Vec::from(body.len().to_string())));
+        (Response{code: self.code, reason: self.reason, headers}, body.into_bytes())
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.code, self.reason, self.message)
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<io::Error> for HandlerError {
+    fn from(err: io::Error) -> Self {
+        HandlerError::internal(err)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Any(String),
+    Typed(String, fn(&str) -> bool),
+    Matching(String, Regex),
+    Rest(String),
+}
+
+impl Segment {
+    fn parse(segment: &str) -> Segment {
+        if let Some(name) = segment.strip_prefix('*') {
+            return Segment::Rest(name.into());
+        }
+        let capture = match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(capture) => capture,
+            None => return Segment::Literal(segment.into()),
+        };
+        let (name, constraint) = match capture.split_once(':') {
+            Some((name, constraint)) => (name.into(), constraint),
+            None => return Segment::Any(capture.into()),
+        };
+        let parses: fn(&str) -> bool = match constraint {
+            "u8" => |s| s.parse::<u8>().is_ok(),
+            "u16" => |s| s.parse::<u16>().is_ok(),
+            "u32" => |s| s.parse::<u32>().is_ok(),
+            "u64" => |s| s.parse::<u64>().is_ok(),
+            "usize" => |s| s.parse::<usize>().is_ok(),
+            "i8" => |s| s.parse::<i8>().is_ok(),
+            "i16" => |s| s.parse::<i16>().is_ok(),
+            "i32" => |s| s.parse::<i32>().is_ok(),
+            "i64" => |s| s.parse::<i64>().is_ok(),
+            "isize" => |s| s.parse::<isize>().is_ok(),
+            regex => {
+                let regex = Regex::new(&format!("^(?:{})$", regex))
+                    .unwrap_or_else(|err| panic!("bad regex in route segment {}: {}", segment, err));
+                return Segment::Matching(name, regex);
+            },
+        };
+        Segment::Typed(name, parses)
+    }
+}
+
+/// names and values
+type Params = Vec<(String, String)>;
+
+/// A route's path, split at each `/`.
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(path: &str) -> Pattern {
+        let segments: Vec<_> = path.split('/').map(Segment::parse).collect();
+        let rest = segments.iter().position(|s| matches!(s, Segment::Rest(_)));
+        assert!(rest.is_none_or(|i| i == segments.len() - 1), "* has to be the last segment: {}", path);
+        Pattern(segments)
+    }
+
+    /// The captures, if the path matches.
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = vec!();
+        let mut parts = path.split('/');
+        for segment in &self.0 {
+            if let Segment::Rest(name) = segment {
+                // a rest of nothing still needs the / before it
+                let rest: Vec<_> = parts.by_ref().collect();
+                if rest.is_empty() {
+                    return None;
+                }
+                params.push((name.clone(), percent_decode(&rest.join("/"))?));
+                break;
+            }
+            let part = parts.next()?;
+            match segment {
+                Segment::Literal(literal) => if literal != part {
+                    return None;
+                },
+                Segment::Any(name) | Segment::Typed(name, _) | Segment::Matching(name, _) => {
+                    let value = percent_decode(part).filter(|v| !v.is_empty())?;
+                    let fits = match segment {
+                        Segment::Typed(_, parses) => parses(&value),
+                        Segment::Matching(_, regex) => regex.is_match(&value),
+                        _ => true,
+                    };
+                    if !fits {
+                        return None;
+                    }
+                    params.push((name.clone(), value));
+                },
+                Segment::Rest(_) => unreachable!(),
+            }
+        }
+        parts.next().is_none().then_some(params)
+    }
+}
+
+struct Route {
+    method: String,
+    // as given, for labelling metrics
+    template: String,
+    pattern: Pattern,
+    handler: Box<dyn Handler>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+    scopes: Vec<(String, Box<dyn Middleware>)>,
+    not_found: Option<Box<dyn Handler>>,
+    on_error: ErrorHandler,
+    metrics: Option<Arc<HttpMetrics>>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router{
+            routes: vec!(),
+            middleware: vec!(),
+            scopes: vec!(),
+            not_found: None,
+            on_error: Box::new(|_, err| err.reply()),
+            metrics: None,
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Sends requests for the method and path pattern (the query isn't looked at) to the
+    /// handler; the first route added wins. HEAD requests go to GET routes when there's no
+    /// HEAD route, and the body is dropped. Panics if the pattern's regex doesn't compile,
+    /// or `*` isn't last.
+    pub fn route<H, R>(mut self, method: &str, path: &str, handler: H) -> Self
+    where
+        H: for<'a> Fn(&'a Context<'a>) -> BoxFuture<'a, Result<R, HandlerError>> + Send + Sync + 'static,
+        R: IntoResponse + 'static,
+    {
+        self.routes.push(Route{
+            method: method.into(),
+            template: path.into(),
+            pattern: Pattern::parse(path),
+            handler: Box::new(Responds(handler, PhantomData)),
+            middleware: vec!(),
+        });
+        self
+    }
+
+    /// Adds middleware for every request, including ones no route matches.
+    pub fn wrap<M>(mut self, middleware: M) -> Self
+    where M: for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds middleware for the path and everything under it (`/admin` covers `/admin` and
+    /// `/admin/...`, but not `/administrator`), whether or not a route matches.
+    pub fn scope<M>(mut self, prefix: &str, middleware: M) -> Self
+    where M: for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static
+    {
+        self.scopes.push((prefix.trim_end_matches('/').into(), Box::new(middleware)));
+        self
+    }
+
+    /// Adds middleware for the route added last.
+    pub fn with<M>(mut self, middleware: M) -> Self
+    where M: for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static
+    {
+        self.routes.last_mut().expect("with() called before any route").middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Answers requests no route matches, instead of a plain 404. Its errors go through the
+    /// error handler like any other.
+    pub fn not_found<H, R>(mut self, handler: H) -> Self
+    where
+        H: for<'a> Fn(&'a Context<'a>) -> BoxFuture<'a, Result<R, HandlerError>> + Send + Sync + 'static,
+        R: IntoResponse + 'static,
+    {
+        self.not_found = Some(Box::new(Responds(handler, PhantomData)));
+        self
+    }
+
+    /// Turns errors, from handlers or the router's own 404s and 405s, into responses; the
+    /// default is HandlerError::reply.
+    pub fn on_error<F>(mut self, handler: F) -> Self
+    where F: Fn(&Request, HandlerError) -> Reply + Send + Sync + 'static
+    {
+        self.on_error = Box::new(handler);
+        self
+    }
+
+    /// Counts and times each request by the template of the route it matched; requests no
+    /// route matched, 405s included, are `unmatched`. See the metrics module.
+    pub fn metrics(mut self, metrics: Arc<HttpMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The answer to the request; always one, since errors are made into responses.
+    pub async fn handle(&self, req: &Request<'_>, body: &[u8]) -> Reply {
+        self.handle_from(&ConnectionInfo::UNKNOWN, req, body).await
+    }
+
+    /// Like handle, with what's known about the connection for handlers to see.
+    pub async fn handle_from(&self, connection: &ConnectionInfo, req: &Request<'_>, body: &[u8]) -> Reply {
+        let started = Instant::now();
+        let refuse;
+        let mut params = vec!();
+        let mut template = metrics::UNMATCHED;
+        let (handler, route_middleware): (&dyn Handler, &[Box<dyn Middleware>]) = match self.find(req) {
+            Ok((route, captured)) => {
+                params = captured;
+                template = &route.template;
+                (&*route.handler, &route.middleware)
+            },
+            Err(err) => match (&self.not_found, err.code) {
+                (Some(not_found), 404) => (&**not_found, &[]),
+                _ => {
+                    refuse = Refuse(err);
+                    (&refuse, &[])
+                },
+            },
+        };
+        let path = req.path.split('?').next().unwrap_or("");
+        let scoped = self.scopes.iter()
+            .filter(|(prefix, _)| path.strip_prefix(&prefix[..]).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+            .map(|(_, middleware)| middleware);
+        let chain: Vec<&dyn Middleware> = self.middleware.iter().chain(scoped).chain(route_middleware)
+            .map(|middleware| &**middleware)
+            .collect();
+        let extensions = Extensions::default();
+        let cx = Context{request: req, body, params: &params, connection, extensions: &extensions};
+        let result = Next{chain: &chain, handler}.run(&cx).await;
+        let (response, body) = match result {
+            Ok(reply) => reply,
+            Err(err) => {
+                if err.code >= 500 {
+                    log::error!("{} {}: {}", req.method, req.path, err);
+                }
+                (self.on_error)(req, err)
+            },
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.record_route(template, response.code, started.elapsed());
+        }
+        if req.method == "HEAD" { (response, vec!()) } else { (response, body) }
+    }
+
+    /// Answers requests on the connection until the client closes it, or either side sends
+    /// `Connection: close`. Requests over the options' limits are answered with 413, 414 or
+    /// 431 (and ones strict parsing refuses, or with bodies that can't be framed, with 400),
+    /// after which the connection is closed, since the rest of the request is still in it.
+    pub async fn serve<S>(&self, connection: &ConnectionInfo, stream: S, options: &ServeOptions) -> io::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut stream = BufReader::new(stream);
+        let mut buf = vec![0; options.max_head];
+        loop {
+            // the client hanging up between requests is how keep-alive ends
+            if stream.fill_buf().await?.is_empty() {
+                return Ok(());
+            }
+            let (req, body) = match read_request(&mut stream, &mut buf, options).await {
+                Ok(read) => read,
+                Err(err) => return match error_response(&err) {
+                    Some(response) => {
+                        respond(&mut stream, response).await?;
+                        stream.flush().await
+                    },
+                    None => Err(err),
+                },
+            };
+            let (response, body) = self.handle_from(connection, &req, &body).await;
+            let close = wants_close(&req) || response.headers.iter()
+                .any(|(name, value)| name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case(b"close"));
+            respond(&mut stream, response).await?;
+            send_content(&mut stream, &body).await?;
+            stream.flush().await?;
+            if close {
+                return Ok(());
+            }
+        }
+    }
+
+    fn find(&self, req: &Request) -> Result<(&Route, Params), HandlerError> {
+        let path = req.path.split('?').next().unwrap_or("");
+        let mut routes: Vec<_> = self.routes.iter()
+            .filter_map(|r| r.pattern.matches(path).map(|params| (r, params)))
+            .collect();
+        let method = |m: &str| routes.iter().position(|(r, _)| r.method == m);
+        let found = match method(&req.method) {
+            Some(i) => Some(i),
+            None if req.method == "HEAD" => method("GET"),
+            None => None,
+        };
+        match found {
+            Some(i) => Ok(routes.swap_remove(i)),
+            None if routes.is_empty() => Err(HandlerError::not_found()),
+            None => {
+                let mut allow: Vec<&str> = vec!();
+                for (route, _) in &routes {
+                    if !allow.contains(&route.method.as_str()) {
+                        allow.push(&route.method);
+                    }
+                }
+                Err(HandlerError::new(405, "Method Not Allowed", "method not allowed")
+                    .header("Allow", allow.join(", ")))
+            },
+        }
+    }
+}
+
+/// How Router::serve reads requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServeOptions {
+    pub parse: ParseOptions,
+    /// The size of the buffer for each request's line and headers; longer heads are a 414
+    /// or 431.
+    pub max_head: usize,
+    /// Longest body handlers are given; longer ones are a 413.
+    pub max_body: u64,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        ServeOptions{
+            parse: ParseOptions::default(),
+            max_head: 16 * 1024,
+            max_body: 1024 * 1024,
+        }
+    }
+}
+
+/// reads the next request and all of its body
+async fn read_request<'b, S>(stream: &mut S, buf: &'b mut [u8], options: &ServeOptions) -> io::Result<(Request<'b>, Vec<u8>)>
+where S: AsyncRead + Unpin
+{
+    let req = http_with(stream, buf, &options.parse).await?;
+    let mut body = vec!();
+    Body::new(&req, stream)
+        .map_err(|_| RequestError::Malformed("bad body framing"))?
+        .limit(options.max_body)?
+        .read_to_end(&mut body).await?;
+    Ok((req, body))
+}
+
+fn wants_close(req: &Request) -> bool {
+    tokens(req, "Connection").iter().any(|t| t.eq_ignore_ascii_case("close"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    fn text(body: &str) -> Reply {
+        let headers = vec!(("Content-Length".into(), Vec::from(body.len().to_string())));
+        (Response{headers, ..Response::default()}, Vec::from(body))
+    }
+
+    fn echo<'a>(cx: &'a Context<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            match std::str::from_utf8(cx.body) {
+                Ok(body) => Ok(text(body)),
+                Err(_) => Err(HandlerError::bad_request("not text")),
+            }
+        })
+    }
+
+    #[async_std::test]
+    async fn test_serve() {
+        use crate::testing::TestClient;
+        let router = Router::new().route("POST", "/echo", echo);
+        let options = ServeOptions{
+            parse: ParseOptions{max_uri: 32, max_headers: 4, ..ParseOptions::default()},
+            max_head: 256,
+            max_body: 8,
+        };
+        let client = TestClient::new(|stream| {
+            let (router, options) = (&router, &options);
+            async move { router.serve(&ConnectionInfo::UNKNOWN, stream, options).await.unwrap() }
+        });
+        let echo = |body: &str| format!("POST /echo HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let res = client.send(echo("hi").as_bytes()).await.unwrap();
+        assert_eq!((res.code, &res.body[..]), (200, &b"hi"[..]));
+        // kept alive for the next one, until the client's done
+        let res = client.send(format!("{}{}", echo("one"), echo("two")).as_bytes()).await.unwrap();
+        assert_eq!(res.body, b"one");
+
+        let refused = |code: u16| move |res: crate::testing::TestResponse| {
+            assert_eq!(res.code, code);
+            assert!(res.headers.contains(&("Connection".into(), Vec::from("close"))));
+        };
+        refused(413)(client.send(echo("far too long").as_bytes()).await.unwrap());
+        refused(414)(client.send(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40)).as_bytes()).await.unwrap());
+        refused(431)(client.send(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\n\r\n").await.unwrap());
+        refused(431)(client.send(format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(300)).as_bytes()).await.unwrap());
+        refused(400)(client.send(b"POST /echo HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn test_router() {
+        let router = Router::new()
+            .route("GET", "/", |_: &Context| Box::pin(async { Ok(text("home")) }))
+            .route("POST", "/echo", echo)
+            .route("GET", "/broken", |_: &Context| Box::pin(async {
+                Err::<Reply, _>(io::Error::other("disk on fire").into())
+            }));
+        let handle = |method: &str, path: &str, body: &[u8]| {
+            let builder = Request::builder().method(method).path(path);
+            let body = body.to_vec();
+            let router = &router;
+            async move {
+                let (response, body) = router.handle(&builder.request(), &body).await;
+                (response.code, String::from_utf8(body).unwrap(), response.headers)
+            }
+        };
+        assert_eq!(handle("GET", "/?x=1", b"").await.1, "home");
+        assert_eq!(handle("HEAD", "/", b"").await.1, "");
+        assert_eq!(handle("POST", "/echo", b"hi").await.1, "hi");
+        assert_eq!(handle("POST", "/echo", b"\xff").await.0, 400);
+        let (code, body, _) = handle("GET", "/nowhere", b"").await;
+        assert_eq!((code, &body[..]), (404, "not found"));
+        let (code, _, headers) = handle("GET", "/echo", b"").await;
+        assert_eq!((code, &headers[0]), (405, &("Allow".into(), Vec::from("POST"))));
+        // internals stay in the logs
+        assert_eq!(handle("GET", "/broken", b"").await.1, "Internal Server Error");
+
+        let branded = Router::new()
+            .not_found(|cx: &Context| Box::pin(async move { Ok(text(&format!("no {} here", cx.request.path))) }))
+            .on_error(|_, err| text(&format!("{{\"error\":{}}}", err.code)));
+        let builder = Request::builder().path("/x");
+        assert_eq!(branded.handle(&builder.request(), b"").await.1, b"no /x here");
+        let builder = Request::builder().method("DELETE").path("/x");
+        let routed = branded.route("GET", "/x", |_: &Context| Box::pin(async { Ok(text("x")) }));
+        assert_eq!(routed.handle(&builder.request(), b"").await.1, b"{\"error\":405}");
+    }
+
+    #[async_std::test]
+    async fn test_patterns() {
+        let params = |cx: &Context| cx.params.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(" ");
+        let router = Router::new()
+            .route("GET", "/users/{id:u64}", move |cx: &Context| {
+                let id: u64 = cx.param_as("id").unwrap();
+                Box::pin(async move { Ok(text(&format!("user {}", id))) })
+            })
+            .route("GET", "/users/me", |_: &Context| Box::pin(async { Ok(text("me")) }))
+            .route("GET", "/files/{name:[a-z0-9-]+}/{rev}", move |cx: &Context| {
+                let body = params(cx);
+                Box::pin(async move { Ok(text(&body)) })
+            })
+            .route("GET", "/static/*file", move |cx: &Context| {
+                let body = params(cx);
+                Box::pin(async move { Ok(text(&body)) })
+            })
+            .route("DELETE", "/users/{id}", |_: &Context| Box::pin(async { Ok(text("gone")) }));
+
+        let router = &router;
+        let get = |path: &'static str| async move {
+            let (response, body) = router.handle(&Request::builder().path(path).request(), b"").await;
+            (response.code, String::from_utf8(body).unwrap())
+        };
+        assert_eq!(get("/users/42").await, (200, "user 42".into()));
+        // not a u64, so the next route gets it
+        assert_eq!(get("/users/me").await, (200, "me".into()));
+        assert_eq!(get("/files/a-1/%7Erev?x=1").await, (200, "name=a-1 rev=~rev".into()));
+        assert_eq!(get("/files/A-1/2").await.0, 404);
+        assert_eq!(get("/files/a-1/").await.0, 404);
+        assert_eq!(get("/files/a-1/2/3").await.0, 404);
+        assert_eq!(get("/static/css/site%20main.css").await, (200, "file=css/site main.css".into()));
+        assert_eq!(get("/static/").await, (200, "file=".into()));
+        assert_eq!(get("/static").await.0, 404);
+        // only the DELETE route fits, so it's a 405, not a 404
+        let (response, _) = router.handle(&Request::builder().path("/users/abc").request(), b"").await;
+        assert_eq!((response.code, &response.headers[0]), (405, &("Allow".into(), Vec::from("DELETE"))));
+    }
+
+    #[async_std::test]
+    async fn test_connection() {
+        let router = Router::new().route("GET", "/", |cx: &Context| {
+            let scheme = if cx.connection.is_tls() { "https" } else { "http" };
+            Box::pin(async move { Ok(text(scheme)) })
+        });
+        let tls = ConnectionInfo{tls: Some(Default::default()), ..ConnectionInfo::default()};
+        assert_eq!(router.handle_from(&tls, &Request::builder().request(), b"").await.1, b"https");
+        assert_eq!(router.handle(&Request::builder().request(), b"").await.1, b"http");
+    }
+
+    #[test]
+    #[should_panic(expected = "last segment")]
+    fn test_rest_not_last() {
+        Router::new().route("GET", "/*rest/x", |_: &Context| Box::pin(async { Ok(text("")) }));
+    }
+
+    type Order = Arc<Mutex<Vec<&'static str>>>;
+
+    /// notes that it ran, and passes the request on
+    fn tag(order: &Order, name: &'static str) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync {
+        let order = order.clone();
+        move |cx, next| {
+            order.lock().unwrap().push(name);
+            next.run(cx)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_extensions() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct User(&'static str);
+        let router = Router::new()
+            .wrap(|cx: &Context, next: Next| Box::pin(async move {
+                cx.extensions.insert(User("alice"));
+                let reply = next.run(cx).await;
+                assert_eq!(cx.extensions.remove::<u32>(), Some(2));
+                reply
+            }))
+            .route("GET", "/", |cx: &Context| Box::pin(async move {
+                cx.extensions.insert(1u32);
+                cx.extensions.with(|n: &mut u32| *n += 1);
+                Ok(text(cx.extensions.get::<User>().unwrap().0))
+            }));
+        assert_eq!(router.handle(&Request::builder().request(), b"").await.1, b"alice");
+        let extensions = Extensions::default();
+        assert!(!extensions.contains::<User>() && extensions.get::<User>().is_none());
+        assert_eq!(extensions.insert(User("bob")), None);
+        assert_eq!(extensions.insert(User("carol")), Some(User("bob")));
+    }
+
+    #[test]
+    fn test_error_from_response() {
+        let response = crate::auth::unauthorized("Basic realm=\"x\"");
+        let (response, body) = HandlerError::from_response(response, "who are you?").reply();
+        assert_eq!((response.code, response.reason, body), (401, "Unauthorized", Vec::from("who are you?")));
+        assert_eq!(response.headers, vec!(
+            ("WWW-Authenticate".into(), Vec::from("Basic realm=\"x\"")),
+            ("Content-Type".into(), Vec::from("text/plain; charset=utf-8")),
+            ("Content-Length".into(), Vec::from("12")),
+        ));
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        let order = Order::default();
+        let tag = |name| tag(&order, name);
+        let router = Router::new()
+            .route("GET", "/admin/users", |_: &Context| Box::pin(async { Ok(text("users")) }))
+            .with(tag("route"))
+            .scope("/admin/", |cx: &Context, next: Next| Box::pin(async move {
+                if cx.request.header("Authorization").is_none() {
+                    return Err(HandlerError::new(401, "Unauthorized", "log in"));
+                }
+                next.run(cx).await
+            }))
+            .wrap(tag("global"))
+            .scope("/admin", tag("scope"))
+            .route("GET", "/public/status", |_: &Context| Box::pin(async { Ok(text("ok")) }))
+            .wrap(tag("global 2"));
+
+        let get = |path: &str| Request::builder().path(path);
+        let authed = get("/admin/users").header("Authorization", "yes");
+        assert_eq!(router.handle(&authed.request(), b"").await.1, b"users");
+        assert_eq!(*order.lock().unwrap(), vec!("global", "global 2", "scope", "route"));
+
+        order.lock().unwrap().clear();
+        assert_eq!(router.handle(&get("/admin/users").request(), b"").await.0.code, 401);
+        assert_eq!(*order.lock().unwrap(), vec!("global", "global 2"));
+        // scopes cover paths no route matches too
+        assert_eq!(router.handle(&get("/admin/nothing").request(), b"").await.0.code, 401);
+        assert_eq!(router.handle(&get("/administrator").request(), b"").await.0.code, 404);
+        assert_eq!(router.handle(&get("/public/status").request(), b"").await.1, b"ok");
+    }
+
+    #[async_std::test]
+    async fn test_metrics() {
+        let metrics = Arc::new(HttpMetrics::new());
+        let router = Router::new()
+            .metrics(metrics.clone())
+            .route("GET", "/users/{id}", echo);
+        for path in &["/users/1", "/users/2", "/nowhere"] {
+            router.handle(&Request::builder().path(path).request(), b"").await;
+        }
+        let rendered = metrics.render();
+        assert!(rendered.contains("oc_http_route_requests_total{route=\"/users/{id}\",class=\"2xx\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("oc_http_route_requests_total{route=\"unmatched\",class=\"4xx\"} 1\n"), "{}", rendered);
+        assert!(!rendered.contains("/users/1"));
+    }
+}
@@ -0,0 +1,471 @@
+//! Server-side sessions: the data lives in a SessionStore, and the client only gets a random
+//! id in a cookie.
+//!
+//! Sessions::middleware is for a Router: it loads the session before the handler runs, attaches it
+//! to the request's extensions, and saves it once the handler's done, setting the cookie on
+//! the response. Saving only touches the store and the cookie if something changed.
+//!
+//! MemoryStore and FileStore are ready to use, or implement SessionStore for your own storage.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use oc_http::{router::{Context, Router}, session::{memory::MemoryStore, Session, Sessions}};
+//! let router = Router::new()
+//!     .wrap(Sessions::new(MemoryStore::new(Duration::from_secs(3600))).middleware())
+//!     .route("GET", "/", |cx: &Context| Box::pin(async move {
+//!         let visits = cx.extensions.with(|session: &mut Session| {
+//!             let visits: u32 = session.get("visits").unwrap_or(0) + 1;
+//!             session.insert("visits", visits);
+//!             visits
+//!         });
+//!         Ok(format!("visit {}", visits.unwrap_or(1)))
+//!     }));
+//! ```
+//!
+//! Outside a Router, load and save the session yourself:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use oc_http::{cookies::Cookies, session::{memory::MemoryStore, Sessions}, Request, Response};
+//! # async fn serve(request: &Request<'_>, mut response: Response) -> std::io::Result<()> {
+//! let sessions = Sessions::new(MemoryStore::new(Duration::from_secs(3600)));
+//! let mut cookies = Cookies::new(&request);
+//! let mut session = sessions.load(&cookies).await?;
+//! let visits: u32 = session.get("visits").unwrap_or(0);
+//! session.insert("visits", visits + 1);
+//! sessions.save(session, &mut cookies).await?;
+//! cookies.write_cookies(&mut response);
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    io,
+    mem,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures::future::BoxFuture;
+
+use crate::{
+    cookies::{Cookie, Cookies},
+    router::{Context, HandlerResult, Next},
+};
+
+pub mod file;
+pub mod memory;
+
+/// Name of the cookie holding the session id, unless changed with Sessions::cookie_name.
+pub const DEFAULT_COOKIE_NAME: &str = "session";
+
+/// What a store keeps for each session.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionData {
+    pub values: HashMap<String, String>,
+    /// Flash values waiting for the next request.
+    pub flash: HashMap<String, String>,
+    /// When the session was first saved, and last saved; set by Sessions::save.
+    pub created: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+}
+
+/// Somewhere to keep sessions between requests. The futures are boxed so that stores can be
+/// used as trait objects and written for any runtime.
+pub trait SessionStore: Send + Sync {
+    /// The session's data; None if there's no such session (any more).
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<Option<SessionData>>>;
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData) -> BoxFuture<'a, io::Result<()>>;
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>>;
+}
+
+/// One client's session, as loaded by Sessions::load.
+#[derive(Debug, Default)]
+pub struct Session {
+    // None until the session has been saved for the first time
+    id: Option<String>,
+    data: SessionData,
+    // flash values set by the previous request
+    flashed: HashMap<String, String>,
+    changed: bool,
+    regenerate: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    /// The id of a session that's been saved before.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    /// Parses the value stored under the key; None if it's missing or doesn't parse.
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get_str(key)?.parse().ok()
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.data.values.get(key).map(|v| &v[..])
+    }
+
+    pub fn insert<T: ToString>(&mut self, key: &str, value: T) {
+        self.data.values.insert(key.into(), value.to_string());
+        self.changed = true;
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let old = self.data.values.remove(key);
+        self.changed |= old.is_some();
+        old
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.values.is_empty()
+    }
+
+    /// Sets a value only the next request can read, e.g. "Saved!" after a form posts and
+    /// redirects.
+    pub fn flash<T: ToString>(&mut self, key: &str, value: T) {
+        self.data.flash.insert(key.into(), value.to_string());
+        self.changed = true;
+    }
+
+    /// Takes a flash value set by the previous request. They're gone after this request
+    /// whether they're read or not.
+    pub fn take_flash(&mut self, key: &str) -> Option<String> {
+        self.flashed.remove(key)
+    }
+
+    /// Moves the session to a new id on save, keeping its data, and throws the old id away.
+    /// Call it whenever the user's privileges change, e.g. on login, so that an id someone
+    /// planted before can't be used to ride along (session fixation).
+    pub fn regenerate_id(&mut self) {
+        self.regenerate = true;
+        self.changed = true;
+    }
+
+    /// Throws the session away on save, e.g. when logging out; the cookie is removed too.
+    pub fn destroy(&mut self) {
+        self.data.values.clear();
+        self.data.flash.clear();
+        self.destroyed = true;
+    }
+}
+
+/// Connects a store to the session cookie.
+pub struct Sessions<S: SessionStore> {
+    store: S,
+    cookie_name: String,
+    secure: bool,
+    idle_timeout: Option<Duration>,
+    absolute_timeout: Option<Duration>,
+    clock: Arc<Clock>,
+}
+
+type Clock = dyn Fn() -> SystemTime + Send + Sync;
+
+impl<S: SessionStore> Sessions<S> {
+    pub fn new(store: S) -> Self {
+        Sessions{
+            store,
+            cookie_name: DEFAULT_COOKIE_NAME.into(),
+            secure: true,
+            idle_timeout: None,
+            absolute_timeout: None,
+            clock: Arc::new(SystemTime::now),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> Self {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// Whether the session cookie is only sent over https; it is unless this says otherwise,
+    /// e.g. for plain http during development. The cookie is always HttpOnly.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Expires sessions that go unused for this long. Every request then saves the session,
+    /// to record that it was used.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Expires sessions this long after they were created, however much they're used.
+    pub fn absolute_timeout(mut self, timeout: Duration) -> Self {
+        self.absolute_timeout = Some(timeout);
+        self
+    }
+
+    /// Where the time comes from for timing sessions out; SystemTime::now unless a test
+    /// needs to say otherwise.
+    pub fn clock<F>(mut self, now: F) -> Self
+    where F: Fn() -> SystemTime + Send + Sync + 'static
+    {
+        self.clock = Arc::new(now);
+        self
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// The session for the request the cookies came from; a new, empty one if the request
+    /// doesn't have a session cookie, the store doesn't know its id or it has expired.
+    pub async fn load(&self, cookies: &Cookies<'_>) -> io::Result<Session> {
+        let id = match cookies.get_value(&self.cookie_name) {
+            Some(id) => id,
+            None => return Ok(Session::default()),
+        };
+        Ok(match self.store.load(id).await? {
+            Some(data) if self.expired(&data) => {
+                self.store.destroy(id).await?;
+                Session::default()
+            },
+            Some(mut data) => {
+                // flash values only last one request, so the store has to forget them
+                let flashed = mem::take(&mut data.flash);
+                Session{
+                    id: Some(id.into()),
+                    data,
+                    changed: !flashed.is_empty() || self.idle_timeout.is_some(),
+                    flashed,
+                    ..Session::default()
+                }
+            },
+            None => Session::default(),
+        })
+    }
+
+    /// Writes any changes to the store, setting (or removing) the session cookie as needed.
+    pub async fn save(&self, mut session: Session, cookies: &mut Cookies<'_>) -> io::Result<()> {
+        if session.destroyed {
+            if let Some(id) = &session.id {
+                self.store.destroy(id).await?;
+                cookies.remove(&self.cookie_name);
+            }
+            return Ok(());
+        }
+        if !session.changed {
+            return Ok(());
+        }
+        let mut regenerated = false;
+        if session.regenerate {
+            if let Some(old) = session.id.take() {
+                self.store.destroy(&old).await?;
+                regenerated = true;
+            }
+        }
+        let now = (self.clock)();
+        session.data.created.get_or_insert(now);
+        session.data.accessed = Some(now);
+        match &session.id {
+            Some(id) => self.store.save(id, &session.data).await,
+            // don't hand out sessions that don't hold anything
+            None if session.is_empty() && session.data.flash.is_empty() => {
+                // nor leave the old id's cookie pointing at nothing
+                if regenerated {
+                    cookies.remove(&self.cookie_name);
+                }
+                Ok(())
+            },
+            None => {
+                let id = new_id();
+                self.store.save(&id, &session.data).await?;
+                let mut cookie = Cookie::new(self.cookie_name.clone(), id);
+                cookie.set_secure(self.secure);
+                cookie.set_http_only(true);
+                cookies.add_cookie(cookie);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl<S: SessionStore + 'static> Sessions<S> {
+    /// Router middleware loading the session into the request's extensions for the
+    /// handler, and saving what the handler leaves there. Nothing is saved for requests the
+    /// handler fails.
+    pub fn middleware(self) -> impl for<'a> Fn(&'a Context<'a>, Next<'a>) -> BoxFuture<'a, HandlerResult> + Send + Sync + 'static {
+        let sessions = Arc::new(self);
+        move |cx, next| {
+            let sessions = sessions.clone();
+            Box::pin(async move { sessions.run(cx, next).await })
+        }
+    }
+
+    async fn run<'a>(&'a self, cx: &'a Context<'a>, next: Next<'a>) -> HandlerResult {
+        let mut cookies = Cookies::new(cx.request);
+        cx.extensions.insert(self.load(&cookies).await?);
+        let (mut response, body) = next.run(cx).await?;
+        let session = cx.extensions.remove::<Session>().unwrap_or_default();
+        self.save(session, &mut cookies).await?;
+        cookies.write_cookies(&mut response);
+        Ok((response, body))
+    }
+}
+
+impl<S: SessionStore> Sessions<S> {
+    fn expired(&self, data: &SessionData) -> bool {
+        let now = (self.clock)();
+        let older_than = |time: Option<SystemTime>, timeout: Option<Duration>| match (time, timeout) {
+            (Some(time), Some(timeout)) => now.duration_since(time).unwrap_or_default() > timeout,
+            _ => false,
+        };
+        older_than(data.accessed, self.idle_timeout) || older_than(data.created, self.absolute_timeout)
+    }
+}
+
+/// 256 random bits; unguessable, so the id doesn't need signing
+fn new_id() -> String {
+    base64::encode_config(rand::random::<[u8; 32]>(), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use super::memory::MemoryStore;
+    use crate::{Request, Response};
+
+    /// runs a request carrying the cookie header through `f`, returning the Set-Cookie headers
+    async fn request<F>(sessions: &Sessions<MemoryStore>, cookie: &str, f: F) -> Vec<String>
+    where F: FnOnce(&mut Session)
+    {
+        let mut headers = HashMap::default();
+        headers.insert("Cookie", (cookie.as_bytes(), None));
+        let req = Request{
+            method: "GET".into(),
+            path: "/".into(),
+            headers,
+        };
+        let mut cookies = Cookies::new(&req);
+        let mut session = sessions.load(&cookies).await.unwrap();
+        f(&mut session);
+        sessions.save(session, &mut cookies).await.unwrap();
+        let mut resp = Response::default();
+        cookies.write_cookies(&mut resp);
+        resp.headers.into_iter().map(|(_, v)| String::from_utf8(v).unwrap()).collect()
+    }
+
+    #[async_std::test]
+    async fn test_sessions() {
+        let sessions = Sessions::new(MemoryStore::new(Duration::from_secs(60)));
+        // nothing stored, nothing set
+        assert!(request(&sessions, "", |s| assert!(s.id().is_none())).await.is_empty());
+        let set = request(&sessions, "", |s| s.insert("visits", 1)).await;
+        assert_eq!(set.len(), 1);
+        let cookie = set[0].split(';').next().unwrap().to_string();
+        assert!(cookie.starts_with("session="));
+        assert!(set[0].contains("; Secure") && set[0].contains("; HttpOnly"), "{}", set[0]);
+
+        // sessions that already have a cookie only update the store
+        let set = request(&sessions, &cookie, |s| {
+            assert_eq!(s.get::<u32>("visits"), Some(1));
+            s.insert("visits", s.get::<u32>("visits").unwrap() + 1);
+        }).await;
+        assert!(set.is_empty());
+        request(&sessions, &cookie, |s| assert_eq!(s.get_str("visits"), Some("2"))).await;
+
+        // unknown ids get a fresh session
+        request(&sessions, "session=made-up", |s| assert!(s.is_empty() && s.id().is_none())).await;
+
+        // flash values are there for exactly one request
+        request(&sessions, &cookie, |s| s.flash("notice", "Saved!")).await;
+        request(&sessions, &cookie, |s| {
+            assert_eq!(s.take_flash("notice").as_deref(), Some("Saved!"));
+            assert_eq!(s.take_flash("notice"), None);
+        }).await;
+        request(&sessions, &cookie, |s| s.flash("notice", "Again")).await;
+        request(&sessions, &cookie, |_| {}).await;
+        request(&sessions, &cookie, |s| assert_eq!(s.take_flash("notice"), None)).await;
+        // and are enough to start a session
+        let set = request(&sessions, "", |s| s.flash("notice", "Welcome")).await;
+        let fresh = set[0].split(';').next().unwrap().to_string();
+        request(&sessions, &fresh, |s| assert_eq!(s.take_flash("notice").as_deref(), Some("Welcome"))).await;
+
+        // regenerating keeps the data but not the id
+        let set = request(&sessions, &cookie, |s| s.regenerate_id()).await;
+        let regenerated = set[0].split(';').next().unwrap().to_string();
+        assert_ne!(regenerated, cookie);
+        request(&sessions, &cookie, |s| assert!(s.is_empty())).await;
+        request(&sessions, &regenerated, |s| assert_eq!(s.get::<u32>("visits"), Some(2))).await;
+        let cookie = regenerated;
+
+        let set = request(&sessions, &cookie, |s| s.destroy()).await;
+        assert!(set[0].starts_with("session=;"), "{}", set[0]);
+        assert_eq!(sessions.store().len(), 1);
+    }
+
+    #[async_std::test]
+    async fn test_expiry() {
+        let now = Arc::new(std::sync::Mutex::new(SystemTime::now()));
+        let clock = now.clone();
+        let sessions = Sessions::new(MemoryStore::new(Duration::from_secs(60)))
+            .idle_timeout(Duration::from_secs(200))
+            .absolute_timeout(Duration::from_secs(600))
+            .clock(move || *clock.lock().unwrap());
+        let advance = |secs| *now.lock().unwrap() += Duration::from_secs(secs);
+        let set = request(&sessions, "", |s| s.insert("user", "alice")).await;
+        let cookie = set[0].split(';').next().unwrap().to_string();
+        // each use pushes the idle timeout back...
+        for _ in 0..4 {
+            advance(120);
+            request(&sessions, &cookie, |s| assert_eq!(s.get_str("user"), Some("alice"))).await;
+        }
+        // ...but not the absolute one
+        advance(121);
+        request(&sessions, &cookie, |s| assert!(s.is_empty())).await;
+        assert!(sessions.store().is_empty());
+
+        let set = request(&sessions, "", |s| s.insert("user", "bob")).await;
+        let cookie = set[0].split(';').next().unwrap().to_string();
+        advance(201);
+        request(&sessions, &cookie, |s| assert!(s.is_empty())).await;
+    }
+
+    #[async_std::test]
+    async fn test_regenerate_empty() {
+        let sessions = Sessions::new(MemoryStore::new(Duration::from_secs(60)));
+        let set = request(&sessions, "", |s| s.insert("user", "alice")).await;
+        let cookie = set[0].split(';').next().unwrap().to_string();
+        let set = request(&sessions, &cookie, |s| {
+            s.remove("user");
+            s.regenerate_id();
+        }).await;
+        assert_eq!(set.len(), 1);
+        assert!(set[0].starts_with("session=;"), "{}", set[0]);
+        assert!(sessions.store().is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_middleware() {
+        use crate::router::Router;
+
+        let router = Router::new()
+            .wrap(Sessions::new(MemoryStore::new(Duration::from_secs(60))).secure(false).middleware())
+            .route("GET", "/", |cx: &Context| Box::pin(async move {
+                let visits = cx.extensions.with(|session: &mut Session| {
+                    let visits = session.get::<u32>("visits").unwrap_or(0) + 1;
+                    session.insert("visits", visits);
+                    visits
+                });
+                Ok(visits.unwrap().to_string())
+            }));
+        let (response, body) = router.handle(&Request::builder().request(), b"").await;
+        assert_eq!(body, b"1");
+        let set_cookie = String::from_utf8(response.headers.iter()
+            .find(|(name, _)| name == "Set-Cookie").unwrap().1.clone()).unwrap();
+        assert!(!set_cookie.contains("Secure"), "{}", set_cookie);
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let builder = Request::builder().header("Cookie", &*cookie);
+        let (response, body) = router.handle(&builder.request(), b"").await;
+        assert_eq!(body, b"2");
+        assert!(response.headers.iter().all(|(name, _)| name != "Set-Cookie"));
+    }
+}
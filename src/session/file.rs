@@ -0,0 +1,248 @@
+//! A store keeping each session in its own file, so sessions survive restarts.
+//!
+//! Files are read and written with blocking std::fs calls, right on the task calling load,
+//! save or destroy, so each one holds up that executor thread for a small read or write;
+//! sweeping reads the whole directory, so spawn sweep_every where blocking is allowed
+//! (async_std::task::spawn_blocking, or a thread of its own). On a busy server a store
+//! backed by a database, or by the runtime's async file APIs, may be the better fit.
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, Future};
+
+use super::{SessionData, SessionStore};
+use crate::encoding::{form_urldecode, form_urlencode};
+
+// prefixes telling the kinds of pairs in a file apart
+const VALUE: &str = "v.";
+const FLASH: &str = "f.";
+const CREATED: &str = "created";
+const ACCESSED: &str = "accessed";
+// ends the names of files being written; never a valid id, so never loaded
+const TMP: &str = ".tmp";
+
+/// With a ttl, sessions expire once they haven't been saved for that long; expired sessions
+/// are never loaded, but their files are only removed when swept; see sweep_every.
+#[derive(Clone)]
+pub struct FileStore {
+    dir: Arc<Path>,
+    ttl: Option<Duration>,
+}
+
+impl FileStore {
+    /// Keeps sessions in `dir`, creating it if it doesn't exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore{
+            dir: dir.as_ref().into(),
+            ttl: None,
+        })
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Removes the files of expired sessions, and temp files left by saves that never
+    /// finished; without a ttl nothing expires. Blocks while it reads the directory.
+    pub fn sweep(&self) -> io::Result<()> {
+        match self.ttl {
+            Some(ttl) => sweep(&self.dir, ttl),
+            None => Ok(()),
+        }
+    }
+
+    /// A future that sweeps every `interval`, for the caller to spawn; it finishes once every
+    /// clone of the store has been dropped. Errors reading the directory are tried again on
+    /// the next sweep.
+    pub fn sweep_every(&self, interval: Duration) -> impl Future<Output = ()> {
+        let dir: Weak<Path> = Arc::downgrade(&self.dir);
+        let ttl = self.ttl;
+        async move {
+            loop {
+                futures_timer::Delay::new(interval).await;
+                match (dir.upgrade(), ttl) {
+                    (Some(dir), Some(ttl)) => {
+                        if let Err(e) = sweep(&dir, ttl) {
+                            log::warn!("Failed to sweep sessions in {}: {}", dir.display(), e);
+                        }
+                    },
+                    (Some(_), None) => (),
+                    (None, _) => return,
+                }
+            }
+        }
+    }
+
+    /// None for ids that aren't safe file names; they come from the client, and the ids
+    /// Sessions makes are only ever url-safe base64.
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        safe_id(id).then(|| self.dir.join(id))
+    }
+}
+
+fn safe_id(id: &str) -> bool {
+    !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// whether a file last written at `modified` has outlived the ttl
+fn expired(modified: SystemTime, ttl: Duration) -> bool {
+    modified.elapsed().unwrap_or_default() > ttl
+}
+
+fn sweep(dir: &Path, ttl: Duration) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let ours = name.to_str().is_some_and(|name| safe_id(name) || name.ends_with(TMP));
+        if !ours {
+            continue;
+        }
+        // another sweep or a destroy may get there first
+        let stale = entry.metadata().and_then(|meta| meta.modified()).is_ok_and(|modified| expired(modified, ttl));
+        if stale {
+            match fs::remove_file(entry.path()) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+impl SessionStore for FileStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<Option<SessionData>>> {
+        Box::pin(async move {
+            let path = match self.path(id) {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+            if let Some(ttl) = self.ttl {
+                match fs::metadata(&path).and_then(|meta| meta.modified()) {
+                    Ok(modified) if expired(modified, ttl) => return Ok(None),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                    _ => (),
+                }
+            }
+            let contents = match fs::read(path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut data = SessionData::default();
+            for (key, value) in form_urldecode(&contents) {
+                if let Some(key) = key.strip_prefix(VALUE) {
+                    data.values.insert(key.into(), value);
+                } else if let Some(key) = key.strip_prefix(FLASH) {
+                    data.flash.insert(key.into(), value);
+                } else if key == CREATED {
+                    data.created = parse_time(&value);
+                } else if key == ACCESSED {
+                    data.accessed = parse_time(&value);
+                }
+            }
+            Ok(Some(data))
+        })
+    }
+
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = self.path(id)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bad session id"))?;
+            let mut pairs = vec!();
+            for (key, value) in &data.values {
+                pairs.push((format!("{}{}", VALUE, key), value.clone()));
+            }
+            for (key, value) in &data.flash {
+                pairs.push((format!("{}{}", FLASH, key), value.clone()));
+            }
+            for (key, time) in &[(CREATED, data.created), (ACCESSED, data.accessed)] {
+                if let Some(time) = time {
+                    let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+                    pairs.push((key.to_string(), millis.to_string()));
+                }
+            }
+            let contents = form_urlencode(pairs);
+            // write then rename, so a crash never leaves half a session behind; each save gets
+            // its own temp file, so saves racing on one id can't interleave their writes
+            let tmp = self.dir.join(format!("{}.{:016x}{}", id, rand::random::<u64>(), TMP));
+            fs::write(&tmp, contents)?;
+            fs::rename(&tmp, path).inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+        })
+    }
+
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let path = match self.path(id) {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// times are kept as milliseconds since the epoch
+fn parse_time(millis: &str) -> Option<SystemTime> {
+    Some(UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("oc-http-sessions-{}", rand::random::<u32>()));
+        let store = FileStore::new(&dir).unwrap();
+        let mut data = SessionData::default();
+        data.values.insert("user".into(), "alice & bob = friends".into());
+        data.values.insert("visits".into(), "3".into());
+        data.flash.insert("visits".into(), "flash".into());
+        data.created = Some(UNIX_EPOCH + Duration::from_millis(1_600_000_000_123));
+        data.accessed = Some(UNIX_EPOCH + Duration::from_millis(1_600_000_100_000));
+        store.save("abc-_1", &data).await.unwrap();
+        assert_eq!(store.load("abc-_1").await.unwrap(), Some(data.clone()));
+        // survives the store going away
+        let store = FileStore::new(&dir).unwrap();
+        assert_eq!(store.load("abc-_1").await.unwrap(), Some(data.clone()));
+
+        assert_eq!(store.load("../abc-_1").await.unwrap(), None);
+        assert!(store.save("../escape", &data).await.is_err());
+        store.destroy("abc-_1").await.unwrap();
+        store.destroy("abc-_1").await.unwrap();
+        assert_eq!(store.load("abc-_1").await.unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_sweep() {
+        let dir = std::env::temp_dir().join(format!("oc-http-sessions-{}", rand::random::<u32>()));
+        let store = FileStore::new(&dir).unwrap().ttl(Duration::from_millis(50));
+        store.save("a", &SessionData::default()).await.unwrap();
+        fs::write(dir.join("b.0123.tmp"), "").unwrap();
+        fs::write(dir.join("not.a.session"), "").unwrap();
+        assert!(store.load("a").await.unwrap().is_some());
+        let sweeper = async_std::task::spawn(store.sweep_every(Duration::from_millis(20)));
+        async_std::task::sleep(Duration::from_millis(150)).await;
+        assert_eq!(store.load("a").await.unwrap(), None);
+        let mut left: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        left.sort();
+        assert_eq!(left, vec!("not.a.session"));
+        // the sweeper stops along with the store
+        drop(store);
+        async_std::future::timeout(Duration::from_secs(1), sweeper).await.unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
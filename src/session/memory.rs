@@ -0,0 +1,111 @@
+//! A store keeping sessions in memory; they're lost on restart and not shared between
+//! processes, but there's nothing to set up.
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+
+use super::{SessionData, SessionStore};
+
+type Entries = Mutex<HashMap<String, (SessionData, Instant)>>;
+
+/// Sessions expire once they haven't been saved for the ttl. Expired sessions are never
+/// loaded, but they only free their memory when swept; see sweep_every.
+#[derive(Clone)]
+pub struct MemoryStore {
+    entries: Arc<Entries>,
+    ttl: Duration,
+}
+
+impl MemoryStore {
+    pub fn new(ttl: Duration) -> Self {
+        MemoryStore{
+            entries: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Removes every expired session.
+    pub fn sweep(&self) {
+        sweep(&self.entries);
+    }
+
+    /// A future that sweeps every `interval`, for the caller to spawn; it finishes once every
+    /// clone of the store has been dropped.
+    pub fn sweep_every(&self, interval: Duration) -> impl Future<Output = ()> {
+        let entries: Weak<Entries> = Arc::downgrade(&self.entries);
+        async move {
+            loop {
+                futures_timer::Delay::new(interval).await;
+                match entries.upgrade() {
+                    Some(entries) => sweep(&entries),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn sweep(entries: &Entries) {
+    let now = Instant::now();
+    entries.lock().unwrap().retain(|_, (_, expires)| *expires > now);
+}
+
+impl SessionStore for MemoryStore {
+    fn load<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<Option<SessionData>>> {
+        let entries = self.entries.lock().unwrap();
+        let data = entries.get(id)
+            .filter(|(_, expires)| *expires > Instant::now())
+            .map(|(data, _)| data.clone());
+        Box::pin(async move { Ok(data) })
+    }
+
+    fn save<'a>(&'a self, id: &'a str, data: &'a SessionData) -> BoxFuture<'a, io::Result<()>> {
+        let expires = Instant::now() + self.ttl;
+        self.entries.lock().unwrap().insert(id.into(), (data.clone(), expires));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn destroy<'a>(&'a self, id: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        self.entries.lock().unwrap().remove(id);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new(Duration::from_millis(50));
+        let mut data = SessionData::default();
+        data.values.insert("user".into(), "alice".into());
+        store.save("a", &data).await.unwrap();
+        store.save("b", &data).await.unwrap();
+        assert_eq!(store.load("a").await.unwrap(), Some(data.clone()));
+        store.destroy("b").await.unwrap();
+        assert_eq!(store.load("b").await.unwrap(), None);
+
+        let sweeper = async_std::task::spawn(store.sweep_every(Duration::from_millis(20)));
+        async_std::task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(store.load("a").await.unwrap(), None);
+        assert!(store.is_empty());
+        // the sweeper stops along with the store
+        drop(store);
+        async_std::future::timeout(Duration::from_secs(1), sweeper).await.unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+//! Options for accepted TCP sockets: Nagle's algorithm, keepalives, buffer sizes and TTL;
+//! and listeners sharing a port with SO_REUSEPORT.
+//!
+//! Sockets are the caller's, so apply the options to each one as it's accepted. Websockets
+//! sending small messages want `nodelay`, or each one can sit around for up to 40ms:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use async_std::net::TcpListener;
+//! # use futures::prelude::*;
+//! # use oc_http::socket::{Keepalive, SocketOptions};
+//! # async fn serve(listener: TcpListener) -> std::io::Result<()> {
+//! let options = SocketOptions::new()
+//!     .nodelay(true)
+//!     .keepalive(Keepalive::new(Duration::from_secs(60)));
+//! while let Some(stream) = listener.incoming().next().await {
+//!     let stream = stream?;
+//!     options.apply(&stream)?;
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Under very high connection rates, one accept loop becomes the bottleneck. Give each worker
+//! its own listener on the same port instead, and the kernel spreads connections across them:
+//!
+//! ```no_run
+//! # use async_std::task;
+//! # use oc_http::socket::bind_workers;
+//! # async fn accept_loop(listener: async_std::net::TcpListener) {}
+//! # fn serve(num_cpus: usize) -> Result<(), Box<dyn std::error::Error>> {
+//! for listener in bind_workers("0.0.0.0:8080".parse()?, num_cpus)? {
+//!     let listener = async_std::net::TcpListener::from(listener);
+//!     task::spawn(accept_loop(listener));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To serve on more than one address, bind them all and merge their incoming connections
+//! into one accept loop; the handlers and their state are shared, and which address a
+//! connection came in on is its local address. Plain http on :80 often only redirects:
+//!
+//! ```no_run
+//! # use futures::{prelude::*, stream};
+//! # use oc_http::{host, router::{Context, Router}, socket::bind_all};
+//! # async fn serve() -> Result<(), Box<dyn std::error::Error>> {
+//! let listeners: Vec<_> = bind_all(&["[::]:443".parse()?, "0.0.0.0:443".parse()?])?
+//!     .into_iter().map(async_std::net::TcpListener::from).collect();
+//! let mut incoming = stream::select_all(listeners.iter().map(|l| l.incoming()));
+//! while let Some(stream) = incoming.next().await { /* tls, then router */ }
+//!
+//! let redirect = Router::new()
+//!     .not_found(|cx: &Context| Box::pin(async move { Ok(host::redirect_to_https(cx.request, None)) }));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Listeners can also come from systemd socket activation, or from the process being
+//! replaced. For a restart without refusing connections, the old process hands its
+//! listeners to the new one, and stops accepting while the new one takes over. The kernel
+//! keeps queueing connections on the shared socket the whole time:
+//!
+//! ```no_run
+//! # use std::{env, net::TcpListener, process::Command};
+//! # use oc_http::socket::{listen_fds, spawn_successor};
+//! # struct Event; impl Event { fn notify(&self) {} }
+//! # fn serve(stop_accepting: Event) -> std::io::Result<()> {
+//! // at startup: inherited listeners if there are any, otherwise bind
+//! let listeners = match listen_fds()? {
+//!     inherited if !inherited.is_empty() => inherited.into_iter().map(|(_, l)| l).collect(),
+//!     _ => vec!(TcpListener::bind("0.0.0.0:8080")?),
+//! };
+//! # let listener = listeners.into_iter().next().unwrap();
+//!
+//! // on SIGHUP: start the new binary on the same listeners, then stop accepting and drain
+//! spawn_successor(&mut Command::new(env::current_exe()?), &[("http", &listener)])?;
+//! stop_accepting.notify();
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    env,
+    io,
+    mem,
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    process::{self, Child, Command},
+    time::Duration,
+};
+
+/// connections waiting to be accepted, per listener
+const BACKLOG: libc::c_int = 1024;
+
+/// the first descriptor passed with LISTEN_FDS, as systemd has it
+const LISTEN_FDS_START: RawFd = 3;
+
+/// TCP keepalive probes, for noticing peers that went away without closing the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long the connection has to be idle before the first probe.
+    pub idle: Duration,
+    /// Time between probes; only set on Linux, elsewhere the system default is used.
+    pub interval: Option<Duration>,
+    /// Probes without an answer before the connection is dropped; only set on Linux.
+    pub retries: Option<u32>,
+}
+
+impl Keepalive {
+    pub fn new(idle: Duration) -> Self {
+        Keepalive{idle, interval: None, retries: None}
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+}
+
+/// Options to set on each accepted socket; anything left unset keeps the system default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub nodelay: Option<bool>,
+    pub keepalive: Option<Keepalive>,
+    /// SO_RCVBUF, in bytes.
+    pub recv_buffer: Option<usize>,
+    /// SO_SNDBUF, in bytes.
+    pub send_buffer: Option<usize>,
+    pub ttl: Option<u32>,
+}
+
+impl SocketOptions {
+    pub fn new() -> Self {
+        SocketOptions::default()
+    }
+
+    /// Turns Nagle's algorithm off (or back on), with TCP_NODELAY.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    pub fn recv_buffer(mut self, bytes: usize) -> Self {
+        self.recv_buffer = Some(bytes);
+        self
+    }
+
+    pub fn send_buffer(mut self, bytes: usize) -> Self {
+        self.send_buffer = Some(bytes);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Sets the options on the socket, stopping at the first the system refuses.
+    pub fn apply<S: AsRawFd>(&self, socket: &S) -> io::Result<()> {
+        let fd = socket.as_raw_fd();
+        if let Some(nodelay) = self.nodelay {
+            set(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, nodelay as libc::c_int)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            set(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            set_keepalive(fd, &keepalive)?;
+        }
+        if let Some(bytes) = self.recv_buffer {
+            set(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, clamp(bytes as u64))?;
+        }
+        if let Some(bytes) = self.send_buffer {
+            set(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, clamp(bytes as u64))?;
+        }
+        if let Some(ttl) = self.ttl {
+            set(fd, libc::IPPROTO_IP, libc::IP_TTL, clamp(ttl as u64))?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds a listener with SO_REUSEPORT set, so others bound the same way can share the address.
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<TcpListener> {
+    bind(addr, true, false)
+}
+
+/// Binds a listener on each address, to be served together. IPv6 addresses only take IPv6
+/// connections, so `[::]` and `0.0.0.0` can share a port; list both to take both.
+pub fn bind_all(addrs: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    addrs.iter().map(|&addr| bind(addr, false, true)).collect()
+}
+
+fn bind(addr: SocketAddr, reuseport: bool, v6only: bool) -> io::Result<TcpListener> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = check(unsafe { libc::socket(family, libc::SOCK_STREAM, 0) })?;
+    // SAFETY: fd was just opened, and nothing else owns it; the listener closes it on errors
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    check(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    set(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if reuseport {
+        set(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+    }
+    if v6only && family == libc::AF_INET6 {
+        set(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, 1)?;
+    }
+    let (storage, len) = sockaddr(&addr);
+    // SAFETY: storage holds a sockaddr of the family, len long
+    check(unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) })?;
+    check(unsafe { libc::listen(fd, BACKLOG) })?;
+    Ok(listener)
+}
+
+/// Binds `count` listeners sharing the address, one for each worker. Port 0 picks one port
+/// for all of them.
+pub fn bind_workers(addr: SocketAddr, count: usize) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = vec!();
+    let mut addr = addr;
+    for _ in 0..count {
+        let listener = bind_reuseport(addr)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// The listeners passed to the process with LISTEN_FDS, by systemd socket activation or
+/// spawn_successor, with their names from LISTEN_FDNAMES (`unknown` where there isn't one).
+/// Empty if there aren't any, or they're for another process: if LISTEN_PID is set, it has
+/// to be this one. The variables are removed, so child processes don't take the listeners
+/// for theirs.
+pub fn listen_fds() -> io::Result<Vec<(String, TcpListener)>> {
+    let var = |name| env::var(name).ok();
+    let passed = passed_fds(var("LISTEN_PID"), var("LISTEN_FDS"), var("LISTEN_FDNAMES"), process::id())?;
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    passed.into_iter()
+        // SAFETY: whoever set LISTEN_FDS passed these to this process, for it to own
+        .map(|(name, fd)| unsafe { listener_from_raw_fd(fd) }.map(|listener| (name, listener)))
+        .collect()
+}
+
+fn passed_fds(pid: Option<String>, fds: Option<String>, names: Option<String>, me: u32) -> io::Result<Vec<(String, RawFd)>> {
+    let count = match fds {
+        Some(count) => count,
+        None => return Ok(vec!()),
+    };
+    if pid.is_some_and(|pid| pid.trim().parse() != Ok(me)) {
+        return Ok(vec!());
+    }
+    let count: RawFd = count.trim().parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad LISTEN_FDS: {}", count)))?;
+    let names = names.unwrap_or_default();
+    let mut names = names.split(':').filter(|n| !n.is_empty());
+    Ok((0..count)
+        .map(|i| (names.next().unwrap_or("unknown").to_string(), LISTEN_FDS_START + i))
+        .collect())
+}
+
+/// Takes a listening TCP socket the process already has open, like one passed on the
+/// command line; fails with InvalidInput if it isn't one. It's set close-on-exec.
+///
+/// # Safety
+///
+/// Nothing else may own the descriptor; the listener closes it when dropped.
+pub unsafe fn listener_from_raw_fd(fd: RawFd) -> io::Result<TcpListener> {
+    let stream = get(fd, libc::SOL_SOCKET, libc::SO_TYPE)? == libc::SOCK_STREAM;
+    if !stream || get(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("descriptor {} isn't a listening socket", fd)));
+    }
+    check(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+    Ok(TcpListener::from_raw_fd(fd))
+}
+
+/// Starts the command with the listeners passed as LISTEN_FDS and LISTEN_FDNAMES, for it
+/// to pick up with listen_fds; this process keeps its copies, to stop accepting on once the
+/// new one is up. LISTEN_PID isn't set, since the pid isn't known until it's running.
+/// Names can't have `:` in them.
+pub fn spawn_successor(command: &mut Command, listeners: &[(&str, &TcpListener)]) -> io::Result<Child> {
+    let mut fds: Vec<RawFd> = listeners.iter().map(|(_, listener)| listener.as_raw_fd()).collect();
+    let names: Vec<&str> = listeners.iter().map(|(name, _)| *name).collect();
+    if names.iter().any(|name| name.contains(':')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "listener names can't have : in them"));
+    }
+    command.env("LISTEN_FDS", fds.len().to_string())
+        .env("LISTEN_FDNAMES", names.join(":"))
+        .env_remove("LISTEN_PID");
+    let above = LISTEN_FDS_START + fds.len() as RawFd;
+    // SAFETY: only async-signal-safe calls, and no allocating, between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // out of the way first, since the listeners might hold each other's places
+            for fd in fds.iter_mut() {
+                *fd = check(libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above))?;
+            }
+            // dup2 clears close-on-exec on the copies
+            for (i, fd) in fds.iter().enumerate() {
+                check(libc::dup2(*fd, LISTEN_FDS_START + i as RawFd))?;
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all zeroes is a valid sockaddr_storage, and it's big enough for either family
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr{s_addr: u32::from_ne_bytes(addr.ip().octets())};
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr = libc::in6_addr{s6_addr: addr.ip().octets()};
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        },
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_keepalive(fd: RawFd, keepalive: &Keepalive) -> io::Result<()> {
+    set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, clamp(keepalive.idle.as_secs()))?;
+    if let Some(interval) = keepalive.interval {
+        set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, clamp(interval.as_secs()))?;
+    }
+    if let Some(retries) = keepalive.retries {
+        set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, clamp(retries as u64))?;
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn set_keepalive(fd: RawFd, keepalive: &Keepalive) -> io::Result<()> {
+    set(fd, libc::IPPROTO_TCP, libc::TCP_KEEPALIVE, clamp(keepalive.idle.as_secs()))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios")))]
+fn set_keepalive(_fd: RawFd, _keepalive: &Keepalive) -> io::Result<()> {
+    Ok(())
+}
+
+fn clamp(value: u64) -> libc::c_int {
+    value.min(libc::c_int::MAX as u64) as libc::c_int
+}
+
+fn set(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    // SAFETY: value outlives the call, and the length passed is its size
+    check(unsafe {
+        libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t)
+    })?;
+    Ok(())
+}
+
+fn get(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value and len are valid for writes, and len is value's size
+    check(unsafe {
+        libc::getsockopt(fd, level, name, &mut value as *mut _ as *mut libc::c_void, &mut len)
+    })?;
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        SocketOptions::new()
+            .nodelay(true)
+            .keepalive(Keepalive::new(Duration::from_secs(30)).interval(Duration::from_secs(5)).retries(3))
+            .recv_buffer(64 * 1024)
+            .ttl(32)
+            .apply(&stream)
+            .unwrap();
+        let fd = stream.as_raw_fd();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.ttl().unwrap(), 32);
+        assert_ne!(get(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE).unwrap(), 0);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(get(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE).unwrap(), 30);
+            assert_eq!(get(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT).unwrap(), 3);
+        }
+        // the system may round buffer sizes, but not below what was asked for
+        assert!(get(fd, libc::SOL_SOCKET, libc::SO_RCVBUF).unwrap() >= 64 * 1024);
+        // untouched options are left alone
+        assert!(!client.nodelay().unwrap());
+    }
+
+    #[test]
+    fn test_bind_workers() {
+        let listeners = bind_workers("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(listeners[1].local_addr().unwrap(), addr);
+        // one of them gets each connection
+        for listener in &listeners {
+            listener.set_nonblocking(true).unwrap();
+        }
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"hi").unwrap();
+        let accepted = (0..100).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            listeners.iter().find_map(|l| l.accept().ok())
+        });
+        assert!(accepted.is_some());
+
+        let v6 = bind_workers("[::1]:0".parse().unwrap(), 2);
+        // not every sandbox has IPv6
+        if let Ok(v6) = v6 {
+            assert_eq!(v6[0].local_addr().unwrap(), v6[1].local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_bind_all() {
+        let v6 = TcpListener::bind("[::1]:0").is_ok();
+        let listeners = bind_all(&["127.0.0.1:0".parse().unwrap(), "127.0.0.1:0".parse().unwrap()]).unwrap();
+        assert_ne!(listeners[0].local_addr().unwrap(), listeners[1].local_addr().unwrap());
+        for listener in &listeners {
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            listener.accept().unwrap();
+        }
+        if v6 {
+            // the same port on both families
+            let any = bind_all(&["[::]:0".parse().unwrap()]).unwrap().remove(0);
+            let port = any.local_addr().unwrap().port();
+            assert_eq!(get(any.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY).unwrap(), 1);
+            bind_all(&[SocketAddr::from(([0, 0, 0, 0], port))]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_passed_fds() {
+        let passed = |pid: Option<&str>, fds: Option<&str>, names: Option<&str>| {
+            passed_fds(pid.map(String::from), fds.map(String::from), names.map(String::from), 42)
+        };
+        assert_eq!(passed(None, None, None).unwrap(), vec!());
+        assert_eq!(passed(Some("42"), Some("2"), Some("http:https")).unwrap(), vec!(("http".into(), 3), ("https".into(), 4)));
+        assert_eq!(passed(None, Some("2"), Some("http")).unwrap(), vec!(("http".into(), 3), ("unknown".into(), 4)));
+        // for some other process
+        assert_eq!(passed(Some("41"), Some("1"), None).unwrap(), vec!());
+        assert!(passed(None, Some("x"), None).is_err());
+    }
+
+    #[test]
+    fn test_listener_from_raw_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert_eq!(unsafe { listener_from_raw_fd(client.as_raw_fd()) }.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let addr = listener.local_addr().unwrap();
+        let fd = std::os::unix::io::IntoRawFd::into_raw_fd(listener);
+        let listener = unsafe { listener_from_raw_fd(fd) }.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_spawn_successor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut command = Command::new("sh");
+        command.arg("-c")
+            .arg(r#"echo "$LISTEN_FDS $LISTEN_FDNAMES"; [ -S /dev/fd/3 ] && echo socket"#)
+            .stdout(process::Stdio::piped());
+        let child = spawn_successor(&mut command, &[("http", &listener)]).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 http\nsocket\n");
+        assert!(spawn_successor(&mut Command::new("true"), &[("a:b", &listener)]).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+//! Live numbers about the server's connections, for ops dashboards: how many are open, how
+//! many are kept alive waiting for their next request, open websockets, how fast
+//! connections are being accepted, and bytes in and out.
+//!
+//! Track each connection as it's accepted, and mark it idle while it waits between
+//! requests; give the same stats to UpgradeConfig::metrics to count websockets:
+//!
+//! ```no_run
+//! # use std::{sync::Arc, time::Duration};
+//! # use async_std::{net::TcpListener, task};
+//! # use futures::prelude::*;
+//! # use log::info;
+//! # use oc_http::{stats::ConnectionStats, websocket::UpgradeConfig};
+//! # async fn serve(listener: TcpListener) -> std::io::Result<()> {
+//! # let mut incoming = listener.incoming();
+//! let stats = ConnectionStats::new();
+//! task::spawn(stats.report_every(Duration::from_secs(10), |s| info!("{:?}", s)));
+//! let upgrades = UpgradeConfig::new().metrics(Arc::new(stats.clone()));
+//! while let Some(stream) = incoming.next().await {
+//!     let mut stream = stats.track(stream?);
+//!     // between requests
+//!     stream.set_idle(true);
+//! }
+//! // for a /stats route
+//! let snapshot = stats.stats();
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{AsyncRead, AsyncWrite};
+
+use crate::websocket::metrics::Metrics;
+
+/// A snapshot; see ConnectionStats::stats.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub open_connections: u64,
+    /// Open connections waiting for their next request.
+    pub keep_alive_connections: u64,
+    pub websockets: u64,
+    /// Connections accepted in all.
+    pub accepted: u64,
+    /// Since the stats were made, or for report_every, since the last report.
+    pub accepted_per_sec: f64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    open: AtomicI64,
+    idle: AtomicI64,
+    websockets: AtomicI64,
+    accepted: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+/// Counts for every connection tracked with it; clones share the counts.
+#[derive(Clone)]
+pub struct ConnectionStats {
+    counters: Arc<Counters>,
+    started: Instant,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        ConnectionStats{counters: Arc::default(), started: Instant::now()}
+    }
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        ConnectionStats::default()
+    }
+
+    /// Counts the stream as accepted and open until it's dropped, and what's read from and
+    /// written to it.
+    pub fn track<S>(&self, stream: S) -> Tracked<S> {
+        self.counters.accepted.fetch_add(1, Ordering::Relaxed);
+        self.counters.open.fetch_add(1, Ordering::Relaxed);
+        Tracked{stream, counters: self.counters.clone(), idle: false}
+    }
+
+    pub fn stats(&self) -> Stats {
+        snapshot(&self.counters, self.started.elapsed(), 0)
+    }
+
+    /// A future that calls `report` with a snapshot every `interval`, for the caller to
+    /// spawn; it finishes once every clone of the stats, and every stream tracked with them,
+    /// has been dropped.
+    pub fn report_every<F>(&self, interval: Duration, mut report: F) -> impl Future<Output = ()>
+    where F: FnMut(&Stats)
+    {
+        let counters: Weak<Counters> = Arc::downgrade(&self.counters);
+        async move {
+            let mut last = Instant::now();
+            let mut accepted = counters.upgrade().map_or(0, |c| c.accepted.load(Ordering::Relaxed));
+            loop {
+                futures_timer::Delay::new(interval).await;
+                let counters = match counters.upgrade() {
+                    Some(counters) => counters,
+                    None => return,
+                };
+                let stats = snapshot(&counters, last.elapsed(), accepted);
+                last = Instant::now();
+                accepted = stats.accepted;
+                report(&stats);
+            }
+        }
+    }
+}
+
+/// accepted/sec from the count `before`, over `elapsed`
+fn snapshot(counters: &Counters, elapsed: Duration, before: u64) -> Stats {
+    let gauge = |value: &AtomicI64| value.load(Ordering::Relaxed).max(0) as u64;
+    let accepted = counters.accepted.load(Ordering::Relaxed);
+    let secs = elapsed.as_secs_f64();
+    Stats{
+        open_connections: gauge(&counters.open),
+        keep_alive_connections: gauge(&counters.idle),
+        websockets: gauge(&counters.websockets),
+        accepted,
+        accepted_per_sec: if secs > 0.0 { (accepted - before) as f64 / secs } else { 0.0 },
+        bytes_in: counters.bytes_in.load(Ordering::Relaxed),
+        bytes_out: counters.bytes_out.load(Ordering::Relaxed),
+    }
+}
+
+/// Only counts websockets opening and closing; their bytes are the connection's, and
+/// already counted if it's tracked.
+impl Metrics for ConnectionStats {
+    fn connection_opened(&self) {
+        self.counters.websockets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.counters.websockets.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A stream being counted; see ConnectionStats::track.
+pub struct Tracked<S> {
+    stream: S,
+    counters: Arc<Counters>,
+    idle: bool,
+}
+
+impl<S> Tracked<S> {
+    /// Marks the connection as kept alive waiting for its next request, or as handling one
+    /// again.
+    pub fn set_idle(&mut self, idle: bool) {
+        if idle != self.idle {
+            self.idle = idle;
+            self.counters.idle.fetch_add(if idle { 1 } else { -1 }, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S> Drop for Tracked<S> {
+    fn drop(&mut self) {
+        self.set_idle(false);
+        self.counters.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            self.counters.bytes_in.fetch_add(count as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            self.counters.bytes_out.fetch_add(count as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+
+    use super::*;
+    use crate::testing::duplex;
+
+    #[async_std::test]
+    async fn test_stats() {
+        let stats = ConnectionStats::new();
+        let (server, mut client) = duplex();
+        let mut server = stats.track(server);
+        let other = stats.track(duplex().0);
+        client.write_all(b"GET").await.unwrap();
+        let mut buf = [0; 3];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"HTTP/1.1").await.unwrap();
+        server.set_idle(true);
+        server.set_idle(true);
+        stats.connection_opened();
+        let snapshot = stats.stats();
+        assert_eq!(
+            (snapshot.open_connections, snapshot.keep_alive_connections, snapshot.websockets, snapshot.accepted),
+            (2, 1, 1, 2),
+        );
+        assert_eq!((snapshot.bytes_in, snapshot.bytes_out), (3, 8));
+        assert!(snapshot.accepted_per_sec > 0.0);
+        drop(server);
+        drop(other);
+        let snapshot = stats.stats();
+        assert_eq!((snapshot.open_connections, snapshot.keep_alive_connections, snapshot.accepted), (0, 0, 2));
+    }
+
+    #[async_std::test]
+    async fn test_report_every() {
+        let stats = ConnectionStats::new();
+        let reports = Arc::new(std::sync::Mutex::new(vec!()));
+        let sink = reports.clone();
+        let reporting = async_std::task::spawn(stats.report_every(Duration::from_millis(10), move |s| {
+            sink.lock().unwrap().push(s.accepted)
+        }));
+        let stream = stats.track(duplex().0);
+        async_std::task::sleep(Duration::from_millis(35)).await;
+        drop(stats);
+        drop(stream);
+        reporting.await;
+        let reports = reports.lock().unwrap();
+        assert!(!reports.is_empty() && reports.iter().all(|&accepted| accepted == 1), "{:?}", reports);
+    }
+}
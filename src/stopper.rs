@@ -1,44 +1,101 @@
-use async_std::{
-    io,
-    net::{
-        TcpStream,
-    },
-    channel::{
-        Sender,
-        Receiver,
-        bounded,
-    },
+//! A shutdown signal for loops that should stop together: accept loops, websocket
+//! readers, sweepers. The Stopper fires it; any number of StopTokens wait for it.
+//!
+//! ```no_run
+//! # use oc_http::{stopper::Stopper, websocket::WebSocketReader};
+//! # async fn serve(mut rdr: WebSocketReader<async_std::net::TcpStream>) {
+//! let (stopper, token) = Stopper::new();
+//! // in each connection's task, with a clone of the token
+//! while let Ok(Some(msg)) = rdr.recv_until(token.clone()).await {
+//!     // ...
+//! }
+//! // and when it's time to go
+//! stopper.shutdown();
+//! # }
+//! ```
+use std::{
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
 };
 
+use futures::{
+    channel::oneshot,
+    future::{FutureExt, Shared},
+    Future,
+};
+
+/// Completes once the Stopper shuts down or is dropped; clones all wait for the same signal.
 #[derive(Clone)]
 pub struct StopToken {
-    done: Receiver<u8>,
+    done: Shared<oneshot::Receiver<()>>,
 }
 
 impl StopToken {
-    pub async fn wait(&self) -> Option<io::Result<TcpStream>> {
-        while let Ok(_) = self.done.recv().await {
-            // loop until we get an error about the sender being closed
+    /// Waits for the signal, then gives None; for racing against a stream's next(), so that the
+    /// loop ends with the stream.
+    pub fn wait<T>(&self) -> impl Future<Output = Option<T>> {
+        let token = self.clone();
+        async move {
+            token.await;
+            None
         }
-        None
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.done.peek().is_some()
+    }
+}
+
+impl Future for StopToken {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // cancelled (the Stopper was dropped) counts the same as shutting down
+        self.done.poll_unpin(cx).map(|_| ())
     }
 }
 
 pub struct Stopper {
-    done: Sender<u8>,
+    done: Mutex<Option<oneshot::Sender<()>>>,
 }
 
 impl Stopper {
     pub fn new() -> (Self, StopToken) {
-        let (s, r) = bounded(1);
+        let (s, r) = oneshot::channel();
         (Stopper{
-            done: s,
+            done: Mutex::new(Some(s)),
         }, StopToken{
-            done: r,
+            done: r.shared(),
         })
     }
 
+    /// Wakes every token; calling it again does nothing.
     pub fn shutdown(&self) {
-        self.done.close();
+        if let Some(done) = self.done.lock().unwrap().take() {
+            let _ = done.send(());
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_stopper() {
+        let (stopper, token) = Stopper::new();
+        let waiting = async_std::task::spawn(token.wait::<u8>());
+        assert!(!token.is_stopped());
+        stopper.shutdown();
+        stopper.shutdown();
+        assert_eq!(waiting.await, None);
+        token.clone().await;
+        assert!(token.is_stopped());
+
+        // so does dropping the stopper
+        let (stopper, token) = Stopper::new();
+        drop(stopper);
+        token.await;
+    }
+}
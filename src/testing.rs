@@ -0,0 +1,427 @@
+//! Helpers for testing handlers without binding TCP ports.
+//!
+//! duplex() gives the two ends of an in-memory connection. Hand one to the code under test,
+//! and play the client on the other:
+//!
+//! ```no_run
+//! # use async_std::task;
+//! # use futures::prelude::*;
+//! # use oc_http::testing::{self, Duplex};
+//! # async fn handle_request(stream: Duplex) {}
+//! # async fn serve() -> std::io::Result<()> {
+//! let (mut client, server) = testing::duplex();
+//! task::spawn(handle_request(server));
+//! client.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or let a TestClient do the talking, and check what comes back:
+//!
+//! ```no_run
+//! # use async_std::task;
+//! # use oc_http::testing::{Duplex, TestClient};
+//! # async fn handle_request(stream: Duplex) {}
+//! # async fn serve() -> std::io::Result<()> {
+//! let client = TestClient::new(|stream| handle_request(stream));
+//! let response = client.get("/").await?;
+//! assert_eq!(response.code, 200);
+//! assert_eq!(response.text(), Some("<h1>Hello world!</h1>"));
+//!
+//! // websockets need the handler kept running alongside
+//! let (server, socket) = client.websocket("/ws");
+//! task::spawn(server);
+//! let (mut rdr, mut wrt) = socket.await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures::{prelude::*, AsyncRead, AsyncWrite};
+
+use crate::{
+    websocket::{self, WebSocketReader, WebSocketWriter},
+    Request,
+};
+
+// most headers a response may have
+const MAX_HEADERS: usize = 64;
+
+type HeaderList = Vec<(String, Vec<u8>)>;
+/// the client's side of a websocket
+pub type TestSocket = (WebSocketReader<Duplex>, WebSocketWriter<Duplex>);
+
+/// bytes going one way
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    // the writing end is gone; reads return EOF once buf is empty
+    closed: bool,
+    // the reading end is gone; writes fail
+    abandoned: bool,
+    reader: Option<Waker>,
+}
+
+impl Pipe {
+    fn wake(&mut self) {
+        if let Some(waker) = self.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct End {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        let mut write = self.write.lock().unwrap();
+        write.closed = true;
+        write.wake();
+        self.read.lock().unwrap().abandoned = true;
+    }
+}
+
+/// One end of an in-memory connection. Clones share the end, like clones of a TcpStream.
+#[derive(Clone)]
+pub struct Duplex {
+    end: Arc<End>,
+}
+
+/// A connected pair; what's written to one is read from the other. Closing an end, or
+/// dropping every clone of it, is seen by the other as EOF.
+pub fn duplex() -> (Duplex, Duplex) {
+    let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+    let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+    let a = Duplex{end: Arc::new(End{read: b_to_a.clone(), write: a_to_b.clone()})};
+    let b = Duplex{end: Arc::new(End{read: a_to_b, write: b_to_a})};
+    (a, b)
+}
+
+impl AsyncRead for Duplex {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.end.read.lock().unwrap();
+        if pipe.buf.is_empty() && !buf.is_empty() {
+            if pipe.closed {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let count = buf.len().min(pipe.buf.len());
+        for (to, from) in buf.iter_mut().zip(pipe.buf.drain(..count)) {
+            *to = from;
+        }
+        Poll::Ready(Ok(count))
+    }
+}
+
+impl AsyncWrite for Duplex {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.end.write.lock().unwrap();
+        if pipe.closed || pipe.abandoned {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        pipe.buf.extend(buf);
+        pipe.wake();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Closes this direction for every clone; the other end still writes back until it's
+    /// closed too.
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut pipe = self.end.write.lock().unwrap();
+        pipe.closed = true;
+        pipe.wake();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A response, as the client saw it. With the serde feature it serializes and deserializes,
+/// like RequestBuilder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TestResponse {
+    pub code: u16,
+    pub reason: String,
+    #[cfg_attr(feature = "serde", serde(with = "crate::bytes_repr::list"))]
+    pub headers: HeaderList,
+    /// With any chunked encoding taken off.
+    #[cfg_attr(feature = "serde", serde(with = "crate::bytes_repr"))]
+    pub body: Vec<u8>,
+    /// Headers after a chunked body.
+    #[cfg_attr(feature = "serde", serde(with = "crate::bytes_repr::list"))]
+    pub trailers: HeaderList,
+}
+
+impl TestResponse {
+    /// The first value of the header, whatever case its name was sent in.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| &v[..])
+    }
+
+    /// The body, if it's utf-8.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(&self.body).ok()
+    }
+
+    /// Parses the response to a request, skipping interim 1xx responses other than 101. HEAD
+    /// responses have no body, and neither do ones where `head` is true.
+    pub fn parse(raw: &[u8], head: bool) -> io::Result<Self> {
+        let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidData, why.to_string());
+        let mut raw = raw;
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            let mut parsed = httparse::Response::new(&mut headers);
+            let len = match parsed.parse(raw).map_err(|e| invalid(&e.to_string()))? {
+                httparse::Status::Complete(len) => len,
+                httparse::Status::Partial => return Err(invalid("incomplete response head")),
+            };
+            let mut response = TestResponse{
+                code: parsed.code.unwrap_or(0),
+                reason: parsed.reason.unwrap_or("").into(),
+                headers: parsed.headers.iter().map(|h| (h.name.to_string(), h.value.to_vec())).collect(),
+                body: vec!(),
+                trailers: vec!(),
+            };
+            raw = &raw[len..];
+            if (100..200).contains(&response.code) && response.code != 101 {
+                continue;
+            }
+            if head || response.code == 101 || response.code == 204 || response.code == 304 {
+                return Ok(response);
+            }
+            let chunked = response.header("Transfer-Encoding")
+                .is_some_and(|te| String::from_utf8_lossy(te).to_ascii_lowercase().trim_end().ends_with("chunked"));
+            let length = response.header("Content-Length")
+                .map(|cl| String::from_utf8_lossy(cl).trim().parse::<usize>().map_err(|_| invalid("bad Content-Length")))
+                .transpose()?;
+            if chunked {
+                let (body, trailers) = dechunk(raw).ok_or_else(|| invalid("bad chunked body"))?;
+                response.body = body;
+                response.trailers = trailers;
+            } else if let Some(length) = length {
+                response.body = raw.get(..length).ok_or_else(|| invalid("body shorter than Content-Length"))?.into();
+            } else {
+                response.body = raw.into();
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// decodes a chunked body, returning it and the trailers
+fn dechunk(mut raw: &[u8]) -> Option<(Vec<u8>, HeaderList)> {
+    let mut body = vec!();
+    let mut line = || -> Option<&[u8]> {
+        let end = raw.windows(2).position(|w| w == b"\r\n")?;
+        let line = &raw[..end];
+        raw = &raw[end + 2..];
+        Some(line)
+    };
+    loop {
+        let size = std::str::from_utf8(line()?).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        if size == 0 {
+            break;
+        }
+        let chunk = line()?;
+        if chunk.len() != size {
+            return None;
+        }
+        body.extend_from_slice(chunk);
+    }
+    let mut trailers = vec!();
+    loop {
+        let trailer = line()?;
+        if trailer.is_empty() {
+            return Some((body, trailers));
+        }
+        let colon = trailer.iter().position(|b| *b == b':')?;
+        let value = String::from_utf8_lossy(&trailer[colon + 1..]).trim().as_bytes().to_vec();
+        trailers.push((String::from_utf8_lossy(&trailer[..colon]).into_owned(), value));
+    }
+}
+
+/// Sends requests to a handler over in-memory connections, one connection per request.
+pub struct TestClient<H> {
+    handler: H,
+}
+
+impl<H, Fut> TestClient<H>
+where
+    H: Fn(Duplex) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    /// The handler is given the server's end of each connection, and should return once
+    /// it's done with it.
+    pub fn new(handler: H) -> Self {
+        TestClient{handler}
+    }
+
+    /// Sends the request as it is, and reads the response until the handler's done. The
+    /// client's side is closed after the request, so handlers reading more see EOF.
+    pub async fn send(&self, request: &[u8]) -> io::Result<TestResponse> {
+        let (mut client, server) = duplex();
+        let mut raw = vec!();
+        let talk = async {
+            client.write_all(request).await?;
+            client.close().await?;
+            client.read_to_end(&mut raw).await
+        };
+        let (sent, ()) = futures::join!(talk, (self.handler)(server));
+        sent?;
+        TestResponse::parse(&raw, request.starts_with(b"HEAD "))
+    }
+
+    pub async fn get(&self, path: &str) -> io::Result<TestResponse> {
+        self.request("GET", path, &[], b"").await
+    }
+
+    /// Sends a request with the headers and body, adding Host.
+    pub async fn request(&self, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> io::Result<TestResponse> {
+        let request = headers.iter()
+            .fold(Request::builder().method(method).path(path).header("Host", "test"), |r, (k, v)| r.header(k, *v))
+            .body(body);
+        self.send(&request.to_bytes()).await
+    }
+
+    /// Opens a websocket to the path. Returns the handler's future, which has to keep
+    /// running for the socket to be answered (spawn it, or join it with the test), and the
+    /// client's reader and writer once the handshake is done; fails unless it's a 101.
+    pub fn websocket(&self, path: &str) -> (Fut, impl Future<Output = io::Result<TestSocket>>) {
+        let (mut client, server) = duplex();
+        let request = format!("GET {} HTTP/1.1\r\nHost: test\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", path);
+        let handshake = async move {
+            client.write_all(request.as_bytes()).await?;
+            // a byte at a time, so no frames are read along with the head
+            let mut head = vec!();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                if client.read(&mut byte).await? == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                head.push(byte[0]);
+            }
+            let response = TestResponse::parse(&head, true)?;
+            if response.code != 101 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("websocket handshake answered with {}", response.code)));
+            }
+            Ok(websocket::from_stream(client, websocket::Role::Client))
+        };
+        ((self.handler)(server), handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::task;
+    use futures::prelude::*;
+
+    use super::*;
+    use crate::{body::Body, http, respond, send_content, Response};
+
+    #[async_std::test]
+    async fn test_duplex() {
+        let (mut client, server) = duplex();
+        let handle = task::spawn(async move {
+            let mut reader = server.clone();
+            let mut writer = server;
+            let mut buf = vec![0; 1024];
+            let req = http(&mut reader, &mut buf).await.unwrap();
+            assert_eq!(req.path, "/hello");
+            respond(&mut writer, Response{
+                headers: vec!(("Content-Length".into(), Vec::from("2"))),
+                ..Response::default()
+            }).await.unwrap();
+            writer.write_all(b"hi").await.unwrap();
+        });
+        client.write_all(b"GET /hello HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        handle.await;
+        // the server's end is gone, so this stops at EOF
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+        assert_eq!(client.write(b"x").await.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[async_std::test]
+    async fn test_websocket() {
+        let (client, server) = duplex();
+        let (mut server_rdr, mut server_wrt) = websocket::from_stream(server, websocket::Role::Server);
+        let (mut client_rdr, mut client_wrt) = websocket::from_stream(client, websocket::Role::Client);
+        client_wrt.send_text("ping").await.unwrap();
+        assert_eq!(server_rdr.recv().await.unwrap().as_text(), Some("ping"));
+        server_wrt.send_text("pong").await.unwrap();
+        assert_eq!(client_rdr.recv().await.unwrap().as_text(), Some("pong"));
+    }
+
+    async fn echo(stream: Duplex) {
+        let mut reader = stream.clone();
+        let mut writer = stream;
+        let mut buf = vec![0; 1024];
+        let req = http(&mut reader, &mut buf).await.unwrap();
+        if req.path == "/ws" {
+            let (mut rdr, mut wrt) = websocket::upgrade(&req, writer, &[]).await.unwrap();
+            while let Ok(msg) = rdr.recv().await {
+                wrt.write(&msg).await.unwrap();
+            }
+            return;
+        }
+        let mut body = vec!();
+        Body::new(&req, &mut reader).unwrap().read_to_end(&mut body).await.unwrap();
+        respond(&mut writer, Response{
+            code: 100,
+            reason: "Continue",
+            headers: vec!(),
+        }).await.unwrap();
+        respond(&mut writer, Response{
+            headers: vec!(("Transfer-Encoding".into(), Vec::from("chunked"))),
+            ..Response::default()
+        }).await.unwrap();
+        let chunk = format!("{:x}\r\n{} {}\r\n", req.path.len() + 1 + body.len(), req.path, String::from_utf8_lossy(&body));
+        send_content(&mut writer, chunk.as_bytes()).await.unwrap();
+        send_content(&mut writer, b"0\r\nX-Done: yes\r\n\r\n").await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_client() {
+        let client = TestClient::new(echo);
+        let response = client.request("POST", "/things", &[("Content-Type", "text/plain")], b"hello").await.unwrap();
+        assert_eq!(response.code, 200);
+        assert_eq!(response.text(), Some("/things hello"));
+        assert_eq!(response.trailers, vec!(("X-Done".into(), Vec::from("yes"))));
+        assert_eq!(response.header("transfer-encoding"), Some(&b"chunked"[..]));
+
+        let head = TestResponse::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true).unwrap();
+        assert!(head.body.is_empty());
+        let fixed = TestResponse::parse(b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\nno", false).unwrap();
+        assert_eq!((fixed.code, fixed.text()), (404, Some("no")));
+        assert!(TestResponse::parse(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nno", false).is_err());
+
+        let (server, socket) = client.websocket("/ws");
+        let talk = async {
+            let (mut rdr, mut wrt) = socket.await.unwrap();
+            wrt.send_text("hi").await.unwrap();
+            let frame = rdr.read_frame().await.unwrap();
+            assert_eq!(&frame.payload[..], b"hi");
+            // dropping the client ends the echo loop
+        };
+        futures::join!(server, talk);
+    }
+}
@@ -0,0 +1,667 @@
+//! Serving over TLS with rustls, on any stream.
+//!
+//! The acceptor does the handshake, and the stream it returns reads and writes plaintext,
+//! so it goes wherever a plain connection would. What the handshake settled on is on
+//! TlsStream::info, or in the ConnectionInfo for handlers:
+//!
+//! ```no_run
+//! # use std::fs;
+//! # use oc_http::tls::TlsAcceptor;
+//! # async fn serve(listener: async_std::net::TcpListener) -> std::io::Result<()> {
+//! let acceptor = TlsAcceptor::from_pem(&fs::read("cert.pem")?, &fs::read("key.pem")?)?;
+//! let (tcp, peer) = listener.accept().await?;
+//! let tls = acceptor.accept(tcp).await?;
+//! let connection = tls.connection_info(Some(peer), None);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Clients can be asked for certificates, checked against a CA, and then by a callback;
+//! who they are is on TlsInfo::client_identity:
+//!
+//! ```no_run
+//! # use std::fs;
+//! # use oc_http::tls::{ClientAuth, TlsAcceptor};
+//! # fn allowed(name: &str) -> bool { true }
+//! # fn serve(cert: Vec<u8>, key: Vec<u8>) -> std::io::Result<()> {
+//! let acceptor = TlsAcceptor::from_pem(&cert, &key)?
+//!     .client_auth(&fs::read("clients-ca.pem")?, ClientAuth::Required)?
+//!     .check_client(|identity| match identity.common_name() {
+//!         Some(name) if allowed(name) => Ok(()),
+//!         _ => Err("not on the list".into()),
+//!     });
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The certificate can be swapped while serving, with reload_pem or by watching the files;
+//! connections already up keep the one they started with:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use async_std::task::spawn;
+//! # fn serve(acceptor: oc_http::tls::TlsAcceptor) {
+//! spawn(acceptor.reload_every("cert.pem".into(), "key.pem".into(), Duration::from_secs(60)));
+//! # }
+//! ```
+use std::{
+    fs,
+    io::{self, Read, Write},
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock, Weak},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use futures::{future, prelude::*, ready};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::CertificateDer,
+    server::WebPkiClientVerifier,
+    sign::{CertifiedKey, SingleCertAndKey},
+    ConnectionCommon, ProtocolVersion, RootCertStore, ServerConfig, ServerConnection, SideData,
+};
+
+use crate::connection::{ClientIdentity, ConnectionInfo, TlsInfo};
+
+mod x509;
+
+type ClientCheck = Arc<dyn Fn(&ClientIdentity) -> Result<(), String> + Send + Sync>;
+
+/// Whether clients asked for a certificate have to send one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// Clients without one are let in, without an identity.
+    Optional,
+    Required,
+}
+
+/// the config new handshakes use; swapped whole on reload
+type SharedConfig = RwLock<Arc<ServerConfig>>;
+
+/// Does the server side of handshakes; clones share the config, reloads included.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<SharedConfig>,
+    check_client: Option<ClientCheck>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        TlsAcceptor{config: Arc::new(RwLock::new(config)), check_client: None}
+    }
+
+    /// From a PEM certificate chain (leaf first) and a PEM private key, PKCS#8, RSA or EC;
+    /// offers http/1.1 with ALPN, and doesn't ask for client certificates. The crypto is
+    /// ring's, with TLS 1.2 and 1.3.
+    pub fn from_pem(certs: &[u8], key: &[u8]) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let cert = certified_key(&provider, certs, key)?;
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(cert)));
+        config.alpn_protocols = vec!(b"http/1.1".to_vec());
+        Ok(TlsAcceptor::new(Arc::new(config)))
+    }
+
+    /// Swaps in a new certificate and key for handshakes from now on, keeping the rest of
+    /// the config; on an error, the old ones stay.
+    pub fn reload_pem(&self, certs: &[u8], key: &[u8]) -> io::Result<()> {
+        update(&self.config, |config| set_cert(config, certs, key))
+    }
+
+    /// Swaps in a whole new config for handshakes from now on.
+    pub fn reload(&self, config: Arc<ServerConfig>) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// A future that checks the files every `interval`, for the caller to spawn, and
+    /// reloads them when either's modification time changes. Files that can't be read or
+    /// loaded are logged, and the old certificate kept until the next change. It finishes
+    /// once every clone of the acceptor has been dropped.
+    pub fn reload_every(&self, certs: PathBuf, key: PathBuf, interval: Duration) -> impl Future<Output = ()> {
+        let config: Weak<SharedConfig> = Arc::downgrade(&self.config);
+        async move {
+            let mut seen = modified(&certs, &key).ok();
+            loop {
+                futures_timer::Delay::new(interval).await;
+                let config = match config.upgrade() {
+                    Some(config) => config,
+                    None => return,
+                };
+                let now = modified(&certs, &key).ok();
+                if now.is_none() || now == seen {
+                    continue;
+                }
+                seen = now;
+                let reloaded = fs::read(&certs).and_then(|c| fs::read(&key).map(|k| (c, k)))
+                    .and_then(|(certs, key)| update(&config, |config| set_cert(config, &certs, &key)));
+                match reloaded {
+                    Ok(()) => log::info!("reloaded the TLS certificate from {}", certs.display()),
+                    Err(err) => log::warn!("couldn't reload the TLS certificate from {}: {}", certs.display(), err),
+                }
+            }
+        }
+    }
+
+    /// Asks clients for certificates, which have to chain to one of the CA certificates
+    /// in the PEM; a client sending one that doesn't fails the handshake. The acceptor
+    /// returned has a config of its own, not shared with clones of this one.
+    ///
+    /// rustls only sets the verifier when building a config, so this builds a new one with
+    /// the same crypto provider, certificate, ALPN protocols, session storage and tickets;
+    /// protocol versions go back to the defaults.
+    pub fn client_auth(self, roots: &[u8], auth: ClientAuth) -> io::Result<Self> {
+        let mut store = RootCertStore::empty();
+        let (added, _) = store.add_parsable_certificates(pem_certs(roots));
+        if added == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no CA certificates in the PEM"));
+        }
+        let old = self.config();
+        let provider = old.crypto_provider().clone();
+        let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(store), provider.clone());
+        let verifier = match auth {
+            ClientAuth::Optional => verifier.allow_unauthenticated(),
+            ClientAuth::Required => verifier,
+        }.build().map_err(invalid)?;
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(old.cert_resolver.clone());
+        config.alpn_protocols = old.alpn_protocols.clone();
+        config.ignore_client_order = old.ignore_client_order;
+        config.max_fragment_size = old.max_fragment_size;
+        config.session_storage = old.session_storage.clone();
+        config.ticketer = old.ticketer.clone();
+        config.send_tls13_tickets = old.send_tls13_tickets;
+        config.key_log = old.key_log.clone();
+        Ok(TlsAcceptor{config: Arc::new(RwLock::new(Arc::new(config))), ..self})
+    }
+
+    /// Checks the identity of each client with a verified certificate, after the
+    /// handshake; refused ones are disconnected, and accept fails with PermissionDenied
+    /// and the reason.
+    pub fn check_client<F>(mut self, check: F) -> Self
+    where F: Fn(&ClientIdentity) -> Result<(), String> + Send + Sync + 'static
+    {
+        self.check_client = Some(Arc::new(check));
+        self
+    }
+
+    /// The config new handshakes use.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Does the handshake; fails if it does, or the stream ends first. There's no timeout,
+    /// so wrap it in one.
+    pub async fn accept<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let session = ServerConnection::new(self.config()).map_err(invalid)?;
+        let mut tls = Tls::new(stream, session);
+        future::poll_fn(|cx| tls.poll_handshake(cx)).await?;
+        let leaf = tls.session.peer_certificates().and_then(|chain| chain.first());
+        let identity = match leaf {
+            Some(leaf) => match x509::identity(leaf) {
+                Some(identity) => Some(identity),
+                None => {
+                    let _ = tls.close().await;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unreadable client certificate"));
+                },
+            },
+            None => None,
+        };
+        if let (Some(check), Some(identity)) = (&self.check_client, &identity) {
+            if let Err(reason) = check(identity) {
+                let _ = tls.close().await;
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason));
+            }
+        }
+        Ok(TlsStream{tls, identity})
+    }
+}
+
+/// A connection after the handshake; reads and writes are plaintext. Closing it sends
+/// close_notify.
+pub struct TlsStream<S> {
+    tls: Tls<S, ServerConnection>,
+    identity: Option<ClientIdentity>,
+}
+
+impl<S> TlsStream<S> {
+    pub fn info(&self) -> TlsInfo {
+        let session = &self.tls.session;
+        TlsInfo{
+            version: session.protocol_version().map(version_name).unwrap_or_default(),
+            cipher_suite: session.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())).unwrap_or_default(),
+            server_name: session.server_name().map(String::from),
+            alpn_protocol: session.alpn_protocol().map(Vec::from),
+            peer_certificates: session.peer_certificates().map(|chain| chain.iter().map(|c| c.to_vec()).collect()),
+            client_identity: self.identity.clone(),
+        }
+    }
+
+    pub fn connection_info(&self, peer: Option<std::net::SocketAddr>, local: Option<std::net::SocketAddr>) -> ConnectionInfo {
+        ConnectionInfo{peer, local, tls: Some(self.info())}
+    }
+
+    /// Who the client is, if it sent a certificate.
+    pub fn client_identity(&self) -> Option<&ClientIdentity> {
+        self.identity.as_ref()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.tls.io
+    }
+
+    pub fn session(&self) -> &ServerConnection {
+        &self.tls.session
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.tls).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.tls).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.tls).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.tls).poll_close(cx)
+    }
+}
+
+/// changes a copy of the config, and swaps it in if that worked
+fn update<F>(shared: &SharedConfig, change: F) -> io::Result<()>
+where F: FnOnce(&mut ServerConfig) -> io::Result<()>
+{
+    let mut config = (**shared.read().unwrap()).clone();
+    change(&mut config)?;
+    *shared.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+fn set_cert(config: &mut ServerConfig, certs: &[u8], key: &[u8]) -> io::Result<()> {
+    let cert = certified_key(config.crypto_provider(), certs, key)?;
+    config.cert_resolver = Arc::new(SingleCertAndKey::from(cert));
+    Ok(())
+}
+
+/// the chain and key, checked to go together
+fn certified_key(provider: &CryptoProvider, certs: &[u8], key: &[u8]) -> io::Result<CertifiedKey> {
+    let certs = pem_certs(certs);
+    if certs.is_empty() {
+        return Err(invalid("no certificates in the PEM"));
+    }
+    let key = rustls_pemfile::private_key(&mut &key[..]).ok().flatten()
+        .ok_or_else(|| invalid("no private key in the PEM"))?;
+    CertifiedKey::from_der(certs, key, provider).map_err(invalid)
+}
+
+/// the certificates in a PEM, skipping anything that isn't one
+fn pem_certs(mut pem: &[u8]) -> Vec<CertificateDer<'static>> {
+    rustls_pemfile::certs(&mut pem).filter_map(Result::ok).collect()
+}
+
+fn invalid<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+fn modified(certs: &Path, key: &Path) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((fs::metadata(certs)?.modified()?, fs::metadata(key)?.modified()?))
+}
+
+fn version_name(version: ProtocolVersion) -> String {
+    match version {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".into(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".into(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// an async stream over a rustls connection, for either side
+pub(crate) struct Tls<S, C> {
+    io: S,
+    session: C,
+    /// the underlying stream has ended
+    eof: bool,
+    closing: bool,
+}
+
+impl<S, C, D> Tls<S, C>
+where S: AsyncRead + AsyncWrite + Unpin,
+    C: DerefMut<Target = ConnectionCommon<D>>,
+    D: SideData,
+{
+    pub(crate) fn new(io: S, session: C) -> Self {
+        Tls{io, session, eof: false, closing: false}
+    }
+
+    /// reads some TLS records and processes them
+    fn read_io(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        let count = match self.session.read_tls(&mut Blocking{io: &mut self.io, cx}) {
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Poll::Pending,
+            Err(err) => return Poll::Ready(Err(err)),
+        };
+        if let Err(err) = self.session.process_new_packets() {
+            // try to tell the other side what went wrong
+            let _ = self.write_io(cx);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)));
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn write_io(&mut self, cx: &mut Context) -> Poll<io::Result<usize>> {
+        match self.session.write_tls(&mut Blocking{io: &mut self.io, cx}) {
+            Ok(0) => Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+            Ok(count) => Poll::Ready(Ok(count)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+
+    fn write_all_io(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.session.wants_write() {
+            ready!(self.write_io(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    pub(crate) fn poll_handshake(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.write_all_io(cx))?;
+            if !self.session.is_handshaking() {
+                break;
+            }
+            if ready!(self.read_io(cx))? == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+}
+
+impl<S, C, D> AsyncRead for Tls<S, C>
+where S: AsyncRead + AsyncWrite + Unpin,
+    C: DerefMut<Target = ConnectionCommon<D>> + Unpin,
+    D: SideData,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.session.reader().read(buf) {
+                // Ok(0) is close_notify; the end of the stream
+                Ok(count) => return Poll::Ready(Ok(count)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                // the stream ended without a close_notify, which counts as the end too
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Poll::Ready(Ok(0)),
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+            if this.eof {
+                return Poll::Ready(Ok(0));
+            }
+            if ready!(this.read_io(cx))? == 0 {
+                this.eof = true;
+            }
+            // anything the records called for, like key updates, goes out on a best effort
+            if this.session.wants_write() {
+                let _ = this.write_io(cx);
+            }
+        }
+    }
+}
+
+impl<S, C, D> AsyncWrite for Tls<S, C>
+where S: AsyncRead + AsyncWrite + Unpin,
+    C: DerefMut<Target = ConnectionCommon<D>> + Unpin,
+    D: SideData,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // the session buffers what it's given; only take more once that's out
+        ready!(this.write_all_io(cx))?;
+        let count = this.session.writer().write(buf)?;
+        // start sending it, but what doesn't go now goes on flush
+        match this.write_all_io(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(count)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.session.writer().flush()?;
+        ready!(this.write_all_io(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            this.session.send_close_notify();
+            this.closing = true;
+        }
+        ready!(this.write_all_io(cx))?;
+        Pin::new(&mut this.io).poll_close(cx)
+    }
+}
+
+/// The sync reads and writes rustls wants, over an async stream: Pending becomes
+/// WouldBlock, with the waker registered.
+struct Blocking<'a, 'b, S> {
+    io: &'a mut S,
+    cx: &'a mut Context<'b>,
+}
+
+impl<S: AsyncRead + Unpin> Read for Blocking<'_, '_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Write for Blocking<'_, '_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::convert::TryFrom;
+    use rustls::{client::WantsClientCert, pki_types::ServerName, ClientConfig, ClientConnection, ConfigBuilder};
+
+    use super::*;
+    use crate::testing::duplex;
+
+    pub(crate) const CA: &[u8] = include_bytes!("../../testdata/ca.pem");
+    pub(crate) const CERT: &[u8] = include_bytes!("../../testdata/localhost.pem");
+    pub(crate) const KEY: &[u8] = include_bytes!("../../testdata/localhost.key");
+    const RENEWED: &[u8] = include_bytes!("../../testdata/localhost-renewed.pem");
+    const ALICE: &[u8] = include_bytes!("../../testdata/alice.pem");
+    const ALICE_KEY: &[u8] = include_bytes!("../../testdata/alice.key");
+
+    /// a client trusting the CA certificates in the PEM
+    fn client_builder(roots: &[u8]) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+        let mut store = RootCertStore::empty();
+        store.add_parsable_certificates(pem_certs(roots));
+        ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+    }
+
+    /// a client trusting the test CA
+    pub(crate) fn client_config() -> ClientConfig {
+        client_builder(CA).with_no_client_auth()
+    }
+
+    pub(crate) async fn connect<S>(config: ClientConfig, stream: S) -> io::Result<Tls<S, ClientConnection>>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let name = ServerName::try_from("localhost").unwrap();
+        let session = ClientConnection::new(Arc::new(config), name).map_err(invalid)?;
+        let mut tls = Tls::new(stream, session);
+        future::poll_fn(|cx| tls.poll_handshake(cx)).await?;
+        Ok(tls)
+    }
+
+    #[async_std::test]
+    async fn test_tls() {
+        let acceptor = TlsAcceptor::from_pem(CERT, KEY).unwrap();
+        let (server, client) = duplex();
+        let serve = async {
+            let mut tls = acceptor.accept(server).await?;
+            let mut buf = [0; 4];
+            tls.read_exact(&mut buf).await?;
+            assert_eq!(&buf, b"ping");
+            tls.write_all(b"pong").await?;
+            tls.close().await?;
+            Ok::<_, io::Error>(tls.connection_info(None, None))
+        };
+        let talk = async {
+            let mut config = client_config();
+            config.alpn_protocols = vec!(b"http/1.1".to_vec());
+            let mut tls = connect(config, client).await?;
+            tls.write_all(b"ping").await?;
+            tls.flush().await?;
+            let mut reply = vec!();
+            tls.read_to_end(&mut reply).await?;
+            Ok::<_, io::Error>(reply)
+        };
+        let (info, reply) = futures::join!(serve, talk);
+        assert_eq!(reply.unwrap(), b"pong");
+        let info = info.unwrap().tls.unwrap();
+        assert_eq!(info.version, "TLSv1.3");
+        assert!(info.cipher_suite.starts_with("TLS13_"), "{}", info.cipher_suite);
+        assert_eq!(info.server_name.as_deref(), Some("localhost"));
+        assert_eq!(info.alpn_protocol.as_deref(), Some(&b"http/1.1"[..]));
+        assert_eq!(info.peer_certificates, None);
+
+        // a client that doesn't trust the certificate fails the handshake on both sides
+        let (server, client) = duplex();
+        let (accepted, connected) = futures::join!(acceptor.accept(server), connect(client_builder(b"").with_no_client_auth(), client));
+        assert!(accepted.is_err() && connected.is_err());
+    }
+
+    /// a client presenting alice's certificate
+    fn alice() -> ClientConfig {
+        let key = rustls_pemfile::private_key(&mut &ALICE_KEY[..]).unwrap().unwrap();
+        client_builder(CA).with_client_auth_cert(pem_certs(ALICE), key).unwrap()
+    }
+
+    /// the server's result; the client's only matters to the server
+    async fn handshake(acceptor: &TlsAcceptor, config: ClientConfig) -> io::Result<Option<ClientIdentity>> {
+        let (server, client) = duplex();
+        let talk = async {
+            let mut tls = connect(config, client).await?;
+            // TLS 1.3 clients finish before the server has checked their certificate
+            let mut buf = vec!();
+            tls.read_to_end(&mut buf).await
+        };
+        let serve = async {
+            let mut tls = acceptor.accept(server).await?;
+            tls.close().await?;
+            Ok(tls.info().client_identity)
+        };
+        futures::join!(serve, talk).0
+    }
+
+    #[async_std::test]
+    async fn test_client_auth() {
+        let optional = TlsAcceptor::from_pem(CERT, KEY).unwrap().client_auth(CA, ClientAuth::Optional).unwrap();
+        let identity = handshake(&optional, alice()).await.unwrap().unwrap();
+        assert_eq!(identity.common_name(), Some("alice"));
+        assert_eq!(handshake(&optional, client_config()).await.unwrap(), None);
+
+        let required = optional.clone().client_auth(CA, ClientAuth::Required).unwrap();
+        assert!(handshake(&required, client_config()).await.is_err());
+        assert!(handshake(&required, alice()).await.is_ok());
+
+        let checked = required.check_client(|identity| match identity.common_name() {
+            Some("bob") => Ok(()),
+            _ => Err("only bob".into()),
+        });
+        let err = handshake(&checked, alice()).await.unwrap_err();
+        assert_eq!((err.kind(), err.to_string()), (io::ErrorKind::PermissionDenied, "only bob".into()));
+
+        assert!(TlsAcceptor::from_pem(CERT, KEY).unwrap().client_auth(b"", ClientAuth::Required).is_err());
+    }
+
+    /// the leaf the server presents to a new client
+    async fn presented(acceptor: &TlsAcceptor) -> Vec<u8> {
+        let (server, client) = duplex();
+        let (_, client) = futures::join!(acceptor.accept(server), connect(client_config(), client));
+        client.unwrap().session.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[async_std::test]
+    async fn test_reload() {
+        let acceptor = TlsAcceptor::from_pem(CERT, KEY).unwrap();
+        let original = pem_certs(CERT)[0].to_vec();
+        let renewed = pem_certs(RENEWED)[0].to_vec();
+        assert_eq!(presented(&acceptor).await, original);
+
+        // a connection made before the reload keeps working after it
+        let (server, client) = duplex();
+        let (server, client) = futures::join!(acceptor.accept(server), connect(client_config(), client));
+        let (mut server, mut client) = (server.unwrap(), client.unwrap());
+        assert!(acceptor.reload_pem(b"not a cert", KEY).is_err());
+        acceptor.clone().reload_pem(RENEWED, KEY).unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(presented(&acceptor).await, renewed);
+
+        // watching the files
+        let dir = std::env::temp_dir().join(format!("oc-http-tls-{}", rand::random::<u32>()));
+        fs::create_dir(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, CERT).unwrap();
+        fs::write(&key_path, KEY).unwrap();
+        let acceptor = TlsAcceptor::from_pem(CERT, KEY).unwrap();
+        let watching = async_std::task::spawn(acceptor.reload_every(cert_path.clone(), key_path, Duration::from_millis(5)));
+        async_std::task::sleep(Duration::from_millis(20)).await;
+        let before = acceptor.config();
+        fs::write(&cert_path, RENEWED).unwrap();
+        for _ in 0..100 {
+            if !Arc::ptr_eq(&before, &acceptor.config()) {
+                break;
+            }
+            async_std::task::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(presented(&acceptor).await, renewed);
+        drop((acceptor, before));
+        watching.await;
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
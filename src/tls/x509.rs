@@ -0,0 +1,331 @@
+//! Just enough DER to read the subject and subject alternative names out of a certificate
+//! rustls has already verified.
+use std::{convert::TryFrom, net::IpAddr};
+
+use crate::connection::{ClientIdentity, SubjectAltName};
+
+/// DER tags
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const OID: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// A run of TLVs.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        // high tag numbers take more bytes; nothing we read uses them
+        if tag & 0x1f == 0x1f {
+            return None;
+        }
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            // no indefinite lengths (0x80), none too long to be real, and only as many
+            // bytes as it takes, without leading zeros
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count || rest[0] == 0 {
+                return None;
+            }
+            let len = rest[..count].iter().fold(0, |len, &b| len << 8 | b as usize);
+            if len < 0x80 {
+                return None;
+            }
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return None;
+        }
+        self.0 = &rest[len..];
+        Some((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        self.next().filter(|(t, _)| *t == tag).map(|(_, contents)| contents)
+    }
+
+    /// the next TLV, if it has the tag
+    fn optional(&mut self, tag: u8) -> Option<&'a [u8]> {
+        if self.0.first() == Some(&tag) { self.expect(tag) } else { None }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The leaf's subject and SANs; None if it isn't a certificate, or any part of it we read is
+/// malformed.
+pub(crate) fn identity(der: &[u8]) -> Option<ClientIdentity> {
+    let mut der = Der(der);
+    let cert = der.expect(SEQUENCE)?;
+    if !der.is_empty() {
+        return None;
+    }
+    let mut tbs = Der(Der(cert).expect(SEQUENCE)?);
+    tbs.optional(VERSION);
+    // serial, signature algorithm, issuer, validity
+    for _ in 0..4 {
+        tbs.next()?;
+    }
+    let subject = name(tbs.expect(SEQUENCE)?)?;
+    // the public key, then optional unique IDs
+    tbs.next()?;
+    let mut alt_names = vec!();
+    while !tbs.is_empty() {
+        let (tag, contents) = tbs.next()?;
+        if tag == EXTENSIONS {
+            alt_names = extensions(contents)?;
+        }
+    }
+    Some(ClientIdentity{subject, alt_names})
+}
+
+fn name(der: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut attributes = vec!();
+    let mut rdns = Der(der);
+    while !rdns.is_empty() {
+        // a SET of one or more (type, value) SEQUENCEs
+        let mut pairs = Der(rdns.expect(SET)?);
+        if pairs.is_empty() {
+            return None;
+        }
+        while !pairs.is_empty() {
+            let mut pair = Der(pairs.expect(SEQUENCE)?);
+            let oid = pair.expect(OID)?;
+            let (tag, value) = pair.next()?;
+            if !pair.is_empty() {
+                return None;
+            }
+            attributes.push((attribute_name(oid), string(tag, value)?));
+        }
+    }
+    Some(attributes)
+}
+
+fn extensions(der: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut extensions = Der(Der(der).expect(SEQUENCE)?);
+    while !extensions.is_empty() {
+        let mut extension = Der(extensions.expect(SEQUENCE)?);
+        let oid = extension.expect(OID)?;
+        extension.optional(BOOLEAN);
+        let value = extension.expect(OCTET_STRING)?;
+        if oid == SUBJECT_ALT_NAME {
+            return alt_names(Der(value).expect(SEQUENCE)?);
+        }
+    }
+    Some(vec!())
+}
+
+fn alt_names(der: &[u8]) -> Option<Vec<SubjectAltName>> {
+    let mut names = vec!();
+    let mut general_names = Der(der);
+    while !general_names.is_empty() {
+        let (tag, value) = general_names.next()?;
+        let text = || String::from_utf8(value.to_vec()).ok();
+        names.push(match tag {
+            0x81 => SubjectAltName::Email(text()?),
+            0x82 => SubjectAltName::Dns(text()?),
+            0x86 => SubjectAltName::Uri(text()?),
+            0x87 => match value.len() {
+                4 => SubjectAltName::Ip(IpAddr::from(<[u8; 4]>::try_from(value).ok()?)),
+                16 => SubjectAltName::Ip(IpAddr::from(<[u8; 16]>::try_from(value).ok()?)),
+                _ => return None,
+            },
+            // other names, directory names and so on
+            _ => continue,
+        });
+    }
+    Some(names)
+}
+
+fn string(tag: u8, value: &[u8]) -> Option<String> {
+    match tag {
+        // UTF8String, PrintableString, IA5String, TeletexString (close enough)
+        0x0c | 0x13 | 0x16 | 0x14 => String::from_utf8(value.to_vec()).ok(),
+        // BMPString
+        0x1e => {
+            if !value.len().is_multiple_of(2) {
+                return None;
+            }
+            let units: Vec<u16> = value.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok()
+        },
+        _ => None,
+    }
+}
+
+/// the usual short name, or the dotted OID
+fn attribute_name(oid: &[u8]) -> String {
+    let short = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01] => "emailAddress",
+        _ => return dotted(oid),
+    };
+    short.into()
+}
+
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = vec!();
+    let mut arc: u64 = 0;
+    for &b in oid {
+        arc = arc << 7 | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter().map(u64::to_string).collect::<Vec<_>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let der = rustls_pemfile::certs(&mut &include_bytes!("../../testdata/alice.pem")[..]).next().unwrap().unwrap();
+        let alice = identity(&der).unwrap();
+        assert_eq!(alice.subject, vec!(("O".into(), "Example".into()), ("CN".into(), "alice".into())));
+        assert_eq!(alice.common_name(), Some("alice"));
+        assert_eq!(alice.alt_names, vec!(
+            SubjectAltName::Email("alice@example.com".into()),
+            SubjectAltName::Dns("alice.example.com".into()),
+            SubjectAltName::Ip("10.0.0.1".parse().unwrap()),
+        ));
+        assert_eq!(identity(b"\x30\x03\x02\x01"), None);
+        assert_eq!(dotted(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x01]), "1.2.840.113549.1.9.1");
+    }
+
+    /// a TLV, with a long-form length if it needs one
+    fn tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut der = vec!(tag);
+        match contents.len() {
+            len if len < 0x80 => der.push(len as u8),
+            len if len < 0x100 => der.extend_from_slice(&[0x81, len as u8]),
+            len => der.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        der.extend_from_slice(contents);
+        der
+    }
+
+    fn attribute(oid: &[u8], value: &str) -> Vec<u8> {
+        tlv(SEQUENCE, &[tlv(OID, oid), tlv(0x0c, value.as_bytes())].concat())
+    }
+
+    #[test]
+    fn test_lengths() {
+        fn all(der: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+            let mut der = Der(der);
+            let mut tlvs = vec!();
+            while !der.is_empty() {
+                tlvs.push(der.next()?);
+            }
+            Some(tlvs)
+        }
+        let long = vec![7; 200];
+        assert_eq!(all(&tlv(OCTET_STRING, &long)), Some(vec!((OCTET_STRING, &long[..]))));
+        assert_eq!(all(&[0x04, 0x82, 0x01, 0x00]), None);
+        // truncated: the tag alone, the length's bytes, or the contents
+        for truncated in &[&[0x30][..], &[0x30, 0x82, 0x01], &[0x30, 0x03, 0x02, 0x01]] {
+            assert_eq!(all(truncated), None, "{:?}", truncated);
+        }
+        // indefinite, too many length bytes, and lengths that aren't as short as they can be
+        for bad in &[&[0x30, 0x80, 0x00, 0x00][..], &[0x04, 0x85, 0, 0, 0, 0, 1, 0], &[0x04, 0x81, 0x01, 0x00], &[0x04, 0x82, 0x00, 0x81]] {
+            assert_eq!(all(bad), None, "{:?}", bad);
+        }
+        // a length bigger than anything in memory
+        assert_eq!(all(&[0x04, 0x84, 0xff, 0xff, 0xff, 0xff, 0x00]), None);
+        // a high tag number
+        assert_eq!(all(&[0x1f, 0x81, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn test_malformed_names() {
+        let cn = attribute(&[0x55, 0x04, 0x03], "alice");
+        let o = attribute(&[0x55, 0x04, 0x0a], "Example");
+        // more than one attribute in an RDN
+        assert_eq!(name(&tlv(SET, &[o.clone(), cn.clone()].concat())), Some(vec!(
+            ("O".into(), "Example".into()),
+            ("CN".into(), "alice".into()),
+        )));
+        let bad = [
+            // a SET nested in the RDN's SET, where the attributes go
+            tlv(SET, &tlv(SET, &cn)),
+            // an RDN that isn't a SET, or is an empty one
+            tlv(SEQUENCE, &cn),
+            tlv(SET, &[]),
+            // a good RDN, then a truncated one
+            [tlv(SET, &o), tlv(SET, &cn)[..6].to_vec()].concat(),
+            // an attribute without a value, or with two
+            tlv(SET, &tlv(SEQUENCE, &tlv(OID, &[0x55, 0x04, 0x03]))),
+            tlv(SET, &tlv(SEQUENCE, &[tlv(OID, &[0x55, 0x04, 0x03]), tlv(0x0c, b"a"), tlv(0x0c, b"b")].concat())),
+            // values that aren't strings, or aren't valid ones
+            tlv(SET, &tlv(SEQUENCE, &[tlv(OID, &[0x55, 0x04, 0x03]), tlv(0x02, &[1])].concat())),
+            tlv(SET, &tlv(SEQUENCE, &[tlv(OID, &[0x55, 0x04, 0x03]), tlv(0x1e, &[0, 0x61, 0])].concat())),
+            tlv(SET, &tlv(SEQUENCE, &[tlv(OID, &[0x55, 0x04, 0x03]), tlv(0x0c, &[0xff])].concat())),
+        ];
+        for der in &bad {
+            assert_eq!(name(der), None, "{:02x?}", der);
+        }
+    }
+
+    #[test]
+    fn test_malformed_extensions() {
+        let san = |names: &[u8]| tlv(SEQUENCE, &[
+            tlv(OID, SUBJECT_ALT_NAME),
+            tlv(OCTET_STRING, &tlv(SEQUENCE, names)),
+        ].concat());
+        let basic_constraints = tlv(SEQUENCE, &[
+            tlv(OID, &[0x55, 0x1d, 0x13]),
+            tlv(BOOLEAN, &[0xff]),
+            tlv(OCTET_STRING, &tlv(SEQUENCE, &[])),
+        ].concat());
+        let dns = tlv(0x82, b"alice.example.com");
+        let good = tlv(SEQUENCE, &[basic_constraints.clone(), san(&dns)].concat());
+        assert_eq!(extensions(&good), Some(vec!(SubjectAltName::Dns("alice.example.com".into()))));
+        assert_eq!(extensions(&tlv(SEQUENCE, &basic_constraints)), Some(vec!()));
+        let bad = [
+            // a truncated extension before the SAN, which used to hide it
+            tlv(SEQUENCE, &[&basic_constraints[..4], &san(&dns)].concat()),
+            // an extension without its value
+            tlv(SEQUENCE, &tlv(SEQUENCE, &tlv(OID, SUBJECT_ALT_NAME))),
+            // names past the end of the SAN, or an IP that's neither v4 nor v6
+            tlv(SEQUENCE, &san(&[&dns[..], &[0x82, 0x10, b'x']].concat())),
+            tlv(SEQUENCE, &san(&tlv(0x87, &[10, 0, 0]))),
+            tlv(SEQUENCE, &san(&tlv(0x82, &[0xff, 0xfe]))),
+        ];
+        for der in &bad {
+            assert_eq!(extensions(der), None, "{:02x?}", der);
+        }
+    }
+
+    #[test]
+    fn test_truncated_certificate() {
+        let der = rustls_pemfile::certs(&mut &include_bytes!("../../testdata/alice.pem")[..]).next().unwrap().unwrap();
+        for len in 0..der.len() {
+            assert_eq!(identity(&der[..len]), None, "{}", len);
+        }
+        // or with something after it
+        assert_eq!(identity(&[&der[..], &[0]].concat()), None);
+    }
+}
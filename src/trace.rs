@@ -0,0 +1,214 @@
+//! W3C Trace Context: the `traceparent` and `tracestate` headers, for passing distributed
+//! trace ids along without a full tracing SDK.
+//!
+//! Continue the caller's trace (or start one), and send the child context on with any
+//! requests made while handling this one:
+//!
+//! ```no_run
+//! # use oc_http::{trace::TraceContext, Request};
+//! # fn serve(request: &Request, upstream_headers: &mut Vec<(String, Vec<u8>)>) {
+//! let trace = TraceContext::from_request(&request).unwrap_or_else(TraceContext::new_root);
+//! let outgoing = trace.child();
+//! upstream_headers.extend(outgoing.headers());
+//! # }
+//! ```
+use std::fmt::Write;
+
+use crate::Request;
+
+/// tracestate may carry at most this many entries
+const MAX_STATE_ENTRIES: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    /// The id of the caller's span, which this request is part of.
+    pub parent_id: [u8; 8],
+    pub flags: u8,
+    /// Vendor specific entries from tracestate, most recently updated first.
+    pub state: Vec<(String, String)>,
+}
+
+impl TraceContext {
+    /// A new trace, with this as the first span; it's marked sampled.
+    pub fn new_root() -> Self {
+        TraceContext{
+            trace_id: random_nonzero(),
+            parent_id: random_nonzero(),
+            flags: 1,
+            state: vec!(),
+        }
+    }
+
+    /// The trace context the request carries; None if it has none, or it isn't valid.
+    pub fn from_request(req: &Request) -> Option<Self> {
+        // a traceparent sent more than once is ambiguous, so it's ignored
+        let traceparent = match req.header_values("traceparent")[..] {
+            [traceparent] => traceparent,
+            _ => return None,
+        };
+        let mut context = TraceContext::parse(std::str::from_utf8(traceparent).ok()?)?;
+        // the header may be split across several lines
+        let state: Vec<_> = req.header_values("tracestate").into_iter()
+            .filter_map(|v| std::str::from_utf8(v).ok())
+            .collect();
+        context.state = parse_state(&state.join(","));
+        Some(context)
+    }
+
+    /// Parses a traceparent header value.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = decode_hex::<1>(parts.next()?)?[0];
+        let trace_id = decode_hex::<16>(parts.next()?)?;
+        let parent_id = decode_hex::<8>(parts.next()?)?;
+        let flags = decode_hex::<1>(parts.next()?)?[0];
+        // later versions may add fields, but version 00 has exactly four
+        if version == 0xff || (version == 0 && parts.next().is_some()) {
+            return None;
+        }
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext{trace_id, parent_id, flags, state: vec!()})
+    }
+
+    /// The context to send to the next service: same trace, with a new span as the parent.
+    pub fn child(&self) -> Self {
+        TraceContext{
+            parent_id: random_nonzero(),
+            ..self.clone()
+        }
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+
+    /// Adds or updates a vendor's tracestate entry, moving it to the front as the spec says.
+    pub fn set_state(&mut self, key: &str, value: &str) {
+        self.state.retain(|(k, _)| k != key);
+        self.state.insert(0, (key.into(), value.into()));
+        self.state.truncate(MAX_STATE_ENTRIES);
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", encode_hex(&self.trace_id), encode_hex(&self.parent_id), self.flags)
+    }
+
+    /// The tracestate header value; None if there aren't any entries.
+    pub fn tracestate(&self) -> Option<String> {
+        if self.state.is_empty() {
+            return None;
+        }
+        Some(self.state.iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(","))
+    }
+
+    /// Headers carrying the context, for a request or response.
+    pub fn headers(&self) -> Vec<(String, Vec<u8>)> {
+        let mut headers = vec!(("traceparent".to_string(), self.traceparent().into_bytes()));
+        if let Some(state) = self.tracestate() {
+            headers.push(("tracestate".into(), state.into_bytes()));
+        }
+        headers
+    }
+}
+
+/// parses tracestate, dropping entries that aren't `key=value`
+fn parse_state(value: &str) -> Vec<(String, String)> {
+    let mut state: Vec<(String, String)> = vec!();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (key, value) = match entry.split_once('=') {
+            Some((k, v)) if !k.is_empty() && !v.is_empty() => (k.trim(), v.trim()),
+            _ => continue,
+        };
+        let valid_key = key.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"_-*/@".contains(&b));
+        let valid_value = value.bytes().all(|b| (0x20..=0x7e).contains(&b) && b != b',' && b != b'=');
+        // the first of any duplicates wins
+        if valid_key && valid_value && !state.iter().any(|(k, _)| k == key) {
+            state.push((key.into(), value.into()));
+        }
+    }
+    state.truncate(MAX_STATE_ENTRIES);
+    state
+}
+
+fn random_nonzero<const N: usize>() -> [u8; N] {
+    loop {
+        let mut id = [0; N];
+        id.iter_mut().for_each(|b| *b = rand::random());
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+/// lowercase hex only, as the spec requires
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const EXAMPLE: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn test_trace_context() {
+        let mut headers = HashMap::default();
+        headers.insert("traceparent", (EXAMPLE.as_bytes(), None));
+        headers.insert("tracestate", (&b"rojo=00f067aa0ba902b7, bad key=x"[..], Some(vec!(&b"congo=t61rcWkgMzE,rojo=dup"[..]))));
+        let req = Request{method: "GET".into(), path: "/".into(), headers};
+        let mut context = TraceContext::from_request(&req).unwrap();
+        assert_eq!(context.trace_id_hex(), "0af7651916cd43dd8448eb211c80319c");
+        assert!(context.sampled());
+        assert_eq!(context.traceparent(), EXAMPLE);
+        assert_eq!(context.tracestate().as_deref(), Some("rojo=00f067aa0ba902b7,congo=t61rcWkgMzE"));
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.parent_id, context.parent_id);
+        context.set_state("congo", "new");
+        assert_eq!(context.headers()[1].1, b"congo=new,rojo=00f067aa0ba902b7");
+
+        // later versions may have more fields
+        assert!(TraceContext::parse("01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00-extra").is_some());
+        for bad in &[
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b716920333-01",
+            "",
+        ] {
+            assert_eq!(TraceContext::parse(bad), None, "{}", bad);
+        }
+        let root = TraceContext::new_root();
+        assert_eq!(TraceContext::parse(&root.traceparent()), Some(root));
+    }
+}
@@ -0,0 +1,157 @@
+//! Switching a connection to another protocol with `Upgrade:`, the way the websocket
+//! module does, for protocols the crate doesn't know.
+//!
+//! Once the 101 is written the stream is the new protocol's; if the request was parsed
+//! through a BufReader, what's left in its buffer already belongs to it:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use oc_http::{respond, upgrade, Request};
+//! # mod irc { pub async fn serve(stream: async_std::net::TcpStream) -> std::io::Result<()> { Ok(()) } }
+//! # async fn serve(req: &Request<'_>, mut stream: TcpStream) -> std::io::Result<()> {
+//! if upgrade::requested(&req, "irc") {
+//!     if let Err(err) = upgrade::switch_protocols(&req, &mut stream, "irc", vec!()).await {
+//!         return respond(&mut stream, err.response()).await;
+//!     }
+//!     return irc::serve(stream).await;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{fmt, io};
+
+use futures::prelude::*;
+
+use crate::{respond, Request, Response};
+
+#[derive(Debug)]
+pub enum UpgradeError {
+    /// The request's Connection header doesn't have `upgrade`; for the protocol it was
+    /// checked for.
+    NotRequested(String),
+    /// The request's Upgrade header doesn't offer the protocol.
+    NotOffered(String),
+    Io(io::Error),
+}
+
+impl UpgradeError {
+    /// A 426 naming the protocol for a request that didn't ask for it, or a 500.
+    pub fn response(&self) -> Response {
+        let mut headers = vec!(("Content-Length".into(), Vec::from("0")));
+        match self {
+            UpgradeError::NotRequested(protocol) | UpgradeError::NotOffered(protocol) => {
+                headers.push(("Upgrade".into(), Vec::from(protocol.as_str())));
+                headers.push(("Connection".into(), Vec::from("Upgrade")));
+                Response{code: 426, reason: "Upgrade Required", headers}
+            },
+            UpgradeError::Io(_) => Response{code: 500, reason: "Internal Server Error", headers},
+        }
+    }
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpgradeError::NotRequested(_) => write!(f, "the request's Connection header doesn't include upgrade"),
+            UpgradeError::NotOffered(protocol) => write!(f, "the request doesn't offer to upgrade to {}", protocol),
+            UpgradeError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {}
+
+impl From<io::Error> for UpgradeError {
+    fn from(err: io::Error) -> Self {
+        UpgradeError::Io(err)
+    }
+}
+
+/// The protocols the request's Upgrade headers offer, in order, if its Connection header
+/// asks for an upgrade at all.
+pub fn offered(req: &Request) -> Vec<String> {
+    if !tokens(req, "Connection").iter().any(|t| t.eq_ignore_ascii_case("upgrade")) {
+        return vec!();
+    }
+    tokens(req, "Upgrade")
+}
+
+/// Whether the request offers to upgrade to the protocol; one named without a version
+/// matches offers of any version, so `irc` matches `IRC/2`.
+pub fn requested(req: &Request, protocol: &str) -> bool {
+    offered(req).iter().any(|offer| matches(offer, protocol))
+}
+
+/// Checks the request offers the protocol, then writes the 101 (Upgrade, Connection, and
+/// the extra headers) and hands back the stream for the new protocol to use.
+pub async fn switch_protocols<S>(req: &Request<'_>, mut stream: S, protocol: &str, headers: Vec<(String, Vec<u8>)>) -> Result<S, UpgradeError>
+where S: AsyncWrite + Unpin
+{
+    if !tokens(req, "Connection").iter().any(|t| t.eq_ignore_ascii_case("upgrade")) {
+        return Err(UpgradeError::NotRequested(protocol.into()));
+    }
+    if !tokens(req, "Upgrade").iter().any(|offer| matches(offer, protocol)) {
+        return Err(UpgradeError::NotOffered(protocol.into()));
+    }
+    switch(&mut stream, protocol, headers).await?;
+    Ok(stream)
+}
+
+/// Writes the 101 without checking the request; for callers that have.
+pub(crate) async fn switch<S>(stream: &mut S, protocol: &str, extra: Vec<(String, Vec<u8>)>) -> io::Result<()>
+where S: AsyncWrite + Unpin
+{
+    let mut headers = vec!(
+        ("Upgrade".into(), Vec::from(protocol)),
+        ("Connection".into(), Vec::from("Upgrade")),
+    );
+    headers.extend(extra);
+    respond(stream, Response{code: 101, reason: "Switching Protocols", headers}).await?;
+    stream.flush().await
+}
+
+/// the comma-separated tokens of every value of the header
+pub(crate) fn tokens(req: &Request, name: &str) -> Vec<String> {
+    req.header_values(name).iter()
+        .flat_map(|value| String::from_utf8_lossy(value).split(',').map(|t| t.trim().to_string()).collect::<Vec<_>>())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+fn matches(offer: &str, protocol: &str) -> bool {
+    offer.eq_ignore_ascii_case(protocol)
+        || !protocol.contains('/') && offer.split('/').next().is_some_and(|name| name.eq_ignore_ascii_case(protocol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::duplex;
+
+    #[async_std::test]
+    async fn test_switch_protocols() {
+        let builder = Request::builder()
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Upgrade", "h2c, IRC/2");
+        let req = builder.request();
+        assert_eq!(offered(&req), vec!("h2c", "IRC/2"));
+        assert!(requested(&req, "irc") && requested(&req, "irc/2") && !requested(&req, "irc/3"));
+        assert!(!requested(&Request::builder().header("Upgrade", "irc").request(), "irc"));
+
+        let (server, mut client) = duplex();
+        let extra = vec!(("Irc-Nick".into(), Vec::from("ferris")));
+        let mut stream = switch_protocols(&req, server, "irc", extra).await.unwrap();
+        stream.write_all(b"PING").await.unwrap();
+        stream.close().await.unwrap();
+        let mut raw = vec!();
+        client.read_to_end(&mut raw).await.unwrap();
+        let expected = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: irc\r\nConnection: Upgrade\r\nIrc-Nick: ferris\r\n\r\nPING";
+        assert_eq!(String::from_utf8(raw).unwrap(), expected);
+
+        let (mut server, _client) = duplex();
+        let err = switch_protocols(&req, &mut server, "websocket", vec!()).await.err().unwrap();
+        assert!(matches!(err, UpgradeError::NotOffered(_)));
+        let response = err.response();
+        assert_eq!((response.code, &response.headers[1].1[..]), (426, &b"websocket"[..]));
+    }
+}
@@ -0,0 +1,777 @@
+//! An experimental transport on io_uring (Linux 5.6 and later), with the `uring` feature.
+//!
+//! A Ring hands reads, writes and accepts to the kernel, and a thread of its own waits for
+//! them to complete and wakes whichever task is waiting, so it works under any runtime. Its
+//! streams are AsyncRead + AsyncWrite + Clone, like async-std's, and go wherever those do:
+//!
+//! ```no_run
+//! # use oc_http::uring::{Ring, RingOptions, UringListener};
+//! # async fn serve() -> std::io::Result<()> {
+//! let ring = Ring::new(RingOptions::default())?;
+//! let listener = UringListener::bind(&ring, "0.0.0.0:8080")?;
+//! loop {
+//!     let (stream, peer) = listener.accept().await?;
+//!     // ...
+//! }
+//! # }
+//! ```
+//!
+//! Reads go into buffers registered with the kernel up front, each big enough for a request's
+//! head, so the kernel doesn't have to map the pages for every read; when they're all in use,
+//! reads fall back to buffers of their own. Writes are copied, started at once, and waited for
+//! by the next write or flush, so a failed write shows up there.
+use std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    pin::Pin,
+    ptr::{self, NonNull},
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use futures::{prelude::*, ready};
+use io_uring::{opcode, squeue, types::Fd, IoUring};
+use log::warn;
+
+// user_data of the entries that aren't anyone's operation
+const STOP: u64 = u64::MAX;
+const CANCEL: u64 = u64::MAX - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RingOptions {
+    /// How many operations can be submitted at once; the kernel rounds it up to a power of two.
+    pub entries: u32,
+    /// How many read buffers to register with the kernel; 0 for none.
+    pub fixed_buffers: usize,
+    /// The size of each read, and of each registered buffer; ServeOptions::max_head fits in
+    /// one by default.
+    pub buffer_size: usize,
+}
+
+impl Default for RingOptions {
+    fn default() -> Self {
+        RingOptions{
+            entries: 256,
+            fixed_buffers: 64,
+            buffer_size: 16 * 1024,
+        }
+    }
+}
+
+impl RingOptions {
+    pub fn new() -> Self {
+        RingOptions::default()
+    }
+
+    pub fn entries(mut self, entries: u32) -> Self {
+        self.entries = entries;
+        self
+    }
+
+    pub fn fixed_buffers(mut self, count: usize) -> Self {
+        self.fixed_buffers = count.min(u16::MAX as usize);
+        self
+    }
+
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.buffer_size = bytes.clamp(1, u32::MAX as usize);
+        self
+    }
+}
+
+/// An io_uring instance and the thread completing its operations; clones share both. The
+/// thread stops once every clone, and every listener and stream made with one, is dropped.
+#[derive(Clone)]
+pub struct Ring {
+    driver: Arc<Driver>,
+}
+
+// tells the thread to stop when the last handle goes
+struct Driver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    // dropped before the buffers it has registered
+    ring: IoUring,
+    // the submission queue is only pushed to holding this
+    submitting: Mutex<()>,
+    ops: Mutex<Ops>,
+    fixed: Option<FixedBuffers>,
+    buffer_size: usize,
+}
+
+/// The operations in flight, indexed by their user_data.
+#[derive(Default)]
+struct Ops {
+    slots: Vec<Option<Op>>,
+    free: Vec<usize>,
+    live: usize,
+    stopping: bool,
+}
+
+struct Op {
+    result: Option<i32>,
+    waker: Option<Waker>,
+    // nobody's waiting any more; the buffer's released once the kernel's done with it
+    abandoned: bool,
+    // an accept, so if it's abandoned and succeeds anyway, the socket's closed
+    accept: bool,
+    buf: Option<Buf>,
+}
+
+/// Memory the kernel reads or writes while an operation is in flight, so it's kept with the
+/// operation rather than by whoever started it.
+enum Buf {
+    Fixed(u16),
+    Owned(Vec<u8>),
+}
+
+struct FixedBuffers {
+    base: NonNull<u8>,
+    len: usize,
+    free: Mutex<Vec<u16>>,
+}
+
+// each buffer is only touched by the kernel or by whoever checked it out of `free`
+unsafe impl Send for FixedBuffers {}
+unsafe impl Sync for FixedBuffers {}
+
+impl Drop for FixedBuffers {
+    fn drop(&mut self) {
+        unsafe { drop(Vec::from_raw_parts(self.base.as_ptr(), self.len, self.len)) };
+    }
+}
+
+impl Ring {
+    /// Sets up the ring and registers its buffers, then starts the thread completing its
+    /// operations. Fails where io_uring isn't available (or is blocked, as container runtimes
+    /// often do); if the buffers can't be registered, usually for RLIMIT_MEMLOCK, it goes
+    /// without.
+    pub fn new(options: RingOptions) -> io::Result<Self> {
+        let ring = IoUring::new(options.entries)?;
+        let fixed = match register_buffers(&ring, options.fixed_buffers, options.buffer_size) {
+            Ok(fixed) => fixed,
+            Err(err) => {
+                warn!("io_uring: reading without fixed buffers, couldn't register them: {}", err);
+                None
+            },
+        };
+        let shared = Arc::new(Shared{
+            ring,
+            submitting: Mutex::new(()),
+            ops: Mutex::default(),
+            fixed,
+            buffer_size: options.buffer_size,
+        });
+        let completing = shared.clone();
+        thread::Builder::new()
+            .name("oc-http-uring".into())
+            .spawn(move || completing.complete())?;
+        Ok(Ring{driver: Arc::new(Driver{shared})})
+    }
+
+    fn shared(&self) -> &Shared {
+        &self.driver.shared
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        if let Err(err) = self.shared.push(opcode::Nop::new().build().user_data(STOP)) {
+            warn!("io_uring: couldn't stop the ring: {}", err);
+        }
+    }
+}
+
+fn register_buffers(ring: &IoUring, count: usize, size: usize) -> io::Result<Option<FixedBuffers>> {
+    if count == 0 {
+        return Ok(None);
+    }
+    let mut memory = std::mem::ManuallyDrop::new(vec![0u8; count * size]);
+    let base = memory.as_mut_ptr();
+    let fixed = FixedBuffers{
+        base: NonNull::new(base).unwrap(),
+        len: memory.len(),
+        free: Mutex::new((0..count as u16).rev().collect()),
+    };
+    let iovecs: Vec<libc::iovec> = (0..count)
+        .map(|i| libc::iovec{iov_base: unsafe { base.add(i * size) } as *mut _, iov_len: size})
+        .collect();
+    // the memory lives as long as the ring: Shared drops the ring first
+    unsafe { ring.submitter().register_buffers(&iovecs)? };
+    Ok(Some(fixed))
+}
+
+impl Shared {
+    /// Pushes an entry and submits it.
+    fn push(&self, entry: squeue::Entry) -> io::Result<()> {
+        let _submitting = self.submitting.lock().unwrap();
+        // only ever one SubmissionQueue at a time, under the lock
+        let pushed = unsafe { self.ring.submission_shared().push(&entry).is_ok() };
+        if !pushed {
+            // full; submitting empties it
+            self.ring.submit()?;
+            unsafe { self.ring.submission_shared().push(&entry) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        }
+        match self.ring.submit() {
+            // whatever's left in the queue goes with the completion thread's next submit
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EBUSY) | Some(libc::EAGAIN) | Some(libc::EINTR)) => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+
+    /// Starts an operation, keeping `buf` for as long as it's in flight; returns its id.
+    fn start(&self, entry: squeue::Entry, buf: Option<Buf>) -> io::Result<usize> {
+        self.start_op(entry, Op{result: None, waker: None, abandoned: false, accept: false, buf})
+    }
+
+    fn start_accept(&self, entry: squeue::Entry) -> io::Result<usize> {
+        self.start_op(entry, Op{result: None, waker: None, abandoned: false, accept: true, buf: None})
+    }
+
+    fn start_op(&self, entry: squeue::Entry, op: Op) -> io::Result<usize> {
+        let id = {
+            let mut ops = self.ops.lock().unwrap();
+            ops.live += 1;
+            match ops.free.pop() {
+                Some(id) => {
+                    ops.slots[id] = Some(op);
+                    id
+                },
+                None => {
+                    ops.slots.push(Some(op));
+                    ops.slots.len() - 1
+                },
+            }
+        };
+        if let Err(err) = self.push(entry.user_data(id as u64)) {
+            let buf = self.ops.lock().unwrap().remove(id);
+            self.release(buf);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// The operation's result, and its buffer back, once it's complete.
+    fn poll_op(&self, id: usize, cx: &mut Context<'_>) -> Poll<(i32, Option<Buf>)> {
+        let mut ops = self.ops.lock().unwrap();
+        let op = ops.slots[id].as_mut().unwrap();
+        match op.result {
+            Some(result) => Poll::Ready((result, ops.remove(id))),
+            None => {
+                op.waker = Some(cx.waker().clone());
+                Poll::Pending
+            },
+        }
+    }
+
+    /// Gives up on an operation, asking the kernel to cancel it if `cancel`; its buffer is
+    /// released once it completes.
+    fn abandon(&self, id: usize, cancel: bool) {
+        let mut ops = self.ops.lock().unwrap();
+        let op = ops.slots[id].as_mut().unwrap();
+        if op.result.is_some() {
+            let buf = ops.remove(id);
+            drop(ops);
+            self.release(buf);
+            return;
+        }
+        op.abandoned = true;
+        op.waker = None;
+        drop(ops);
+        if cancel {
+            let entry = opcode::AsyncCancel::new(id as u64).build().user_data(CANCEL);
+            if let Err(err) = self.push(entry) {
+                warn!("io_uring: couldn't cancel an operation: {}", err);
+            }
+        }
+    }
+
+    /// A buffer to read into: a registered one if there's one free.
+    fn read_buf(&self) -> (Buf, *mut u8) {
+        if let Some(fixed) = &self.fixed {
+            if let Some(index) = fixed.free.lock().unwrap().pop() {
+                let ptr = unsafe { fixed.base.as_ptr().add(index as usize * self.buffer_size) };
+                return (Buf::Fixed(index), ptr);
+            }
+        }
+        let mut owned = vec![0u8; self.buffer_size];
+        // the allocation doesn't move with the Vec
+        let ptr = owned.as_mut_ptr();
+        (Buf::Owned(owned), ptr)
+    }
+
+    /// The first `len` bytes of a buffer the kernel is done with.
+    fn bytes<'a>(&'a self, buf: &'a Buf, len: usize) -> &'a [u8] {
+        match buf {
+            Buf::Fixed(index) => unsafe {
+                let fixed = self.fixed.as_ref().unwrap();
+                std::slice::from_raw_parts(fixed.base.as_ptr().add(*index as usize * self.buffer_size), len)
+            },
+            Buf::Owned(owned) => &owned[..len],
+        }
+    }
+
+    fn release(&self, buf: Option<Buf>) {
+        if let (Some(Buf::Fixed(index)), Some(fixed)) = (buf, &self.fixed) {
+            fixed.free.lock().unwrap().push(index);
+        }
+    }
+
+    /// The completion thread: waits for operations to complete and wakes their tasks, until
+    /// it's told to stop and nothing's left in flight.
+    fn complete(&self) {
+        loop {
+            match self.ring.submitter().submit_and_wait(1) {
+                Ok(_) => {},
+                Err(err) if err.raw_os_error() == Some(libc::EINTR) => continue,
+                // completions are backed up; reaping them makes room
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {},
+                Err(err) => {
+                    warn!("io_uring: the ring failed: {}", err);
+                    return self.fail();
+                },
+            }
+            let mut wake = vec!();
+            let mut released = vec!();
+            let mut ops = self.ops.lock().unwrap();
+            // only this thread reads completions
+            for cqe in unsafe { self.ring.completion_shared() } {
+                match cqe.user_data() {
+                    STOP => ops.stopping = true,
+                    CANCEL => {},
+                    id => {
+                        let id = id as usize;
+                        let op = ops.slots[id].as_mut().unwrap();
+                        if op.abandoned {
+                            if op.accept && cqe.result() >= 0 {
+                                unsafe { libc::close(cqe.result()) };
+                            }
+                            released.push(ops.remove(id));
+                            continue;
+                        }
+                        op.result = Some(cqe.result());
+                        wake.extend(op.waker.take());
+                    },
+                }
+            }
+            let done = ops.stopping && ops.live == 0;
+            drop(ops);
+            released.into_iter().for_each(|buf| self.release(buf));
+            wake.into_iter().for_each(Waker::wake);
+            if done {
+                return;
+            }
+        }
+    }
+
+    // with the ring broken, nothing in flight will complete
+    fn fail(&self) {
+        let mut ops = self.ops.lock().unwrap();
+        let wake: Vec<Waker> = ops.slots.iter_mut().flatten()
+            .filter_map(|op| {
+                op.result = Some(-libc::EIO);
+                op.waker.take()
+            })
+            .collect();
+        drop(ops);
+        wake.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Ops {
+    fn remove(&mut self, id: usize) -> Option<Buf> {
+        let op = self.slots[id].take().unwrap();
+        self.free.push(id);
+        self.live -= 1;
+        op.buf
+    }
+}
+
+fn result(res: i32) -> io::Result<usize> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(res as usize)
+    }
+}
+
+/// A TCP listener accepting through a Ring.
+pub struct UringListener {
+    ring: Ring,
+    listener: TcpListener,
+}
+
+impl UringListener {
+    pub fn bind<A: ToSocketAddrs>(ring: &Ring, addr: A) -> io::Result<Self> {
+        UringListener::from_std(ring, TcpListener::bind(addr)?)
+    }
+
+    /// Accepts on a listener bound elsewhere, like socket::bind_workers or listen_fds.
+    pub fn from_std(ring: &Ring, listener: TcpListener) -> io::Result<Self> {
+        // the ring does the waiting; a nonblocking socket would fail with EAGAIN instead
+        listener.set_nonblocking(false)?;
+        Ok(UringListener{ring: ring.clone(), listener})
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn accept(&self) -> Accept<'_> {
+        Accept{listener: self, op: None}
+    }
+}
+
+impl AsRawFd for UringListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+/// The future from UringListener::accept; dropping it cancels the accept.
+pub struct Accept<'a> {
+    listener: &'a UringListener,
+    op: Option<usize>,
+}
+
+impl Future for Accept<'_> {
+    type Output = io::Result<(UringStream, SocketAddr)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let listener = self.listener;
+        let shared = listener.ring.shared();
+        let id = match self.op {
+            Some(id) => id,
+            None => {
+                let fd = Fd(listener.listener.as_raw_fd());
+                let entry = opcode::Accept::new(fd, ptr::null_mut(), ptr::null_mut())
+                    .flags(libc::SOCK_CLOEXEC)
+                    .build();
+                *self.op.insert(shared.start_accept(entry)?)
+            },
+        };
+        let (res, _) = ready!(shared.poll_op(id, cx));
+        self.op = None;
+        let socket = unsafe { TcpStream::from_raw_fd(result(res)? as RawFd) };
+        let peer = socket.peer_addr()?;
+        Poll::Ready(Ok((UringStream::from_socket(&listener.ring, socket), peer)))
+    }
+}
+
+impl Drop for Accept<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.op {
+            self.listener.ring.shared().abandon(id, true);
+        }
+    }
+}
+
+/// A TCP stream reading and writing through a Ring; clones share the socket, like async-std's.
+#[derive(Clone)]
+pub struct UringStream {
+    inner: Arc<StreamInner>,
+}
+
+struct StreamInner {
+    ring: Ring,
+    socket: TcpStream,
+    read: Mutex<ReadState>,
+    write: Mutex<WriteState>,
+}
+
+enum ReadState {
+    Idle,
+    Reading(usize),
+    // read, and not all handed out yet
+    Filled{buf: Buf, pos: usize, len: usize},
+}
+
+#[derive(Default)]
+struct WriteState {
+    // the write in flight, and how much of its buffer was written before it
+    op: Option<(usize, usize)>,
+    // the last write's buffer, for the next one to reuse
+    spare: Vec<u8>,
+}
+
+impl UringStream {
+    /// Reads and writes a connected socket, like one from socket::bind or a std listener,
+    /// through the ring.
+    pub fn from_std(ring: &Ring, socket: TcpStream) -> io::Result<Self> {
+        socket.set_nonblocking(false)?;
+        Ok(UringStream::from_socket(ring, socket))
+    }
+
+    fn from_socket(ring: &Ring, socket: TcpStream) -> Self {
+        UringStream{
+            inner: Arc::new(StreamInner{
+                ring: ring.clone(),
+                socket,
+                read: Mutex::new(ReadState::Idle),
+                write: Mutex::default(),
+            }),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.socket.local_addr()
+    }
+
+    /// For socket::SocketOptions and the like.
+    pub fn as_std(&self) -> &TcpStream {
+        &self.inner.socket
+    }
+
+    fn fd(&self) -> Fd {
+        Fd(self.inner.socket.as_raw_fd())
+    }
+
+    /// Starts writing `buf` from `offset`.
+    fn start_write(&self, state: &mut WriteState, buf: Vec<u8>, offset: usize) -> io::Result<()> {
+        let rest = &buf[offset..];
+        let entry = opcode::Write::new(self.fd(), rest.as_ptr(), rest.len().min(u32::MAX as usize) as u32).build();
+        let id = self.inner.ring.shared().start(entry, Some(Buf::Owned(buf)))?;
+        state.op = Some((id, offset));
+        Ok(())
+    }
+
+    /// Waits for the write in flight, and the rest of its buffer after a short write.
+    fn poll_written(&self, state: &mut WriteState, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some((id, offset)) = state.op {
+            let (res, buf) = ready!(self.inner.ring.shared().poll_op(id, cx));
+            state.op = None;
+            let buf = match buf {
+                Some(Buf::Owned(buf)) => buf,
+                _ => unreachable!("writes are from owned buffers"),
+            };
+            let offset = offset + match result(res)? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => n,
+            };
+            if offset < buf.len() {
+                self.start_write(state, buf, offset)?;
+            } else {
+                state.spare = buf;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsRawFd for UringStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.socket.as_raw_fd()
+    }
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut [u8]) -> Poll<io::Result<usize>> {
+        let shared = self.inner.ring.shared();
+        let mut state = self.inner.read.lock().unwrap();
+        loop {
+            match &mut *state {
+                ReadState::Idle => {
+                    if out.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let (buf, ptr) = shared.read_buf();
+                    let len = shared.buffer_size as u32;
+                    let entry = match buf {
+                        Buf::Fixed(index) => opcode::ReadFixed::new(self.fd(), ptr, len, index).build(),
+                        Buf::Owned(_) => opcode::Read::new(self.fd(), ptr, len).build(),
+                    };
+                    *state = ReadState::Reading(shared.start(entry, Some(buf))?);
+                },
+                ReadState::Reading(id) => {
+                    let (res, buf) = ready!(shared.poll_op(*id, cx));
+                    *state = ReadState::Idle;
+                    match result(res) {
+                        Ok(len) if len > 0 => *state = ReadState::Filled{buf: buf.unwrap(), pos: 0, len},
+                        res => {
+                            shared.release(buf);
+                            return Poll::Ready(res);
+                        },
+                    }
+                },
+                ReadState::Filled{buf, pos, len} => {
+                    let filled = &shared.bytes(buf, *len)[*pos..];
+                    let n = filled.len().min(out.len());
+                    out[..n].copy_from_slice(&filled[..n]);
+                    *pos += n;
+                    if *pos == *len {
+                        if let ReadState::Filled{buf, ..} = std::mem::replace(&mut *state, ReadState::Idle) {
+                            shared.release(Some(buf));
+                        }
+                    }
+                    return Poll::Ready(Ok(n));
+                },
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = self.inner.write.lock().unwrap();
+        ready!(self.poll_written(&mut state, cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut buf = std::mem::take(&mut state.spare);
+        buf.clear();
+        buf.extend_from_slice(data);
+        self.start_write(&mut state, buf, 0)?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.inner.write.lock().unwrap();
+        self.poll_written(&mut state, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        match self.inner.socket.shutdown(Shutdown::Write) {
+            Err(err) if err.kind() != io::ErrorKind::NotConnected => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for StreamInner {
+    fn drop(&mut self) {
+        let shared = self.ring.shared();
+        match std::mem::replace(self.read.get_mut().unwrap(), ReadState::Idle) {
+            ReadState::Reading(id) => shared.abandon(id, true),
+            ReadState::Filled{buf, ..} => shared.release(Some(buf)),
+            ReadState::Idle => {},
+        }
+        // what was written still goes out; the kernel keeps the socket open until it has
+        if let Some((id, _)) = self.write.get_mut().unwrap().op {
+            shared.abandon(id, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        error::Error,
+        io::{Read, Write},
+    };
+    use async_std::{
+        io::BufReader,
+        task,
+    };
+
+    use super::*;
+    use crate::{http, respond, Response};
+
+    // io_uring is often blocked in containers; there's nothing to test then
+    fn ring(options: RingOptions) -> Option<Ring> {
+        match Ring::new(options) {
+            Ok(ring) => Some(ring),
+            Err(err) => {
+                log::warn!("skipping, no io_uring: {}", err);
+                None
+            },
+        }
+    }
+
+    fn free_buffers(ring: &Ring) -> usize {
+        ring.shared().fixed.as_ref().map_or(0, |fixed| fixed.free.lock().unwrap().len())
+    }
+
+    #[async_std::test]
+    async fn test_echo() -> Result<(), Box<dyn Error>> {
+        // reads smaller than the message, from both kinds of buffer
+        for fixed_buffers in &[0, 1] {
+            let ring = match ring(RingOptions::new().fixed_buffers(*fixed_buffers).buffer_size(5)) {
+                Some(ring) => ring,
+                None => return Ok(()),
+            };
+            let listener = UringListener::bind(&ring, "127.0.0.1:0")?;
+            let addr = listener.local_addr()?;
+            let client = thread::spawn(move || {
+                let mut socket = TcpStream::connect(addr).unwrap();
+                socket.write_all(b"hello, world").unwrap();
+                socket.shutdown(Shutdown::Write).unwrap();
+                let mut echoed = vec!();
+                socket.read_to_end(&mut echoed).unwrap();
+                echoed
+            });
+            let (mut stream, peer) = listener.accept().await?;
+            assert_eq!(peer.ip(), addr.ip());
+            let mut received = vec!();
+            stream.read_to_end(&mut received).await?;
+            assert_eq!(received, b"hello, world");
+            stream.write_all(&received).await?;
+            stream.close().await?;
+            drop(stream);
+            assert_eq!(free_buffers(&ring), *fixed_buffers);
+            assert_eq!(task::spawn_blocking(|| client.join().unwrap()).await, b"hello, world");
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_http() -> Result<(), Box<dyn Error>> {
+        let ring = match ring(RingOptions::default()) {
+            Some(ring) => ring,
+            None => return Ok(()),
+        };
+        let listener = UringListener::bind(&ring, "127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream.clone());
+            let mut writer = stream;
+            let mut buf = vec![0; 65536];
+            let req = http(&mut reader, &mut buf).await.unwrap();
+            assert_eq!(req.path, "/uring");
+            respond(&mut writer, Response{code: 200, reason: "OK", headers: vec!()}).await.unwrap();
+            writer.close().await.unwrap();
+        });
+        let resp = task::spawn_blocking(move || ureq::get(&format!("http://{}/uring", addr)).call()).await;
+        assert_eq!(resp.status(), 200);
+        server.await;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dropped() -> Result<(), Box<dyn Error>> {
+        let ring = match ring(RingOptions::new().fixed_buffers(2)) {
+            Some(ring) => ring,
+            None => return Ok(()),
+        };
+        let listener = UringListener::bind(&ring, "127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        // an accept that's given up on is cancelled
+        assert!(futures::poll!(listener.accept()).is_pending());
+        let _client = TcpStream::connect(addr)?;
+        let (mut stream, _) = listener.accept().await?;
+        // so is a read that's waiting, and its buffer goes back
+        let mut buf = [0; 16];
+        assert!(futures::poll!(stream.read(&mut buf)).is_pending());
+        assert_eq!(free_buffers(&ring), 1);
+        drop(stream);
+        for _ in 0..100 {
+            if free_buffers(&ring) == 2 {
+                break;
+            }
+            task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(free_buffers(&ring), 2);
+        assert_eq!(ring.shared().ops.lock().unwrap().live, 0);
+        Ok(())
+    }
+}
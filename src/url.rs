@@ -0,0 +1,215 @@
+//! Request targets, split into their parts.
+//!
+//! Servers mostly see origin-form targets (`/path?query`), but forward proxies are sent
+//! absolute-form ones (`http://example.com/path?query`), and have to pass them on in
+//! origin-form:
+//!
+//! ```no_run
+//! # use std::io;
+//! # use async_std::net::TcpStream;
+//! # use oc_http::Request;
+//! # async fn serve(request: &Request<'_>) -> io::Result<()> {
+//! # let bad_request = io::Error::from(io::ErrorKind::InvalidInput);
+//! let url = request.url().ok_or(bad_request)?;
+//! if let Some(authority) = &url.authority {
+//!     let upstream = TcpStream::connect(authority).await?;
+//!     let forwarded = format!("{} {} HTTP/1.1\r\n", request.method, url.origin_form());
+//!     // ...
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Paths can be taken apart a segment at a time, and built back up:
+//!
+//! ```no_run
+//! # fn serve(mut url: oc_http::url::Url, id: String) {
+//! if let Some([users, id]) = url.decoded_segments().as_deref() {
+//!     if users == "users" { /* ... */ }
+//! }
+//! url.set_segments(["users", &id, "avatar"]);
+//! let location = url.to_string();
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{
+    encoding::{percent_decode, percent_encode, Set},
+    Request,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// Lowercased; only absolute-form targets have one.
+    pub scheme: Option<String>,
+    /// host[:port]; absolute-form and authority-form (CONNECT) targets have one.
+    pub authority: Option<String>,
+    /// Still percent-encoded. `/` for an absolute-form target without a path, `*` for
+    /// `OPTIONS *`, and empty for authority-form.
+    pub path: String,
+    /// Without the `?`.
+    pub query: Option<String>,
+    /// Without the `#`. Clients shouldn't send one, so it's only here for URLs being built.
+    pub fragment: Option<String>,
+}
+
+impl Url {
+    /// Splits a request target up; None if it's absolute-form with a scheme that isn't valid.
+    pub fn parse(target: &str) -> Option<Self> {
+        if target == "*" || target.starts_with('/') {
+            let (path, query, fragment) = split(target);
+            return Some(Url{scheme: None, authority: None, path: path.into(), query, fragment});
+        }
+        let (scheme, rest) = match target.split_once("://") {
+            Some((scheme, rest)) => (scheme, rest),
+            // authority-form, as CONNECT uses
+            None => return Some(Url{
+                scheme: None,
+                authority: Some(target.into()),
+                path: String::new(),
+                query: None,
+                fragment: None,
+            }),
+        };
+        let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+        if !valid_scheme {
+            return None;
+        }
+        let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+        let (authority, rest) = rest.split_at(end);
+        let (path, query, fragment) = split(rest);
+        Some(Url{
+            scheme: Some(scheme.to_ascii_lowercase()),
+            authority: Some(authority.into()),
+            path: if path.is_empty() { "/".into() } else { path.into() },
+            query,
+            fragment,
+        })
+    }
+
+    /// Whether this came from an absolute-form target.
+    pub fn is_absolute(&self) -> bool {
+        self.scheme.is_some()
+    }
+
+    /// The path and query, as a target to send on to an origin server.
+    pub fn origin_form(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+
+    /// The path's segments, still percent-encoded; `/a/b/` has three, the last empty.
+    /// There are none for `*` and authority-form targets, which have no path.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.path.strip_prefix('/').into_iter().flat_map(|path| path.split('/'))
+    }
+
+    /// The segments, decoded; None if any of them isn't utf-8 once it is. Decoded
+    /// segments can have `/` in them, which is why the path isn't decoded as a whole.
+    pub fn decoded_segments(&self) -> Option<Vec<String>> {
+        self.segments().map(percent_decode).collect()
+    }
+
+    /// Replaces the path with one made of the (not yet encoded) segments.
+    pub fn set_segments<I, S>(&mut self, segments: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.path = segments.into_iter()
+            .map(|segment| format!("/{}", percent_encode(segment.as_ref(), Set::PathSegment)))
+            .collect();
+        if self.path.is_empty() {
+            self.path.push('/');
+        }
+    }
+}
+
+/// Puts the URL back together; scheme and authority only if there are both.
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.scheme, &self.authority) {
+            (Some(scheme), Some(authority)) => write!(f, "{}://{}", scheme, authority)?,
+            (None, Some(authority)) if self.path.is_empty() => write!(f, "{}", authority)?,
+            _ => {},
+        }
+        write!(f, "{}", self.origin_form())?;
+        match &self.fragment {
+            Some(fragment) => write!(f, "#{}", fragment),
+            None => Ok(()),
+        }
+    }
+}
+
+/// the path, query and fragment
+fn split(target: &str) -> (&str, Option<String>, Option<String>) {
+    let (target, fragment) = match target.split_once('#') {
+        Some((target, fragment)) => (target, Some(fragment.into())),
+        None => (target, None),
+    };
+    match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.into()), fragment),
+        None => (target, None, fragment),
+    }
+}
+
+impl Request<'_> {
+    /// The request target, split up; see Url::parse.
+    pub fn url(&self) -> Option<Url> {
+        Url::parse(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http;
+
+    #[async_std::test]
+    async fn test_absolute_form() {
+        let mut stream = futures::io::Cursor::new(Vec::from(&b"GET HTTP://example.com:8080/a%20b?c=d HTTP/1.1\r\nHost: example.com:8080\r\n\r\n"[..]));
+        let mut buf = vec![0; 1024];
+        let req = http(&mut stream, &mut buf).await.unwrap();
+        let url = req.url().unwrap();
+        assert_eq!(url, Url{
+            scheme: Some("http".into()),
+            authority: Some("example.com:8080".into()),
+            path: "/a%20b".into(),
+            query: Some("c=d".into()),
+            fragment: None,
+        });
+        assert!(url.is_absolute());
+        assert_eq!(url.origin_form(), "/a%20b?c=d");
+
+        let url = Url::parse("http://example.com?x").unwrap();
+        assert_eq!((&url.path[..], url.origin_form()), ("/", "/?x".into()));
+        let url = Url::parse("/p?q#frag").unwrap();
+        assert_eq!((url.authority.as_deref(), url.origin_form()), (None, "/p?q".into()));
+        assert_eq!(url.fragment.as_deref(), Some("frag"));
+        assert_eq!(Url::parse("*").unwrap().path, "*");
+        assert_eq!(Url::parse("example.com:443").unwrap().authority.as_deref(), Some("example.com:443"));
+        assert_eq!(Url::parse("1http://example.com/"), None);
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut url = Url::parse("http://example.com/users/a%2Fb/?x=1#top").unwrap();
+        assert_eq!(url.segments().collect::<Vec<_>>(), vec!("users", "a%2Fb", ""));
+        assert_eq!(url.decoded_segments().unwrap(), vec!("users", "a/b", ""));
+        assert_eq!(url.to_string(), "http://example.com/users/a%2Fb/?x=1#top");
+
+        url.set_segments(["users", "é d", "avatar"]);
+        assert_eq!(url.path, "/users/%C3%A9%20d/avatar");
+        assert_eq!(url.to_string(), "http://example.com/users/%C3%A9%20d/avatar?x=1#top");
+        url.set_segments(Vec::<String>::new());
+        assert_eq!(url.origin_form(), "/?x=1");
+
+        assert_eq!(Url::parse("/").unwrap().segments().collect::<Vec<_>>(), vec!(""));
+        assert_eq!(Url::parse("*").unwrap().segments().count(), 0);
+        assert_eq!(Url::parse("/%ff").unwrap().decoded_segments(), None);
+        assert_eq!(Url::parse("example.com:443").unwrap().to_string(), "example.com:443");
+    }
+}
@@ -0,0 +1,140 @@
+//! The Vary header, gathered up while a request is handled.
+//!
+//! Anything that picks a response based on a request header adds it, and the merged header
+//! goes on the response at the end, along with anything already there:
+//!
+//! ```no_run
+//! # use oc_http::{vary::Vary, Response};
+//! # fn serve(mut response: Response, cors_applies: bool) {
+//! let mut vary = Vary::new();
+//! vary.add("Accept-Encoding");
+//! if cors_applies {
+//!     vary.add("Origin");
+//! }
+//! vary.apply(&mut response);
+//! # }
+//! ```
+use std::fmt;
+
+use crate::{split_quoted, Response};
+
+/// Header names the response depends on, each once, in the order they were added.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vary {
+    names: Vec<String>,
+    // `*`: the response depends on more than headers, so caches can't reuse it
+    any: bool,
+}
+
+impl Vary {
+    pub fn new() -> Self {
+        Vary::default()
+    }
+
+    /// Parses a Vary header value.
+    pub fn parse(value: &[u8]) -> Self {
+        let mut vary = Vary::new();
+        for name in split_quoted(&String::from_utf8_lossy(value), ',') {
+            vary.add(name);
+        }
+        vary
+    }
+
+    /// Adds a header name, unless it's there already in any case. `*` takes the place of
+    /// everything else.
+    pub fn add(&mut self, name: &str) -> &mut Self {
+        if name == "*" {
+            self.any = true;
+            self.names.clear();
+        } else if !self.any && !self.names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            self.names.push(name.into());
+        }
+        self
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.any || self.names.iter().any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// The header names, in the order they were added; None for `*`.
+    pub fn names(&self) -> Option<&[String]> {
+        (!self.any).then_some(&self.names[..])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.any && self.names.is_empty()
+    }
+
+    /// Merges in the response's own Vary headers, and replaces them with one for the lot;
+    /// the response is left alone if nothing varies.
+    pub fn apply(&self, response: &mut Response) {
+        let mut merged = Vary::new();
+        response.headers.retain(|(name, value)| {
+            if !name.eq_ignore_ascii_case("Vary") {
+                return true;
+            }
+            for name in split_quoted(&String::from_utf8_lossy(value), ',') {
+                merged.add(name);
+            }
+            false
+        });
+        if self.any {
+            merged.add("*");
+        }
+        for name in &self.names {
+            merged.add(name);
+        }
+        if !merged.is_empty() {
+            response.headers.push(merged.header());
+        }
+    }
+
+    pub fn header(&self) -> (String, Vec<u8>) {
+        ("Vary".into(), self.to_string().into_bytes())
+    }
+}
+
+impl fmt::Display for Vary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.any {
+            return write!(f, "*");
+        }
+        write!(f, "{}", self.names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vary() {
+        let mut vary = Vary::new();
+        vary.add("Accept-Encoding").add("Accept").add("accept-encoding");
+        assert_eq!(vary.to_string(), "Accept-Encoding, Accept");
+        assert!(vary.contains("ACCEPT"));
+        assert!(!vary.contains("Origin"));
+
+        let mut response = Response{
+            headers: vec!(
+                ("vary".into(), Vec::from("Origin, accept")),
+                ("Content-Length".into(), Vec::from("0")),
+            ),
+            ..Response::default()
+        };
+        vary.apply(&mut response);
+        assert_eq!(response.headers, vec!(
+            ("Content-Length".into(), Vec::from("0")),
+            ("Vary".into(), Vec::from("Origin, accept, Accept-Encoding")),
+        ));
+
+        // nothing to say, so nothing's added
+        let mut response = Response::default();
+        Vary::new().apply(&mut response);
+        assert!(response.headers.is_empty());
+
+        vary.add("*");
+        assert_eq!(vary.to_string(), "*");
+        assert_eq!(Vary::parse(b"a, *"), Vary::parse(b"*"));
+    }
+}
@@ -2,10 +2,15 @@ use std::{
     io,
     convert::TryFrom,
     fmt,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
+use bytes::{Bytes, BytesMut};
 use sha1::{Sha1, Digest};
-use crate::{respond, Request, Response};
+use crate::{respond, upgrade, Request, Response};
 use nom::{
     IResult,
     bits::{
@@ -14,13 +19,38 @@ use nom::{
     },
 };
 use futures::{
+    channel::oneshot,
+    future,
+    lock::{Mutex, OwnedMutexGuard, OwnedMutexLockFuture},
+    pin_mut,
+    ready,
+    task::{Context, Poll},
     AsyncRead,
     AsyncWrite,
     AsyncWriteExt,
-    AsyncReadExt,
+    Future,
+    Sink,
+    Stream,
 };
 
-const MAX_PAYLOAD_SIZE: u64 = 16_000;
+pub mod deflate;
+pub mod extensions;
+pub mod hub;
+pub mod limit;
+pub mod metrics;
+pub mod pubsub;
+pub mod queue;
+pub mod reconnect;
+
+use deflate::{DeflateConfig, Deflater, Inflater, RSV1};
+use extensions::Extension;
+use limit::{ConnectionLimit, Permit};
+use metrics::{ConnectionMetrics, Metrics};
+
+/// Default limit on the size of a message passed to recv(); see set_max_payload_size.
+pub const MAX_PAYLOAD_SIZE: u64 = 16_000;
+/// How long WebSocketConfig waits for a Pong, or for the peer's Close, by default.
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub enum WebSocketError {
@@ -32,9 +62,69 @@ pub enum WebSocketError {
     NoKey,
     TooBig,
     ProtocolError,
-    IOError(String),
+    /// The underlying stream failed; shared so the error stays Clone.
+    IOError(Arc<io::Error>),
     BadOpcode,
+    InvalidUtf8,
+    TimedOut,
+    TooSlow,
     ConnectionClosed,
+    /// The request's Origin was refused by UpgradeConfig; a 403 has been sent.
+    OriginNotAllowed,
+    /// The client's IP already has as many websockets open as UpgradeConfig::limit_per_ip
+    /// allows; a 429 has been sent.
+    TooManyConnections,
+    /// The Sec-WebSocket-Extensions header couldn't be parsed.
+    BadExtensions,
+    /// A message couldn't be converted to or from JSON (see the json feature).
+    Json(Arc<dyn std::error::Error + Send + Sync>),
+}
+
+impl WebSocketError {
+    /// The status code to send in a Close frame when this error fails the connection, if
+    /// it was caused by the peer breaking the protocol.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::ProtocolError | WebSocketError::BadOpcode => Some(1002),
+            WebSocketError::InvalidUtf8 => Some(1007),
+            WebSocketError::TooBig => Some(1009),
+            _ => None,
+        }
+    }
+
+    /// The request header that made the handshake fail, if that's what this error is about.
+    pub fn header(&self) -> Option<&'static str> {
+        match self {
+            WebSocketError::ConnectionNotUpgrade | WebSocketError::NoConnectionHeader => Some("Connection"),
+            WebSocketError::NoUpgradeHeader | WebSocketError::UpgradeNotToWebSocket => Some("Upgrade"),
+            WebSocketError::WrongVersion => Some("Sec-WebSocket-Version"),
+            WebSocketError::NoKey => Some("Sec-WebSocket-Key"),
+            WebSocketError::OriginNotAllowed => Some("Origin"),
+            WebSocketError::BadExtensions => Some("Sec-WebSocket-Extensions"),
+            _ => None,
+        }
+    }
+
+    /// The HTTP response to send when this error fails the handshake; None for errors that
+    /// don't come from the handshake.
+    pub fn handshake_response(&self) -> Option<Response> {
+        let (code, reason, mut headers): (_, _, Vec<(String, Vec<u8>)>) = match self {
+            WebSocketError::ConnectionNotUpgrade | WebSocketError::NoConnectionHeader | WebSocketError::NoUpgradeHeader =>
+                (426, "Upgrade Required", vec!(
+                    ("Upgrade".into(), Vec::from("websocket")),
+                    ("Connection".into(), Vec::from("Upgrade")),
+                )),
+            WebSocketError::WrongVersion =>
+                (426, "Upgrade Required", vec!(("Sec-WebSocket-Version".into(), Vec::from("13")))),
+            WebSocketError::UpgradeNotToWebSocket | WebSocketError::NoKey | WebSocketError::BadExtensions =>
+                (400, "Bad Request", vec!()),
+            WebSocketError::OriginNotAllowed => (403, "Forbidden", vec!()),
+            WebSocketError::TooManyConnections => (429, "Too Many Requests", vec!()),
+            _ => return None,
+        };
+        headers.push(("Content-Length".into(), Vec::from("0")));
+        Some(Response{code, reason, headers})
+    }
 }
 
 impl From<io::Error> for WebSocketError {
@@ -42,7 +132,7 @@ impl From<io::Error> for WebSocketError {
         if err.kind() == io::ErrorKind::UnexpectedEof {
             WebSocketError::ConnectionClosed
         } else {
-            WebSocketError::IOError(format!("{:?}", err))
+            WebSocketError::IOError(Arc::new(err))
         }
     }
 }
@@ -55,7 +145,36 @@ impl<E> From<nom::Err<E>> for WebSocketError {
 
 impl fmt::Display for WebSocketError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "problem establishing websocket connection")
+        match self {
+            WebSocketError::ConnectionNotUpgrade => write!(f, "websocket handshake: Connection header doesn't include Upgrade"),
+            WebSocketError::NoConnectionHeader => write!(f, "websocket handshake: missing Connection header"),
+            WebSocketError::NoUpgradeHeader => write!(f, "websocket handshake: missing Upgrade header"),
+            WebSocketError::UpgradeNotToWebSocket => write!(f, "websocket handshake: Upgrade header isn't websocket"),
+            WebSocketError::WrongVersion => write!(f, "websocket handshake: missing or unsupported Sec-WebSocket-Version"),
+            WebSocketError::NoKey => write!(f, "websocket handshake: missing Sec-WebSocket-Key header"),
+            WebSocketError::OriginNotAllowed => write!(f, "websocket handshake: Origin not allowed"),
+            WebSocketError::TooManyConnections => write!(f, "websocket handshake: too many connections from the client's IP"),
+            WebSocketError::BadExtensions => write!(f, "websocket handshake: malformed Sec-WebSocket-Extensions header"),
+            WebSocketError::TooBig => write!(f, "websocket message too big"),
+            WebSocketError::ProtocolError => write!(f, "websocket protocol error"),
+            WebSocketError::IOError(err) => write!(f, "websocket io error: {}", err),
+            WebSocketError::BadOpcode => write!(f, "websocket frame with a bad opcode"),
+            WebSocketError::InvalidUtf8 => write!(f, "websocket text message isn't valid utf-8"),
+            WebSocketError::TimedOut => write!(f, "timed out waiting for a websocket message"),
+            WebSocketError::TooSlow => write!(f, "websocket client too slow to keep up"),
+            WebSocketError::ConnectionClosed => write!(f, "websocket connection closed"),
+            WebSocketError::Json(err) => write!(f, "websocket message json: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebSocketError::IOError(err) => Some(err.as_ref()),
+            WebSocketError::Json(err) => Some(err.as_ref()),
+            _ => None,
+        }
     }
 }
 
@@ -71,10 +190,7 @@ pub enum MessageType {
 
 impl MessageType {
     pub fn is_control(&self) -> bool {
-        match self {
-            MessageType::Ping | MessageType::Pong | MessageType::Close => true,
-            _ => false,
-        }
+        matches!(self, MessageType::Ping | MessageType::Pong | MessageType::Close)
     }
 }
 
@@ -94,9 +210,9 @@ impl TryFrom<u8> for MessageType {
     }
 }
 
-impl Into<u8> for MessageType {
-    fn into(self) -> u8 {
-        match self {
+impl From<MessageType> for u8 {
+    fn from(typ: MessageType) -> u8 {
+        match typ {
             MessageType::Continuation => 0x0,
             MessageType::Text => 0x1,
             MessageType::Binary => 0x2,
@@ -107,21 +223,430 @@ impl Into<u8> for MessageType {
     }
 }
 
+/// A single frame on the wire, for when messages are too high level; e.g. when implementing
+/// an extension or streaming a message out while it's being produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame{
+    pub fin: bool,
+    /// RSV1-3, with RSV1 as the highest of the three bits
+    pub rsv: u8,
+    pub typ: MessageType,
+    pub payload: Bytes,
+}
+
+/// Which end of the connection we are; servers require clients to mask every frame, and
+/// clients must reject masked frames from the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message{
     pub typ: MessageType,
-    pub contents: Vec<u8>,
+    /// Cheap to clone, so one message can be sent to many connections without copying it.
+    pub contents: Bytes,
+}
+
+impl Message {
+    pub fn text(txt: &str) -> Self {
+        Message{
+            typ: MessageType::Text,
+            contents: Bytes::copy_from_slice(txt.as_bytes()),
+        }
+    }
+
+    /// Builds a Close message with the given status code and (possibly empty) reason.
+    pub fn close(code: u16, reason: &str) -> Self {
+        let mut contents = Vec::from(&code.to_be_bytes()[..]);
+        contents.extend(reason.as_bytes());
+        Message{
+            typ: MessageType::Close,
+            contents: contents.into(),
+        }
+    }
+
+    pub fn binary<B: Into<Bytes>>(contents: B) -> Self {
+        Message{
+            typ: MessageType::Binary,
+            contents: contents.into(),
+        }
+    }
+
+    /// Returns the contents of a Text message; None for any other message type.
+    pub fn as_text(&self) -> Option<&str> {
+        if self.typ != MessageType::Text {
+            return None;
+        }
+        // text frames are validated as they're received
+        std::str::from_utf8(&self.contents).ok()
+    }
+
+    /// Like as_text, but takes the message.
+    pub fn into_text(self) -> Option<String> {
+        self.as_text().map(Into::into)
+    }
+
+    /// Encodes the value as a Text message.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(value: &T) -> Result<Self, WebSocketError> {
+        let contents = serde_json::to_vec(value).map_err(|err| WebSocketError::Json(Arc::new(err)))?;
+        Ok(Message{
+            typ: MessageType::Text,
+            contents: contents.into(),
+        })
+    }
+
+    /// Decodes a Text or Binary message as JSON.
+    #[cfg(feature = "json")]
+    pub fn into_json<T: serde::de::DeserializeOwned>(self) -> Result<T, WebSocketError> {
+        if self.typ != MessageType::Text && self.typ != MessageType::Binary {
+            return Err(WebSocketError::BadOpcode);
+        }
+        serde_json::from_slice(&self.contents).map_err(|err| WebSocketError::Json(Arc::new(err)))
+    }
+}
+
+/// Completes the websocket handshake for the request and splits the stream into a reader and
+/// a writer. `buffered` is what's left in the buffer of the BufReader the request was parsed
+/// with (reader.buffer()), since a client may send its first frame right behind the request;
+/// the reader reads it before anything from the stream. Pass `&[]` if there wasn't one.
+pub async fn upgrade<'a, S>(req: &Request<'a>, stream: S, buffered: &[u8]) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
+where S: AsyncRead + AsyncWrite + Clone + Unpin
+{
+    upgrade_with(&UpgradeConfig::default(), req, stream, buffered).await
+}
+
+type OriginCheck = Box<dyn Fn(Option<&str>) -> bool + Send + Sync>;
+// accepted extensions, each with the RSV bits it uses
+type Accepted = Vec<(Extension, u8)>;
+type ExtensionNegotiator = Box<dyn Fn(&[Extension]) -> Accepted + Send + Sync>;
+
+/// Checks applied by upgrade_with before it agrees to switch protocols.
+#[derive(Default)]
+pub struct UpgradeConfig {
+    origin_check: Option<OriginCheck>,
+    negotiator: Option<ExtensionNegotiator>,
+    metrics: Option<Arc<dyn Metrics>>,
+    websocket: Option<WebSocketConfig>,
+    limit: Option<ConnectionLimit>,
+}
+
+impl UpgradeConfig {
+    pub fn new() -> Self {
+        UpgradeConfig::default()
+    }
+
+    /// Decides whether to accept a connection based on its Origin header (None if the
+    /// client didn't send one). Refused connections get a 403.
+    pub fn check_origin<F>(mut self, check: F) -> Self
+    where F: Fn(Option<&str>) -> bool + Send + Sync + 'static
+    {
+        self.origin_check = Some(Box::new(check));
+        self
+    }
+
+    /// Only accepts browsers from one of the given origins (e.g. "https://example.com").
+    /// Clients that don't send an Origin at all aren't browsers, so they're still accepted;
+    /// use check_origin to refuse those too.
+    pub fn allow_origins<I, T>(self, origins: I) -> Self
+    where I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let origins: Vec<String> = origins.into_iter().map(Into::into).collect();
+        self.check_origin(move |origin| match origin {
+            Some(origin) => origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+            None => true,
+        })
+    }
+
+    /// Picks which of the extensions the client offered to use. The callback gets the offers
+    /// in the client's order of preference and returns the accepted ones, with the parameters
+    /// to send back, each paired with the RSV bits its frames use. Those bits are allowed on
+    /// the reader, and WebSocketReader::extensions lists what was agreed. Without this, no
+    /// extensions are accepted.
+    pub fn negotiate_extensions<F>(mut self, negotiate: F) -> Self
+    where F: Fn(&[Extension]) -> Vec<(Extension, u8)> + Send + Sync + 'static
+    {
+        self.negotiator = Some(Box::new(negotiate));
+        self
+    }
+
+    /// Reports activity on every connection upgraded with this config to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets the reader's and writer's limits from the config on every connection upgraded
+    /// with this one, and offers its compression; the rest of it needs a shared writer, so
+    /// see WebSocketConfig::apply.
+    pub fn websocket(mut self, config: WebSocketConfig) -> Self {
+        self.websocket = Some(config);
+        self
+    }
+
+    /// Caps the websockets each client IP can have open at once; over the cap, handshakes
+    /// are answered with a 429. Only upgrade_from knows the client's IP, so the other
+    /// upgrades aren't limited.
+    pub fn limit_per_ip(mut self, limit: ConnectionLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn origin_allowed(&self, req: &Request) -> bool {
+        let check = match &self.origin_check {
+            Some(check) => check,
+            None => return true,
+        };
+        match req.header("Origin") {
+            // an Origin that isn't even utf-8 can't match anything we'd allow
+            Some(header) => match std::str::from_utf8(header) {
+                Ok(origin) => check(Some(origin)),
+                Err(_) => false,
+            },
+            None => check(None),
+        }
+    }
+}
+
+/// Like upgrade, but applies the checks in config before sending the 101. A request refused
+/// by config is answered with a 403 and WebSocketError::OriginNotAllowed is returned.
+pub async fn upgrade_with<'a, S>(config: &UpgradeConfig, req: &Request<'a>, stream: S, buffered: &[u8]) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
+where S: AsyncRead + AsyncWrite + Clone + Unpin
+{
+    handshake(config, None, req, stream, buffered, false).await
+}
+
+/// Like upgrade_with, but every failed handshake is answered with an HTTP error (see
+/// WebSocketError::handshake_response) instead of leaving the client hanging.
+pub async fn upgrade_or_reject<'a, S>(config: &UpgradeConfig, req: &Request<'a>, stream: S, buffered: &[u8]) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
+where S: AsyncRead + AsyncWrite + Clone + Unpin
+{
+    handshake(config, None, req, stream, buffered, true).await
+}
+
+/// Like upgrade_or_reject, for a connection from `peer`, which counts against
+/// UpgradeConfig::limit_per_ip until the reader is dropped.
+pub async fn upgrade_from<'a, S>(config: &UpgradeConfig, peer: IpAddr, req: &Request<'a>, stream: S, buffered: &[u8]) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
+where S: AsyncRead + AsyncWrite + Clone + Unpin
+{
+    handshake(config, Some(peer), req, stream, buffered, true).await
 }
 
-pub async fn upgrade<'a, S>(req: &Request<'a>, mut stream: S) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
+async fn handshake<'a, S>(config: &UpgradeConfig, peer: Option<IpAddr>, req: &Request<'a>, mut stream: S, buffered: &[u8], reject: bool) -> Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>
 where S: AsyncRead + AsyncWrite + Clone + Unpin
 {
+    let checked = check_request(config, req).and_then(|checked| {
+        let permit = match (&config.limit, peer) {
+            (Some(limit), Some(peer)) => Some(limit.try_acquire(peer).ok_or(WebSocketError::TooManyConnections)?),
+            _ => None,
+        };
+        Ok((checked, permit))
+    });
+    let ((key, accepted, deflate), permit) = match checked {
+        Ok(checked) => checked,
+        Err(err) => {
+            // refusals by config always get an answer; it's what the checks are for
+            if reject || matches!(err, WebSocketError::OriginNotAllowed | WebSocketError::TooManyConnections) {
+                if let Some(response) = err.handshake_response() {
+                    respond(&mut stream, response).await?;
+                    stream.flush().await?;
+                }
+            }
+            return Err(err);
+        },
+    };
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    // magic string from the interwebs
+    hasher.update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
+    let result = hasher.finalize();
+    let mut headers = vec!(("Sec-WebSocket-Accept".into(), base64::encode(&result[..]).into()));
+    let (extensions, rsv): (Vec<Extension>, Vec<u8>) = accepted.into_iter().unzip();
+    if !extensions.is_empty() {
+        headers.push(("Sec-WebSocket-Extensions".into(), extensions::format(&extensions).into()));
+    }
+    // complete the handshake
+    upgrade::switch(&mut stream, "websocket", headers).await?;
+    let (mut rdr, mut wrt) = from_stream(stream, Role::Server);
+    rdr.prepend(buffered);
+    if let Some(metrics) = &config.metrics {
+        rdr.set_metrics(metrics.clone());
+        wrt.set_metrics(metrics.clone());
+    }
+    rdr.allow_rsv_bits(rsv.into_iter().fold(0, |bits, b| bits | b));
+    rdr.extensions = extensions;
+    rdr.permit = permit;
+    if let Some(websocket) = &config.websocket {
+        websocket.set_limits(&mut rdr, &mut wrt);
+        if let (Some(client_resets), Some(compression)) = (deflate, &websocket.compression) {
+            rdr.inflate = Some(Inflater::new(client_resets));
+            wrt.deflate = Some(Deflater::new(compression.level, compression.min_size));
+        }
+    }
+    Ok((rdr, wrt))
+}
+
+/// The tunables for an endpoint's connections, in one place, so they can differ between
+/// endpoints:
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use async_std::{io::BufReader, net::TcpStream, task};
+/// # use oc_http::{websocket::{upgrade, WebSocketConfig, WebSocketError}, Request};
+/// # async fn serve(req: &Request<'_>, stream: TcpStream, reader: BufReader<TcpStream>) -> Result<(), WebSocketError> {
+/// let config = WebSocketConfig::new().max_payload_size(1 << 20).heartbeat_interval(Duration::from_secs(30));
+/// let (mut rdr, wrt) = config.apply(upgrade(&req, stream, reader.buffer()).await?);
+/// task::spawn(config.heartbeat(wrt.clone()));
+/// while let Ok(msg) = rdr.recv().await { /* ... */ }
+/// config.close(rdr, &wrt, 1000, "bye").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketConfig {
+    /// For from_stream_with; upgrades are always the server.
+    pub role: Role,
+    /// See WebSocketReader::set_max_payload_size.
+    pub max_payload_size: u64,
+    /// See WebSocketWriter::set_auto_flush.
+    pub auto_flush: Option<usize>,
+    /// Answer Pings from inside recv() (see WebSocketReader::answer_pings); heartbeat needs
+    /// it, to see the Pongs.
+    pub auto_pong: bool,
+    /// How often heartbeat pings; None for never.
+    pub heartbeat_interval: Option<Duration>,
+    /// How long heartbeat waits for each Pong, and close for the peer's Close.
+    pub close_timeout: Duration,
+    /// permessage-deflate, for clients that offer it; None (the default) to never compress.
+    /// It's agreed on in the handshake, so it's only used on connections upgraded with an
+    /// UpgradeConfig holding this config (see UpgradeConfig::websocket).
+    pub compression: Option<DeflateConfig>,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig{
+            role: Role::Server,
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            auto_flush: None,
+            auto_pong: true,
+            heartbeat_interval: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            compression: None,
+        }
+    }
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        WebSocketConfig::default()
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn max_payload_size(mut self, size: u64) -> Self {
+        self.max_payload_size = size;
+        self
+    }
+
+    pub fn auto_flush(mut self, threshold: Option<usize>) -> Self {
+        self.auto_flush = threshold;
+        self
+    }
+
+    pub fn auto_pong(mut self, auto_pong: bool) -> Self {
+        self.auto_pong = auto_pong;
+        self
+    }
+
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    pub fn compression(mut self, compression: DeflateConfig) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    fn set_limits<S>(&self, rdr: &mut WebSocketReader<S>, wrt: &mut WebSocketWriter<S>)
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        rdr.set_max_payload_size(self.max_payload_size);
+        wrt.set_auto_flush(self.auto_flush);
+    }
+
+    /// Sets up a connection from upgrade() or from_stream() with the config, sharing the
+    /// writer so Pongs can be sent from inside recv().
+    pub fn apply<S>(&self, connection: (WebSocketReader<S>, WebSocketWriter<S>)) -> (WebSocketReader<S>, SharedWebSocketWriter<S>)
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let (mut rdr, mut wrt) = connection;
+        self.set_limits(&mut rdr, &mut wrt);
+        let wrt = wrt.into_shared();
+        if self.auto_pong {
+            rdr.answer_pings(wrt.clone());
+        }
+        (rdr, wrt)
+    }
+
+    /// Pings every heartbeat_interval until a Pong doesn't come back within close_timeout
+    /// (TimedOut) or a ping can't be sent; for the caller to spawn alongside whatever calls
+    /// recv(), and to fail the connection when it finishes. Without an interval it finishes
+    /// straight away.
+    pub fn heartbeat<S>(&self, writer: SharedWebSocketWriter<S>) -> impl Future<Output = Result<(), WebSocketError>>
+    where S: AsyncWrite + Unpin
+    {
+        let (interval, timeout) = (self.heartbeat_interval, self.close_timeout);
+        async move {
+            let interval = match interval {
+                Some(interval) => interval,
+                None => return Ok(()),
+            };
+            loop {
+                futures_timer::Delay::new(interval).await;
+                let ping = writer.ping();
+                let deadline = futures_timer::Delay::new(timeout);
+                pin_mut!(ping, deadline);
+                match future::select(ping, deadline).await {
+                    future::Either::Left((Ok(_), _)) => continue,
+                    future::Either::Left((Err(err), _)) => return Err(err),
+                    future::Either::Right(_) => return Err(WebSocketError::TimedOut),
+                }
+            }
+        }
+    }
+
+    /// WebSocketReader::close, with close_timeout.
+    pub async fn close<S>(&self, rdr: WebSocketReader<S>, wrt: &SharedWebSocketWriter<S>, code: u16, reason: &str) -> Result<bool, WebSocketError>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        rdr.close(wrt.clone(), code, reason, self.close_timeout).await
+    }
+}
+
+
+/// validates the upgrade request, returning the key to hash in the response, the
+/// extensions accepted by the config, and if permessage-deflate was one, whether the client
+/// resets its context after each message
+fn check_request<'a>(config: &UpgradeConfig, req: &Request<'a>) -> Result<(&'a [u8], Accepted, Option<bool>), WebSocketError> {
     // sanity check that required headers are in place
-    match req.headers.get("Connection") {
+    match req.header("Connection") {
         Some(header) => {
             let mut ok = false;
-            if let Ok(txt) = std::str::from_utf8(header.0) {
-                if let Some(_) = txt.find("Upgrade") {
+            if let Ok(txt) = std::str::from_utf8(header) {
+                if txt.contains("Upgrade") {
                     ok = true;
                 }
             }
@@ -131,246 +656,1813 @@ where S: AsyncRead + AsyncWrite + Clone + Unpin
         },
         None => Err(WebSocketError::NoConnectionHeader)?,
     };
-    match req.headers.get("Upgrade") {
-        Some(header) => if header.0 != b"websocket" { Err(WebSocketError::UpgradeNotToWebSocket)? },
+    match req.header("Upgrade") {
+        Some(header) => if header != b"websocket" { Err(WebSocketError::UpgradeNotToWebSocket)? },
         None => Err(WebSocketError::NoUpgradeHeader)?,
     };
-    match req.headers.get("Sec-WebSocket-Version") {
-        Some(header) => if header.0 != b"13" { Err(WebSocketError::WrongVersion)? },
+    match req.header("Sec-WebSocket-Version") {
+        Some(header) => if header != b"13" { Err(WebSocketError::WrongVersion)? },
         None => Err(WebSocketError::WrongVersion)?,
     };
     // get the key we need to hash in the response
-    let key = match req.headers.get("Sec-WebSocket-Key") {
-        Some(k) => k.0,
+    let key = match req.header("Sec-WebSocket-Key") {
+        Some(k) => k,
         None => Err(WebSocketError::NoKey)?,
     };
-    let mut hasher = Sha1::new();
-    hasher.update(&key);
-    // magic string from the interwebs
-    hasher.update("258EAFA5-E914-47DA-95CA-C5AB0DC85B11");
-    let result = hasher.finalize();
-    let mut headers = vec!();
-    headers.push(("Upgrade".into(), Vec::from("websocket")));
-    headers.push(("Connection".into(), Vec::from("Upgrade")));
-    headers.push(("Sec-WebSocket-Accept".into(), base64::encode(&result[..]).into()));
-    // complete the handshake
-    respond(&mut stream, Response{
-        code: 101,
-        reason: "Switching Protocols",
-        headers,
-    }).await?;
-    stream.flush().await?;
-    Ok((WebSocketReader{
-        stream: stream.clone(),
-        buffered_message: None,
-    }, WebSocketWriter{
-        stream,
-    }))
-}
-
-pub struct WebSocketReader<S>
-where S: AsyncRead + Unpin
-{
-    stream: S,
-    buffered_message: Option<(MessageType, Vec<u8>)>,
-}
-
-impl<S> WebSocketReader<S>
-where S: AsyncRead + Unpin
-{
-    pub async fn recv(&mut self) -> Result<Message, WebSocketError> {
-        loop {
-            let header = read_header(&mut self.stream).await?;
-            if header.payload_len > MAX_PAYLOAD_SIZE {
-                Err(WebSocketError::TooBig)?;
-            }
-            // read the body
-            let mut contents = vec![0u8; header.payload_len as usize];
-            self.stream.read_exact(&mut contents).await?;
-            // unmask the value in-place
-            let len = contents.len();
-            for i in 0..len {
-                contents[i] = contents[i] ^ header.masking_key[i % header.masking_key.len()];
-            }
-            let typ = MessageType::try_from(header.opcode)?;
-            if typ.is_control() {
-                return Ok(Message{contents, typ});
-            }
-            // if this is a new fragment chain, start it
-            if header.fin == 0 && typ != MessageType::Continuation {
-                self.buffered_message = Some((typ, contents));
-            } else if header.fin == 0 {
-                match &mut self.buffered_message {
-                    Some((_, old)) => {
-                        old.append(&mut contents);
-                    },
-                    None => return Err(WebSocketError::BadOpcode),
-                }
-            } else {
-                let (typ, contents) = self.buffered_message.take().unwrap_or((typ, contents));
-                return Ok(Message{typ, contents});
-            }
+    if !config.origin_allowed(req) {
+        return Err(WebSocketError::OriginNotAllowed);
+    }
+    let offers = || extensions::parse(&req.header_values("Sec-WebSocket-Extensions"));
+    let mut accepted = match &config.negotiator {
+        Some(negotiate) => negotiate(&offers()?),
+        None => vec!(),
+    };
+    let mut deflate = None;
+    let compression = config.websocket.as_ref().and_then(|websocket| websocket.compression.as_ref());
+    // unless the negotiator has already given RSV1 to something else
+    if let Some(compression) = compression.filter(|_| accepted.iter().all(|(_, rsv)| rsv & RSV1 == 0)) {
+        if let Some((response, client_resets)) = compression.negotiate(&offers()?) {
+            accepted.push((response, RSV1));
+            deflate = Some(client_resets);
         }
     }
+    Ok((key, accepted, deflate))
 }
 
-pub struct WebSocketWriter<S>
-where S: AsyncWrite + Unpin
+/// Wraps a stream whose handshake has already been done elsewhere; e.g. as a client, or when
+/// proxying a connection to an upstream server.
+pub fn from_stream<S>(stream: S, role: Role) -> (WebSocketReader<S>, WebSocketWriter<S>)
+where S: AsyncRead + AsyncWrite + Clone + Unpin
 {
-    stream: S,
+    (WebSocketReader::new(stream.clone(), role), WebSocketWriter::new(stream, role))
 }
 
-impl<S> WebSocketWriter<S>
-where S: AsyncWrite + Unpin
+/// Like from_stream, in the config's role and set up by it; see WebSocketConfig::apply.
+pub fn from_stream_with<S>(stream: S, config: &WebSocketConfig) -> (WebSocketReader<S>, SharedWebSocketWriter<S>)
+where S: AsyncRead + AsyncWrite + Clone + Unpin
 {
-    pub async fn write(&mut self, msg: &Message) -> Result<(), WebSocketError> {
-        let res = WebSocketHeader{
-            fin: 1,
-            opcode: msg.typ.into(),
-            mask: 0,
-            payload_len: msg.contents.len() as u64,
-            masking_key: vec!(),
-        };
-        self.stream.write_all(&mut res.to_vec()).await?;
-        self.stream.write_all(&msg.contents).await?;
-        self.stream.flush().await?;
-        Ok(())
-    }
+    config.apply(from_stream(stream, config.role))
 }
 
-#[derive(Debug, Clone)]
-struct WebSocketHeader{
-    fin: u8,
-    opcode: u8,
-    mask: u8,
-    payload_len: u64,
-    masking_key: Vec<u8>,
+pub struct WebSocketReader<S> {
+    stream: S,
+    role: Role,
+    // RSV bits that a negotiated extension has given meaning to; any others fail the connection
+    allowed_rsv: u8,
+    extensions: Vec<Extension>,
+    max_payload_size: u64,
+    // bytes read off the stream before we got it (see prepend)
+    buffered: Vec<u8>,
+    metrics: Option<ConnectionMetrics>,
+    // bytes of the frame currently being read; first the header, then the payload
+    // payloads are split off of it, so its allocation is reused once they've been dropped
+    frame_buf: BytesMut,
+    filled: usize,
+    header: Option<WebSocketHeader>,
+    buffered_message: Option<(MessageType, BytesMut)>,
+    // when set, control frames are answered from inside recv() using this writer
+    control_writer: Option<SharedWebSocketWriter<S>>,
+    // control frame we still owe the peer, plus the lock needed to send it
+    reply: Option<Message>,
+    reply_lock: Option<OwnedMutexLockFuture<WebSocketWriter<S>>>,
+    reply_guard: Option<OwnedMutexGuard<WebSocketWriter<S>>>,
+    // error to return once the close frame for it has been sent
+    failure: Option<WebSocketError>,
+    // the message being read through recv_stream
+    streaming: Option<Streaming>,
+    closed: bool,
+    // the peer's Close arrived, and answer_pings answered it
+    peer_closed: bool,
+    // counts the connection against UpgradeConfig::limit_per_ip
+    permit: Option<Permit>,
+    // set when permessage-deflate was agreed on
+    inflate: Option<Inflater>,
+    // whether the message in buffered_message is compressed
+    compressed: bool,
+    // the compressed message being read through recv_stream
+    inflating: Option<InflateStream>,
 }
 
-impl WebSocketHeader {
-    fn to_vec(&self) -> Vec<u8> {
-        let mut ret = Vec::with_capacity(70);
-        ret.push((self.fin << 7) | self.opcode);
-        ret.extend(if self.payload_len < 126 {
-            vec!(self.payload_len as u8)
-        } else if self.payload_len < u16::MAX as u64 {
-            let mut ret = vec!(126u8);
-            ret.extend(&(self.payload_len as u16).to_be_bytes());
-            ret
-        } else {
-            let mut ret = vec!(127u8);
-            ret.extend(&(self.payload_len as u16).to_be_bytes());
-            ret
-        });
-        ret
-    }
+/// what's been read of a compressed message's payload, and not yet inflated
+#[derive(Default)]
+struct InflateStream {
+    raw: Vec<u8>,
+    used: usize,
+    // all of the payload has been read
+    read: bool,
 }
 
-/// handles control message (ping, pong) to make sure the socket stays open
-pub async fn handle_control<S>(msg: &Message, wrt: &mut WebSocketWriter<S>) -> Result<bool, WebSocketError>
-where S: AsyncWrite + Unpin
+struct Streaming {
+    // bytes left in the current frame, and how far into it we are (for unmasking)
+    remaining: u64,
+    offset: usize,
+    masking_key: Vec<u8>,
+    fin: bool,
+}
+
+impl<S> WebSocketReader<S>
+where S: AsyncRead + AsyncWrite + Unpin
 {
-    match msg.typ {
-        MessageType::Pong => {
-            let msg = Message{
-                typ: MessageType::Pong,
-                contents: msg.contents.clone(),
-            };
-            wrt.write(&msg).await?;
-            Ok(true)
-        },
-        MessageType::Close => {
-            Err(WebSocketError::ConnectionClosed)
+    fn new(stream: S, role: Role) -> Self {
+        WebSocketReader{
+            stream,
+            role,
+            allowed_rsv: 0,
+            extensions: vec!(),
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            buffered: vec!(),
+            metrics: None,
+            frame_buf: BytesMut::new(),
+            filled: 0,
+            header: None,
+            buffered_message: None,
+            control_writer: None,
+            reply: None,
+            reply_lock: None,
+            reply_guard: None,
+            failure: None,
+            streaming: None,
+            closed: false,
+            peer_closed: false,
+            permit: None,
+            inflate: None,
+            compressed: false,
+            inflating: None,
         }
-        _ => {
-            Ok(false)
-        },
     }
-}
 
-async fn read_header<S>(stream: &mut S) -> Result<WebSocketHeader, WebSocketError>
-where S: AsyncRead + Unpin
-{
-    // fixed-length header size is 2 bytes, followed by optional extended length
-    // and finally mask
-    let mut header_fixed = vec![0u8; 2];
-    stream.read_exact(&mut header_fixed).await?;
-    let (_, mut res) = read_header_internal(&header_fixed)?;
-    header_fixed[1] &= 0b01111111;
-    if res.payload_len == 126 {
-        // read 16 bites, 2 bytes
-        let mut len = [0u8; 2];
-        stream.read_exact(&mut len).await?;
-        res.payload_len = u16::from_be_bytes(len) as u64;
-    } else if res.payload_len == 127 {
-        // read 64 bits, 8 bytes
-        let mut len = [0u8; 8];
-        stream.read_exact(&mut len).await?;
-        res.payload_len = u64::from_be_bytes(len) as u64;
-    }
-    if res.mask != 0 {
-        let mut mask_key = vec![0u8; 4];
-        stream.read_exact(&mut mask_key).await?;
-        res.masking_key =  mask_key;
-    }
-    Ok(res)
-}
+    /// Answer Pings transparently with matching Pongs written to the provided writer.
+    ///
+    /// Once set, recv() only returns data messages; Pongs are dropped and a Close is echoed
+    /// back with the peer's code, then results in WebSocketError::ConnectionClosed. When the peer breaks the protocol, a Close with
+    /// the matching code (see WebSocketError::close_code) is sent before the error is returned.
+    /// Pass a clone of the SharedWebSocketWriter the rest of the application sends on, so
+    /// that control frames never interleave with other frames.
+    pub fn answer_pings<W>(&mut self, writer: W)
+    where W: Into<SharedWebSocketWriter<S>>
+    {
+        self.control_writer = Some(writer.into());
+    }
 
-fn read_header_internal(input: &[u8]) -> IResult<&[u8], WebSocketHeader> {
-    bits(read_header_internal_bits)(input)
-}
+    /// Allows RSV bits (RSV1 = 0b100, RSV2 = 0b010, RSV3 = 0b001) that an extension negotiated
+    /// during the handshake has given a meaning to; frames using other RSV bits are rejected.
+    pub fn allow_rsv_bits(&mut self, bits: u8) {
+        self.allowed_rsv = bits & 0b111;
+    }
 
-fn read_header_internal_bits(input: (&[u8], usize)) -> IResult<(&[u8], usize), WebSocketHeader>
-{
-    let (input, fin) = take(1usize)(input)?;
-    let (input, _rsv1): ((&[u8], usize), u8) = take(1usize)(input)?;
-    let (input, _rsv2): ((&[u8], usize), u8) = take(1usize)(input)?;
-    let (input, _rsv3): ((&[u8], usize), u8) = take(1usize)(input)?;
-    let (input, opcode) = take(4usize)(input)?;
-    let (input, mask) = take(1usize)(input)?;
-    let (input, payload_len) = take(7usize)(input)?;
-    Ok((input, WebSocketHeader{fin, opcode, mask, payload_len, masking_key: vec!()}))
-}
+    /// Hands the reader bytes that were already read from the stream, to be read before
+    /// anything else; the upgrades do this with their `buffered`, so it's for connections
+    /// from from_stream.
+    pub fn prepend(&mut self, bytes: &[u8]) {
+        self.buffered.splice(..0, bytes.iter().copied());
+    }
 
+    /// Reports what's received on this connection to `metrics`, counting it as open until the
+    /// reader is dropped.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = Some(ConnectionMetrics::new(metrics));
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::error::Error;
-    use async_std::{
-        prelude::*,
-        task,
-        net::{
-            TcpListener,
-            SocketAddr,
-            TcpStream,
-        },
-        io::{
-            BufReader,
-        }
-    };
-    use websocket::client::ClientBuilder;
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        self.metrics.as_ref().map(|m| m.0.as_ref())
+    }
+
+    /// The extensions agreed on during the handshake, if it was done by upgrade_with.
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    /// Sets the largest message (or frame) that recv() and read_frame() will accept; anything
+    /// larger fails with WebSocketError::TooBig. Defaults to MAX_PAYLOAD_SIZE.
+    pub fn set_max_payload_size(&mut self, size: u64) {
+        self.max_payload_size = size;
+    }
+
+    /// Reads the next frame as-is, without reassembling fragments, checking UTF-8 or handling
+    /// control frames. Frames are still checked against the framing rules (masking, RSV bits,
+    /// control frame size). Don't mix this with recv() in the middle of a fragmented message.
+    pub async fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        let (header, payload) = future::poll_fn(|cx| self.poll_frame(cx)).await?;
+        Ok(Frame{
+            fin: header.fin != 0,
+            rsv: header.rsv,
+            typ: MessageType::try_from(header.opcode)?,
+            payload: payload.freeze(),
+        })
+    }
+
+    /// Waits for the next message and returns its type along with a reader over the payload,
+    /// which is read straight from the connection as it's consumed (and inflated, if it's
+    /// compressed); fragments are joined together and the payload size limit doesn't apply.
+    ///
+    /// Text is not checked for valid UTF-8 here. Control frames that arrive in the middle of
+    /// the message are handled as set up by answer_pings (and otherwise dropped, except for a
+    /// Close, which ends the stream with an error). If the reader is dropped before the end of
+    /// the message, the rest of it is skipped by the next call on this WebSocketReader.
+    pub async fn recv_stream(&mut self) -> Result<(MessageType, MessageReader<'_, S>), WebSocketError> {
+        let typ = future::poll_fn(|cx| self.poll_stream_start(cx)).await?;
+        Ok((typ, MessageReader{reader: self}))
+    }
+
+    fn poll_stream_start(&mut self, cx: &mut Context) -> Poll<Result<MessageType, WebSocketError>> {
+        loop {
+            ready!(self.poll_reply(cx))?;
+            ready!(self.poll_skip_stream(cx))?;
+            if self.closed {
+                return Poll::Ready(Err(WebSocketError::ConnectionClosed));
+            }
+            ready!(self.poll_header(cx))?;
+            let header = self.header.as_ref().unwrap();
+            let typ = MessageType::try_from(header.opcode)?;
+            if typ.is_control() && self.control_writer.is_some() {
+                ready!(self.poll_message(cx))?;
+                continue;
+            }
+            // same rules as recv(); a data frame has to start a new message
+            if !typ.is_control() && (typ == MessageType::Continuation || self.buffered_message.is_some()) {
+                Err(WebSocketError::ProtocolError)?;
+            }
+            let compressed = self.inflate.is_some() && header.rsv & RSV1 != 0;
+            self.start_streaming_frame();
+            if compressed {
+                self.inflating = Some(InflateStream::default());
+            }
+            if let Some(metrics) = self.metrics() {
+                metrics.message_received(typ);
+            }
+            return Poll::Ready(Ok(typ));
+        }
+    }
+
+    /// moves the payload of the frame in self.header into the streaming state
+    fn start_streaming_frame(&mut self) {
+        let header = self.header.take().unwrap();
+        self.streaming = Some(Streaming{
+            remaining: header.payload_len,
+            offset: 0,
+            masking_key: header.masking_key,
+            fin: header.fin != 0,
+        });
+    }
+
+    /// discards whatever is left of a streamed message
+    fn poll_skip_stream(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut scratch = [0u8; 1024];
+        // compressed messages are still inflated, since the next may use their window
+        while self.streaming.is_some() || self.inflating.is_some() {
+            ready!(self.poll_stream_read(cx, &mut scratch))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_stream_read(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut inflating = match self.inflating.take() {
+            Some(inflating) => inflating,
+            None => return self.poll_stream_read_raw(cx, buf),
+        };
+        let res = self.poll_inflate(cx, &mut inflating, buf);
+        // done once it's given the end of the message, or failed
+        if !matches!(res, Poll::Ready(Ok(0)) | Poll::Ready(Err(_))) || buf.is_empty() {
+            self.inflating = Some(inflating);
+        }
+        res
+    }
+
+    /// reads the compressed payload through the inflater
+    fn poll_inflate(&mut self, cx: &mut Context, inflating: &mut InflateStream, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, err);
+        loop {
+            if inflating.used == inflating.raw.len() && !inflating.read {
+                let mut chunk = [0u8; 4096];
+                let count = ready!(self.poll_stream_read_raw(cx, &mut chunk))?;
+                inflating.raw.clear();
+                inflating.raw.extend_from_slice(&chunk[..count]);
+                inflating.used = 0;
+                inflating.read = count == 0;
+            }
+            let inflater = self.inflate.as_mut().unwrap();
+            let (used, made) = inflater.inflate_some(&inflating.raw[inflating.used..], buf).map_err(invalid)?;
+            inflating.used += used;
+            if inflating.read && inflater.is_done(made) {
+                inflater.finish();
+                return Poll::Ready(Ok(0));
+            }
+            if made > 0 {
+                return Poll::Ready(Ok(made));
+            }
+        }
+    }
+
+    fn poll_stream_read_raw(&mut self, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let streaming = match &mut self.streaming {
+                Some(streaming) => streaming,
+                None => return Poll::Ready(Ok(0)),
+            };
+            if streaming.remaining > 0 {
+                let max = buf.len().min(streaming.remaining.min(usize::MAX as u64) as usize);
+                if max == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                let count = ready!(poll_read_buffered(&mut self.buffered, &mut self.stream, cx, &mut buf[..max]))?;
+                if count == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                if let Some(metrics) = &self.metrics {
+                    metrics.0.bytes_received(count);
+                }
+                unmask(&mut buf[..count], &streaming.masking_key, streaming.offset);
+                streaming.offset += count;
+                streaming.remaining -= count as u64;
+                return Poll::Ready(Ok(count));
+            }
+            if streaming.fin {
+                self.streaming = None;
+                return Poll::Ready(Ok(0));
+            }
+            // the message goes on in another frame, possibly after some control frames
+            if let Err(err) = ready!(self.poll_next_stream_frame(cx)) {
+                if err.close_code().is_some() {
+                    self.closed = true;
+                }
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, err)));
+            }
+        }
+    }
+
+    fn poll_next_stream_frame(&mut self, cx: &mut Context) -> Poll<Result<(), WebSocketError>> {
+        loop {
+            ready!(self.poll_reply(cx))?;
+            if let Some(err) = self.failure.take() {
+                return Poll::Ready(Err(err));
+            }
+            ready!(self.poll_header(cx))?;
+            let typ = MessageType::try_from(self.header.as_ref().unwrap().opcode)?;
+            if typ == MessageType::Continuation {
+                self.start_streaming_frame();
+                return Poll::Ready(Ok(()));
+            }
+            if !typ.is_control() {
+                Err(WebSocketError::ProtocolError)?;
+            }
+            let (_, contents) = ready!(self.poll_frame(cx))?;
+            match typ {
+                MessageType::Ping if self.control_writer.is_some() => {
+                    self.reply = Some(Message{typ: MessageType::Pong, contents: contents.freeze()});
+                },
+                MessageType::Close if self.control_writer.is_some() => self.answer_close(&contents),
+                MessageType::Close => Err(WebSocketError::ConnectionClosed)?,
+                MessageType::Pong => if let Some(writer) = &self.control_writer {
+                    writer.pong_received(&contents);
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Waits for the next complete message. This is safe to cancel (e.g. in a `select!`);
+    /// a partially read frame is kept and picked up by the next call.
+    pub async fn recv(&mut self) -> Result<Message, WebSocketError> {
+        future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Like recv(), but gives up with Ok(None) as soon as `cancel` completes; e.g. a
+    /// stopper::StopToken, or a timer from your runtime. The connection stays usable afterwards.
+    pub async fn recv_until<F>(&mut self, cancel: F) -> Result<Option<Message>, WebSocketError>
+    where F: Future
+    {
+        pin_mut!(cancel);
+        future::poll_fn(|cx| {
+            if let Poll::Ready(res) = self.poll_recv(cx) {
+                return Poll::Ready(res.map(Some));
+            }
+            cancel.as_mut().poll(cx).map(|_| Ok(None))
+        }).await
+    }
+
+    /// Like recv(), but fails with WebSocketError::TimedOut if no message arrives in time.
+    /// The connection stays usable afterwards, so this can be used to spot idle clients.
+    pub async fn recv_deadline(&mut self, timeout: Duration) -> Result<Message, WebSocketError> {
+        match self.recv_until(futures_timer::Delay::new(timeout)).await? {
+            Some(msg) => Ok(msg),
+            None => Err(WebSocketError::TimedOut),
+        }
+    }
+
+    /// Starts the closing handshake: sends a Close with the code and reason on the writer,
+    /// then reads and discards whatever the peer sends for up to `timeout` while waiting for
+    /// its Close. The stream is shut down afterwards either way, so a peer that never answers
+    /// can't hold the connection open. Returns whether the peer's Close arrived in time; if
+    /// it already did, and recv() echoed it under answer_pings, this only shuts the stream.
+    pub async fn close<W>(mut self, writer: W, code: u16, reason: &str, timeout: Duration) -> Result<bool, WebSocketError>
+    where W: Into<SharedWebSocketWriter<S>>
+    {
+        let writer = writer.into();
+        // recv() already answered the peer's Close, so there's nothing to wait for
+        if self.peer_closed {
+            let sent = future::poll_fn(|cx| self.poll_reply(cx)).await;
+            writer.close_stream().await;
+            return sent.map(|_| true).map_err(WebSocketError::from);
+        }
+        if let Err(err) = writer.write(&Message::close(code, reason)).await {
+            writer.close_stream().await;
+            return Err(err);
+        }
+        let closed = self.closed;
+        let drain = async {
+            // after a protocol error there's nothing left worth reading
+            if closed {
+                return false;
+            }
+            loop {
+                let frame = future::poll_fn(|cx| {
+                    ready!(self.poll_skip_stream(cx))?;
+                    self.poll_frame(cx)
+                }).await;
+                match frame {
+                    Ok((header, _)) if header.opcode == u8::from(MessageType::Close) => return true,
+                    Ok(_) => continue,
+                    Err(_) => return false,
+                }
+            }
+        };
+        let deadline = futures_timer::Delay::new(timeout);
+        pin_mut!(drain, deadline);
+        let answered = match future::select(drain, deadline).await {
+            future::Either::Left((answered, _)) => answered,
+            future::Either::Right(_) => false,
+        };
+        writer.close_stream().await;
+        Ok(answered)
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<Message, WebSocketError>> {
+        loop {
+            // finish sending any control frame we owe before reading more (or giving up)
+            ready!(self.poll_reply(cx))?;
+            if let Some(err) = self.failure.take() {
+                return Poll::Ready(Err(err));
+            }
+            if self.closed {
+                return Poll::Ready(Err(WebSocketError::ConnectionClosed));
+            }
+            ready!(self.poll_skip_stream(cx))?;
+            match ready!(self.poll_message(cx)) {
+                Ok(Some(msg)) => return Poll::Ready(Ok(msg)),
+                Ok(None) => continue,
+                Err(err) => {
+                    let code = match err.close_code() {
+                        Some(code) => code,
+                        None => return Poll::Ready(Err(err)),
+                    };
+                    // the peer broke the protocol; nothing more can be read from this connection
+                    self.closed = true;
+                    if self.control_writer.is_none() {
+                        return Poll::Ready(Err(err));
+                    }
+                    self.reply = Some(Message::close(code, ""));
+                    self.failure = Some(err);
+                },
+            }
+        }
+    }
+
+    /// reads one frame, returning the message if it completed one
+    fn poll_message(&mut self, cx: &mut Context) -> Poll<Result<Option<Message>, WebSocketError>> {
+        let (header, contents) = ready!(self.poll_frame(cx))?;
+        let typ = MessageType::try_from(header.opcode)?;
+        if typ.is_control() {
+            if let Some(metrics) = self.metrics() {
+                metrics.message_received(typ);
+            }
+            if let Some(writer) = &self.control_writer {
+                match typ {
+                    MessageType::Ping => self.reply = Some(Message{typ: MessageType::Pong, contents: contents.freeze()}),
+                    MessageType::Close => self.answer_close(&contents),
+                    MessageType::Pong => writer.pong_received(&contents),
+                    _ => {},
+                }
+                return Poll::Ready(Ok(None));
+            }
+            return Poll::Ready(Ok(Some(Message{contents: contents.freeze(), typ})));
+        }
+        // data frames either start a message or continue the one in progress
+        let (typ, contents) = match (typ, self.buffered_message.take()) {
+            (MessageType::Continuation, Some((typ, mut old))) => {
+                // free when the frames were read back to back into the same buffer
+                old.unsplit(contents);
+                (typ, old)
+            },
+            (MessageType::Continuation, None) => Err(WebSocketError::ProtocolError)?,
+            (_, Some(_)) => Err(WebSocketError::ProtocolError)?,
+            (typ, None) => {
+                self.compressed = self.inflate.is_some() && header.rsv & RSV1 != 0;
+                (typ, contents)
+            },
+        };
+        if contents.len() as u64 > self.max_payload_size {
+            Err(WebSocketError::TooBig)?;
+        }
+        if header.fin == 0 {
+            self.buffered_message = Some((typ, contents));
+            return Poll::Ready(Ok(None));
+        }
+        let contents = match &mut self.inflate {
+            Some(inflater) if self.compressed => Bytes::from(inflater.inflate(&contents, self.max_payload_size)?),
+            _ => contents.freeze(),
+        };
+        // text has to be valid utf-8 as a whole, so it's checked once the message is complete
+        if typ == MessageType::Text && std::str::from_utf8(&contents).is_err() {
+            Err(WebSocketError::InvalidUtf8)?;
+        }
+        if let Some(metrics) = self.metrics() {
+            metrics.message_received(typ);
+        }
+        Poll::Ready(Ok(Some(Message{typ, contents})))
+    }
+
+    /// echoes the peer's Close (RFC 6455 section 5.5.1); the error follows once it's sent
+    fn answer_close(&mut self, contents: &[u8]) {
+        self.reply = Some(match contents {
+            [high, low, ..] => Message::close(u16::from_be_bytes([*high, *low]), ""),
+            _ => Message{typ: MessageType::Close, contents: Bytes::new()},
+        });
+        self.closed = true;
+        self.peer_closed = true;
+        self.failure = Some(WebSocketError::ConnectionClosed);
+    }
+
+    /// sends the pending control frame (if any), holding the writer lock until it's flushed
+    fn poll_reply(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        let shared = match &self.control_writer {
+            Some(shared) => shared,
+            None => return Poll::Ready(Ok(())),
+        };
+        if self.reply_guard.is_none() {
+            if self.reply.is_none() {
+                return Poll::Ready(Ok(()));
+            }
+            if shared.interleave(self.reply.as_ref().unwrap()) {
+                self.reply = None;
+                self.reply_lock = None;
+                return Poll::Ready(Ok(()));
+            }
+            let lock = self.reply_lock.get_or_insert_with(|| shared.inner.clone().lock_owned());
+            let mut guard = ready!(Pin::new(lock).poll(cx));
+            self.reply_lock = None;
+            guard.queue(&self.reply.take().unwrap());
+            self.reply_guard = Some(guard);
+        }
+        ready!(self.reply_guard.as_mut().unwrap().poll_flush_frames(cx))?;
+        self.reply_guard = None;
+        Poll::Ready(Ok(()))
+    }
+
+    /// reads a single frame, returning the header and the unmasked payload
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<Result<(WebSocketHeader, BytesMut), WebSocketError>> {
+        ready!(self.poll_header(cx))?;
+        let payload_len = self.header.as_ref().unwrap().payload_len;
+        if payload_len > self.max_payload_size {
+            Err(WebSocketError::TooBig)?;
+        }
+        ready!(self.poll_fill(cx, payload_len as usize))?;
+        let header = self.header.take().unwrap();
+        let mut contents = self.frame_buf.split_to(payload_len as usize);
+        self.filled = 0;
+        unmask(&mut contents, &header.masking_key, 0);
+        if header.opcode == u8::from(MessageType::Close) && contents.len() >= 2 {
+            if let Some(metrics) = self.metrics() {
+                metrics.close_received(u16::from_be_bytes([contents[0], contents[1]]));
+            }
+        }
+        Poll::Ready(Ok((header, contents)))
+    }
+
+    /// reads and checks the next frame header, leaving it in self.header
+    fn poll_header(&mut self, cx: &mut Context) -> Poll<Result<(), WebSocketError>> {
+        if self.header.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        // fixed-length header size is 2 bytes, followed by optional extended length
+        // and finally mask
+        ready!(self.poll_fill(cx, 2))?;
+        let (_, mut header) = read_header_internal(&self.frame_buf[..2])?;
+        // frames from the client are always masked, frames from the server never are
+        if (header.mask != 0) != (self.role == Role::Server) {
+            Err(WebSocketError::ProtocolError)?;
+        }
+        if header.rsv & !self.allowed_rsv != 0 {
+            Err(WebSocketError::ProtocolError)?;
+        }
+        // with permessage-deflate, only the first frame of a data message may say it's compressed
+        if self.inflate.is_some() && header.rsv & RSV1 != 0 && (header.opcode & 0x8 != 0 || header.opcode == 0) {
+            Err(WebSocketError::ProtocolError)?;
+        }
+        // control frames can't be fragmented and must fit in the 7 bit length
+        if header.opcode & 0x8 != 0 && (header.fin == 0 || header.payload_len > 125) {
+            Err(WebSocketError::ProtocolError)?;
+        }
+        let ext_len = match header.payload_len {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_len = if header.mask != 0 { 4 } else { 0 };
+        ready!(self.poll_fill(cx, 2 + ext_len + mask_len))?;
+        let rest = &self.frame_buf[2..2 + ext_len + mask_len];
+        if ext_len == 2 {
+            header.payload_len = u16::from_be_bytes([rest[0], rest[1]]) as u64;
+        } else if ext_len == 8 {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&rest[..8]);
+            header.payload_len = u64::from_be_bytes(len);
+        }
+        header.masking_key = Vec::from(&rest[ext_len..]);
+        // from here on the buffer holds the payload
+        self.frame_buf.clear();
+        self.filled = 0;
+        self.header = Some(header);
+        Poll::Ready(Ok(()))
+    }
+
+    /// reads from the stream until at least `needed` bytes of the frame buffer are filled
+    fn poll_fill(&mut self, cx: &mut Context, needed: usize) -> Poll<io::Result<()>> {
+        if self.frame_buf.len() < needed {
+            self.frame_buf.resize(needed, 0);
+        }
+        while self.filled < needed {
+            let count = ready!(poll_read_buffered(&mut self.buffered, &mut self.stream, cx, &mut self.frame_buf[self.filled..needed]))?;
+            if count == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            if let Some(metrics) = self.metrics() {
+                metrics.bytes_received(count);
+            }
+            self.filled += count;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// reads whatever was handed to WebSocketReader::prepend before reading from the stream
+fn poll_read_buffered<S>(buffered: &mut Vec<u8>, stream: &mut S, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>>
+where S: AsyncRead + Unpin
+{
+    if buffered.is_empty() {
+        return Pin::new(stream).poll_read(cx, buf);
+    }
+    let count = buf.len().min(buffered.len());
+    buf[..count].copy_from_slice(&buffered[..count]);
+    buffered.drain(..count);
+    Poll::Ready(Ok(count))
+}
+
+/// A single message's payload, as returned by WebSocketReader::recv_stream.
+pub struct MessageReader<'a, S>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    reader: &'a mut WebSocketReader<S>,
+}
+
+impl<'a, S> AsyncRead for MessageReader<'a, S>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().reader.poll_stream_read(cx, buf)
+    }
+}
+
+/// Yields messages until the connection is closed.
+impl<S> Stream for WebSocketReader<S>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    type Item = Result<Message, WebSocketError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(None);
+        }
+        match ready!(this.poll_recv(cx)) {
+            Err(WebSocketError::ConnectionClosed) => {
+                this.closed = true;
+                Poll::Ready(None)
+            },
+            res => Poll::Ready(Some(res)),
+        }
+    }
+}
+
+pub struct WebSocketWriter<S> {
+    stream: S,
+    // clients mask every frame they send
+    role: Role,
+    // encoded frames waiting to be written to the stream
+    buf: Vec<u8>,
+    written: usize,
+    // see set_auto_flush
+    auto_flush: Option<usize>,
+    metrics: Option<Arc<dyn Metrics>>,
+    // set when permessage-deflate was agreed on
+    deflate: Option<Deflater>,
+}
+
+impl<S> Clone for WebSocketWriter<S>
+where S: AsyncWrite + Clone + Unpin
+{
+    fn clone(&self) -> Self {
+        // frames that are still buffered belong to the original
+        let mut writer = WebSocketWriter::new(self.stream.clone(), self.role);
+        writer.auto_flush = self.auto_flush;
+        writer.metrics = self.metrics.clone();
+        writer.deflate = self.deflate.clone();
+        writer
+    }
+}
+
+impl<S> WebSocketWriter<S>
+where S: AsyncWrite + Unpin
+{
+    fn new(stream: S, role: Role) -> Self {
+        WebSocketWriter{
+            stream,
+            role,
+            buf: vec!(),
+            written: 0,
+            auto_flush: None,
+            metrics: None,
+            deflate: None,
+        }
+    }
+
+    /// Reports what's sent on this connection to `metrics`.
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Changes how feed() and the Sink impl batch frames. With None (the default) each frame
+    /// is handed to the stream when the next one is fed, and nothing is flushed until flush();
+    /// that's the right choice when the stream is a BufWriter. With a threshold, frames are
+    /// collected here and written and flushed together once at least that many bytes are
+    /// waiting, so a burst of small messages costs one write instead of one per message.
+    pub fn set_auto_flush(&mut self, threshold: Option<usize>) {
+        self.auto_flush = threshold;
+    }
+
+    /// Queues a message without flushing it; call flush() (or write()) once the burst is done.
+    pub async fn feed(&mut self, msg: &Message) -> Result<(), WebSocketError> {
+        future::poll_fn(|cx| self.poll_make_room(cx)).await?;
+        self.queue(msg);
+        if self.auto_flush.is_some_and(|threshold| self.buf.len() >= threshold) {
+            future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes and flushes everything fed so far.
+    pub async fn flush(&mut self) -> Result<(), WebSocketError> {
+        future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+        Ok(())
+    }
+
+    /// Converts this writer into a handle that can be cloned and shared between tasks.
+    pub fn into_shared(self) -> SharedWebSocketWriter<S> {
+        self.into()
+    }
+
+    pub async fn write(&mut self, msg: &Message) -> Result<(), WebSocketError> {
+        self.queue(msg);
+        future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+        Ok(())
+    }
+
+    pub async fn send_text(&mut self, txt: &str) -> Result<(), WebSocketError> {
+        self.write(&Message::text(txt)).await
+    }
+
+    pub async fn send_binary(&mut self, contents: &[u8]) -> Result<(), WebSocketError> {
+        self.write(&Message::binary(Bytes::copy_from_slice(contents))).await
+    }
+
+    /// Sends the value encoded as JSON in a Text message.
+    #[cfg(feature = "json")]
+    pub async fn send_json<T: serde::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), WebSocketError> {
+        self.write(&Message::json(value)?).await
+    }
+
+    /// Sends a single frame exactly as given; it's up to the caller to follow the framing rules.
+    pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), WebSocketError> {
+        self.queue_frame(frame.fin, frame.rsv, frame.typ.into(), &frame.payload);
+        future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+        Ok(())
+    }
+
+    /// Sends a message split into frames that carry at most `frame_size` bytes each: the first
+    /// with the message's type, the rest as Continuations, and FIN set on the last one.
+    ///
+    /// Each frame is written before the next is encoded, so large payloads are never copied
+    /// into one big buffer. Control messages can't be fragmented and are sent whole. If this
+    /// is cancelled part way through, the peer is left with an unfinished message; don't send
+    /// anything else on the connection afterwards.
+    pub async fn write_fragmented(&mut self, msg: &Message, frame_size: usize) -> Result<(), WebSocketError> {
+        self.write_fragments(msg, frame_size, Vec::new).await
+    }
+
+    /// write_fragmented, sending the control messages `interleaved` has for it before each
+    /// frame; a Close ends the message there, since no data may follow it
+    async fn write_fragments<F>(&mut self, msg: &Message, frame_size: usize, mut interleaved: F) -> Result<(), WebSocketError>
+    where F: FnMut() -> Vec<Message>
+    {
+        if msg.typ.is_control() || msg.contents.len() <= frame_size {
+            return self.write(msg).await;
+        }
+        // compressed as a whole, then split; the first frame says so
+        let compressed = self.compressed(msg);
+        let (payload, mut rsv) = match &compressed {
+            Some(compressed) => (&compressed[..], RSV1),
+            None => (&msg.contents[..], 0),
+        };
+        let mut chunks = payload.chunks(frame_size.max(1)).peekable();
+        let mut opcode = msg.typ.into();
+        while let Some(chunk) = chunks.next() {
+            let controls = interleaved();
+            let close = controls.iter().position(|control| control.typ == MessageType::Close);
+            for control in &controls[..close.map_or(controls.len(), |i| i + 1)] {
+                self.queue(control);
+            }
+            if !controls.is_empty() {
+                // keepalives are no good sitting in a buffer
+                future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+            }
+            if close.is_some() {
+                return Err(WebSocketError::ConnectionClosed);
+            }
+            let fin = chunks.peek().is_none();
+            self.queue_frame(fin, rsv, opcode, chunk);
+            future::poll_fn(|cx| self.poll_write_buf(cx)).await?;
+            opcode = MessageType::Continuation.into();
+            rsv = 0;
+        }
+        future::poll_fn(|cx| self.poll_flush_frames(cx)).await?;
+        Ok(())
+    }
+
+    /// encodes the message as a single frame at the end of the outgoing buffer
+    fn queue(&mut self, msg: &Message) {
+        match self.compressed(msg) {
+            Some(compressed) => self.queue_frame(true, RSV1, msg.typ.into(), &compressed),
+            None => self.queue_frame(true, 0, msg.typ.into(), &msg.contents),
+        }
+    }
+
+    /// the message's payload compressed, if permessage-deflate was agreed on and it's worth it
+    fn compressed(&mut self, msg: &Message) -> Option<Vec<u8>> {
+        if msg.typ.is_control() {
+            return None;
+        }
+        self.deflate.as_mut()?.compress(&msg.contents)
+    }
+
+    fn queue_frame(&mut self, fin: bool, rsv: u8, opcode: u8, payload: &[u8]) {
+        // a fresh, unpredictable key for every frame
+        let masking_key = match self.role {
+            Role::Client => Vec::from(&rand::random::<[u8; 4]>()[..]),
+            Role::Server => vec!(),
+        };
+        let res = WebSocketHeader{
+            fin: fin as u8,
+            rsv: rsv & 0b111,
+            opcode,
+            mask: (self.role == Role::Client) as u8,
+            payload_len: payload.len() as u64,
+            masking_key,
+        };
+        if let Some(metrics) = &self.metrics {
+            // continuations are part of a message that's already been counted
+            match MessageType::try_from(opcode) {
+                Ok(MessageType::Continuation) | Err(_) => {},
+                Ok(typ) => metrics.message_sent(typ),
+            }
+            if opcode == u8::from(MessageType::Close) && payload.len() >= 2 {
+                metrics.close_sent(u16::from_be_bytes([payload[0], payload[1]]));
+            }
+        }
+        self.buf.extend(res.to_vec());
+        let start = self.buf.len();
+        self.buf.extend(payload);
+        unmask(&mut self.buf[start..], &res.masking_key, 0);
+    }
+
+    /// closes the stream right away, dropping anything that hasn't been written yet
+    async fn close_stream(&mut self) {
+        self.buf.clear();
+        self.written = 0;
+        let _ = future::poll_fn(|cx| Pin::new(&mut self.stream).poll_close(cx)).await;
+    }
+
+    /// writes out everything that's been queued, without flushing the stream
+    fn poll_write_buf(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.written < self.buf.len() {
+            let count = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.buf[self.written..]))?;
+            if count == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.bytes_sent(count);
+            }
+            self.written += count;
+        }
+        self.buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_frames(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// gets ready to queue another frame, following the auto flush setting
+    fn poll_make_room(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.auto_flush {
+            None => self.poll_write_buf(cx),
+            Some(threshold) if self.buf.len() >= threshold => self.poll_flush_frames(cx),
+            Some(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// A clone-able handle to a WebSocketWriter that any number of tasks can send on at once.
+///
+/// Each message is written (and flushed) while holding a lock, so frames from different
+/// senders never interleave on the wire.
+pub struct SharedWebSocketWriter<S> {
+    inner: Arc<Mutex<WebSocketWriter<S>>>,
+    pings: Arc<std::sync::Mutex<Pings>>,
+    interleave: Arc<std::sync::Mutex<Interleave>>,
+}
+
+/// control messages handed to a write_fragmented in progress, to go out between its frames
+/// instead of waiting for the whole message
+#[derive(Default)]
+struct Interleave {
+    fragmenting: bool,
+    pending: Vec<Message>,
+}
+
+/// pings sent by SharedWebSocketWriter::ping that are waiting for their Pong
+struct Pings {
+    // ping payloads are nanoseconds since this, as 8 big endian bytes
+    epoch: Instant,
+    last: u64,
+    pending: Vec<(u64, oneshot::Sender<Duration>)>,
+}
+
+impl<S> Clone for SharedWebSocketWriter<S>
+where S: AsyncWrite + Unpin
+{
+    fn clone(&self) -> Self {
+        SharedWebSocketWriter{
+            inner: self.inner.clone(),
+            pings: self.pings.clone(),
+            interleave: self.interleave.clone(),
+        }
+    }
+}
+
+impl<S> From<WebSocketWriter<S>> for SharedWebSocketWriter<S>
+where S: AsyncWrite + Unpin
+{
+    fn from(writer: WebSocketWriter<S>) -> Self {
+        SharedWebSocketWriter{
+            inner: Arc::new(Mutex::new(writer)),
+            pings: Arc::new(std::sync::Mutex::new(Pings{
+                epoch: Instant::now(),
+                last: 0,
+                pending: vec!(),
+            })),
+            interleave: Arc::default(),
+        }
+    }
+}
+
+impl<S> SharedWebSocketWriter<S>
+where S: AsyncWrite + Unpin
+{
+    /// Control messages sent while another task is in write_fragmented go out between its
+    /// frames, and this returns once they're handed over.
+    pub async fn write(&self, msg: &Message) -> Result<(), WebSocketError> {
+        if msg.typ.is_control() && self.interleave(msg) {
+            return Ok(());
+        }
+        self.inner.lock().await.write(msg).await
+    }
+
+    /// hands the control message to the write_fragmented in progress, if there is one
+    fn interleave(&self, msg: &Message) -> bool {
+        let mut interleave = self.interleave.lock().unwrap();
+        if interleave.fragmenting {
+            interleave.pending.push(msg.clone());
+        }
+        interleave.fragmenting
+    }
+
+    pub async fn write_frame(&self, frame: &Frame) -> Result<(), WebSocketError> {
+        self.inner.lock().await.write_frame(frame).await
+    }
+
+    pub async fn send_text(&self, txt: &str) -> Result<(), WebSocketError> {
+        self.write(&Message::text(txt)).await
+    }
+
+    pub async fn send_binary(&self, contents: &[u8]) -> Result<(), WebSocketError> {
+        self.write(&Message::binary(Bytes::copy_from_slice(contents))).await
+    }
+
+    #[cfg(feature = "json")]
+    pub async fn send_json<T: serde::Serialize + ?Sized>(&self, value: &T) -> Result<(), WebSocketError> {
+        self.write(&Message::json(value)?).await
+    }
+
+    /// Like WebSocketWriter::feed; other senders' write() calls flush fed messages too.
+    pub async fn feed(&self, msg: &Message) -> Result<(), WebSocketError> {
+        self.inner.lock().await.feed(msg).await
+    }
+
+    pub async fn flush(&self) -> Result<(), WebSocketError> {
+        self.inner.lock().await.flush().await
+    }
+
+    /// See WebSocketWriter::set_auto_flush.
+    pub async fn set_auto_flush(&self, threshold: Option<usize>) {
+        self.inner.lock().await.set_auto_flush(threshold)
+    }
+
+    /// Sends a Ping and waits for its Pong, returning the round trip time.
+    ///
+    /// Pongs are matched up by the reader, so it has to be answering pings with this writer
+    /// (see WebSocketReader::answer_pings) and something has to be calling recv() on it. If
+    /// the peer never answers this never finishes; wrap it in a timeout.
+    pub async fn ping(&self) -> Result<Duration, WebSocketError> {
+        let (sender, receiver) = oneshot::channel();
+        let payload = {
+            let mut pings = self.pings.lock().unwrap();
+            // timestamps double as ids, so make sure no two are the same
+            let now = (pings.epoch.elapsed().as_nanos() as u64).max(pings.last + 1);
+            pings.last = now;
+            // forget pings whose caller stopped waiting
+            pings.pending.retain(|(_, sender)| !sender.is_canceled());
+            pings.pending.push((now, sender));
+            now.to_be_bytes()
+        };
+        self.write(&Message{typ: MessageType::Ping, contents: Bytes::copy_from_slice(&payload)}).await?;
+        receiver.await.or(Err(WebSocketError::ConnectionClosed))
+    }
+
+    /// resolves the ping() waiting for this Pong, if any
+    fn pong_received(&self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let mut sent = [0u8; 8];
+        sent.copy_from_slice(payload);
+        let sent = u64::from_be_bytes(sent);
+        let mut pings = self.pings.lock().unwrap();
+        if let Some(i) = pings.pending.iter().position(|(id, _)| *id == sent) {
+            let (_, sender) = pings.pending.swap_remove(i);
+            let now = pings.epoch.elapsed().as_nanos() as u64;
+            let _ = sender.send(Duration::from_nanos(now.saturating_sub(sent)));
+        }
+    }
+
+    pub(crate) async fn close_stream(&self) {
+        self.inner.lock().await.close_stream().await
+    }
+
+    /// Like WebSocketWriter::write_fragmented; other messages wait until the last frame is
+    /// sent, but control messages (write()'s, ping()'s and the Pongs of a reader answering
+    /// pings) go out between the frames, so keepalives aren't held up by a long transfer. A
+    /// Close sent meanwhile ends the message early, with ConnectionClosed.
+    pub async fn write_fragmented(&self, msg: &Message, frame_size: usize) -> Result<(), WebSocketError> {
+        let mut writer = self.inner.lock().await;
+        self.interleave.lock().unwrap().fragmenting = true;
+        let take = || std::mem::take(&mut self.interleave.lock().unwrap().pending);
+        let result = writer.write_fragments(msg, frame_size, take).await;
+        // whatever came in after the last frame
+        let rest = {
+            let mut interleave = self.interleave.lock().unwrap();
+            interleave.fragmenting = false;
+            std::mem::take(&mut interleave.pending)
+        };
+        result?;
+        for control in &rest {
+            writer.feed(control).await?;
+        }
+        writer.flush().await
+    }
+}
+
+impl<S> Sink<Message> for WebSocketWriter<S>
+where S: AsyncWrite + Unpin
+{
+    type Error = WebSocketError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        // batches the same way as feed(); see set_auto_flush
+        self.get_mut().poll_make_room(cx).map_err(Into::into)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), Self::Error> {
+        self.get_mut().queue(&item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_flush_frames(cx).map_err(Into::into)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_frames(cx))?;
+        Pin::new(&mut this.stream).poll_close(cx).map_err(Into::into)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct WebSocketHeader{
+    fin: u8,
+    // RSV1-3, with RSV1 as the highest bit
+    rsv: u8,
+    opcode: u8,
+    mask: u8,
+    payload_len: u64,
+    masking_key: Vec<u8>,
+}
+
+impl WebSocketHeader {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(70);
+        ret.push((self.fin << 7) | (self.rsv << 4) | self.opcode);
+        ret.extend(if self.payload_len < 126 {
+            vec!(self.payload_len as u8)
+        } else if self.payload_len <= u16::MAX as u64 {
+            let mut ret = vec!(126u8);
+            ret.extend(&(self.payload_len as u16).to_be_bytes());
+            ret
+        } else {
+            let mut ret = vec!(127u8);
+            ret.extend(&self.payload_len.to_be_bytes());
+            ret
+        });
+        if self.mask != 0 {
+            ret[1] |= 0x80;
+            ret.extend(&self.masking_key);
+        }
+        ret
+    }
+}
+
+/// handles control message (ping, pong) to make sure the socket stays open
+pub async fn handle_control<S>(msg: &Message, wrt: &mut WebSocketWriter<S>) -> Result<bool, WebSocketError>
+where S: AsyncWrite + Unpin
+{
+    match msg.typ {
+        MessageType::Ping => {
+            let msg = Message{
+                typ: MessageType::Pong,
+                contents: msg.contents.clone(),
+            };
+            wrt.write(&msg).await?;
+            Ok(true)
+        },
+        MessageType::Pong => {
+            // unsolicited pongs are allowed and require no response
+            Ok(true)
+        },
+        MessageType::Close => {
+            Err(WebSocketError::ConnectionClosed)
+        }
+        _ => {
+            Ok(false)
+        },
+    }
+}
+
+/// xors the payload with the masking key, where `offset` is the payload's position in the frame;
+/// masking and unmasking are the same operation
+fn unmask(payload: &mut [u8], masking_key: &[u8], offset: usize) {
+    if masking_key.is_empty() {
+        return;
+    }
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= masking_key[(offset + i) % masking_key.len()];
+    }
+}
+
+fn read_header_internal(input: &[u8]) -> IResult<&[u8], WebSocketHeader> {
+    bits(read_header_internal_bits)(input)
+}
+
+fn read_header_internal_bits(input: (&[u8], usize)) -> IResult<(&[u8], usize), WebSocketHeader>
+{
+    let (input, fin) = take(1usize)(input)?;
+    let (input, rsv) = take(3usize)(input)?;
+    let (input, opcode) = take(4usize)(input)?;
+    let (input, mask) = take(1usize)(input)?;
+    let (input, payload_len) = take(7usize)(input)?;
+    Ok((input, WebSocketHeader{fin, rsv, opcode, mask, payload_len, masking_key: vec!()}))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use async_std::{
+        prelude::*,
+        task,
+        net::{
+            TcpListener,
+            SocketAddr,
+            TcpStream,
+        },
+        io::{
+            BufReader,
+        }
+    };
+    use websocket::client::ClientBuilder;
     
     use super::*;
     use crate::stopper::Stopper;
 
-    async fn server<F: Fn(TcpStream) + 'static + Send>(func: F) -> (SocketAddr, Stopper) {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let local_addr = listener.local_addr().unwrap();
-        let (stopper, token) = Stopper::new();
-        // Accepting incoming reqeusts
+    async fn server<F: Fn(TcpStream) + 'static + Send>(func: F) -> (SocketAddr, Stopper) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let (stopper, token) = Stopper::new();
+        // Accepting incoming reqeusts
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(stream) = incoming.next().race(token.wait()).await {
+                if let Ok(stream) = stream {
+                    func(stream);
+                }
+            }
+        });
+        (local_addr, stopper)
+    }
+
+    /// runs a server that answers pings itself and echoes data messages back
+    async fn echo_server() -> (SocketAddr, Stopper) {
+        server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let wrt = wrt.into_shared();
+                rdr.answer_pings(wrt.clone());
+                while let Ok(msg) = rdr.recv().await {
+                    wrt.write(&msg).await.unwrap();
+                }
+            });
+        }).await
+    }
+
+    /// connects and does the handshake by hand, so tests can send arbitrary frames
+    async fn raw_client(sock: SocketAddr) -> TcpStream {
+        let (stream, head) = raw_handshake(sock, "").await;
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    /// sends an upgrade request with the extra header lines, returning the response head
+    async fn raw_handshake(sock: SocketAddr, extra: &str) -> (TcpStream, Vec<u8>) {
+        let mut stream = TcpStream::connect(sock).await.unwrap();
+        let req = format!("GET /ws HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            {}\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", extra);
+        AsyncWriteExt::write_all(&mut stream, req.as_bytes()).await.unwrap();
+        let mut head = vec!();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut b = [0u8];
+            stream.read_exact(&mut b).await.unwrap();
+            head.push(b[0]);
+        }
+        (stream, head)
+    }
+
+    /// writes a frame with the given first byte (FIN, RSV and opcode), masking it if asked
+    async fn send_raw_frame(stream: &mut TcpStream, first: u8, payload: &[u8], masked: bool) {
+        let mut frame = vec!(first);
+        let mask_bit = if masked { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else {
+            frame.push(mask_bit | 126);
+            frame.extend(&(payload.len() as u16).to_be_bytes());
+        }
+        let key = [1u8, 2, 3, 4];
+        if masked {
+            frame.extend(&key);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        } else {
+            frame.extend(payload);
+        }
+        AsyncWriteExt::write_all(stream, &frame).await.unwrap();
+    }
+
+    /// reads an (unmasked, short) frame sent by the server, returning the first byte and payload
+    async fn read_raw_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0u8; 2];
+        stream.read_exact(&mut head).await.unwrap();
+        let mut len = head[1] as usize;
+        if len == 126 {
+            let mut ext = [0u8; 2];
+            stream.read_exact(&mut ext).await.unwrap();
+            len = u16::from_be_bytes(ext) as usize;
+        }
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await.unwrap();
+        (head[0], payload)
+    }
+
+    #[async_std::test]
+    async fn test_hello_world() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, mut wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let want_msg = Message::text("hello world!");
+                let msg = rdr.recv().await.unwrap();
+                assert_eq!(msg, want_msg);
+                wrt.write(&want_msg).await.unwrap();
+            });
+        }).await;
+        let mut client = ClientBuilder::new(&format!("ws://{}/ws", sock)).unwrap()
+            .connect(None)
+            .unwrap();
+        let msg = websocket::Message::text("hello world!");
+        client.send_message(&msg).unwrap();
+        let resp = client.recv_message().unwrap();
+        assert_eq!(resp, websocket::OwnedMessage::Text("hello world!".to_string()));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_origin_check() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let config = UpgradeConfig::new().allow_origins(vec!("https://good.example"));
+                if let Ok((_, mut wrt)) = upgrade_with(&config, &request, stream, reader.buffer()).await {
+                    wrt.write(&Message::text("welcome")).await.unwrap();
+                }
+            });
+        }).await;
+        let (_, head) = raw_handshake(sock, "Origin: https://evil.example\r\n").await;
+        assert!(head.starts_with(b"HTTP/1.1 403"));
+        for extra in &["Origin: https://good.example\r\n", ""] {
+            let (stream, head) = raw_handshake(sock, extra).await;
+            assert!(head.starts_with(b"HTTP/1.1 101"));
+            let (mut rdr, _) = from_stream(stream, Role::Client);
+            assert_eq!(rdr.recv().await.unwrap(), Message::text("welcome"));
+        }
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_upgrade_or_reject() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let _ = upgrade_or_reject(&UpgradeConfig::new(), &request, stream, reader.buffer()).await;
+            });
+        }).await;
+        // (request, start of the response, header the response has to include)
+        let cases: &[(&[u8], &str, &str)] = &[
+            (b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", "HTTP/1.1 426", "Upgrade: websocket"),
+            (b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: a2V5\r\n\r\n", "HTTP/1.1 426", "Sec-WebSocket-Version: 13"),
+            (b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+                Sec-WebSocket-Version: 13\r\n\r\n", "HTTP/1.1 400", "Content-Length: 0"),
+        ];
+        for (req, status, header) in cases {
+            let mut stream = TcpStream::connect(sock).await?;
+            AsyncWriteExt::write_all(&mut stream, req).await?;
+            let mut head = String::new();
+            stream.read_to_string(&mut head).await?;
+            assert!(head.starts_with(status), "{}", head);
+            assert!(head.contains(header), "{}", head);
+        }
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_limit_per_ip() -> Result<(), Box<dyn Error>> {
+        let limit = ConnectionLimit::new(1);
+        let config = Arc::new(UpgradeConfig::new().limit_per_ip(limit.clone()));
+        let (sock, stop) = server(move |stream| {
+            let config = config.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let peer = stream.peer_addr().unwrap().ip();
+                if let Ok((mut rdr, _)) = upgrade_from(&config, peer, &request, stream, reader.buffer()).await {
+                    // hold the connection until the client goes
+                    let _ = rdr.recv().await;
+                }
+            });
+        }).await;
+        let (first, head) = raw_handshake(sock, "").await;
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        let (_, head) = raw_handshake(sock, "").await;
+        assert!(head.starts_with(b"HTTP/1.1 429"), "{}", String::from_utf8_lossy(&head));
+        drop(first);
+        while limit.count(sock.ip()) > 0 {
+            task::sleep(Duration::from_millis(5)).await;
+        }
+        let (_, head) = raw_handshake(sock, "").await;
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_negotiate_extensions() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let config = UpgradeConfig::new().negotiate_extensions(|offered| {
+                    offered.iter()
+                        .filter(|e| e.name == "x-test")
+                        .map(|e| (e.clone(), 0b100))
+                        .take(1)
+                        .collect()
+                });
+                let (mut rdr, mut wrt) = upgrade_or_reject(&config, &request, stream, reader.buffer()).await.unwrap();
+                assert_eq!(rdr.extensions(), &[Extension::new("x-test").with_param("level", Some("2"))]);
+                let frame = rdr.read_frame().await.unwrap();
+                assert_eq!(frame.rsv, 0b100);
+                wrt.write(&Message::binary(frame.payload)).await.unwrap();
+            });
+        }).await;
+        let (mut stream, head) = raw_handshake(sock, "Sec-WebSocket-Extensions: x-other\r\n\
+            Sec-WebSocket-Extensions: x-test; level=2, x-test\r\n").await;
+        let head = String::from_utf8(head)?;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Extensions: x-test; level=2\r\n"), "{}", head);
+        send_raw_frame(&mut stream, 0x80 | 0x40 | 0x2, b"compressed?", true).await;
+        assert_eq!(read_raw_frame(&mut stream).await, (0x82, Vec::from("compressed?")));
+        // and a header that doesn't parse fails the handshake
+        let (_, head) = raw_handshake(sock, "Sec-WebSocket-Extensions: x-test; =\r\n").await;
+        assert!(head.starts_with(b"HTTP/1.1 400"));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_compression() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let config = UpgradeConfig::new()
+                    .websocket(WebSocketConfig::new().compression(DeflateConfig::new().min_size(16)));
+                let (mut rdr, mut wrt) = upgrade_or_reject(&config, &request, stream, reader.buffer()).await.unwrap();
+                // whole, fragmented, and streamed; each echoed back, compressed if it's long enough
+                for _ in 0..2 {
+                    let msg = rdr.recv().await.unwrap();
+                    wrt.write(&msg).await.unwrap();
+                }
+                let mut streamed = String::new();
+                let (typ, mut body) = rdr.recv_stream().await.unwrap();
+                body.read_to_string(&mut streamed).await.unwrap();
+                wrt.write_fragmented(&Message{typ, contents: streamed.into()}, 8).await.unwrap();
+                // a compressed control frame breaks the protocol
+                assert!(matches!(rdr.recv().await, Err(WebSocketError::ProtocolError)));
+            });
+        }).await;
+        let (mut stream, head) = raw_handshake(sock, "Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=9,             permessage-deflate; client_max_window_bits\r\n").await;
+        let head = String::from_utf8(head)?;
+        assert!(head.contains("Sec-WebSocket-Extensions: permessage-deflate; server_no_context_takeover\r\n"), "{}", head);
+        let mut inflater = Inflater::new(false);
+        // "Hello" from RFC 7692, the second time using the first's window
+        send_raw_frame(&mut stream, 0x80 | 0x40 | 0x1, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], true).await;
+        assert_eq!(read_raw_frame(&mut stream).await, (0x81, Vec::from("Hello")));
+        send_raw_frame(&mut stream, 0x40 | 0x1, &[0xf2, 0x00], true).await;
+        send_raw_frame(&mut stream, 0x80, &[0x11, 0x00, 0x00], true).await;
+        assert_eq!(read_raw_frame(&mut stream).await, (0x81, Vec::from("Hello")));
+        let text = "hello hello hello hello hello";
+        let compressed = Deflater::new(6, 0).compress(text.as_bytes()).unwrap();
+        send_raw_frame(&mut stream, 0x80 | 0x40 | 0x1, &compressed, true).await;
+        let (first, mut payload) = read_raw_frame(&mut stream).await;
+        assert_eq!(first, 0x40 | 0x1);
+        loop {
+            let (first, more) = read_raw_frame(&mut stream).await;
+            assert_eq!(first & 0x7f, 0);
+            payload.extend(more);
+            if first & 0x80 != 0 {
+                break;
+            }
+        }
+        assert_eq!(inflater.inflate(&payload, 1024)?, text.as_bytes());
+        send_raw_frame(&mut stream, 0x80 | 0x40 | 0x9, b"", true).await;
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_frame_sent_with_handshake() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let mut stream = TcpStream::connect(sock).await?;
+        // the request and the first frame go out in a single write, so the server's BufReader
+        // is likely to read part of the frame along with the headers
+        let mut req = Vec::from(&b"GET /ws HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"[..]);
+        req.extend(&[0x81, 0x80 | 5, 0, 0, 0, 0]);
+        req.extend(b"eager");
+        AsyncWriteExt::write_all(&mut stream, &req).await?;
+        let mut head = vec!();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut b = [0u8];
+            stream.read_exact(&mut b).await?;
+            head.push(b[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        assert_eq!(read_raw_frame(&mut stream).await, (0x81, Vec::from("eager")));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_source() {
+        let err = WebSocketError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let source = err.source().and_then(|e| e.downcast_ref::<io::Error>()).unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(WebSocketError::WrongVersion.header(), Some("Sec-WebSocket-Version"));
+        assert!(WebSocketError::NoKey.to_string().contains("Sec-WebSocket-Key"));
+        assert!(WebSocketError::TooBig.source().is_none());
+    }
+
+    /// a stream that keeps everything written to it, counting writes and flushes
+    #[derive(Default)]
+    struct Recorder {
+        data: Vec<u8>,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.data.extend(buf);
+            this.writes += 1;
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            self.get_mut().flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn test_feed_and_flush() {
+        // each "tick" is a 6 byte frame
+        let mut wrt = WebSocketWriter::new(Recorder::default(), Role::Server);
+        for _ in 0..3 {
+            wrt.feed(&Message::text("tick")).await.unwrap();
+        }
+        assert_eq!((wrt.stream.writes, wrt.stream.flushes), (2, 0));
+        wrt.flush().await.unwrap();
+        assert_eq!((wrt.stream.writes, wrt.stream.flushes, wrt.stream.data.len()), (3, 1, 18));
+
+        let mut wrt = WebSocketWriter::new(Recorder::default(), Role::Server);
+        wrt.set_auto_flush(Some(30));
+        for _ in 0..12 {
+            wrt.feed(&Message::text("tick")).await.unwrap();
+        }
+        assert_eq!((wrt.stream.writes, wrt.stream.flushes, wrt.stream.data.len()), (2, 2, 60));
+        wrt.flush().await.unwrap();
+        assert_eq!(wrt.stream.data.len(), 72);
+    }
+
+    #[async_std::test]
+    async fn test_websocket_config() -> Result<(), Box<dyn Error>> {
+        let (a, b) = crate::testing::duplex();
+        let config = WebSocketConfig::new().max_payload_size(10).close_timeout(Duration::from_millis(50));
+        let (mut server, _server_wrt) = from_stream_with(a, &config);
+        let client_config = config.clone().role(Role::Client).heartbeat_interval(Duration::from_millis(5));
+        let (mut client, client_wrt) = from_stream_with(b, &client_config);
+        task::spawn(async move { while client.recv().await.is_ok() {} });
+        // answered while the server reads
+        let heartbeat = client_config.heartbeat(client_wrt.clone());
+        let reading = server.recv_deadline(Duration::from_millis(40));
+        match future::select(Box::pin(heartbeat), Box::pin(reading)).await {
+            future::Either::Right((Err(WebSocketError::TimedOut), _)) => (),
+            _ => panic!("the heartbeat should still be going"),
+        }
+        // and not once it stops
+        assert!(matches!(client_config.heartbeat(client_wrt.clone()).await, Err(WebSocketError::TimedOut)));
+        client_wrt.send_text("far too big").await?;
+        assert!(matches!(server.recv().await, Err(WebSocketError::TooBig)));
+        assert!(WebSocketConfig::new().heartbeat(client_wrt).await.is_ok());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_ping_rtt() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let (mut rdr, wrt) = from_stream(raw_client(sock).await, Role::Client);
+        let wrt = wrt.into_shared();
+        rdr.answer_pings(wrt.clone());
+        let (msgs_tx, msgs_rx) = async_std::channel::unbounded();
         task::spawn(async move {
-            let mut incoming = listener.incoming();
-            while let Some(stream) = incoming.next().race(token.wait()).await {
-                if let Ok(stream) = stream {
-                    func(stream);
-                }
+            while let Ok(msg) = rdr.recv().await {
+                msgs_tx.send(msg).await.unwrap();
             }
         });
-        (local_addr, stopper)
+        let (first, second) = wrt.ping().join(wrt.ping()).await;
+        assert!(first? < Duration::from_secs(5));
+        assert!(second? < Duration::from_secs(5));
+        // pongs never show up as messages
+        wrt.write(&Message::text("after")).await?;
+        assert_eq!(msgs_rx.recv().await?, Message::text("after"));
+        stop.shutdown();
+        Ok(())
     }
 
     #[async_std::test]
-    async fn test_hello_world() -> Result<(), Box<dyn Error>> {
+    async fn test_send_helpers() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let (mut rdr, mut wrt) = from_stream(raw_client(sock).await, Role::Client);
+        wrt.send_text("some text").await?;
+        assert_eq!(rdr.recv().await?.into_text().unwrap(), "some text");
+        wrt.send_binary(&[1, 2, 3]).await?;
+        let msg = rdr.recv().await?;
+        assert_eq!((msg.typ, &msg.contents[..]), (MessageType::Binary, &[1u8, 2, 3][..]));
+        assert_eq!(msg.into_text(), None);
+        #[cfg(feature = "json")]
+        {
+            wrt.send_json(&vec!("a", "b")).await?;
+            let got: Vec<String> = rdr.recv().await?.into_json()?;
+            assert_eq!(got, vec!("a", "b"));
+            assert!(Message::text("not json").into_json::<Vec<String>>().is_err());
+        }
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_close_timeout() -> Result<(), Box<dyn Error>> {
+        let (done_tx, done_rx) = async_std::channel::unbounded();
+        let (sock, stop) = server(move |stream| {
+            let done_tx = done_tx.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let (rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let answered = rdr.close(wrt, 1001, "going away", Duration::from_millis(200)).await.unwrap();
+                done_tx.send(answered).await.unwrap();
+            });
+        }).await;
+        // a well behaved peer answers the Close, after some frames that are thrown away
+        let mut stream = raw_client(sock).await;
+        let (first, payload) = read_raw_frame(&mut stream).await;
+        assert_eq!((first, &payload[2..]), (0x88, &b"going away"[..]));
+        send_raw_frame(&mut stream, 0x81, b"late", true).await;
+        send_raw_frame(&mut stream, 0x88, &payload[..2], true).await;
+        assert!(done_rx.recv().await?);
+        // one that doesn't is cut off once the timeout runs out
+        let mut stream = raw_client(sock).await;
+        read_raw_frame(&mut stream).await;
+        send_raw_frame(&mut stream, 0x81, b"not listening", true).await;
+        assert!(!done_rx.recv().await?);
+        assert_eq!(stream.read(&mut [0u8; 16]).await?, 0);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_metrics() -> Result<(), Box<dyn Error>> {
+        use std::sync::atomic::Ordering;
+        let counters = Arc::new(metrics::Counters::default());
+        let (done_tx, done_rx) = async_std::channel::bounded(1);
+        let server_counters = counters.clone();
+        let (sock, stop) = server(move |stream| {
+            let counters = server_counters.clone();
+            let done_tx = done_tx.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let config = UpgradeConfig::new().metrics(counters.clone());
+                let (mut rdr, mut wrt) = upgrade_with(&config, &request, stream, reader.buffer()).await.unwrap();
+                assert_eq!(counters.open.load(Ordering::Relaxed), 1);
+                while let Ok(msg) = rdr.recv().await {
+                    if msg.typ == MessageType::Close {
+                        break;
+                    }
+                    wrt.write(&msg).await.unwrap();
+                }
+                wrt.write(&Message::close(1000, "")).await.unwrap();
+                drop(rdr);
+                done_tx.send(()).await.unwrap();
+            });
+        }).await;
+        let mut stream = raw_client(sock).await;
+        for _ in 0..2 {
+            send_raw_frame(&mut stream, 0x81, b"hello", true).await;
+            read_raw_frame(&mut stream).await;
+        }
+        send_raw_frame(&mut stream, 0x88, &1001u16.to_be_bytes(), true).await;
+        read_raw_frame(&mut stream).await;
+        done_rx.recv().await?;
+        assert_eq!(counters.open.load(Ordering::Relaxed), 0);
+        assert_eq!(counters.messages_in.load(Ordering::Relaxed), 3);
+        assert_eq!(counters.messages_out.load(Ordering::Relaxed), 3);
+        // masked frames from the client: 6 byte header + 5 byte payload, and 6 + 2 for the Close
+        assert_eq!(counters.bytes_in.load(Ordering::Relaxed), 2 * 11 + 8);
+        assert_eq!(counters.bytes_out.load(Ordering::Relaxed), 2 * 7 + 4);
+        assert_eq!(counters.close_codes.lock().unwrap().get(&1001), Some(&1));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_stream_forward_to_sink() -> Result<(), Box<dyn Error>> {
+        let (done_tx, done_rx) = async_std::channel::bounded(1);
+        let (sock, stop) = server(move |stream| {
+            let done_tx = done_tx.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                // echo everything back until the client goes away
+                futures::StreamExt::forward(rdr, wrt).await.unwrap();
+                done_tx.send(()).await.unwrap();
+            });
+        }).await;
+        let mut client = ClientBuilder::new(&format!("ws://{}/ws", sock)).unwrap()
+            .connect(None)
+            .unwrap();
+        for txt in &["one", "two"] {
+            client.send_message(&websocket::Message::text(*txt)).unwrap();
+            let resp = client.recv_message().unwrap();
+            assert_eq!(resp, websocket::OwnedMessage::Text(txt.to_string()));
+        }
+        client.shutdown().unwrap();
+        done_rx.recv().await?;
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_shared_writer_concurrent_senders() -> Result<(), Box<dyn Error>> {
         let (sock, stop) = server(|stream| {
             task::spawn(async move {
                 let mut reader = BufReader::new(stream.clone());
@@ -381,24 +2473,450 @@ mod tests {
                         return;
                     },
                 };
-                let (mut rdr, mut wrt) = upgrade(&request, stream).await.unwrap();
-                let want_msg = Message{
-                    typ: MessageType::Text,
-                    contents: Vec::from("hello world!"),
+                let (_rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let wrt = wrt.into_shared();
+                let mut senders = vec!();
+                for i in 0..4u8 {
+                    let wrt = wrt.clone();
+                    senders.push(task::spawn(async move {
+                        let msg = Message::binary(vec![i; 5000]);
+                        for _ in 0..10 {
+                            wrt.write(&msg).await.unwrap();
+                        }
+                    }));
+                }
+                for sender in senders {
+                    sender.await;
+                }
+            });
+        }).await;
+        let mut client = ClientBuilder::new(&format!("ws://{}/ws", sock)).unwrap()
+            .connect(None)
+            .unwrap();
+        let mut counts = [0; 4];
+        for _ in 0..40 {
+            match client.recv_message().unwrap() {
+                websocket::OwnedMessage::Binary(contents) => {
+                    assert_eq!(contents.len(), 5000);
+                    assert!(contents.iter().all(|b| *b == contents[0]));
+                    counts[contents[0] as usize] += 1;
+                },
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+        assert_eq!(counts, [10; 4]);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_answer_pings() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
                 };
+                let (mut rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let wrt = wrt.into_shared();
+                rdr.answer_pings(wrt.clone());
+                // the ping is answered internally, so the first message we see is the text
                 let msg = rdr.recv().await.unwrap();
-                assert_eq!(msg, want_msg);
-                wrt.write(&want_msg).await.unwrap();
+                assert_eq!(msg.typ, MessageType::Text);
+                wrt.write(&msg).await.unwrap();
             });
         }).await;
         let mut client = ClientBuilder::new(&format!("ws://{}/ws", sock)).unwrap()
             .connect(None)
             .unwrap();
-        let msg = websocket::Message::text("hello world!");
-        client.send_message(&msg).unwrap();
+        client.send_message(&websocket::Message::ping(&b"are you there?"[..])).unwrap();
+        client.send_message(&websocket::Message::text("hello world!")).unwrap();
+        let resp = client.recv_message().unwrap();
+        assert_eq!(resp, websocket::OwnedMessage::Pong(Vec::from("are you there?")));
         let resp = client.recv_message().unwrap();
         assert_eq!(resp, websocket::OwnedMessage::Text("hello world!".to_string()));
         stop.shutdown();
         Ok(())
     }
-}
\ No newline at end of file
+
+    #[async_std::test]
+    async fn test_answer_pings_echoes_close() -> Result<(), Box<dyn Error>> {
+        let (done_tx, done_rx) = async_std::channel::unbounded();
+        let (sock, stop) = server(move |stream| {
+            let done_tx = done_tx.clone();
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let (mut rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let wrt = wrt.into_shared();
+                rdr.answer_pings(wrt.clone());
+                assert!(matches!(rdr.recv().await, Err(WebSocketError::ConnectionClosed)));
+                // nothing more is read from the connection
+                assert!(matches!(rdr.recv().await, Err(WebSocketError::ConnectionClosed)));
+                let started = Instant::now();
+                let answered = rdr.close(wrt, 1000, "bye", Duration::from_secs(5)).await.unwrap();
+                done_tx.send((answered, started.elapsed())).await.unwrap();
+            });
+        }).await;
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x88, &1001u16.to_be_bytes(), true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x88, Vec::from(&1001u16.to_be_bytes()[..])));
+        let (answered, took) = done_rx.recv().await?;
+        assert!(answered);
+        assert!(took < Duration::from_secs(1), "{:?}", took);
+        assert_eq!(client.read(&mut [0u8; 16]).await?, 0);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_recv_stream_echoes_close() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = crate::http(&mut reader, &mut buf).await.unwrap();
+                let (mut rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                rdr.answer_pings(wrt);
+                let (_, mut msg) = rdr.recv_stream().await.unwrap();
+                let mut contents = vec!();
+                assert!(msg.read_to_end(&mut contents).await.is_err());
+                assert_eq!(contents, b"he");
+                assert!(matches!(rdr.recv_stream().await, Err(WebSocketError::ConnectionClosed)));
+            });
+        }).await;
+        // the Close comes in the middle of a fragmented message, without a code
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x01, b"he", true).await;
+        send_raw_frame(&mut client, 0x88, b"", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x88, vec!()));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invalid_utf8_fails_connection() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let mut client = raw_client(sock).await;
+        // valid utf-8 split across fragments is fine (é is 0xC3 0xA9)
+        send_raw_frame(&mut client, 0x01, &[b'h', 0xC3], true).await;
+        send_raw_frame(&mut client, 0x80, &[0xA9], true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x81, Vec::from("hé")));
+        // but a lone continuation byte is not
+        send_raw_frame(&mut client, 0x81, &[0xA9], true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x88, Vec::from(&1007u16.to_be_bytes()[..])));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_text() {
+        let msg = Message::text("hello");
+        assert_eq!(msg.as_text(), Some("hello"));
+        assert_eq!(Message::close(1000, "").as_text(), None);
+    }
+
+    #[async_std::test]
+    async fn test_unmasked_client_frame_fails_connection() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x81, b"hello", false).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x88, Vec::from(&1002u16.to_be_bytes()[..])));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invalid_frames_fail_connection() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let protocol_error = (0x88, Vec::from(&1002u16.to_be_bytes()[..]));
+        // RSV1 without an extension
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0xC1, b"hello", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, protocol_error);
+        // ping that's too large
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x89, &[0u8; 126], true).await;
+        assert_eq!(read_raw_frame(&mut client).await, protocol_error);
+        // fragmented ping
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x09, b"ping", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, protocol_error);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_fragmentation() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        let protocol_error = (0x88, Vec::from(&1002u16.to_be_bytes()[..]));
+        // control frames can be interleaved, and the message keeps its original type
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x02, b"one ", true).await;
+        send_raw_frame(&mut client, 0x89, b"ping", true).await;
+        send_raw_frame(&mut client, 0x00, b"two ", true).await;
+        send_raw_frame(&mut client, 0x80, b"three", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x8A, Vec::from("ping")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x82, Vec::from("one two three")));
+        // continuing nothing
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x80, b"nope", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, protocol_error);
+        // starting a new message before the last one finished
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x01, b"one", true).await;
+        send_raw_frame(&mut client, 0x81, b"two", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, protocol_error);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_write_fragmented() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (_rdr, mut wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                wrt.write_fragmented(&Message::text("hello world!"), 5).await.unwrap();
+            });
+        }).await;
+        let mut client = raw_client(sock).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x01, Vec::from("hello")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x00, Vec::from(" worl")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x80, Vec::from("d!")));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_interleave_control_frames() -> Result<(), Box<dyn Error>> {
+        // a stream that makes writers wait every other write, like a full socket buffer
+        #[derive(Clone)]
+        struct Slow(crate::testing::Duplex, Arc<std::sync::atomic::AtomicBool>);
+        impl AsyncRead for Slow {
+            fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_read(cx, buf)
+            }
+        }
+        impl AsyncWrite for Slow {
+            fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+                if !self.1.fetch_xor(true, std::sync::atomic::Ordering::Relaxed) {
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Pin::new(&mut self.0).poll_write(cx, buf)
+            }
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_flush(cx)
+            }
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_close(cx)
+            }
+        }
+        let (a, b) = crate::testing::duplex();
+        let (_, wrt) = from_stream(Slow(a, Arc::default()), Role::Server);
+        let wrt = wrt.into_shared();
+        let (mut client, _) = from_stream(b, Role::Client);
+        let big = Message::binary(vec![7u8; 64]);
+        let ping = async {
+            task::yield_now().await;
+            wrt.write(&Message{typ: MessageType::Ping, contents: Bytes::from("hi")}).await
+        };
+        let (sent, pinged) = future::join(wrt.write_fragmented(&big, 8), ping).await;
+        sent?;
+        pinged?;
+        let mut frames = vec!();
+        while frames.last().is_none_or(|frame: &Frame| !frame.fin || frame.typ.is_control()) {
+            frames.push(client.read_frame().await?);
+        }
+        let ping = frames.iter().position(|frame| frame.typ == MessageType::Ping).unwrap();
+        assert!(ping > 0 && ping < frames.len() - 1, "ping at {} of {}", ping, frames.len());
+        assert_eq!(frames.len(), 9);
+
+        // a Close ends the message there
+        let close = async {
+            task::yield_now().await;
+            wrt.write(&Message::close(1001, "")).await
+        };
+        let (sent, closed) = future::join(wrt.write_fragmented(&big, 8), close).await;
+        closed?;
+        assert!(matches!(sent, Err(WebSocketError::ConnectionClosed)));
+        loop {
+            let frame = client.read_frame().await?;
+            if frame.typ == MessageType::Close {
+                break;
+            }
+            assert!(!frame.fin);
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_frames() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, mut wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                rdr.allow_rsv_bits(0b100);
+                while let Ok(frame) = rdr.read_frame().await {
+                    wrt.write_frame(&frame).await.unwrap();
+                }
+            });
+        }).await;
+        let mut client = raw_client(sock).await;
+        send_raw_frame(&mut client, 0x41, b"one", true).await;
+        send_raw_frame(&mut client, 0x89, b"ping", true).await;
+        send_raw_frame(&mut client, 0x80, b"two", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x41, Vec::from("one")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x89, Vec::from("ping")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x80, Vec::from("two")));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_recv_stream() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                let wrt = wrt.into_shared();
+                rdr.answer_pings(wrt.clone());
+                // larger than the payload size limit, which only applies to recv()
+                let (typ, mut payload) = rdr.recv_stream().await.unwrap();
+                assert_eq!(typ, MessageType::Binary);
+                let mut contents = vec!();
+                futures::AsyncReadExt::read_to_end(&mut payload, &mut contents).await.unwrap();
+                assert!(contents.iter().enumerate().all(|(i, b)| *b == (i % 251) as u8));
+                wrt.write(&Message::text(&format!("{}", contents.len()))).await.unwrap();
+                // dropping the payload without reading it skips the rest of the message
+                let (_, _payload) = rdr.recv_stream().await.unwrap();
+                let msg = rdr.recv().await.unwrap();
+                wrt.write(&msg).await.unwrap();
+            });
+        }).await;
+        let mut client = raw_client(sock).await;
+        let contents: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        send_raw_frame(&mut client, 0x02, &contents[..10000], true).await;
+        send_raw_frame(&mut client, 0x89, b"ping", true).await;
+        send_raw_frame(&mut client, 0x00, &contents[10000..15000], true).await;
+        send_raw_frame(&mut client, 0x80, &contents[15000..], true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x8A, Vec::from("ping")));
+        assert_eq!(read_raw_frame(&mut client).await, (0x81, Vec::from("20000")));
+        send_raw_frame(&mut client, 0x01, b"skip ", true).await;
+        send_raw_frame(&mut client, 0x80, b"me", true).await;
+        send_raw_frame(&mut client, 0x81, b"hello", true).await;
+        assert_eq!(read_raw_frame(&mut client).await, (0x81, Vec::from("hello")));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_large_messages() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, mut wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                rdr.set_max_payload_size(1 << 20);
+                let msg = rdr.recv().await.unwrap();
+                wrt.write(&msg).await.unwrap();
+            });
+        }).await;
+        let mut client = ClientBuilder::new(&format!("ws://{}/ws", sock)).unwrap()
+            .connect(None)
+            .unwrap();
+        // big enough to need the 64 bit length
+        let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        client.send_message(&websocket::Message::binary(contents.clone())).unwrap();
+        let resp = client.recv_message().unwrap();
+        assert_eq!(resp, websocket::OwnedMessage::Binary(contents));
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_client_role() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = echo_server().await;
+        // the server only accepts masked frames, and only sends unmasked ones
+        let client = raw_client(sock).await;
+        let (mut rdr, mut wrt) = from_stream(client, Role::Client);
+        let msg = Message::text("hello world!");
+        wrt.write(&msg).await.unwrap();
+        assert_eq!(rdr.recv().await.unwrap(), msg);
+        let msg = Message::binary(vec![7; 10_000]);
+        wrt.write(&msg).await.unwrap();
+        assert_eq!(rdr.recv().await.unwrap(), msg);
+        stop.shutdown();
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_recv_deadline() -> Result<(), Box<dyn Error>> {
+        let (sock, stop) = server(|stream| {
+            task::spawn(async move {
+                let mut reader = BufReader::new(stream.clone());
+                let mut buf = vec![0; 65536];
+                let request = match crate::http(&mut reader, &mut buf).await {
+                    Ok(req) => req,
+                    Err(_) => {
+                        return;
+                    },
+                };
+                let (mut rdr, mut wrt) = upgrade(&request, stream, reader.buffer()).await.unwrap();
+                // times out in the middle of a frame, which is picked up again afterwards
+                match rdr.recv_deadline(Duration::from_millis(50)).await {
+                    Err(WebSocketError::TimedOut) => {},
+                    other => panic!("expected a timeout, got {:?}", other),
+                }
+                let (stopper, token) = Stopper::new();
+                stopper.shutdown();
+                assert_eq!(rdr.recv_until(token).await.unwrap(), None);
+                wrt.write(&Message::text("timed out")).await.unwrap();
+                let msg = rdr.recv_deadline(Duration::from_secs(5)).await.unwrap();
+                wrt.write(&msg).await.unwrap();
+            });
+        }).await;
+        let mut client = raw_client(sock).await;
+        // half of a masked text frame with "hello"
+        let frame = [0x81, 0x85, 1, 2, 3, 4, b'h' ^ 1, b'e' ^ 2, b'l' ^ 3, b'l' ^ 4, b'o' ^ 1];
+        AsyncWriteExt::write_all(&mut client, &frame[..4]).await.unwrap();
+        assert_eq!(read_raw_frame(&mut client).await, (0x81, Vec::from("timed out")));
+        AsyncWriteExt::write_all(&mut client, &frame[4..]).await.unwrap();
+        assert_eq!(read_raw_frame(&mut client).await, (0x81, Vec::from("hello")));
+        stop.shutdown();
+        Ok(())
+    }
+}
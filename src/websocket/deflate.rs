@@ -0,0 +1,282 @@
+//! permessage-deflate (RFC 7692): agreeing on it in the handshake, then compressing the
+//! messages sent and inflating the ones received.
+//!
+//! Turn it on with WebSocketConfig::compression, on the config given to UpgradeConfig::websocket;
+//! it's only used with clients that offer it. This side compresses each message on its own
+//! (it always answers with server_no_context_takeover), so clones of a writer don't need to
+//! share any state, and never limits its window, so offers asking for a smaller
+//! server_max_window_bits are declined. What clients send is inflated whatever window they
+//! use, with or without context takeover.
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use super::{extensions::Extension, WebSocketError};
+
+pub const NAME: &str = "permessage-deflate";
+/// The RSV bit set on the first frame of a compressed message.
+pub const RSV1: u8 = 0b100;
+
+// a sync flush ends with this; it's left off the wire, and put back to inflate
+const TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateConfig {
+    /// Messages smaller than this, in bytes, are sent as they are.
+    pub min_size: usize,
+    /// From 0 (fastest) to 9 (smallest).
+    pub level: u32,
+    /// Asks clients to compress each message on its own too, so the connection doesn't keep
+    /// their 32KB window between messages; they compress less well for it.
+    pub client_no_context_takeover: bool,
+}
+
+impl Default for DeflateConfig {
+    fn default() -> Self {
+        DeflateConfig{
+            min_size: 256,
+            level: 6,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+impl DeflateConfig {
+    pub fn new() -> Self {
+        DeflateConfig::default()
+    }
+
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    pub fn client_no_context_takeover(mut self, reset: bool) -> Self {
+        self.client_no_context_takeover = reset;
+        self
+    }
+
+    /// The response to the first of the client's permessage-deflate offers that can be
+    /// accepted, and whether the client resets its context after each message.
+    pub(crate) fn negotiate(&self, offers: &[Extension]) -> Option<(Extension, bool)> {
+        let offer = offers.iter().find(|offer| offer.name.eq_ignore_ascii_case(NAME) && acceptable(offer))?;
+        let client_resets = self.client_no_context_takeover || offer.has_param("client_no_context_takeover");
+        let mut response = Extension::new(NAME).with_param("server_no_context_takeover", None);
+        if client_resets {
+            response = response.with_param("client_no_context_takeover", None);
+        }
+        Some((response, client_resets))
+    }
+}
+
+/// whether every parameter of the offer is known, given at most once, and one we can honour
+fn acceptable(offer: &Extension) -> bool {
+    let window_bits = |value: &str| value.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits));
+    offer.params.iter().enumerate().all(|(i, (name, value))| {
+        let repeated = offer.params[..i].iter().any(|(other, _)| other.eq_ignore_ascii_case(name));
+        let valid = match (name.to_ascii_lowercase().as_str(), value.as_deref()) {
+            ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => true,
+            // we always compress with the largest window
+            ("server_max_window_bits", Some(bits)) => bits == "15",
+            // and inflate with it, so any window the client picks is fine
+            ("client_max_window_bits", None) => true,
+            ("client_max_window_bits", Some(bits)) => window_bits(bits),
+            _ => false,
+        };
+        valid && !repeated
+    })
+}
+
+/// Compresses outgoing messages, each on its own.
+pub(crate) struct Deflater {
+    compress: Compress,
+    level: u32,
+    min_size: usize,
+}
+
+impl Clone for Deflater {
+    fn clone(&self) -> Self {
+        Deflater::new(self.level, self.min_size)
+    }
+}
+
+impl Deflater {
+    pub fn new(level: u32, min_size: usize) -> Self {
+        Deflater{
+            compress: Compress::new(Compression::new(level), false),
+            level,
+            min_size,
+        }
+    }
+
+    /// The payload compressed, unless it's too small to bother with or doesn't get smaller.
+    pub fn compress(&mut self, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < self.min_size {
+            return None;
+        }
+        self.compress.reset();
+        let mut out = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            let used = self.compress.total_in() as usize;
+            self.compress.compress_vec(&payload[used..], &mut out, FlushCompress::Sync).ok()?;
+            // once the flush has room to finish, everything's out
+            if self.compress.total_in() as usize == payload.len() && out.len() < out.capacity() {
+                break;
+            }
+            out.reserve(out.capacity().max(64));
+        }
+        if out.ends_with(&TAIL) {
+            out.truncate(out.len() - TAIL.len());
+        }
+        (out.len() < payload.len()).then_some(out)
+    }
+}
+
+/// Inflates incoming messages, keeping the window between them unless the client resets its.
+pub(crate) struct Inflater {
+    decompress: Decompress,
+    reset: bool,
+    // how much of TAIL has been fed in after the current message
+    tail: usize,
+    // the client ended the stream with a final block, so the next message starts afresh
+    ended: bool,
+}
+
+impl Inflater {
+    pub fn new(reset: bool) -> Self {
+        Inflater{
+            decompress: Decompress::new(false),
+            reset,
+            tail: 0,
+            ended: false,
+        }
+    }
+
+    /// Inflates as much of `input` into `out` as fits, returning how much of each was used.
+    /// Once the message's compressed bytes have all been given, keep calling with an empty
+    /// `input` until is_done.
+    pub fn inflate_some(&mut self, input: &[u8], out: &mut [u8]) -> Result<(usize, usize), WebSocketError> {
+        let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+        let ending = input.is_empty();
+        let input = if ending { &TAIL[self.tail..] } else { input };
+        let status = self.decompress.decompress(input, out, FlushDecompress::Sync)
+            .or(Err(WebSocketError::ProtocolError))?;
+        if status == Status::StreamEnd {
+            self.ended = true;
+        }
+        let used = (self.decompress.total_in() - before_in) as usize;
+        let made = (self.decompress.total_out() - before_out) as usize;
+        let stuck = used == 0 && made == 0 && !out.is_empty();
+        if ending {
+            if stuck && !self.ended && self.tail < TAIL.len() {
+                return Err(WebSocketError::ProtocolError);
+            }
+            // after a final block the rest of the tail is never taken
+            self.tail = if self.ended { TAIL.len() } else { self.tail + used };
+            return Ok((0, made));
+        }
+        // stuck on input that isn't deflate (or that follows a final block)
+        if stuck {
+            return Err(WebSocketError::ProtocolError);
+        }
+        Ok((used, made))
+    }
+
+    /// Whether the last call to inflate_some, with an empty input, finished the message.
+    pub fn is_done(&self, made: usize) -> bool {
+        self.tail == TAIL.len() && made == 0
+    }
+
+    /// Gets ready for the next message.
+    pub fn finish(&mut self) {
+        self.tail = 0;
+        if self.reset || self.ended {
+            self.decompress.reset(false);
+            self.ended = false;
+        }
+    }
+
+    /// A whole message's payload inflated, failing with TooBig once it's over `limit`.
+    pub fn inflate(&mut self, mut payload: &[u8], limit: u64) -> Result<Vec<u8>, WebSocketError> {
+        let mut inflated = vec!();
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            let (used, made) = self.inflate_some(payload, &mut buf)?;
+            inflated.extend_from_slice(&buf[..made]);
+            if inflated.len() as u64 > limit {
+                return Err(WebSocketError::TooBig);
+            }
+            if payload.is_empty() && self.is_done(made) {
+                self.finish();
+                return Ok(inflated);
+            }
+            payload = &payload[used..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let config = DeflateConfig::new();
+        let offer = |params: &[(&str, Option<&str>)]| {
+            params.iter().fold(Extension::new(NAME), |offer, (name, value)| offer.with_param(name, *value))
+        };
+        let (response, resets) = config.negotiate(&[offer(&[("client_max_window_bits", None)])]).unwrap();
+        assert_eq!(response, offer(&[("server_no_context_takeover", None)]));
+        assert!(!resets);
+        // the first one we can do
+        let offers = [
+            offer(&[("server_max_window_bits", Some("10"))]),
+            offer(&[("x-unknown", None)]),
+            offer(&[("client_no_context_takeover", None), ("client_no_context_takeover", None)]),
+            offer(&[("client_no_context_takeover", None), ("client_max_window_bits", Some("12"))]),
+        ];
+        let (response, resets) = config.negotiate(&offers).unwrap();
+        assert_eq!(response, offer(&[("server_no_context_takeover", None), ("client_no_context_takeover", None)]));
+        assert!(resets);
+        assert!(config.negotiate(&offers[..3]).is_none());
+        assert!(config.negotiate(&[Extension::new("x-deflate")]).is_none());
+        let (_, resets) = config.client_no_context_takeover(true).negotiate(&[offer(&[])]).unwrap();
+        assert!(resets);
+    }
+
+    #[test]
+    fn test_inflate() {
+        // RFC 7692 section 7.2.3.2: "Hello" twice, the second using the first's window
+        let mut inflater = Inflater::new(false);
+        assert_eq!(inflater.inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 100).unwrap(), b"Hello");
+        assert_eq!(inflater.inflate(&[0xf2, 0x00, 0x11, 0x00, 0x00], 100).unwrap(), b"Hello");
+        // 7.2.3.3: with a final block, and no tail
+        let mut inflater = Inflater::new(false);
+        assert_eq!(inflater.inflate(&[0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 100).unwrap(), b"Hello");
+        assert_eq!(inflater.inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 100).unwrap(), b"Hello");
+        assert!(matches!(inflater.inflate(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00], 4), Err(WebSocketError::TooBig)));
+        assert!(matches!(Inflater::new(false).inflate(b"\xff\xff\xff", 100), Err(WebSocketError::ProtocolError)));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut deflater = Deflater::new(6, 16);
+        let mut inflater = Inflater::new(false);
+        assert_eq!(deflater.compress(b"short"), None);
+        let text = "all work and no play makes jack a dull boy ".repeat(100);
+        for _ in 0..2 {
+            let compressed = deflater.compress(text.as_bytes()).unwrap();
+            assert!(compressed.len() < text.len() / 10, "{}", compressed.len());
+            assert!(!compressed.ends_with(&TAIL));
+            // each message stands alone, so even a fresh inflater can read it
+            assert_eq!(inflater.inflate(&compressed, 1 << 20).unwrap(), text.as_bytes());
+            assert_eq!(Inflater::new(true).inflate(&compressed, 1 << 20).unwrap(), text.as_bytes());
+        }
+        // random bytes don't get any smaller
+        let noise: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        assert_eq!(deflater.compress(&noise), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Parsing and formatting of the Sec-WebSocket-Extensions header (RFC 6455 section 9.1),
+//! e.g. `permessage-deflate; client_max_window_bits, x-custom; mode="fast"`.
+use std::fmt;
+
+use super::WebSocketError;
+
+/// One extension from the header, with its parameters in the order they were given.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extension {
+    pub name: String,
+    /// Parameters like `client_no_context_takeover` are flags and have no value.
+    pub params: Vec<(String, Option<String>)>,
+}
+
+impl Extension {
+    pub fn new(name: &str) -> Self {
+        Extension{
+            name: name.into(),
+            params: vec!(),
+        }
+    }
+
+    /// Adds a parameter; pass None for a flag.
+    pub fn with_param(mut self, name: &str, value: Option<&str>) -> Self {
+        self.params.push((name.into(), value.map(Into::into)));
+        self
+    }
+
+    pub fn has_param(&self, name: &str) -> bool {
+        self.params.iter().any(|(k, _)| k.eq_ignore_ascii_case(name))
+    }
+
+    /// The value of the named parameter; None if it's missing or a flag.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .and_then(|(_, v)| v.as_deref())
+    }
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for (name, value) in &self.params {
+            write!(f, "; {}", name)?;
+            match value {
+                Some(value) if !value.is_empty() && value.chars().all(is_tchar) => write!(f, "={}", value)?,
+                Some(value) => write!(f, "=\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))?,
+                None => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses every value of the header (it may be sent more than once) into one list, in order.
+pub fn parse(values: &[&[u8]]) -> Result<Vec<Extension>, WebSocketError> {
+    let mut extensions = vec!();
+    for value in values {
+        let value = std::str::from_utf8(value).or(Err(WebSocketError::BadExtensions))?;
+        let mut p = Parser{rest: value};
+        loop {
+            // empty list elements ("a, , b") are allowed
+            if p.eat(',') {
+                continue;
+            }
+            p.skip_ws();
+            if p.rest.is_empty() {
+                break;
+            }
+            let mut extension = Extension::new(p.token()?);
+            while p.eat(';') {
+                let name = p.token()?;
+                let value = if p.eat('=') {
+                    p.skip_ws();
+                    if p.rest.starts_with('"') {
+                        Some(p.quoted()?)
+                    } else {
+                        Some(p.token()?.into())
+                    }
+                } else {
+                    None
+                };
+                extension.params.push((name.into(), value));
+            }
+            extensions.push(extension);
+            p.skip_ws();
+            if !p.rest.is_empty() && !p.eat(',') {
+                return Err(WebSocketError::BadExtensions);
+            }
+        }
+    }
+    Ok(extensions)
+}
+
+/// Formats extensions as a header value.
+pub fn format(extensions: &[Extension]) -> String {
+    extensions.iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_ws(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t']);
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        if self.rest.starts_with(c) {
+            self.rest = &self.rest[1..];
+            return true;
+        }
+        false
+    }
+
+    fn token(&mut self) -> Result<&'a str, WebSocketError> {
+        self.skip_ws();
+        let end = self.rest.find(|c| !is_tchar(c)).unwrap_or(self.rest.len());
+        if end == 0 {
+            return Err(WebSocketError::BadExtensions);
+        }
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(token)
+    }
+
+    /// reads a quoted string, starting at the opening quote
+    fn quoted(&mut self) -> Result<String, WebSocketError> {
+        let mut out = String::new();
+        let mut chars = self.rest.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(out);
+                },
+                '\\' => match chars.next() {
+                    Some((_, c)) => out.push(c),
+                    None => break,
+                },
+                c => out.push(c),
+            }
+        }
+        Err(WebSocketError::BadExtensions)
+    }
+}
+
+/// characters allowed in an RFC 7230 token
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let got = parse(&[
+            b"permessage-deflate; client_max_window_bits, permessage-deflate;server_max_window_bits=10",
+            b"x-custom; mode=\"fast \\\"mode\\\"\" , ,x-other",
+        ]).unwrap();
+        assert_eq!(got, vec!(
+            Extension::new("permessage-deflate").with_param("client_max_window_bits", None),
+            Extension::new("permessage-deflate").with_param("server_max_window_bits", Some("10")),
+            Extension::new("x-custom").with_param("mode", Some("fast \"mode\"")),
+            Extension::new("x-other"),
+        ));
+        assert!(got[0].has_param("client_max_window_bits"));
+        assert_eq!(got[1].param("server_max_window_bits"), Some("10"));
+        assert_eq!(format(&got[1..3]), "permessage-deflate; server_max_window_bits=10, x-custom; mode=\"fast \\\"mode\\\"\"");
+        for bad in &[&b"; foo"[..], b"foo bar", b"foo; =1", b"foo; a=\"unterminated", b"foo; a=b c"] {
+            assert!(parse(&[bad]).is_err(), "{:?}", std::str::from_utf8(bad));
+        }
+    }
+}
@@ -0,0 +1,321 @@
+//! A registry of websocket connections grouped into rooms, for broadcasting messages to
+//! everyone in a room (chat, presence, live dashboards, ...).
+//!
+//! Each connection gets its own bounded send queue (see the queue module), so how a slow
+//! client affects broadcasts is down to the hub's Backpressure policy. The hub doesn't spawn
+//! anything itself; `register` hands back the future that drains a connection's queue, and
+//! it's up to you to run it on whatever executor you use.
+//!
+//! Clients that vanish without closing (a laptop lid shut, a dropped mobile connection) can
+//! hold on to their queue and room memberships for a long time. Touch a connection whenever
+//! something arrives from it, and reap_every closes the ones that have gone quiet:
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use async_std::{net::TcpStream, task};
+//! # use oc_http::websocket::{hub::Hub, WebSocketReader, WebSocketWriter};
+//! # async fn serve(hub: Hub, mut rdr: WebSocketReader<TcpStream>, wrt: WebSocketWriter<TcpStream>) {
+//! task::spawn(hub.reap_every(Duration::from_secs(30), Duration::from_secs(120)));
+//! let (id, send_loop) = hub.register(wrt);
+//! task::spawn(send_loop);
+//! while let Ok(msg) = rdr.recv().await {
+//!     hub.touch(id);
+//!     // ...
+//! }
+//! # }
+//! ```
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    AsyncWrite,
+    Future,
+};
+
+use super::{
+    queue::{Backpressure, SendQueue},
+    Message,
+    SharedWebSocketWriter,
+    WebSocketError,
+};
+
+/// Size of each connection's send queue for Hub::new().
+pub const DEFAULT_QUEUE_SIZE: usize = 1024;
+
+/// The close code reaped connections are sent: going away.
+pub const IDLE_CLOSE_CODE: u16 = 1001;
+
+/// Identifies a connection registered with a Hub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u64);
+
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<Mutex<HubState>>,
+}
+
+struct HubState {
+    next_id: u64,
+    queue_size: usize,
+    policy: Backpressure,
+    connections: HashMap<ConnectionId, Connection>,
+    rooms: HashMap<String, HashSet<ConnectionId>>,
+}
+
+struct Connection {
+    queue: SendQueue,
+    last_active: Instant,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub::with_queue(DEFAULT_QUEUE_SIZE, Backpressure::Disconnect)
+    }
+}
+
+impl Hub {
+    /// A hub that disconnects clients which fall DEFAULT_QUEUE_SIZE messages behind.
+    pub fn new() -> Self {
+        Hub::default()
+    }
+
+    /// A hub whose connections each queue up to `queue_size` messages, with `policy` deciding
+    /// what happens once a connection's queue is full.
+    pub fn with_queue(queue_size: usize, policy: Backpressure) -> Self {
+        Hub{
+            inner: Arc::new(Mutex::new(HubState{
+                next_id: 0,
+                queue_size,
+                policy,
+                connections: HashMap::default(),
+                rooms: HashMap::default(),
+            })),
+        }
+    }
+
+    /// Adds a connection to the hub, returning its id and the future that writes everything
+    /// queued for it. Spawn the future; it finishes (and the connection is unregistered) when
+    /// the connection is unregistered or writing to it fails.
+    pub fn register<S, W>(&self, writer: W) -> (ConnectionId, impl Future<Output = Result<(), WebSocketError>>)
+    where S: AsyncWrite + Unpin,
+        W: Into<SharedWebSocketWriter<S>>,
+    {
+        let mut state = self.inner.lock().unwrap();
+        let (queue, drain) = SendQueue::new(writer, state.queue_size, state.policy);
+        let id = ConnectionId(state.next_id);
+        state.next_id += 1;
+        state.connections.insert(id, Connection{queue, last_active: Instant::now()});
+        let hub = self.clone();
+        let send_loop = async move {
+            let res = drain.await;
+            hub.unregister(id);
+            res
+        };
+        (id, send_loop)
+    }
+
+    /// Removes the connection from every room and stops its send loop once the messages
+    /// already queued have been written.
+    pub fn unregister(&self, id: ConnectionId) {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(connection) = state.connections.remove(&id) {
+            connection.queue.close();
+        }
+        state.rooms.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+
+    /// Notes that something was heard from the connection, so it isn't reaped as idle.
+    pub fn touch(&self, id: ConnectionId) {
+        if let Some(connection) = self.inner.lock().unwrap().connections.get_mut(&id) {
+            connection.last_active = Instant::now();
+        }
+    }
+
+    /// Unregisters every connection that hasn't been touched (or registered) for `idle`,
+    /// sending it a Close with IDLE_CLOSE_CODE after whatever's already queued for it.
+    /// Returns the connections reaped.
+    pub fn reap(&self, idle: Duration) -> Vec<ConnectionId> {
+        reap(&self.inner, idle)
+    }
+
+    /// A future that reaps connections idle for `idle` every `interval`, for the caller to
+    /// spawn; it finishes once every clone of the hub has been dropped and every send loop
+    /// has finished.
+    pub fn reap_every(&self, interval: Duration, idle: Duration) -> impl Future<Output = ()> {
+        let inner: Weak<Mutex<HubState>> = Arc::downgrade(&self.inner);
+        async move {
+            loop {
+                futures_timer::Delay::new(interval).await;
+                match inner.upgrade() {
+                    Some(inner) => reap(&inner, idle),
+                    None => return,
+                };
+            }
+        }
+    }
+
+    /// Adds the connection to a room, creating the room if needed. Unknown connections are ignored.
+    pub fn join(&self, id: ConnectionId, room: &str) {
+        let mut state = self.inner.lock().unwrap();
+        if state.connections.contains_key(&id) {
+            state.rooms.entry(room.into()).or_default().insert(id);
+        }
+    }
+
+    pub fn leave(&self, id: ConnectionId, room: &str) {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(members) = state.rooms.get_mut(room) {
+            members.remove(&id);
+            if members.is_empty() {
+                state.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Queues the message for a single connection; returns false if it isn't registered or
+    /// the message was dropped by the backpressure policy.
+    pub async fn send(&self, id: ConnectionId, msg: &Message) -> bool {
+        let queue = self.inner.lock().unwrap().connections.get(&id).map(|c| c.queue.clone());
+        match queue {
+            Some(queue) => queue.send(msg.clone()).await,
+            None => false,
+        }
+    }
+
+    /// Queues the message for everyone in the room, returning how many connections it was
+    /// queued for. With Backpressure::Block this waits for room in every member's queue.
+    pub async fn broadcast(&self, room: &str, msg: &Message) -> usize {
+        let queues: Vec<SendQueue> = {
+            let state = self.inner.lock().unwrap();
+            match state.rooms.get(room) {
+                Some(members) => members.iter()
+                    .filter_map(|id| state.connections.get(id).map(|c| c.queue.clone()))
+                    .collect(),
+                None => return 0,
+            }
+        };
+        let mut count = 0;
+        for queue in queues {
+            if queue.send(msg.clone()).await {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// The connections currently in the room.
+    pub fn members(&self, room: &str) -> Vec<ConnectionId> {
+        let state = self.inner.lock().unwrap();
+        state.rooms.get(room)
+            .map(|members| members.iter().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+fn reap(inner: &Mutex<HubState>, idle: Duration) -> Vec<ConnectionId> {
+    let mut state = inner.lock().unwrap();
+    let now = Instant::now();
+    let reaped: Vec<ConnectionId> = state.connections.iter()
+        .filter(|(_, c)| now.duration_since(c.last_active) >= idle)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in &reaped {
+        if let Some(connection) = state.connections.remove(id) {
+            connection.queue.close_with(Message::close(IDLE_CLOSE_CODE, "idle"));
+        }
+    }
+    state.rooms.retain(|_, members| {
+        members.retain(|id| !reaped.contains(id));
+        !members.is_empty()
+    });
+    reaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use async_std::{
+        task,
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+    };
+
+    use super::*;
+    use crate::websocket::{from_stream, Role, WebSocketReader, WebSocketWriter};
+
+    /// a connected (server writer, client reader) pair, skipping the handshake
+    async fn pair() -> (WebSocketWriter<TcpStream>, WebSocketReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, wrt) = from_stream(server, Role::Server);
+        let (rdr, _) = from_stream(client, Role::Client);
+        (wrt, rdr)
+    }
+
+    #[async_std::test]
+    async fn test_broadcast_to_room() -> Result<(), Box<dyn Error>> {
+        let hub = Hub::new();
+        let mut clients = vec!();
+        let mut ids = vec!();
+        for _ in 0..3 {
+            let (wrt, rdr) = pair().await;
+            let (id, send_loop) = hub.register(wrt);
+            task::spawn(send_loop);
+            clients.push(rdr);
+            ids.push(id);
+        }
+        hub.join(ids[0], "chat");
+        hub.join(ids[1], "chat");
+        hub.join(ids[2], "lobby");
+        assert_eq!(hub.broadcast("chat", &Message::text("hi chat")).await, 2);
+        assert_eq!(hub.broadcast("nobody", &Message::text("hello?")).await, 0);
+        hub.leave(ids[1], "chat");
+        hub.broadcast("chat", &Message::text("just you")).await;
+        assert!(hub.send(ids[2], &Message::text("direct")).await);
+        assert_eq!(clients[0].recv().await.unwrap(), Message::text("hi chat"));
+        assert_eq!(clients[0].recv().await.unwrap(), Message::text("just you"));
+        assert_eq!(clients[1].recv().await.unwrap(), Message::text("hi chat"));
+        assert_eq!(clients[2].recv().await.unwrap(), Message::text("direct"));
+        hub.unregister(ids[0]);
+        assert!(hub.members("chat").is_empty());
+        assert!(!hub.send(ids[0], &Message::text("gone")).await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reap_idle() -> Result<(), Box<dyn Error>> {
+        let hub = Hub::new();
+        let (quiet_wrt, mut quiet) = pair().await;
+        let (busy_wrt, mut busy) = pair().await;
+        let (quiet_id, quiet_loop) = hub.register(quiet_wrt);
+        let (busy_id, busy_loop) = hub.register(busy_wrt);
+        let quiet_loop = task::spawn(quiet_loop);
+        task::spawn(busy_loop);
+        hub.join(quiet_id, "chat");
+        hub.join(busy_id, "chat");
+        let reaper = task::spawn(hub.reap_every(Duration::from_millis(10), Duration::from_millis(50)));
+        for _ in 0..8 {
+            task::sleep(Duration::from_millis(10)).await;
+            hub.touch(busy_id);
+        }
+        assert_eq!(hub.members("chat"), vec!(busy_id));
+        assert!(!hub.send(quiet_id, &Message::text("too late")).await);
+        assert_eq!(quiet.recv().await.unwrap(), Message::close(IDLE_CLOSE_CODE, "idle"));
+        quiet_loop.await?;
+        assert!(hub.send(busy_id, &Message::text("still here")).await);
+        assert_eq!(busy.recv().await.unwrap(), Message::text("still here"));
+        assert_eq!(hub.reap(Duration::from_secs(0)), vec!(busy_id));
+        drop(hub);
+        reaper.await;
+        Ok(())
+    }
+}
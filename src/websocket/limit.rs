@@ -0,0 +1,95 @@
+//! Caps on how many websockets one client IP can hold open at once, so a single client
+//! can't use up the server's connections.
+//!
+//! Hand a limit to UpgradeConfig::limit_per_ip and upgrade with upgrade_from, which knows
+//! the peer; handshakes over the limit are answered with a 429 instead of the 101:
+//!
+//! ```no_run
+//! # use async_std::{io::BufReader, net::TcpStream};
+//! # use oc_http::{websocket::{self, limit::ConnectionLimit, UpgradeConfig, WebSocketError}, Request};
+//! # async fn serve(req: &Request<'_>, stream: TcpStream, reader: BufReader<TcpStream>, peer: std::net::SocketAddr)
+//! #     -> Result<(), WebSocketError> {
+//! let config = UpgradeConfig::new().limit_per_ip(ConnectionLimit::new(4));
+//! let (rdr, wrt) = websocket::upgrade_from(&config, peer.ip(), &req, stream, reader.buffer()).await?;
+//! # Ok(())
+//! # }
+//! ```
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+type Counts = Mutex<HashMap<IpAddr, usize>>;
+
+/// Counts open connections per IP; clones share the counts.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max: usize,
+    counts: Arc<Counts>,
+}
+
+impl ConnectionLimit {
+    /// Allows up to `max` connections from each IP.
+    pub fn new(max: usize) -> Self {
+        ConnectionLimit{max, counts: Arc::default()}
+    }
+
+    /// Counts a connection from `ip` until the permit is dropped; None if the IP already has
+    /// as many as it's allowed.
+    pub fn try_acquire(&self, ip: IpAddr) -> Option<Permit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.get(&ip).copied().unwrap_or(0);
+        if count >= self.max {
+            return None;
+        }
+        counts.insert(ip, count + 1);
+        Some(Permit{ip, counts: self.counts.clone()})
+    }
+
+    /// How many connections from `ip` are open.
+    pub fn count(&self, ip: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// One connection counted against a ConnectionLimit; upgrade_from gives it to the reader,
+/// so it's held until the reader is dropped.
+pub struct Permit {
+    ip: IpAddr,
+    counts: Arc<Counts>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_acquire() {
+        let limit = ConnectionLimit::new(2);
+        let alice: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob: IpAddr = "::1".parse().unwrap();
+        let first = limit.try_acquire(alice).unwrap();
+        let _second = limit.try_acquire(alice).unwrap();
+        assert!(limit.try_acquire(alice).is_none());
+        assert!(limit.try_acquire(bob).is_some());
+        assert_eq!(limit.count(alice), 2);
+        drop(first);
+        assert!(limit.try_acquire(alice).is_some());
+        assert_eq!(limit.count(bob), 0);
+        assert!(limit.counts.lock().unwrap().get(&bob).is_none());
+        assert!(ConnectionLimit::new(0).try_acquire(bob).is_none());
+    }
+}
@@ -0,0 +1,89 @@
+//! Hooks for feeding websocket activity into whatever telemetry the application uses.
+//!
+//! Implement Metrics (every method defaults to doing nothing) and hand it to
+//! UpgradeConfig::metrics, or to set_metrics on a reader and writer made with from_stream.
+//! Counters is a ready-made implementation that just keeps totals.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use super::MessageType;
+
+pub trait Metrics: Send + Sync {
+    /// A reader was given these metrics; it counts as closed when the reader is dropped.
+    fn connection_opened(&self) {}
+    fn connection_closed(&self) {}
+    /// A complete message (or the start of a streamed one) was received.
+    fn message_received(&self, _typ: MessageType) {}
+    /// A message (or the first frame of a fragmented one) was queued to be sent.
+    fn message_sent(&self, _typ: MessageType) {}
+    /// Bytes read from or written to the stream, frame headers included.
+    fn bytes_received(&self, _count: usize) {}
+    fn bytes_sent(&self, _count: usize) {}
+    /// A Close frame carrying a status code was received or sent.
+    fn close_received(&self, _code: u16) {}
+    fn close_sent(&self, _code: u16) {}
+}
+
+/// Totals across every connection it's attached to.
+#[derive(Default)]
+pub struct Counters {
+    pub open: AtomicI64,
+    pub messages_in: AtomicU64,
+    pub messages_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// How often each close code was received from peers.
+    pub close_codes: Mutex<HashMap<u16, u64>>,
+}
+
+impl Metrics for Counters {
+    fn connection_opened(&self) {
+        self.open.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn connection_closed(&self) {
+        self.open.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn message_received(&self, _typ: MessageType) {
+        self.messages_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_sent(&self, _typ: MessageType) {
+        self.messages_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_received(&self, count: usize) {
+        self.bytes_in.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn bytes_sent(&self, count: usize) {
+        self.bytes_out.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn close_received(&self, code: u16) {
+        *self.close_codes.lock().unwrap().entry(code).or_default() += 1;
+    }
+}
+
+/// held by a reader for as long as the connection is open
+pub(crate) struct ConnectionMetrics(pub(crate) Arc<dyn Metrics>);
+
+impl ConnectionMetrics {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> Self {
+        metrics.connection_opened();
+        ConnectionMetrics(metrics)
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        self.0.connection_closed();
+    }
+}
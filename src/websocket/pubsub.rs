@@ -0,0 +1,262 @@
+//! Named topics that any part of the process can publish to, and websocket connections
+//! subscribe to; like the Hub's rooms, but the publisher doesn't need to know who's
+//! listening, and one subscription can cover many topics.
+//!
+//! Topics are dot-separated, like `metrics.cpu`. A `*` in a subscription stands for any one
+//! part, so `metrics.*` gets `metrics.cpu` and `metrics.memory` but not `metrics.cpu.user`.
+//! Each topic can have its own backpressure policy, so slow clients lose stale metrics
+//! rather than being cut off, while other topics still go through:
+//!
+//! ```no_run
+//! # use async_std::{net::TcpStream, task};
+//! # use oc_http::websocket::{pubsub::PubSub, queue::Backpressure, Message, WebSocketWriter};
+//! # async fn serve(wrt: WebSocketWriter<TcpStream>) {
+//! let pubsub = PubSub::new().policy("metrics.*", Backpressure::DropOldest);
+//! // from a handler
+//! let (id, send_loop) = pubsub.register(wrt);
+//! task::spawn(send_loop);
+//! pubsub.subscribe(id, "metrics.*");
+//! // from anywhere else, with a clone
+//! pubsub.publish("metrics.cpu", &Message::text("42")).await;
+//! # }
+//! ```
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use futures::{AsyncWrite, Future};
+
+use super::{
+    hub::DEFAULT_QUEUE_SIZE,
+    queue::{Backpressure, SendQueue},
+    Message,
+    SharedWebSocketWriter,
+    WebSocketError,
+};
+
+/// Identifies a connection registered with a PubSub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriberId(u64);
+
+/// The handle for publishing and subscribing; clones share the topics.
+#[derive(Clone)]
+pub struct PubSub {
+    inner: Arc<Mutex<PubSubState>>,
+}
+
+struct PubSubState {
+    next_id: u64,
+    queue_size: usize,
+    policy: Backpressure,
+    // (topic pattern, policy), in the order they were given
+    policies: Vec<(String, Backpressure)>,
+    subscribers: HashMap<SubscriberId, Subscriber>,
+}
+
+struct Subscriber {
+    queue: SendQueue,
+    patterns: BTreeSet<String>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        PubSub::with_queue(DEFAULT_QUEUE_SIZE, Backpressure::Disconnect)
+    }
+}
+
+impl PubSub {
+    /// Disconnects clients which fall DEFAULT_QUEUE_SIZE messages behind, unless the topic
+    /// has a policy of its own.
+    pub fn new() -> Self {
+        PubSub::default()
+    }
+
+    /// Each connection queues up to `queue_size` messages, with `policy` deciding what
+    /// happens once its queue is full, for topics without a policy of their own.
+    pub fn with_queue(queue_size: usize, policy: Backpressure) -> Self {
+        PubSub{
+            inner: Arc::new(Mutex::new(PubSubState{
+                next_id: 0,
+                queue_size,
+                policy,
+                policies: vec!(),
+                subscribers: HashMap::default(),
+            })),
+        }
+    }
+
+    /// Uses `policy` for topics matching `pattern`; the first pattern given that matches a
+    /// topic is the one used.
+    pub fn policy(self, pattern: &str, policy: Backpressure) -> Self {
+        self.inner.lock().unwrap().policies.push((pattern.into(), policy));
+        self
+    }
+
+    /// Adds a connection, subscribed to nothing yet, returning its id and the future that
+    /// writes what's published to it. Spawn the future; it finishes (and the connection is
+    /// unregistered) when the connection is unregistered or writing to it fails.
+    pub fn register<S, W>(&self, writer: W) -> (SubscriberId, impl Future<Output = Result<(), WebSocketError>>)
+    where S: AsyncWrite + Unpin,
+        W: Into<SharedWebSocketWriter<S>>,
+    {
+        let mut state = self.inner.lock().unwrap();
+        let (queue, drain) = SendQueue::new(writer, state.queue_size, state.policy);
+        let id = SubscriberId(state.next_id);
+        state.next_id += 1;
+        state.subscribers.insert(id, Subscriber{queue, patterns: BTreeSet::new()});
+        let pubsub = self.clone();
+        let send_loop = async move {
+            let res = drain.await;
+            pubsub.unregister(id);
+            res
+        };
+        (id, send_loop)
+    }
+
+    /// Drops the connection's subscriptions and stops its send loop once the messages
+    /// already queued have been written.
+    pub fn unregister(&self, id: SubscriberId) {
+        if let Some(subscriber) = self.inner.lock().unwrap().subscribers.remove(&id) {
+            subscriber.queue.close();
+        }
+    }
+
+    /// Subscribes the connection to a topic, or with `*`s, to the topics matching it.
+    /// Unknown connections are ignored.
+    pub fn subscribe(&self, id: SubscriberId, pattern: &str) {
+        if let Some(subscriber) = self.inner.lock().unwrap().subscribers.get_mut(&id) {
+            subscriber.patterns.insert(pattern.into());
+        }
+    }
+
+    /// Drops a subscription made with the same pattern.
+    pub fn unsubscribe(&self, id: SubscriberId, pattern: &str) {
+        if let Some(subscriber) = self.inner.lock().unwrap().subscribers.get_mut(&id) {
+            subscriber.patterns.remove(pattern);
+        }
+    }
+
+    /// The connection's subscriptions, sorted.
+    pub fn subscriptions(&self, id: SubscriberId) -> Vec<String> {
+        let state = self.inner.lock().unwrap();
+        state.subscribers.get(&id)
+            .map(|subscriber| subscriber.patterns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Queues the message for every connection subscribed to the topic (once each, however
+    /// many of its subscriptions match), returning how many it was queued for. With
+    /// Backpressure::Block this waits for room in every subscriber's queue.
+    pub async fn publish(&self, topic: &str, msg: &Message) -> usize {
+        let (queues, policy) = {
+            let state = self.inner.lock().unwrap();
+            let policy = state.policies.iter()
+                .find(|(pattern, _)| matches(pattern, topic))
+                .map_or(state.policy, |(_, policy)| *policy);
+            let queues: Vec<SendQueue> = state.subscribers.values()
+                .filter(|subscriber| subscriber.patterns.iter().any(|pattern| matches(pattern, topic)))
+                .map(|subscriber| subscriber.queue.clone())
+                .collect();
+            (queues, policy)
+        };
+        let mut count = 0;
+        for queue in queues {
+            if queue.send_with(msg.clone(), policy).await {
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+/// whether the topic matches the pattern, part by part
+fn matches(pattern: &str, topic: &str) -> bool {
+    let mut topic = topic.split('.');
+    pattern.split('.').all(|part| topic.next().is_some_and(|t| part == "*" || part == t))
+        && topic.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use async_std::{
+        task,
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+    };
+
+    use super::*;
+    use crate::websocket::{from_stream, Role, WebSocketReader, WebSocketWriter};
+
+    async fn pair() -> (WebSocketWriter<TcpStream>, WebSocketReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, wrt) = from_stream(server, Role::Server);
+        let (rdr, _) = from_stream(client, Role::Client);
+        (wrt, rdr)
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("metrics.cpu", "metrics.cpu"));
+        assert!(matches("metrics.*", "metrics.cpu"));
+        assert!(matches("*.cpu", "metrics.cpu"));
+        assert!(!matches("metrics.*", "metrics.cpu.user"));
+        assert!(!matches("metrics.*", "metrics"));
+        assert!(!matches("metrics", "metrics.cpu"));
+        assert!(!matches("metrics.cpu", "metrics.memory"));
+    }
+
+    #[async_std::test]
+    async fn test_publish() -> Result<(), Box<dyn Error>> {
+        let pubsub = PubSub::new();
+        let (wrt, mut metrics) = pair().await;
+        let (everything, send_loop) = pubsub.register(wrt);
+        task::spawn(send_loop);
+        let (wrt, mut cpu) = pair().await;
+        let (cpu_only, send_loop) = pubsub.register(wrt);
+        task::spawn(send_loop);
+        pubsub.subscribe(everything, "metrics.*");
+        pubsub.subscribe(everything, "metrics.cpu");
+        pubsub.subscribe(cpu_only, "metrics.cpu");
+        assert_eq!(pubsub.subscriptions(everything), vec!("metrics.*", "metrics.cpu"));
+
+        // handlers publish from clones
+        let publisher = pubsub.clone();
+        assert_eq!(publisher.publish("metrics.cpu", &Message::text("cpu 42")).await, 2);
+        assert_eq!(publisher.publish("metrics.memory", &Message::text("mem 7")).await, 1);
+        assert_eq!(publisher.publish("chat", &Message::text("hi")).await, 0);
+        pubsub.unsubscribe(cpu_only, "metrics.cpu");
+        assert_eq!(publisher.publish("metrics.cpu", &Message::text("cpu 43")).await, 1);
+
+        for text in &["cpu 42", "mem 7", "cpu 43"] {
+            assert_eq!(metrics.recv().await.unwrap(), Message::text(text));
+        }
+        assert_eq!(cpu.recv().await.unwrap(), Message::text("cpu 42"));
+        pubsub.unregister(everything);
+        assert!(pubsub.subscriptions(everything).is_empty());
+        assert_eq!(publisher.publish("metrics.memory", &Message::text("mem 8")).await, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_topic_policy() {
+        let pubsub = PubSub::with_queue(1, Backpressure::Disconnect).policy("metrics.*", Backpressure::DropNewest);
+        let (wrt, mut rdr) = pair().await;
+        // not draining yet, so the queue fills up
+        let (id, send_loop) = pubsub.register(wrt);
+        pubsub.subscribe(id, "*");
+        pubsub.subscribe(id, "metrics.*");
+        assert_eq!(pubsub.publish("metrics.cpu", &Message::text("1")).await, 1);
+        assert_eq!(pubsub.publish("metrics.cpu", &Message::text("2")).await, 0);
+        // a topic without a policy of its own gives up on the connection
+        assert_eq!(pubsub.publish("alerts", &Message::text("3")).await, 0);
+        assert!(matches!(send_loop.await, Err(WebSocketError::TooSlow)));
+        assert!(pubsub.subscriptions(id).is_empty());
+        assert!(rdr.recv().await.is_err());
+    }
+}
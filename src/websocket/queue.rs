@@ -0,0 +1,332 @@
+//! A bounded queue of outgoing messages in front of a writer, so that a client that reads
+//! slowly (or not at all) can't make its senders buffer without limit.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    task::Waker,
+};
+
+use futures::{
+    future::{self, Either},
+    pin_mut,
+    task::{Context, Poll},
+    AsyncWrite,
+    Future,
+};
+
+use super::{Message, SharedWebSocketWriter, WebSocketError};
+
+/// What to do with a message sent to a queue that's already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for room in the queue.
+    Block,
+    /// Make room by dropping the oldest queued message.
+    DropOldest,
+    /// Drop the message being sent.
+    DropNewest,
+    /// Give up on the connection; queued messages are discarded and the stream is closed.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Open,
+    Closed,
+    Disconnected,
+}
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    capacity: usize,
+    policy: Backpressure,
+    status: Status,
+    // number of SendQueue handles; the queue closes when the last one goes away
+    handles: usize,
+    drain_waker: Option<Waker>,
+    space_wakers: Vec<Waker>,
+}
+
+impl QueueState {
+    fn wake_drain(&mut self) {
+        if let Some(waker) = self.drain_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_senders(&mut self) {
+        for waker in self.space_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn close(&mut self, status: Status) {
+        if self.status == Status::Open {
+            self.status = status;
+        }
+        if self.status == Status::Disconnected {
+            self.messages.clear();
+        }
+        self.wake_drain();
+        self.wake_senders();
+    }
+}
+
+/// Closes the queue once the drain future is done with it, however it finishes (or is
+/// dropped), so that blocked senders give up and nothing is left queued for nobody.
+struct Drained(Arc<Mutex<QueueState>>);
+
+impl Drop for Drained {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        state.close(Status::Closed);
+        state.messages.clear();
+    }
+}
+
+/// A clone-able handle for queueing messages to one connection.
+pub struct SendQueue {
+    inner: Arc<Mutex<QueueState>>,
+}
+
+impl SendQueue {
+    /// Creates a queue holding up to `capacity` messages for the writer, returning the handle
+    /// and the future that writes queued messages out. Spawn the future; it finishes once the
+    /// queue is closed and emptied, when writing fails, or with WebSocketError::TooSlow if
+    /// the Disconnect policy kicks in. However it finishes, the queue is closed then, and
+    /// whatever's still queued is dropped.
+    pub fn new<S, W>(writer: W, capacity: usize, policy: Backpressure) -> (SendQueue, impl Future<Output = Result<(), WebSocketError>>)
+    where S: AsyncWrite + Unpin,
+        W: Into<SharedWebSocketWriter<S>>,
+    {
+        let writer = writer.into();
+        let inner = Arc::new(Mutex::new(QueueState{
+            messages: VecDeque::new(),
+            capacity: capacity.max(1),
+            policy,
+            status: Status::Open,
+            handles: 1,
+            drain_waker: None,
+            space_wakers: vec!(),
+        }));
+        let drained = Drained(inner.clone());
+        let drain = async move {
+            let state = &drained.0;
+            loop {
+                let msg = future::poll_fn(|cx| poll_next(state, cx)).await;
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => break,
+                };
+                // stop writing as soon as the connection is dropped for being too slow
+                let write = writer.write(&msg);
+                let kicked = future::poll_fn(|cx| poll_disconnected(state, cx));
+                pin_mut!(write, kicked);
+                if let Either::Left((res, _)) = future::select(write, kicked).await {
+                    res?;
+                }
+            }
+            if state.lock().unwrap().status == Status::Disconnected {
+                writer.close_stream().await;
+                return Err(WebSocketError::TooSlow);
+            }
+            Ok(())
+        };
+        (SendQueue{inner}, drain)
+    }
+
+    /// Queues the message, applying the backpressure policy if the queue is full. Returns
+    /// whether the message was queued.
+    pub async fn send(&self, msg: Message) -> bool {
+        self.send_policy(msg, None).await
+    }
+
+    /// Like send, but applies `policy` instead of the queue's own if the queue is full; e.g.
+    /// to drop stale updates of one kind while still blocking for others.
+    pub async fn send_with(&self, msg: Message, policy: Backpressure) -> bool {
+        self.send_policy(msg, Some(policy)).await
+    }
+
+    async fn send_policy(&self, msg: Message, policy: Option<Backpressure>) -> bool {
+        let mut msg = Some(msg);
+        future::poll_fn(|cx| {
+            let mut state = self.inner.lock().unwrap();
+            if state.status != Status::Open {
+                return Poll::Ready(false);
+            }
+            if state.messages.len() >= state.capacity {
+                match policy.unwrap_or(state.policy) {
+                    Backpressure::Block => {
+                        state.space_wakers.push(cx.waker().clone());
+                        return Poll::Pending;
+                    },
+                    Backpressure::DropOldest => {
+                        state.messages.pop_front();
+                    },
+                    Backpressure::DropNewest => return Poll::Ready(false),
+                    Backpressure::Disconnect => {
+                        state.close(Status::Disconnected);
+                        return Poll::Ready(false);
+                    },
+                }
+            }
+            state.messages.push_back(msg.take().unwrap());
+            state.wake_drain();
+            Poll::Ready(true)
+        }).await
+    }
+
+    /// Number of messages waiting to be written.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stops accepting messages; the ones already queued are still written.
+    pub fn close(&self) {
+        self.inner.lock().unwrap().close(Status::Closed);
+    }
+
+    /// Queues a last message, like a Close, even if the queue is full, then closes it.
+    pub fn close_with(&self, msg: Message) {
+        let mut state = self.inner.lock().unwrap();
+        if state.status == Status::Open {
+            state.messages.push_back(msg);
+        }
+        state.close(Status::Closed);
+    }
+}
+
+impl Clone for SendQueue {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().handles += 1;
+        SendQueue{
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl Drop for SendQueue {
+    fn drop(&mut self) {
+        let mut state = self.inner.lock().unwrap();
+        state.handles -= 1;
+        if state.handles == 0 {
+            state.close(Status::Closed);
+        }
+    }
+}
+
+fn poll_next(state: &Mutex<QueueState>, cx: &mut Context) -> Poll<Option<Message>> {
+    let mut state = state.lock().unwrap();
+    if let Some(msg) = state.messages.pop_front() {
+        state.wake_senders();
+        return Poll::Ready(Some(msg));
+    }
+    if state.status != Status::Open {
+        return Poll::Ready(None);
+    }
+    state.drain_waker = Some(cx.waker().clone());
+    Poll::Pending
+}
+
+fn poll_disconnected(state: &Mutex<QueueState>, cx: &mut Context) -> Poll<()> {
+    let mut state = state.lock().unwrap();
+    if state.status == Status::Disconnected {
+        return Poll::Ready(());
+    }
+    state.drain_waker = Some(cx.waker().clone());
+    Poll::Pending
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Shutdown, time::Duration};
+    use async_std::{
+        future::timeout,
+        task,
+        net::{
+            TcpListener,
+            TcpStream,
+        },
+    };
+
+    use super::*;
+    use crate::websocket::{from_stream, Role, WebSocketReader, WebSocketWriter};
+
+    async fn pair() -> (WebSocketWriter<TcpStream>, WebSocketReader<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, wrt) = from_stream(server, Role::Server);
+        let (rdr, _) = from_stream(client, Role::Client);
+        (wrt, rdr)
+    }
+
+    #[async_std::test]
+    async fn test_drop_policies() {
+        for (policy, expected) in &[(Backpressure::DropNewest, ["1", "2"]), (Backpressure::DropOldest, ["2", "3"])] {
+            let (wrt, mut rdr) = pair().await;
+            let (queue, drain) = SendQueue::new(wrt, 2, *policy);
+            assert!(queue.send(Message::text("1")).await);
+            assert!(queue.send(Message::text("2")).await);
+            assert_eq!(queue.send(Message::text("3")).await, *policy == Backpressure::DropOldest);
+            assert_eq!(queue.len(), 2);
+            drop(queue);
+            task::spawn(drain).await.unwrap();
+            for text in expected {
+                assert_eq!(rdr.recv().await.unwrap(), Message::text(text));
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn test_disconnect_policy() {
+        let (wrt, mut rdr) = pair().await;
+        let (queue, drain) = SendQueue::new(wrt, 1, Backpressure::Disconnect);
+        assert!(queue.send(Message::text("1")).await);
+        assert!(!queue.send(Message::text("2")).await);
+        assert!(!queue.send(Message::text("3")).await);
+        assert!(queue.is_empty());
+        assert!(matches!(drain.await, Err(WebSocketError::TooSlow)));
+        assert!(rdr.recv().await.is_err());
+    }
+
+    #[async_std::test]
+    async fn test_block_policy() {
+        let (wrt, mut rdr) = pair().await;
+        let (queue, drain) = SendQueue::new(wrt, 1, Backpressure::Block);
+        assert!(queue.send(Message::text("1")).await);
+        assert!(timeout(Duration::from_millis(50), queue.send(Message::text("2"))).await.is_err());
+        let drain = task::spawn(drain);
+        assert!(queue.send(Message::text("2")).await);
+        queue.close();
+        drain.await.unwrap();
+        assert_eq!(rdr.recv().await.unwrap(), Message::text("1"));
+        assert_eq!(rdr.recv().await.unwrap(), Message::text("2"));
+    }
+
+    #[async_std::test]
+    async fn test_block_policy_write_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        // so that the first write fails
+        server.shutdown(Shutdown::Write).unwrap();
+        let (_, wrt) = from_stream(server, Role::Server);
+        let (queue, drain) = SendQueue::new(wrt, 1, Backpressure::Block);
+        assert!(queue.send(Message::text("1")).await);
+        let blocked = task::spawn({
+            let queue = queue.clone();
+            async move { queue.send(Message::text("2")).await }
+        });
+        task::sleep(Duration::from_millis(50)).await;
+        assert!(drain.await.is_err());
+        blocked.await;
+        // the queue's closed, rather than full with nobody draining it
+        assert!(!timeout(Duration::from_secs(1), queue.send(Message::text("3"))).await.unwrap());
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,302 @@
+//! A client connection that dials again when it's lost, for clients that should stay
+//! connected (feeds, dashboards, bots).
+//!
+//! The crate doesn't dial websockets itself, so ReconnectingClient takes a closure that
+//! does: connect and handshake, then hand back the reader and writer (from_stream helps
+//! there). Messages that set the connection up, like logging in or subscribing, come from
+//! on_connect, so they're sent again on every new connection:
+//!
+//! ```no_run
+//! # use async_std::net::TcpStream;
+//! # use log::info;
+//! # use oc_http::websocket::{self, reconnect::ReconnectingClient, Message, Role, WebSocketError};
+//! # async fn client_handshake(stream: TcpStream, path: &str) -> Result<(), WebSocketError> { Ok(()) }
+//! # async fn serve() {
+//! let mut client = ReconnectingClient::new(|| async {
+//!     let stream = TcpStream::connect("feed.example:80").await?;
+//!     client_handshake(stream.clone(), "/ws").await?;
+//!     Ok(websocket::from_stream(stream, Role::Client))
+//! })
+//! .on_connect(|| vec!(Message::text(r#"{"subscribe": "prices"}"#)))
+//! .on_state(|state| info!("feed: {:?}", state));
+//! while let Ok(msg) = client.recv().await {
+//!     // ...
+//! }
+//! # }
+//! ```
+use std::time::Duration;
+
+use futures::{AsyncRead, AsyncWrite, Future};
+
+use super::{Message, MessageType, SharedWebSocketWriter, WebSocketError, WebSocketReader, WebSocketWriter};
+
+/// How long to wait between attempts to dial.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// Before the first attempt after losing the connection.
+    pub initial: Duration,
+    /// The longest wait, however many attempts have failed.
+    pub max: Duration,
+    /// What each failed attempt multiplies the wait by.
+    pub multiplier: f64,
+    /// Up to this fraction of each wait (0 to 1) is taken off at random, so clients that
+    /// lost their connections together don't all come back together.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff{
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait before the attempt after `failures` failed ones.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let max = self.max.as_secs_f64();
+        let delay = (self.initial.as_secs_f64() * self.multiplier.powi(failures.min(64) as i32)).min(max);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::random::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// Reported to on_state as the connection comes and goes.
+#[derive(Debug, Clone)]
+pub enum ConnectionState {
+    /// Dialing; `attempt` counts from 0 since the connection was last up.
+    Connecting{attempt: u32},
+    /// Dialed, with the on_connect messages sent.
+    Connected,
+    /// The connection was lost, or dialing failed.
+    Disconnected(WebSocketError),
+    /// max_attempts ran out; recv and send return the last error from now on.
+    GaveUp,
+}
+
+type Hello = Box<dyn FnMut() -> Vec<Message> + Send>;
+type StateListener = Box<dyn FnMut(&ConnectionState) + Send>;
+
+/// A websocket client connection that's dialed again, with backoff, whenever it's lost.
+pub struct ReconnectingClient<S, D>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    dial: D,
+    backoff: Backoff,
+    max_attempts: Option<u32>,
+    hello: Option<Hello>,
+    on_state: Option<StateListener>,
+    connection: Option<(WebSocketReader<S>, SharedWebSocketWriter<S>)>,
+    // whether there's been a connection to lose; the first dial doesn't wait
+    dialed: bool,
+    gave_up: Option<WebSocketError>,
+}
+
+impl<S, D, F> ReconnectingClient<S, D>
+where S: AsyncRead + AsyncWrite + Unpin,
+    D: FnMut() -> F,
+    F: Future<Output = Result<(WebSocketReader<S>, WebSocketWriter<S>), WebSocketError>>,
+{
+    /// Doesn't dial until the first recv or send.
+    pub fn new(dial: D) -> Self {
+        ReconnectingClient{
+            dial,
+            backoff: Backoff::default(),
+            max_attempts: None,
+            hello: None,
+            on_state: None,
+            connection: None,
+            dialed: false,
+            gave_up: None,
+        }
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Gives up after this many failed attempts in a row; by default it never does.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// The messages to send first on every new connection.
+    pub fn on_connect<H>(mut self, hello: H) -> Self
+    where H: FnMut() -> Vec<Message> + Send + 'static
+    {
+        self.hello = Some(Box::new(hello));
+        self
+    }
+
+    pub fn on_state<L>(mut self, listener: L) -> Self
+    where L: FnMut(&ConnectionState) + Send + 'static
+    {
+        self.on_state = Some(Box::new(listener));
+        self
+    }
+
+    /// The next message, dialing again as often as it takes. Pings are answered along the
+    /// way; a Close from the server counts as losing the connection.
+    pub async fn recv(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            self.connect().await?;
+            let (rdr, _) = self.connection.as_mut().unwrap();
+            match rdr.recv().await {
+                Ok(msg) if msg.typ != MessageType::Close => return Ok(msg),
+                Ok(_) => self.lost(WebSocketError::ConnectionClosed),
+                Err(err) => self.lost(err),
+            }
+        }
+    }
+
+    /// Sends the message, dialing first if there's no connection. If the write fails the
+    /// connection is dropped and the error returned; whether the message got there isn't
+    /// known, so it isn't sent again.
+    pub async fn send(&mut self, msg: &Message) -> Result<(), WebSocketError> {
+        self.connect().await?;
+        let (_, wrt) = self.connection.as_ref().unwrap();
+        if let Err(err) = wrt.write(msg).await {
+            self.lost(err.clone());
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// The current connection's writer, to send on from other tasks; it isn't replaced
+    /// when the connection is, so ask again after reconnecting.
+    pub fn writer(&self) -> Option<SharedWebSocketWriter<S>> {
+        self.connection.as_ref().map(|(_, wrt)| wrt.clone())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Sends a Close on the current connection, if there is one, and stops.
+    pub async fn close(mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        match self.connection.take() {
+            Some((_, wrt)) => wrt.write(&Message::close(code, reason)).await,
+            None => Ok(()),
+        }
+    }
+
+    fn report(&mut self, state: ConnectionState) {
+        if let Some(listener) = &mut self.on_state {
+            listener(&state);
+        }
+    }
+
+    fn lost(&mut self, err: WebSocketError) {
+        self.connection = None;
+        self.report(ConnectionState::Disconnected(err));
+    }
+
+    async fn connect(&mut self) -> Result<(), WebSocketError> {
+        if let Some(err) = &self.gave_up {
+            return Err(err.clone());
+        }
+        let mut failures = 0;
+        while self.connection.is_none() {
+            if self.dialed {
+                futures_timer::Delay::new(self.backoff.delay(failures)).await;
+            }
+            self.dialed = true;
+            self.report(ConnectionState::Connecting{attempt: failures});
+            let err = match self.dial_once().await {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            self.report(ConnectionState::Disconnected(err.clone()));
+            failures += 1;
+            if self.max_attempts.is_some_and(|max| failures >= max) {
+                self.gave_up = Some(err.clone());
+                self.report(ConnectionState::GaveUp);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    async fn dial_once(&mut self) -> Result<(), WebSocketError> {
+        let (mut rdr, wrt) = (self.dial)().await?;
+        let wrt = wrt.into_shared();
+        rdr.answer_pings(wrt.clone());
+        let hello = self.hello.as_mut().map(|hello| hello()).unwrap_or_default();
+        for msg in &hello {
+            wrt.feed(msg).await?;
+        }
+        wrt.flush().await?;
+        self.connection = Some((rdr, wrt));
+        self.report(ConnectionState::Connected);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use async_std::{
+        net::{TcpListener, TcpStream},
+        task,
+    };
+
+    use super::*;
+    use crate::websocket::{from_stream, Role};
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff{jitter: 0.0, ..Backoff::default()};
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(100), Duration::from_secs(30));
+        for _ in 0..100 {
+            let delay = Backoff::default().delay(1);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200), "{:?}", delay);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // each connection gets its hello echoed back and a count, then is dropped
+        let server = task::spawn(async move {
+            for count in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut rdr, mut wrt) = from_stream(stream, Role::Server);
+                let hello = rdr.recv().await.unwrap();
+                wrt.write(&hello).await.unwrap();
+                wrt.write(&Message::text(&count.to_string())).await.unwrap();
+            }
+        });
+        let states = Arc::new(Mutex::new(vec!()));
+        let sink = states.clone();
+        let fast = Backoff{initial: Duration::from_millis(1), ..Backoff::default()};
+        let mut client = ReconnectingClient::new(move || async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(from_stream(stream, Role::Client))
+        })
+        .backoff(fast)
+        .max_attempts(3)
+        .on_connect(|| vec!(Message::text("hello")))
+        .on_state(move |state| sink.lock().unwrap().push(format!("{:?}", state)));
+        for count in &["0", "1"] {
+            assert_eq!(client.recv().await.unwrap(), Message::text("hello"));
+            assert_eq!(client.recv().await.unwrap(), Message::text(count));
+        }
+        server.await;
+        assert!(client.recv().await.is_err());
+        assert!(!client.is_connected());
+        assert!(client.send(&Message::text("anyone?")).await.is_err());
+        let states = states.lock().unwrap();
+        assert_eq!(&states[..4], &["Connecting { attempt: 0 }", "Connected", "Disconnected(ConnectionClosed)", "Connecting { attempt: 0 }"]);
+        assert_eq!(states.last().unwrap(), "GaveUp");
+        assert_eq!(states.iter().filter(|s| s.starts_with("Connecting")).count(), 5);
+    }
+}